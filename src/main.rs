@@ -4,43 +4,64 @@ extern crate log;
 extern crate lazy_static;
 extern crate core;
 
-use std::collections::HashMap;
-use fyrox_sound::context::SoundContext;
-use fyrox_sound::engine::SoundEngine;
-use glad_gl::gl::*;
 use std::env::args;
-use std::ops::Deref;
-use std::process;
 use std::sync::atomic::Ordering;
-use std::time::Instant;
 
-use crate::input::keyboard::HTKey;
-use crate::input::{ keyboard, mouse };
 use crate::optimisations::helpers;
-use crate::renderer::{ MutRenderer, RGBA };
-use crate::server::lan::ClientLanConnection;
-use crate::server::ConnectionClientside;
-use crate::ui_defs::chat;
-use crate::worldmachine::player::DEFAULT_FOV;
+use crate::server::accounts::{ Authenticator, Credentials };
+use crate::server::admin::ADMIN_PASSWORD_VAR;
+use crate::server::config::ServerConfig;
+use crate::server::savegame::SaveGame;
+
+#[cfg(feature = "graphical")]
+use {
+    fyrox_sound::context::SoundContext,
+    fyrox_sound::engine::SoundEngine,
+    glad_gl::gl::*,
+    std::ops::Deref,
+    std::process,
+    std::time::{ Duration, Instant },
+    crate::input::keyboard::HTKey,
+    crate::input::{ keyboard, mouse },
+    crate::renderer::{ MutRenderer, RGBA },
+    crate::server::accounts::{ load_session_token, save_session_token },
+    crate::server::identity::{ fingerprint, known_server, ServerTrust },
+    crate::server::lan::{ ClientConnectError, ClientLanConnection },
+    crate::server::disconnect::DisconnectReason,
+    crate::server::savegame::{ take_session_request, SessionRequest },
+    crate::server::websocket::ClientWebSocketConnection,
+    crate::server::{ ConnectionClientside, ServerAddress },
+    crate::ui_defs::chat,
+    crate::worldmachine::player::DEFAULT_FOV,
+};
 
 pub mod animation;
 pub mod animgraph;
+#[cfg(feature = "graphical")]
 pub mod audio;
 pub mod camera;
 pub mod common_anim;
+#[cfg(feature = "graphical")]
 pub mod introsnd;
 pub mod light;
+#[cfg(feature = "graphical")]
 pub mod meshes;
 pub mod optimisations;
 pub mod physics;
+#[cfg(feature = "graphical")]
 pub mod renderer;
 pub mod server;
+#[cfg(feature = "graphical")]
 pub mod shaders;
+#[cfg(feature = "graphical")]
 pub mod skeletal_animation;
+#[cfg(feature = "graphical")]
 pub mod textures;
+#[cfg(feature = "graphical")]
 pub mod ui;
 pub mod ui_defs;
 pub mod worldmachine;
+#[cfg(feature = "graphical")]
 pub mod input;
 pub mod firebase;
 
@@ -52,205 +73,471 @@ async fn main() {
     //firebase::db_initialize::db_start().await;
 
     let mut args = args();
-    let (skip_intro, level_to_load, run_as_lan_server, connect_to_lan_server) = parse_arguments(
-        &mut args
-    );
+    let arguments = parse_arguments(&mut args);
+    let save_to_load = arguments.save_to_load.as_ref().map(|name| {
+        SaveGame::read(name).unwrap_or_else(|e| panic!("failed to read save {}: {:?}", name, e))
+    });
 
-    let start_time = Instant::now();
+    if arguments.run_as_lan_server || arguments.run_as_websocket_server || !cfg!(feature = "graphical") {
+        run_server(arguments, save_to_load).await;
+    } else {
+        #[cfg(feature = "graphical")]
+        run_client(arguments, save_to_load).await;
+    }
+}
 
-    if run_as_lan_server {
-        let physics = physics::PhysicsSystem::init();
-        let server = server::Server::new_host_lan_server(
-            &level_to_load.unwrap_or("lava".to_string()),
-            physics,
-            25566,
-            25567,
-            "0.0.0.0"
-        ).await;
-        let server_clone_a = server.clone();
-        let server_clone_b = server.clone();
-        let mut server_clone_c = server.clone();
-        info!("initialized server");
-        tokio::spawn(async move {
-            server_clone_a.physics_thread().await;
+async fn run_server(arguments: Arguments, save_to_load: Option<SaveGame>) {
+    let Arguments {
+        level_to_load,
+        mut run_as_lan_server,
+        admin_console,
+        run_as_websocket_server,
+        enable_accounts,
+        require_login,
+        allow_registration,
+        config_path,
+        config_overrides,
+        ..
+    } = arguments;
+    if !run_as_lan_server && !run_as_websocket_server {
+        info!("no server type given, hosting a lan server");
+        run_as_lan_server = true;
+    }
+    let config = ServerConfig::load(config_path.as_deref())
+        .and_then(|config| config.apply_overrides(&config_overrides))
+        .and_then(|config| config.validate().map(|_| config))
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
         });
-        tokio::spawn(async move {
-            server_clone_b.player_and_physics_tick_thread().await;
+
+    let physics = physics::PhysicsSystem::init();
+    let server = match &save_to_load {
+        Some(save) => server::Server::new_from_save(save, physics).expect("failed to load save"),
+        None => server::Server::new(level_to_load.as_deref().unwrap_or(config.starting_map()), physics),
+    };
+    server.set_config(config.clone());
+    let network = &config.network;
+    if run_as_lan_server {
+        server.host_lan(&network.bind_address, network.lan_tcp_port, network.lan_udp_port).await;
+        if network.discoverable {
+            server.host_discovery(network.lan_tcp_port, network.lan_udp_port).await;
+        }
+    }
+    if run_as_websocket_server {
+        server.host_websocket(&network.bind_address, network.websocket_port).await;
+    }
+    if enable_accounts || require_login || allow_registration {
+        let authenticator = Authenticator::from_env(require_login, allow_registration).unwrap_or_else(|e| {
+            error!("failed to set up accounts: {}", e);
+            std::process::exit(1);
         });
-        server_clone_c.run().await;
-    } else {
-        let sengine = SoundEngine::new();
-        let scontext = SoundContext::new();
-        sengine.lock().unwrap().add_context(scontext.clone());
-
-        let audio = crate::audio::AudioBackend::new();
-        audio.load_sound("donk.wav");
-        info!("initialized audio subsystem");
-
-        let renderer = MutRenderer::init();
-        if renderer.is_err() {
-            error!("failed to initialize renderer");
-            error!("{:?}", renderer.err());
-            return;
+        server.enable_authentication(authenticator);
+        info!("accounts enabled (login required: {})", require_login);
+    }
+    let mut server_clone_c = server.clone();
+    info!("initialized server");
+    let server_clone_e = server.clone();
+    tokio::spawn(async move {
+        server_clone_e.map_rotation_thread().await;
+    });
+    if let Some(address) = &admin_console {
+        match std::env::var(ADMIN_PASSWORD_VAR) {
+            Ok(password) if !password.is_empty() => {
+                server.host_admin_console(address, &password).await;
+            }
+            _ => error!("not starting the admin console, {} is not set", ADMIN_PASSWORD_VAR),
         }
-        let mut renderer = renderer.unwrap();
-        renderer.initialise_basic_resources();
-        info!("initialized renderer");
+    }
+    let server_clone_d = server.clone();
+    tokio::spawn(async move {
+        server_clone_d.console_thread().await;
+    });
+    server_clone_c.run().await;
+}
 
-        if !skip_intro {
-            introsnd::animate(&mut renderer, &scontext);
-        }
+#[cfg(feature = "graphical")]
+#[allow(unused_must_use)]
+async fn run_client(arguments: Arguments, save_to_load: Option<SaveGame>) {
+    let Arguments {
+        skip_intro,
+        level_to_load,
+        connect_to_lan_server,
+        reliable_udp,
+        connect_to_websocket_server,
+        credentials,
+        ..
+    } = arguments;
+    let start_time = Instant::now();
 
-        let physics = physics::PhysicsSystem::init();
-        info!("initialized physics");
-        let mut player = worldmachine::player::Player::default();
-        let mut worldmachine = worldmachine::WorldMachine::default();
-        worldmachine.initialise(physics.clone(), false);
-        info!("initialized worldmachine");
-
-        if let Some(ip) = connect_to_lan_server {
-            let (server_connection, tcpstream, tcpreceiver) = ClientLanConnection::connect(
-                ip.as_str(),
-                25566,
-                25567
-            ).await.expect("failed to connect to server");
-            worldmachine.connect_to_server(ConnectionClientside::Lan(server_connection.clone()));
-            let the_clone = server_connection.clone();
-            tokio::spawn(async move {
-                the_clone.udp_listener_thread().await;
-            });
-            let the_clone = server_connection.clone();
-            tokio::spawn(async move {
-                the_clone.tcp_listener_thread(tcpstream, tcpreceiver).await;
-            });
-        } else {
-            let mut server = server::Server::new(
+    let sengine = SoundEngine::new();
+    let scontext = SoundContext::new();
+    sengine.lock().unwrap().add_context(scontext.clone());
+
+    let audio = crate::audio::AudioBackend::new();
+    audio.load_sound("donk.wav");
+    info!("initialized audio subsystem");
+
+    let renderer = MutRenderer::init();
+    if renderer.is_err() {
+        error!("failed to initialize renderer");
+        error!("{:?}", renderer.err());
+        return;
+    }
+    let mut renderer = renderer.unwrap();
+    renderer.initialise_basic_resources();
+    info!("initialized renderer");
+
+    if !skip_intro {
+        introsnd::animate(&mut renderer, &scontext);
+    }
+
+    let physics = physics::PhysicsSystem::init();
+    info!("initialized physics");
+    let mut player = worldmachine::player::Player::default();
+    let mut worldmachine = worldmachine::WorldMachine::default();
+    worldmachine.initialise(physics.clone(), false);
+    info!("initialized worldmachine");
+
+    let mut local_server = None;
+
+    if let Some(ip) = connect_to_lan_server {
+        join_lan_server(&mut worldmachine, &ip, 25566, 25567, reliable_udp, credentials, None).await;
+    } else if let Some(url) = connect_to_websocket_server {
+        join_websocket_server(&mut worldmachine, &url, credentials, None).await;
+    } else {
+        let mut server = match &save_to_load {
+            Some(save) => server::Server::new_from_save(save, physics.clone())
+                .expect("failed to load save"),
+            None => server::Server::new(
                 &level_to_load.unwrap_or("lava".to_string()),
                 physics.clone()
-            );
-            let server_clone_a = server.clone();
-            let server_clone_b = server.clone();
-            let mut server_clone_c = server.clone();
-            tokio::spawn(async move {
-                server_clone_a.physics_thread().await;
-            });
-            tokio::spawn(async move {
-                server_clone_b.player_and_physics_tick_thread().await;
-            });
-            tokio::spawn(async move {
-                server_clone_c.run().await;
-            });
-            let server_connection = server.join_local_server().await;
-            worldmachine.connect_to_server(ConnectionClientside::Local(server_connection));
-        }
+            ),
+        };
+        start_local_server(&server);
+        let server_connection = server.join_local_server(true).await;
+        worldmachine.connect_to_server(ConnectionClientside::Local(server_connection));
+        local_server = Some(server);
+    }
 
-        debug!("connected to server");
-
-        renderer.load_mesh_if_not_loaded("player");
-
-        unsafe {
-            let lighting_shader = *renderer.shaders.get("lighting").unwrap();
-            helpers::set_shader_if_not_set(&mut renderer, lighting_shader);
-            let lighting_shader = renderer.backend.shaders
-                .as_ref()
-                .unwrap()
-                .get(lighting_shader)
-                .unwrap();
-            static USE_SHADOWS_C: &'static str = "use_shadows\0";
-            let use_shadows_loc = GetUniformLocation(
-                lighting_shader.program,
-                USE_SHADOWS_C.as_ptr() as *const GLchar
-            );
-            Uniform1i(use_shadows_loc, 1);
-        }
+    debug!("connected to server");
 
-        renderer.backend.clear_colour.store(
-            RGBA {
-                r: 0,
-                g: 0,
-                b: 0,
-                a: 255,
-            },
-            Ordering::SeqCst
+    renderer.load_mesh_if_not_loaded("player");
+
+    unsafe {
+        let lighting_shader = *renderer.shaders.get("lighting").unwrap();
+        helpers::set_shader_if_not_set(&mut renderer, lighting_shader);
+        let lighting_shader = renderer.backend.shaders
+            .as_ref()
+            .unwrap()
+            .get(lighting_shader)
+            .unwrap();
+        static USE_SHADOWS_C: &'static str = "use_shadows\0";
+        let use_shadows_loc = GetUniformLocation(
+            lighting_shader.program,
+            USE_SHADOWS_C.as_ptr() as *const GLchar
         );
-        crate::ui::SHOW_UI.store(true, Ordering::SeqCst);
-        renderer.camera.set_fov(DEFAULT_FOV);
+        Uniform1i(use_shadows_loc, 1);
+    }
 
-        loop {
-            if let Ok(res) = renderer.load_mesh_if_not_loaded("player") {
-                if res {
-                    break;
-                }
-            } else {
-                panic!("encountered a problem while trying to load the player's mesh");
+    renderer.backend.clear_colour.store(
+        RGBA {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        },
+        Ordering::SeqCst
+    );
+    crate::ui::SHOW_UI.store(true, Ordering::SeqCst);
+    renderer.camera.set_fov(DEFAULT_FOV);
+
+    loop {
+        if let Ok(res) = renderer.load_mesh_if_not_loaded("player") {
+            if res {
+                break;
             }
+        } else {
+            panic!("encountered a problem while trying to load the player's mesh");
         }
+    }
+
+    chat::write_chat(
+        "engine".to_string(),
+        "welcome to the mutEngine19 demo! Hit the coma key to unlock your mouse's cursor and the dot key on your keyboard to lock it back in its place!".to_string()
+    );
+
+    let mut last_frame_time = Instant::now();
+    let mut compensation_delta = 0.0;
+    loop {
+        let delta = ((last_frame_time.elapsed().as_millis() as f64) / 1000.0) as f32;
+        last_frame_time = Instant::now();
+
+        let fps = 1.0 / delta;
+        *crate::ui::FPS.lock().unwrap() = fps;
 
-        chat::write_chat(
-            "engine".to_string(),
-            "welcome to the mutEngine19 demo! Hit the coma key to unlock your mouse's cursor and the dot key on your keyboard to lock it back in its place!".to_string()
+        renderer.backend.input_state.lock().unwrap().input.time = Some(
+            start_time.elapsed().as_secs_f64()
         );
+        renderer.backend.egui_context
+            .lock()
+            .unwrap()
+            .begin_frame(renderer.backend.input_state.lock().unwrap().input.take());
+        if let Some(request) = take_session_request() {
+            handle_session_request(request, &mut local_server, &mut worldmachine, &physics).await;
+        }
+        worldmachine.next_frame(&mut renderer);
+        let updates = worldmachine.client_tick(&mut renderer, physics.clone(), delta).await;
+        worldmachine.tick_connection(&updates).await;
+
+        if let Some(delta) = physics.tick(delta + compensation_delta) {
+            compensation_delta += delta;
+        } else {
+            compensation_delta = 0.0;
+        }
 
-        let mut last_frame_time = Instant::now();
-        let mut compensation_delta = 0.0;
-        loop {
-            let delta = ((last_frame_time.elapsed().as_millis() as f64) / 1000.0) as f32;
-            last_frame_time = Instant::now();
+        worldmachine.handle_audio(&renderer, &audio, &scontext);
+        worldmachine.render(&mut renderer, None);
 
-            let fps = 1.0 / delta;
-            *crate::ui::FPS.lock().unwrap() = fps;
+        renderer.clear_every_shadow_buffer();
+        let light_count = renderer.lights.len();
+        for i in 0..light_count {
+            if renderer.lights[i].casts_shadow {
+                worldmachine.render(&mut renderer, Some((1, i)));
+                worldmachine.render(&mut renderer, Some((2, i)));
+                renderer.next_light();
+            }
+        }
+
+        renderer.swap_buffers(&mut worldmachine, &mut player).await;
+        renderer.backend.window.lock().unwrap().glfw.poll_events();
+        keyboard::reset_keyboard_state();
+        mouse::reset_mouse_state();
+        for (_, event) in glfw::flush_messages(
+            renderer.backend.events.lock().unwrap().deref()
+        ) {
+            egui_glfw_gl::handle_event(
+                event.clone(),
+                &mut renderer.backend.input_state.lock().unwrap()
+            );
+            keyboard::tick_keyboard(event.clone());
+            mouse::tick_mouse(event);
+        }
+        if renderer.manage_window() || keyboard::check_key_released(HTKey::Escape) {
+            worldmachine.disconnect(DisconnectReason::Quit).await;
+            // give the connection threads a moment to send it
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            process::exit(0);
+        }
+    }
+}
 
-            renderer.backend.input_state.lock().unwrap().input.time = Some(
-                start_time.elapsed().as_secs_f64()
+#[cfg(feature = "graphical")]
+async fn join_lan_server(
+    worldmachine: &mut worldmachine::WorldMachine,
+    ip: &str,
+    tcp_port: u16,
+    udp_port: u16,
+    reliable_udp: bool,
+    credentials: Option<Credentials>,
+    resume_token: Option<String>
+) {
+    worldmachine.server_address = Some(ServerAddress::Lan {
+        ip: ip.to_string(),
+        tcp_port,
+        udp_port,
+        reliable_udp,
+    });
+    let address = format!("{}:{}", ip, tcp_port);
+    let trust = ServerTrust::Pinned(address.clone());
+    // tokens are kept per server identity, and the handshake refuses anyone but the pinned one before sending it
+    let credentials = credentials.or_else(|| {
+        let pinned = known_server(&address)?;
+        load_session_token(&format!("{}@{}", pinned, address)).map(Credentials::Token)
+    });
+    let connection = if reliable_udp {
+        ClientLanConnection::connect_udp(ip, udp_port, &trust, credentials, resume_token).await.map(
+            |(server_connection, steady_receiver, segments)| {
+                let the_clone = server_connection.clone();
+                tokio::spawn(async move {
+                    the_clone.reliable_thread(steady_receiver, segments).await;
+                });
+                server_connection
+            }
+        )
+    } else {
+        ClientLanConnection::connect(ip, tcp_port, udp_port, &trust, credentials, resume_token).await.map(
+            |(server_connection, tcpstream, tcpreceiver)| {
+                let the_clone = server_connection.clone();
+                tokio::spawn(async move {
+                    the_clone.tcp_listener_thread(tcpstream, tcpreceiver).await;
+                });
+                server_connection
+            }
+        )
+    };
+    match connection {
+        Ok(server_connection) => {
+            if let Some(session) = &server_connection.session {
+                info!("logged in as {}", session.username);
+                let identity = fingerprint(&server_connection.server_identity);
+                save_session_token(&format!("{}@{}", identity, address), &session.token);
+            }
+            worldmachine.connect_to_server(
+                ConnectionClientside::Lan(server_connection.clone())
             );
-            renderer.backend.egui_context
-                .lock()
-                .unwrap()
-                .begin_frame(renderer.backend.input_state.lock().unwrap().input.take());
-            worldmachine.next_frame(&mut renderer);
-            let mut updates = worldmachine.client_tick(&mut renderer, physics.clone(), delta).await;
-            worldmachine.tick_connection(&mut updates).await;
-
-            if let Some(delta) = physics.tick(delta + compensation_delta) {
-                compensation_delta += delta;
-            } else {
-                compensation_delta = 0.0;
-            }
-
-            worldmachine.handle_audio(&renderer, &audio, &scontext);
-            worldmachine.render(&mut renderer, None);
-
-            renderer.clear_every_shadow_buffer();
-            let light_count = renderer.lights.len();
-            for i in 0..light_count {
-                if renderer.lights[i].casts_shadow {
-                    worldmachine.render(&mut renderer, Some((1, i)));
-                    worldmachine.render(&mut renderer, Some((2, i)));
-                    renderer.next_light();
+            tokio::spawn(async move {
+                server_connection.udp_listener_thread().await;
+            });
+        }
+        Err(e) => report_connection_error(e),
+    }
+}
+
+#[cfg(feature = "graphical")]
+async fn join_websocket_server(
+    worldmachine: &mut worldmachine::WorldMachine,
+    url: &str,
+    credentials: Option<Credentials>,
+    resume_token: Option<String>
+) {
+    worldmachine.server_address = Some(ServerAddress::WebSocket(url.to_string()));
+    // without tls nothing says the server answering at this url is the one that issued the token
+    let tls = url.starts_with("wss://");
+    let credentials = credentials.or_else(|| {
+        load_session_token(url).filter(|_| tls).map(Credentials::Token)
+    });
+    match ClientWebSocketConnection::connect(url, credentials, resume_token).await {
+        Ok((server_connection, socket, receiver)) => {
+            if let Some(session) = &server_connection.session {
+                info!("logged in as {}", session.username);
+                if tls {
+                    save_session_token(url, &session.token);
                 }
             }
+            worldmachine.connect_to_server(
+                ConnectionClientside::WebSocket(server_connection.clone())
+            );
+            tokio::spawn(async move {
+                server_connection.socket_thread(socket, receiver).await;
+            });
+        }
+        Err(e) => report_connection_error(e),
+    }
+}
 
-            renderer.swap_buffers(&mut worldmachine, &mut player).await;
-            renderer.backend.window.lock().unwrap().glfw.poll_events();
-            keyboard::reset_keyboard_state();
-            mouse::reset_mouse_state();
-            for (_, event) in glfw::flush_messages(
-                renderer.backend.events.lock().unwrap().deref()
-            ) {
-                egui_glfw_gl::handle_event(
-                    event.clone(),
-                    &mut renderer.backend.input_state.lock().unwrap()
-                );
-                keyboard::tick_keyboard(event.clone());
-                mouse::tick_mouse(event);
+#[cfg(feature = "graphical")]
+fn start_local_server(server: &server::Server) {
+    let mut server_clone_c = server.clone();
+    tokio::spawn(async move {
+        server_clone_c.run().await;
+    });
+    let server_clone_d = server.clone();
+    tokio::spawn(async move {
+        server_clone_d.console_thread().await;
+    });
+}
+
+#[cfg(feature = "graphical")]
+async fn handle_session_request(
+    request: SessionRequest,
+    local_server: &mut Option<server::Server>,
+    worldmachine: &mut worldmachine::WorldMachine,
+    physics: &physics::PhysicsSystem
+) {
+    if let SessionRequest::JoinLan(address, tcp_port, udp_port) = request {
+        if let Some(server) = local_server.take() {
+            server.shutdown();
+        }
+        worldmachine.reset_connection();
+        clear_connection_status();
+        let address = address.to_string();
+        join_lan_server(worldmachine, &address, tcp_port, udp_port, false, None, None).await;
+        chat::write_chat("engine".to_string(), format!("joining {}:{}", address, tcp_port));
+        return;
+    }
+    if let SessionRequest::Reconnect = request {
+        let address = match worldmachine.server_address.clone() {
+            Some(address) => address,
+            None => {
+                return;
             }
-            if renderer.manage_window() || keyboard::check_key_released(HTKey::Escape) {
-                process::exit(0);
+        };
+        let resume_token = worldmachine.resume_token.clone();
+        worldmachine.reset_connection();
+        clear_connection_status();
+        match address {
+            ServerAddress::Lan { ip, tcp_port, udp_port, reliable_udp } => {
+                join_lan_server(worldmachine, &ip, tcp_port, udp_port, reliable_udp, None, resume_token).await;
             }
+            ServerAddress::WebSocket(url) => {
+                join_websocket_server(worldmachine, &url, None, resume_token).await;
+            }
+        }
+        chat::write_chat("engine".to_string(), "reconnecting".to_string());
+        return;
+    }
+    let server = match local_server {
+        Some(server) => server,
+        None => {
+            chat::write_chat(
+                "engine".to_string(),
+                "saving and loading is only available in single player".to_string()
+            );
+            return;
+        }
+    };
+    match request {
+        SessionRequest::Save(name) => {
+            let message = match server.save_game(&name).await {
+                Ok(path) => format!("saved game to {}", path),
+                Err(e) => format!("failed to save game: {:?}", e),
+            };
+            chat::write_chat("engine".to_string(), message);
+        }
+        SessionRequest::Load(name) => {
+            let save = match SaveGame::read(&name) {
+                Ok(save) => save,
+                Err(e) => {
+                    chat::write_chat("engine".to_string(), format!("failed to load game: {:?}", e));
+                    return;
+                }
+            };
+            worldmachine.reset_connection();
+            let mut new_server = match server::Server::new_from_save(&save, physics.clone()) {
+                Ok(new_server) => new_server,
+                Err(e) => {
+                    let server_connection = server.join_local_server(true).await;
+                    worldmachine.connect_to_server(ConnectionClientside::Local(server_connection));
+                    chat::write_chat("engine".to_string(), format!("failed to load game: {:?}", e));
+                    return;
+                }
+            };
+            server.shutdown();
+            start_local_server(&new_server);
+            let server_connection = new_server.join_local_server(true).await;
+            worldmachine.connect_to_server(ConnectionClientside::Local(server_connection));
+            *local_server = Some(new_server);
+            chat::write_chat("engine".to_string(), format!("loaded game {}", name));
         }
+        SessionRequest::JoinLan(..) | SessionRequest::Reconnect => {}
     }
 }
 
+#[cfg(feature = "graphical")]
+fn clear_connection_status() {
+    *crate::ui::CONNECTION_ERROR.lock().unwrap() = None;
+    crate::ui::DISCONNECTED.store(false, Ordering::Relaxed);
+}
+
+#[cfg(feature = "graphical")]
+fn report_connection_error(e: ClientConnectError) {
+    error!("failed to connect to server: {}", e);
+    *crate::ui::CONNECTION_ERROR.lock().unwrap() = Some(e.to_string());
+    crate::ui::DISCONNECTED.store(true, Ordering::Relaxed);
+    chat::write_chat("engine".to_string(), format!("failed to connect: {}", e));
+}
+
 fn init_logger() {
     env_logger::init();
 
@@ -263,33 +550,136 @@ fn timeouts() {
     mutex_timeouts::std::GLOBAL_STD_TIMEOUT.store(20, Ordering::SeqCst);
 }
 
-fn parse_arguments(args: &mut std::env::Args) -> (bool, Option<String>, bool, Option<String>) {
-    let mut skip_intro = false;
-    let mut level_to_load = Option::None;
-    let mut run_as_lan_server = false;
-    let mut connect_to_lan_server = Option::None;
+#[derive(Default)]
+struct Arguments {
+    skip_intro: bool,
+    level_to_load: Option<String>,
+    run_as_lan_server: bool,
+    connect_to_lan_server: Option<String>,
+    reliable_udp: bool,
+    admin_console: Option<String>,
+    run_as_websocket_server: bool,
+    connect_to_websocket_server: Option<String>,
+    save_to_load: Option<String>,
+    enable_accounts: bool,
+    require_login: bool,
+    allow_registration: bool,
+    credentials: Option<Credentials>,
+    config_path: Option<String>,
+    config_overrides: Vec<(String, String)>,
+}
+
+fn parse_arguments(args: &mut std::env::Args) -> Arguments {
+    let mut arguments = Arguments::default();
+    let mut username = Option::None;
+    let mut password = Option::None;
+    let mut register = false;
 
     while let Some(arg) = args.next() {
         match arg.as_str() {
             "--skip-intro" => {
-                skip_intro = true;
+                arguments.skip_intro = true;
             }
             "--level" => {
-                level_to_load = Option::Some(
+                arguments.level_to_load = Option::Some(
                     args.next().expect("expected level name after --level")
                 );
             }
             "--lan-server" => {
-                run_as_lan_server = true;
+                arguments.run_as_lan_server = true;
             }
             "--connect-to-lan-server" => {
-                connect_to_lan_server = Option::Some(
+                arguments.connect_to_lan_server = Option::Some(
                     args.next().expect("expected ip after --connect-to-lan-server")
                 );
             }
+            "--reliable-udp" => {
+                arguments.reliable_udp = true;
+            }
+            "--admin-console" => {
+                arguments.admin_console = Option::Some(
+                    args.next().expect("expected port or address after --admin-console")
+                );
+            }
+            "--websocket-server" => {
+                arguments.run_as_websocket_server = true;
+            }
+            "--connect-to-websocket-server" => {
+                arguments.connect_to_websocket_server = Option::Some(
+                    args.next().expect("expected url after --connect-to-websocket-server")
+                );
+            }
+            "--load-save" => {
+                arguments.save_to_load = Option::Some(
+                    args.next().expect("expected save name after --load-save")
+                );
+            }
+            "--accounts" => {
+                arguments.enable_accounts = true;
+            }
+            "--require-login" => {
+                arguments.require_login = true;
+            }
+            "--allow-registration" => {
+                arguments.allow_registration = true;
+            }
+            "--username" => {
+                username = Option::Some(args.next().expect("expected name after --username"));
+            }
+            "--password" => {
+                password = Option::Some(args.next().expect("expected password after --password"));
+            }
+            "--register" => {
+                register = true;
+            }
+            "--config" => {
+                arguments.config_path = Option::Some(
+                    args.next().expect("expected path after --config")
+                );
+            }
+            "--bind" => {
+                let address = args.next().expect("expected address after --bind");
+                arguments.config_overrides.push(("network.bind_address".to_string(), address));
+            }
+            "--lan-tcp-port" => {
+                let port = args.next().expect("expected port after --lan-tcp-port");
+                arguments.config_overrides.push(("network.lan_tcp_port".to_string(), port));
+            }
+            "--lan-udp-port" => {
+                let port = args.next().expect("expected port after --lan-udp-port");
+                arguments.config_overrides.push(("network.lan_udp_port".to_string(), port));
+            }
+            "--websocket-port" => {
+                let port = args.next().expect("expected port after --websocket-port");
+                arguments.config_overrides.push(("network.websocket_port".to_string(), port));
+            }
+            "--max-players" => {
+                let count = args.next().expect("expected count after --max-players");
+                arguments.config_overrides.push(("network.max_players".to_string(), count));
+            }
+            "--tick-rate" => {
+                let rate = args.next().expect("expected rate after --tick-rate");
+                arguments.config_overrides.push(("simulation.tick_rate".to_string(), rate));
+            }
+            "--set" => {
+                let setting = args.next().expect("expected name=value after --set");
+                let (name, value) = setting
+                    .split_once('=')
+                    .expect("expected name=value after --set");
+                arguments.config_overrides.push((name.to_string(), value.to_string()));
+            }
             _ => {}
         }
     }
 
-    (skip_intro, level_to_load, run_as_lan_server, connect_to_lan_server)
-}
\ No newline at end of file
+    arguments.credentials = match (username, password) {
+        (Some(username), Some(password)) if register => {
+            Some(Credentials::Register { username, password })
+        }
+        (Some(username), Some(password)) => Some(Credentials::Password { username, password }),
+        (None, None) => None,
+        _ => panic!("--username and --password must be given together"),
+    };
+
+    arguments
+}
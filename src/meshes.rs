@@ -880,8 +880,8 @@ impl Mesh {
                 let mut using_autoanim = false;
                 if let Some(anim_weights) = animations_weights.as_mut() {
                 } else {
-                    if let Some(_auto_anim) = animations.animations.get("auto") {
-                        animations_weights = Some(vec![("auto".to_string(), 0.1)]);
+                    if let Some(auto_weights) = animations.auto_weights() {
+                        animations_weights = Some(auto_weights);
                         using_autoanim = true;
                     }
                 }
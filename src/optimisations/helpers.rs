@@ -1,9 +1,13 @@
+#[cfg(feature = "graphical")]
 use crate::renderer::RGBA;
+#[cfg(feature = "graphical")]
 use crate::MutRenderer;
 use gfx_maths::{Mat4, Quaternion, Vec2, Vec3};
+#[cfg(feature = "graphical")]
 use glad_gl::gl::*;
 use std::io::Read;
 
+#[cfg(feature = "graphical")]
 pub fn set_shader_if_not_set(renderer: &mut MutRenderer, shader_index: usize) {
     if renderer.backend.current_shader != Some(shader_index) {
         unsafe {
@@ -13,6 +17,7 @@ pub fn set_shader_if_not_set(renderer: &mut MutRenderer, shader_index: usize) {
     }
 }
 
+#[cfg(feature = "graphical")]
 pub fn gen_rainbow(time: f64) -> RGBA {
     let frequency = 0.05;
     let r = (frequency * (time as f64) + 0.0).sin() * 127.0f64 + 128.0f64;
@@ -99,6 +104,20 @@ pub fn lerp_vec3(a: Vec3, b: Vec3, t: f32) -> Vec3 {
     Vec3::new(lerp(a.x, b.x, t), lerp(a.y, b.y, t), lerp(a.z, b.z, t))
 }
 
+pub fn nlerp_quaternion(a: Quaternion, b: Quaternion, t: f32) -> Quaternion {
+    let dot = a.x * b.x + a.y * b.y + a.z * b.z + a.w * b.w;
+    let sign = if dot < 0.0 { -1.0 } else { 1.0 };
+    let x = lerp(a.x, b.x * sign, t);
+    let y = lerp(a.y, b.y * sign, t);
+    let z = lerp(a.z, b.z * sign, t);
+    let w = lerp(a.w, b.w * sign, t);
+    let length = (x * x + y * y + z * z + w * w).sqrt();
+    if length <= f32::EPSILON {
+        return b;
+    }
+    Quaternion::new(x / length, y / length, z / length, w / length)
+}
+
 pub fn glmatrix_mat4_to_gfx_maths_mat4(a: gl_matrix::common::Mat4) -> gfx_maths::Mat4 {
     let mut b = gfx_maths::Mat4::identity();
     b.values[0] = a[0];
@@ -242,3 +261,19 @@ pub fn to_q64(quat: Quaternion) -> (f64, f64, f64, f64) {
 pub fn from_q64(quat: (f64, f64, f64, f64)) -> Quaternion {
     Quaternion::new(quat.0 as f32, quat.1 as f32, quat.2 as f32, quat.3 as f32)
 }
+
+pub fn invert_mat4(mat: Mat4) -> Option<Mat4> {
+    let gl_matrix = gfx_maths_mat4_to_glmatrix_mat4(mat);
+    let gl_matrix = gl_matrix::mat4::invert(&mut gl_matrix.clone(), &gl_matrix)?;
+    Some(glmatrix_mat4_to_gfx_maths_mat4(gl_matrix))
+}
+
+pub fn decompose_mat4(mat: Mat4) -> (Vec3, Quaternion) {
+    let gl_matrix = gfx_maths_mat4_to_glmatrix_mat4(mat);
+    let translation = gl_matrix::mat4::get_translation(&mut [0.0; 3], &gl_matrix);
+    let rotation = gl_matrix::mat4::get_rotation(&mut [0.0; 4], &gl_matrix);
+    (
+        Vec3::new(translation[0], translation[1], translation[2]),
+        Quaternion::new(rotation[0], rotation[1], rotation[2], rotation[3]),
+    )
+}
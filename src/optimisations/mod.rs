@@ -1,3 +1,4 @@
+#[cfg(feature = "graphical")]
 pub mod keyboardmap;
 pub mod DoubleIndexVec;
 pub mod helpers;
\ No newline at end of file
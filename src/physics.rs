@@ -21,6 +21,15 @@ lazy_static! {
 }
 
 pub const GRAVITY: f32 = -9.81;
+
+// actors are released from whichever scene they ended up in
+unsafe fn remove_actor(actor: *mut PxActor) {
+    let scene = PxActor_getScene(actor);
+    if !scene.is_null() {
+        PxScene_removeActor_mut(scene, actor, false);
+    }
+    PxRigidActor_release_mut(actor as *mut PxRigidActor);
+}
 pub const PLAYER_GRAVITY: f32 = -24.36;
 pub const PLAYER_TERMINAL_VELOCITY: f32 = 90.0;
 pub const PLAYER_JUMP_VELOCITY: f32 = 12.3;
@@ -179,6 +188,46 @@ impl PhysicsSystem {
         }
     }
 
+    // drops the map's colliders and triggers so the next map starts from an empty scene
+    pub fn release_colliders(&self) {
+        let scene = self.scene;
+        let in_scene = |actor: *mut PxActor| unsafe { PxActor_getScene(actor) == scene };
+        let box_colliders = {
+            let mut box_colliders = BOX_COLLIDERS.lock().unwrap();
+            let (released, kept) = box_colliders
+                .drain(..)
+                .partition::<Vec<_>, _>(|collider| in_scene(collider.actor as *mut PxActor));
+            *box_colliders = kept;
+            released
+        };
+        let trigger_shapes = {
+            let mut trigger_shapes = TRIGGER_SHAPES.lock().unwrap();
+            let (released, kept) = trigger_shapes
+                .drain(..)
+                .partition::<Vec<_>, _>(|trigger| in_scene(trigger.actor as *mut PxActor));
+            *trigger_shapes = kept;
+            released
+        };
+        // dropping the last references releases the actors
+        drop(box_colliders);
+        drop(trigger_shapes);
+    }
+
+    // only for scenes made by copy_with_new_scene, nothing may use this copy afterwards
+    pub fn release_scene(&self) {
+        self.release_colliders();
+        let scene = self.scene;
+        let lock = PHYSICS_LOCK.lock().unwrap();
+        unsafe {
+            // also releases every character controller made with it
+            PxControllerManager_release_mut(self.controller_manager);
+            let dispatcher = PxScene_getCpuDispatcher(scene);
+            PxScene_release_mut(scene);
+            PxDefaultCpuDispatcher_release_mut(dispatcher as *mut PxDefaultCpuDispatcher);
+        }
+        drop(lock);
+    }
+
     pub fn tick(&self, delta_time: f32) -> Option<f32> {
         if delta_time <= 0.001 {
             return Some(delta_time);
@@ -551,6 +600,16 @@ impl PhysicsCharacterController {
         }
         drop(lock);
     }
+
+    pub fn get_y_velocity(&self) -> f32 {
+        unsafe { *self.y_velocity.get() }
+    }
+
+    pub fn set_y_velocity(&self, velocity: f32) {
+        unsafe {
+            *self.y_velocity.get() = velocity;
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -587,11 +646,10 @@ impl PhysicsBoxColliderStatic {
         drop(lock);
     }
 
-    pub unsafe fn remove_self(&self, physics: PhysicsSystem) {
+    pub unsafe fn remove_self(&self) {
         let lock = PHYSICS_LOCK.lock().unwrap();
         unsafe {
-            PxScene_removeActor_mut(physics.scene, self.actor as *mut PxActor, false);
-            PxRigidActor_release_mut(self.actor as *mut PxRigidActor);
+            remove_actor(self.actor as *mut PxActor);
         }
         drop(lock);
     }
@@ -602,7 +660,7 @@ impl Drop for PhysicsBoxColliderStatic {
         let ref_count = self.ref_count.fetch_sub(1, Ordering::SeqCst);
         if ref_count == 0 {
             unsafe {
-                self.remove_self(PHYSICS_SYSTEM.lock().unwrap().as_ref().unwrap().clone());
+                self.remove_self();
             }
         }
     }
@@ -637,6 +695,46 @@ impl PhysicsSphereColliderDynamic {
         drop(lock);
     }
 
+    pub fn get_linear_velocity(&self) -> Vec3 {
+        let lock = PHYSICS_LOCK.lock().unwrap();
+        let velocity = unsafe { PxRigidBody_getLinearVelocity(self.actor as *const PxRigidBody) };
+        drop(lock);
+        Vec3::new(velocity.x, velocity.y, velocity.z)
+    }
+
+    pub fn get_angular_velocity(&self) -> Vec3 {
+        let lock = PHYSICS_LOCK.lock().unwrap();
+        let velocity = unsafe { PxRigidBody_getAngularVelocity(self.actor as *const PxRigidBody) };
+        drop(lock);
+        Vec3::new(velocity.x, velocity.y, velocity.z)
+    }
+
+    pub fn set_linear_velocity(&self, velocity: Vec3) {
+        let lock = PHYSICS_LOCK.lock().unwrap();
+        let velocity = PxVec3 {
+            x: velocity.x,
+            y: velocity.y,
+            z: velocity.z,
+        };
+        unsafe {
+            PxRigidDynamic_setLinearVelocity_mut(self.actor, &velocity, true);
+        }
+        drop(lock);
+    }
+
+    pub fn set_angular_velocity(&self, velocity: Vec3) {
+        let lock = PHYSICS_LOCK.lock().unwrap();
+        let velocity = PxVec3 {
+            x: velocity.x,
+            y: velocity.y,
+            z: velocity.z,
+        };
+        unsafe {
+            PxRigidDynamic_setAngularVelocity_mut(self.actor, &velocity, true);
+        }
+        drop(lock);
+    }
+
     pub fn set_velocity(&self, velocity: Vec3) {
         let lock = PHYSICS_LOCK.lock().unwrap();
         let velocity = PxVec3 {
@@ -656,11 +754,10 @@ impl PhysicsSphereColliderDynamic {
         drop(lock);
     }
 
-    pub unsafe fn remove_self(&self, physics: PhysicsSystem) {
+    pub unsafe fn remove_self(&self) {
         let lock = PHYSICS_LOCK.lock().unwrap();
         unsafe {
-            PxScene_removeActor_mut(physics.scene, self.actor as *mut PxActor, false);
-            PxRigidActor_release_mut(self.actor as *mut PxRigidActor);
+            remove_actor(self.actor as *mut PxActor);
         }
         drop(lock);
     }
@@ -702,7 +799,7 @@ impl Drop for PhysicsSphereColliderDynamic {
         let ref_count = self.ref_count.fetch_sub(1, Ordering::SeqCst);
         if ref_count == 0 {
             unsafe {
-                self.remove_self(PHYSICS_SYSTEM.lock().unwrap().as_ref().unwrap().clone());
+                self.remove_self();
             }
         }
     }
@@ -738,11 +835,10 @@ impl PhysicsTriggerShape {
         drop(lock);
     }
 
-    pub unsafe fn remove_self(&self, physics: PhysicsSystem) {
+    pub unsafe fn remove_self(&self) {
         let lock = PHYSICS_LOCK.lock().unwrap();
         unsafe {
-            PxScene_removeActor_mut(physics.scene, self.actor as *mut PxActor, false);
-            PxRigidActor_release_mut(self.actor as *mut PxRigidActor);
+            remove_actor(self.actor as *mut PxActor);
         }
         drop(lock);
     }
@@ -753,7 +849,7 @@ impl Drop for PhysicsTriggerShape {
         let ref_count = self.ref_count.fetch_sub(1, Ordering::SeqCst);
         if ref_count == 0 {
             unsafe {
-                self.remove_self(PHYSICS_SYSTEM.lock().unwrap().as_ref().unwrap().clone());
+                self.remove_self();
             }
         }
     }
@@ -0,0 +1,616 @@
+use crate::server::lan::RejectionReason;
+use crate::server::ratelimit::LoginThrottle;
+use crate::server::Server;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use jsonwebtoken::{ decode, encode, DecodingKey, EncodingKey, Header, Validation };
+use serde::{ Deserialize, Serialize };
+use std::collections::HashMap;
+use std::fmt::{ Display, Formatter };
+use std::net::IpAddr;
+use std::sync::{ Arc, Mutex };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+pub const ACCOUNTS_FILE: &str = "accounts.db";
+pub const SESSION_TOKENS_FILE: &str = "session_tokens.json";
+pub const SESSION_TOKEN_LIFETIME_SECS: u64 = 60 * 60 * 24 * 7;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_USERNAME_LENGTH: usize = 24;
+
+lazy_static! {
+    static ref DUMMY_PASSWORD_HASH: String = bcrypt
+        ::hash("not anyone's password", bcrypt::DEFAULT_COST)
+        .expect("failed to hash the dummy password");
+}
+
+diesel::table! {
+    accounts (username) {
+        username -> Text,
+        password_hash -> Text,
+        created_at -> BigInt,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum AccountError {
+    Io(String),
+    Database(String),
+    Hashing(String),
+    Token(String),
+    InvalidUsername(String),
+    PasswordTooShort,
+    AlreadyExists(String),
+    NotFound(String),
+    WrongPassword,
+    MissingSecret,
+}
+
+impl Display for AccountError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountError::Io(e) => write!(f, "io error: {}", e),
+            AccountError::Database(e) => write!(f, "database error: {}", e),
+            AccountError::Hashing(e) => write!(f, "password hashing failed: {}", e),
+            AccountError::Token(e) => write!(f, "invalid session token: {}", e),
+            AccountError::InvalidUsername(name) => write!(f, "invalid username: {}", name),
+            AccountError::PasswordTooShort =>
+                write!(f, "password must be at least {} characters", MIN_PASSWORD_LENGTH),
+            AccountError::AlreadyExists(name) => write!(f, "account {} already exists", name),
+            AccountError::NotFound(name) => write!(f, "no account named {}", name),
+            AccountError::WrongPassword => write!(f, "wrong password"),
+            AccountError::MissingSecret =>
+                write!(f, "SESSION_SECRET must be set to sign session tokens"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[diesel(table_name = accounts)]
+pub struct Account {
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Credentials {
+    Token(String),
+    Password {
+        username: String,
+        password: String,
+    },
+    Register {
+        username: String,
+        password: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionGrant {
+    pub username: String,
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionClaims {
+    sub: String,
+    iat: u64,
+    exp: u64,
+}
+
+pub trait AccountStore: Send + Sync {
+    fn find(&self, username: &str) -> Result<Option<Account>, AccountError>;
+    fn insert(&self, account: Account) -> Result<(), AccountError>;
+    fn remove(&self, username: &str) -> Result<bool, AccountError>;
+    fn list(&self) -> Result<Vec<String>, AccountError>;
+}
+
+pub struct FileAccountStore {
+    path: String,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+impl FileAccountStore {
+    pub fn open(path: &str) -> Result<Self, AccountError> {
+        let accounts = if std::path::Path::new(path).exists() {
+            let file = std::fs::File::open(path).map_err(|e| AccountError::Io(e.to_string()))?;
+            let mut deserializer = rmp_serde::Deserializer::new(file);
+            Deserialize::deserialize(&mut deserializer).map_err(|e|
+                AccountError::Io(e.to_string())
+            )?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path: path.to_string(),
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    fn flush(&self, accounts: &HashMap<String, Account>) -> Result<(), AccountError> {
+        let data = rmp_serde::to_vec(accounts).map_err(|e| AccountError::Io(e.to_string()))?;
+        let temp_path = format!("{}.tmp", self.path);
+        std::fs::write(&temp_path, data).map_err(|e| AccountError::Io(e.to_string()))?;
+        std::fs::rename(&temp_path, &self.path).map_err(|e| AccountError::Io(e.to_string()))
+    }
+}
+
+impl AccountStore for FileAccountStore {
+    fn find(&self, username: &str) -> Result<Option<Account>, AccountError> {
+        Ok(self.accounts.lock().unwrap().get(username).cloned())
+    }
+
+    fn insert(&self, account: Account) -> Result<(), AccountError> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(&account.username) {
+            return Err(AccountError::AlreadyExists(account.username));
+        }
+        accounts.insert(account.username.clone(), account);
+        self.flush(&accounts)
+    }
+
+    fn remove(&self, username: &str) -> Result<bool, AccountError> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.remove(username).is_none() {
+            return Ok(false);
+        }
+        self.flush(&accounts)?;
+        Ok(true)
+    }
+
+    fn list(&self) -> Result<Vec<String>, AccountError> {
+        let mut usernames = self.accounts.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        usernames.sort();
+        Ok(usernames)
+    }
+}
+
+pub struct PostgresAccountStore {
+    connection: Mutex<PgConnection>,
+}
+
+impl PostgresAccountStore {
+    pub fn connect(database_url: &str) -> Result<Self, AccountError> {
+        let mut connection = PgConnection::establish(database_url).map_err(|e|
+            AccountError::Database(e.to_string())
+        )?;
+        diesel
+            ::sql_query(
+                "CREATE TABLE IF NOT EXISTS accounts (
+                    username TEXT PRIMARY KEY,
+                    password_hash TEXT NOT NULL,
+                    created_at BIGINT NOT NULL
+                )"
+            )
+            .execute(&mut connection)
+            .map_err(|e| AccountError::Database(e.to_string()))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl AccountStore for PostgresAccountStore {
+    fn find(&self, username: &str) -> Result<Option<Account>, AccountError> {
+        let mut connection = self.connection.lock().unwrap();
+        accounts::table
+            .find(username)
+            .first::<Account>(&mut *connection)
+            .optional()
+            .map_err(|e| AccountError::Database(e.to_string()))
+    }
+
+    fn insert(&self, account: Account) -> Result<(), AccountError> {
+        let mut connection = self.connection.lock().unwrap();
+        match diesel::insert_into(accounts::table).values(&account).execute(&mut *connection) {
+            Ok(_) => Ok(()),
+            Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) =>
+                Err(AccountError::AlreadyExists(account.username)),
+            Err(e) => Err(AccountError::Database(e.to_string())),
+        }
+    }
+
+    fn remove(&self, username: &str) -> Result<bool, AccountError> {
+        let mut connection = self.connection.lock().unwrap();
+        diesel
+            ::delete(accounts::table.find(username))
+            .execute(&mut *connection)
+            .map(|removed| removed > 0)
+            .map_err(|e| AccountError::Database(e.to_string()))
+    }
+
+    fn list(&self) -> Result<Vec<String>, AccountError> {
+        let mut connection = self.connection.lock().unwrap();
+        accounts::table
+            .select(accounts::username)
+            .order(accounts::username)
+            .load::<String>(&mut *connection)
+            .map_err(|e| AccountError::Database(e.to_string()))
+    }
+}
+
+pub struct Authenticator {
+    store: Arc<dyn AccountStore>,
+    secret: Vec<u8>,
+    throttle: Mutex<LoginThrottle>,
+    pub required: bool,
+    pub allow_registration: bool,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn validate_username(username: &str) -> Result<(), AccountError> {
+    if
+        username.is_empty() ||
+        username.len() > MAX_USERNAME_LENGTH ||
+        !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(AccountError::InvalidUsername(username.to_string()));
+    }
+    Ok(())
+}
+
+impl Authenticator {
+    pub fn new(
+        store: Arc<dyn AccountStore>,
+        secret: Vec<u8>,
+        required: bool,
+        allow_registration: bool
+    ) -> Self {
+        Self {
+            store,
+            secret,
+            throttle: Mutex::new(LoginThrottle::default()),
+            required,
+            allow_registration,
+        }
+    }
+
+    pub fn from_env(required: bool, allow_registration: bool) -> Result<Self, AccountError> {
+        dotenv::dotenv().ok();
+        let store: Arc<dyn AccountStore> = match std::env::var("ACCOUNTS_DATABASE_URL") {
+            Ok(database_url) => {
+                info!("using postgres account store");
+                Arc::new(PostgresAccountStore::connect(&database_url)?)
+            }
+            Err(_) => {
+                info!("using file account store at {}", ACCOUNTS_FILE);
+                Arc::new(FileAccountStore::open(ACCOUNTS_FILE)?)
+            }
+        };
+        // a made up secret would log everyone out on every restart
+        let secret = match std::env::var("SESSION_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                return Err(AccountError::MissingSecret);
+            }
+        };
+        Ok(Self::new(store, secret, required, allow_registration))
+    }
+
+    pub fn register(&self, username: &str, password: &str) -> Result<(), AccountError> {
+        validate_username(username)?;
+        if password.len() < MIN_PASSWORD_LENGTH {
+            return Err(AccountError::PasswordTooShort);
+        }
+        let password_hash = bcrypt
+            ::hash(password, bcrypt::DEFAULT_COST)
+            .map_err(|e| AccountError::Hashing(e.to_string()))?;
+        self.store.insert(Account {
+            username: username.to_string(),
+            password_hash,
+            created_at: now_secs() as i64,
+        })?;
+        info!("registered account {}", username);
+        Ok(())
+    }
+
+    pub fn remove(&self, username: &str) -> Result<bool, AccountError> {
+        self.store.remove(username)
+    }
+
+    pub fn list(&self) -> Result<Vec<String>, AccountError> {
+        self.store.list()
+    }
+
+    pub fn login(&self, username: &str, password: &str) -> Result<SessionGrant, AccountError> {
+        let account = match self.store.find(username)? {
+            Some(account) => account,
+            None => {
+                // takes as long as a wrong password so the timing doesn't give away who has an account
+                let _ = bcrypt::verify(password, &DUMMY_PASSWORD_HASH);
+                return Err(AccountError::NotFound(username.to_string()));
+            }
+        };
+        let valid = bcrypt
+            ::verify(password, &account.password_hash)
+            .map_err(|e| AccountError::Hashing(e.to_string()))?;
+        if !valid {
+            return Err(AccountError::WrongPassword);
+        }
+        Ok(SessionGrant {
+            username: account.username.clone(),
+            token: self.issue_token(&account.username)?,
+        })
+    }
+
+    pub fn issue_token(&self, username: &str) -> Result<String, AccountError> {
+        let now = now_secs();
+        let claims = SessionClaims {
+            sub: username.to_string(),
+            iat: now,
+            exp: now + SESSION_TOKEN_LIFETIME_SECS,
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(&self.secret)).map_err(|e|
+            AccountError::Token(e.to_string())
+        )
+    }
+
+    pub fn verify_token(&self, token: &str) -> Result<String, AccountError> {
+        let data = decode::<SessionClaims>(
+            token,
+            &DecodingKey::from_secret(&self.secret),
+            &Validation::default()
+        ).map_err(|e| AccountError::Token(e.to_string()))?;
+        // removing an account ends its sessions
+        let account = self.store
+            .find(&data.claims.sub)?
+            .ok_or(AccountError::NotFound(data.claims.sub))?;
+        Ok(account.username)
+    }
+
+    // the client only learns as much as it needs, the details stay in the server log
+    fn rejection(e: AccountError) -> RejectionReason {
+        warn!("login failed: {}", e);
+        let reason = match e {
+            AccountError::NotFound(_) | AccountError::WrongPassword =>
+                "invalid username or password".to_string(),
+            AccountError::Token(_) => "your session has expired, log in again".to_string(),
+            | AccountError::InvalidUsername(_)
+            | AccountError::PasswordTooShort
+            | AccountError::AlreadyExists(_) => e.to_string(),
+            | AccountError::Io(_)
+            | AccountError::Database(_)
+            | AccountError::Hashing(_)
+            | AccountError::MissingSecret => "the server couldn't check your login".to_string(),
+        };
+        RejectionReason::AuthenticationFailed(reason)
+    }
+
+    fn throttle(&self, address: Option<IpAddr>) -> Result<(), RejectionReason> {
+        let address = match address {
+            Some(address) => address,
+            None => {
+                return Ok(());
+            }
+        };
+        self.throttle
+            .lock()
+            .unwrap()
+            .attempt(address)
+            .map_err(|wait| {
+                warn!("throttling logins from {} for another {} seconds", address, wait.as_secs());
+                RejectionReason::AuthenticationFailed(
+                    format!("too many login attempts, try again in {} seconds", wait.as_secs().max(1))
+                )
+            })
+    }
+
+    pub fn authenticate(
+        &self,
+        credentials: Option<Credentials>,
+        address: Option<IpAddr>
+    ) -> Result<Option<SessionGrant>, RejectionReason> {
+        if matches!(credentials, Some(Credentials::Password { .. } | Credentials::Register { .. })) {
+            self.throttle(address)?;
+        }
+        let grant = self.check_credentials(credentials)?;
+        if let (Some(_), Some(address)) = (&grant, address) {
+            self.throttle.lock().unwrap().succeeded(address);
+        }
+        Ok(grant)
+    }
+
+    fn check_credentials(
+        &self,
+        credentials: Option<Credentials>
+    ) -> Result<Option<SessionGrant>, RejectionReason> {
+        let failed = Self::rejection;
+        match credentials {
+            None => {
+                if self.required {
+                    Err(RejectionReason::AuthenticationRequired)
+                } else {
+                    Ok(None)
+                }
+            }
+            Some(Credentials::Token(token)) => {
+                // not renewed, so a session ends a fixed time after the password was last typed in
+                let username = self.verify_token(&token).map_err(failed)?;
+                Ok(Some(SessionGrant { username, token }))
+            }
+            Some(Credentials::Password { username, password }) => {
+                self.login(&username, &password).map(Some).map_err(failed)
+            }
+            Some(Credentials::Register { username, password }) => {
+                if !self.allow_registration {
+                    return Err(
+                        RejectionReason::AuthenticationFailed(
+                            "registration is disabled on this server".to_string()
+                        )
+                    );
+                }
+                self.register(&username, &password).map_err(failed)?;
+                self.login(&username, &password).map(Some).map_err(failed)
+            }
+        }
+    }
+}
+
+pub async fn authenticate_join(
+    authenticator: Option<Arc<Authenticator>>,
+    credentials: Option<Credentials>,
+    address: Option<IpAddr>
+) -> Result<Option<SessionGrant>, RejectionReason> {
+    let authenticator = match authenticator {
+        Some(authenticator) => authenticator,
+        None => {
+            return Ok(None);
+        }
+    };
+    tokio::task
+        ::spawn_blocking(move || authenticator.authenticate(credentials, address)).await
+        .unwrap_or_else(|e| Err(RejectionReason::AuthenticationFailed(e.to_string())))
+}
+
+impl Server {
+    pub fn authenticator(&self) -> Option<Arc<Authenticator>> {
+        self.authenticator.read().unwrap().clone()
+    }
+
+    pub fn enable_authentication(&self, authenticator: Authenticator) {
+        *self.authenticator.write().unwrap() = Some(Arc::new(authenticator));
+    }
+}
+
+// a token is only ever sent back to the server that issued it
+fn load_session_tokens() -> HashMap<String, String> {
+    std::fs
+        ::read_to_string(SESSION_TOKENS_FILE)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+pub fn load_session_token(server: &str) -> Option<String> {
+    load_session_tokens().remove(server)
+}
+
+pub fn save_session_token(server: &str, token: &str) {
+    let mut tokens = load_session_tokens();
+    tokens.insert(server.to_string(), token.to_string());
+    let result = serde_json
+        ::to_string_pretty(&tokens)
+        .map_err(|e| e.to_string())
+        .and_then(|data| std::fs::write(SESSION_TOKENS_FILE, data).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        warn!("failed to save session token: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("accounts-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn store(&self) -> Arc<FileAccountStore> {
+            Arc::new(FileAccountStore::open(self.0.join(ACCOUNTS_FILE).to_str().unwrap()).unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn authenticator(store: Arc<FileAccountStore>, allow_registration: bool) -> Authenticator {
+        Authenticator::new(store, b"test secret".to_vec(), true, allow_registration)
+    }
+
+    #[test]
+    fn registered_accounts_can_log_in_and_keep_their_tokens() {
+        let dir = TempDir::new("login");
+        let accounts = authenticator(dir.store(), true);
+        accounts.register("alice", "correct horse").unwrap();
+        assert!(matches!(accounts.register("alice", "battery staple"), Err(AccountError::AlreadyExists(_))));
+        assert!(matches!(accounts.register("bob", "short"), Err(AccountError::PasswordTooShort)));
+
+        let grant = accounts.login("alice", "correct horse").unwrap();
+        assert_eq!(grant.username, "alice");
+        assert_eq!(accounts.verify_token(&grant.token).unwrap(), "alice");
+
+        // the account was written out, and the token holds for anyone with the same secret
+        let reopened = authenticator(dir.store(), true);
+        assert_eq!(reopened.list().unwrap(), vec!["alice".to_string()]);
+        assert_eq!(reopened.verify_token(&grant.token).unwrap(), "alice");
+        let other_server = Authenticator::new(dir.store(), b"another secret".to_vec(), true, true);
+        assert!(matches!(other_server.verify_token(&grant.token), Err(AccountError::Token(_))));
+    }
+
+    #[test]
+    fn wrong_passwords_and_unknown_accounts_are_refused() {
+        let dir = TempDir::new("wrong-password");
+        let accounts = authenticator(dir.store(), true);
+        accounts.register("alice", "correct horse").unwrap();
+        assert!(matches!(accounts.login("alice", "incorrect horse"), Err(AccountError::WrongPassword)));
+        assert!(matches!(accounts.login("mallory", "correct horse"), Err(AccountError::NotFound(_))));
+
+        let rejected = accounts.authenticate(
+            Some(Credentials::Password {
+                username: "alice".to_string(),
+                password: "incorrect horse".to_string(),
+            }),
+            None
+        );
+        assert_eq!(
+            rejected.unwrap_err(),
+            RejectionReason::AuthenticationFailed("invalid username or password".to_string())
+        );
+        assert_eq!(accounts.authenticate(None, None).unwrap_err(), RejectionReason::AuthenticationRequired);
+    }
+
+    #[test]
+    fn removing_an_account_ends_its_sessions() {
+        let dir = TempDir::new("remove");
+        let accounts = authenticator(dir.store(), true);
+        accounts.register("alice", "correct horse").unwrap();
+        let token = accounts.issue_token("alice").unwrap();
+        assert!(accounts.remove("alice").unwrap());
+        assert!(!accounts.remove("alice").unwrap());
+        assert!(matches!(accounts.verify_token(&token), Err(AccountError::NotFound(_))));
+        assert!(authenticator(dir.store(), true).list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn usernames_are_short_and_plain() {
+        for good in ["alice", "Bob_2", "x-y", &"a".repeat(MAX_USERNAME_LENGTH)] {
+            assert!(validate_username(good).is_ok(), "{} was refused", good);
+        }
+        for bad in ["", "has space", "../admin", "émile", "semi;colon", &"a".repeat(MAX_USERNAME_LENGTH + 1)] {
+            assert!(matches!(validate_username(bad), Err(AccountError::InvalidUsername(_))), "{} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn registration_can_be_turned_off() {
+        let dir = TempDir::new("registration");
+        let accounts = authenticator(dir.store(), false);
+        let credentials = Credentials::Register {
+            username: "alice".to_string(),
+            password: "correct horse".to_string(),
+        };
+        assert_eq!(
+            accounts.authenticate(Some(credentials.clone()), None).unwrap_err(),
+            RejectionReason::AuthenticationFailed("registration is disabled on this server".to_string())
+        );
+        assert!(accounts.list().unwrap().is_empty());
+
+        let open = authenticator(dir.store(), true);
+        let grant = open.authenticate(Some(credentials), None).unwrap().unwrap();
+        assert_eq!(grant.username, "alice");
+    }
+}
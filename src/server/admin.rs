@@ -0,0 +1,258 @@
+use crate::server::disconnect::DisconnectReason;
+use crate::server::moderation::BanTarget;
+use crate::server::ratelimit::LoginThrottle;
+use crate::server::{ Server, SteadyPacket };
+use sha2::{ Digest, Sha256 };
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{ TcpListener, TcpStream };
+use tokio_stream::StreamExt;
+use tokio_util::codec::{ FramedRead, LinesCodec };
+
+pub const ADMIN_PASSWORD_VAR: &str = "ADMIN_CONSOLE_PASSWORD";
+pub const DEFAULT_ADMIN_CONSOLE_HOST: &str = "127.0.0.1";
+const MAX_LOGIN_ATTEMPTS: u32 = 3;
+const MAX_ADMIN_LINE_LENGTH: usize = 4096;
+
+type AdminLines = FramedRead<OwnedReadHalf, LinesCodec>;
+
+fn password_digest(password: &str) -> [u8; 32] {
+    Sha256::digest(password.trim().as_bytes()).into()
+}
+
+// the console is plaintext, so a bare port only listens on this machine
+fn admin_console_address(address: &str) -> String {
+    match address.parse::<u16>() {
+        Ok(port) => format!("{}:{}", DEFAULT_ADMIN_CONSOLE_HOST, port),
+        Err(_) => address.to_string(),
+    }
+}
+
+async fn next_line(lines: &mut AdminLines) -> std::io::Result<Option<String>> {
+    lines
+        .next().await
+        .transpose()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+impl Server {
+    pub fn cvars(&self) -> Vec<(String, String)> {
+        self.config().fields()
+    }
+
+    pub fn set_cvar(&self, name: &str, value: &str) -> Result<String, String> {
+        let config = self.config().with_field(name, value)?;
+        config.validate().map_err(|e| e.to_string())?;
+        self.set_config(config);
+        let note = match name.split_once('.').map(|(section, _)| section) {
+            Some("network") => " (applies after a restart)",
+            Some("rate_limits") => " (applies to new connections)",
+            Some("link_conditioner") => " (applies to new connections, netsim changes existing ones)",
+            _ => "",
+        };
+        Ok(format!("{} = {}{}", name, value, note))
+    }
+
+    pub async fn status(&self) -> String {
+        let worldmachine = self.worldmachine.lock().await;
+        let map = worldmachine.current_map();
+        let tick = worldmachine.tick;
+        drop(worldmachine);
+        let connections = self.get_all_connections().await.len();
+        let bots = self.bots.lock().await.len();
+        let bans = self.bans.read().unwrap().active().len();
+        format!(
+            "map {} at tick {}, {} clients connected, {} bots, {} active bans",
+            map,
+            tick,
+            connections,
+            bots,
+            bans
+        )
+    }
+
+    pub async fn list_players(&self) -> String {
+        let players = match self.worldmachine.lock().await.players.clone() {
+            Some(players) => players.lock().await.clone(),
+            None => {
+                return "no players connected".to_string();
+            }
+        };
+        if players.is_empty() {
+            return "no players connected".to_string();
+        }
+        let mut response = format!("{} players:", players.len());
+        for (uuid, player) in players.iter() {
+            let name = player.player.name.lock().await.clone();
+            let position = player.player.get_position(None, None).await;
+            response.push_str(
+                &format!(
+                    "\n  {} {} via {}, account {}, address {}, at ({:.1}, {:.1}, {:.1})",
+                    uuid,
+                    name,
+                    player.connection.kind(),
+                    player.connection.identity().unwrap_or("none".to_string()),
+                    player.connection
+                        .remote_ip()
+                        .map_or("local".to_string(), |address| address.to_string()),
+                    position.x,
+                    position.y,
+                    position.z
+                )
+            );
+        }
+        response
+    }
+
+    pub async fn say(&self, message: &str) {
+        self.broadcast_steady_packet(SteadyPacket::Message(message.to_string())).await;
+    }
+
+    pub async fn change_map(&self, map_name: &str) -> Result<usize, String> {
+        let map_dir = format!("{}/maps/{}", self.worldmachine.lock().await.game_data_path, map_name);
+        if !std::path::Path::new(&map_dir).exists() {
+            return Err(format!("no map named {}", map_name));
+        }
+        // clients can't swap maps mid session, so everyone rejoins onto the new one
+        let mut kicked = 0;
+        for connection in self.get_all_connections().await {
+            let target = BanTarget::Uuid(connection.uuid());
+            kicked += self.kick(&target, DisconnectReason::MapChange(map_name.to_string())).await;
+        }
+        // their entities go with the old map
+        self.detached.lock().await.clear();
+        self.worldmachine
+            .lock().await
+            .load_map(map_name)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(kicked)
+    }
+
+    pub async fn map_rotation_thread(&self) {
+        let mut last_rotation = Instant::now();
+        while self.running.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let maps = self.config().maps;
+            if
+                maps.rotate_after_secs == 0 ||
+                maps.rotation.len() < 2 ||
+                last_rotation.elapsed() < Duration::from_secs(maps.rotate_after_secs)
+            {
+                continue;
+            }
+            last_rotation = Instant::now();
+            let current = self.worldmachine.lock().await.current_map();
+            let next = maps.rotation
+                .iter()
+                .position(|map| *map == current)
+                .map_or(0, |index| (index + 1) % maps.rotation.len());
+            let map = &maps.rotation[next];
+            info!("rotating to map {}", map);
+            if let Err(e) = self.change_map(map).await {
+                error!("failed to rotate to map {}: {}", map, e);
+            }
+        }
+    }
+
+    pub async fn host_admin_console(&self, address: &str, password: &str) {
+        let address = admin_console_address(address);
+        let listener = match TcpListener::bind(&address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("failed to bind admin console to {}: {:?}", address, e);
+                return;
+            }
+        };
+        if listener.local_addr().is_ok_and(|local| !local.ip().is_loopback()) {
+            warn!("the admin console on {} is unencrypted, its password can be read off the network", address);
+        }
+        let digest = password_digest(password);
+        let throttle = Arc::new(Mutex::new(LoginThrottle::default()));
+        let the_clone = self.clone();
+        tokio::spawn(async move {
+            while the_clone.running.load(Ordering::Relaxed) {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("admin console accept failed: {:?}", e);
+                        continue;
+                    }
+                };
+                let session = the_clone.clone();
+                let throttle = throttle.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = session.admin_session(stream, peer, digest, throttle).await {
+                        debug!("admin console session with {} ended: {:?}", peer, e);
+                    }
+                });
+            }
+        });
+        info!("accepting admin console connections on {}", address);
+    }
+
+    async fn admin_session(
+        &self,
+        stream: TcpStream,
+        peer: SocketAddr,
+        digest: [u8; 32],
+        throttle: Arc<Mutex<LoginThrottle>>
+    ) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_ADMIN_LINE_LENGTH));
+
+        let mut attempts = 0;
+        loop {
+            writer.write_all(b"password: ").await?;
+            let line = match next_line(&mut lines).await? {
+                Some(line) => line,
+                None => {
+                    return Ok(());
+                }
+            };
+            // shared between sessions, so reconnecting doesn't reset the backoff
+            let allowed = throttle.lock().unwrap().attempt(peer.ip());
+            if let Err(wait) = allowed {
+                warn!("admin console: {} is logging in too often, turned away for {:?}", peer, wait);
+                let message = format!("too many failed logins, try again in {} seconds\n", wait.as_secs().max(1));
+                writer.write_all(message.as_bytes()).await?;
+                return Ok(());
+            }
+            // compare digests so the check doesn't leak how much of the password matched
+            if password_digest(&line) == digest {
+                throttle.lock().unwrap().succeeded(peer.ip());
+                break;
+            }
+            attempts += 1;
+            warn!("admin console: failed login from {}", peer);
+            if attempts >= MAX_LOGIN_ATTEMPTS {
+                writer.write_all(b"too many failed attempts\n").await?;
+                return Ok(());
+            }
+        }
+        info!("admin console: {} logged in", peer);
+        writer.write_all(b"logged in, type help for a list of commands\n> ").await?;
+
+        let source = format!("admin console {}", peer);
+        while let Some(line) = next_line(&mut lines).await? {
+            let line = line.trim();
+            if line == "quit" || line == "exit" {
+                break;
+            }
+            let response = self.clone().run_console_command(&source, line).await;
+            if !response.is_empty() {
+                writer.write_all(response.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+            }
+            if !self.running.load(Ordering::Relaxed) {
+                break;
+            }
+            writer.write_all(b"> ").await?;
+        }
+        info!("admin console: {} logged out", peer);
+        Ok(())
+    }
+}
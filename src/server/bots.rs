@@ -0,0 +1,900 @@
+use crate::optimisations::helpers;
+use crate::server::disconnect::DisconnectReason;
+use crate::server::identity::ServerTrust;
+use crate::server::lan::ClientLanConnection;
+use crate::server::replication::SnapshotReceiver;
+use crate::server::server_player::DEFAULT_MOVESPEED;
+use crate::server::websocket::ClientWebSocketConnection;
+use crate::server::{
+    generate_uuid, ConnectionClientside, ConnectionUUID, FastPacket, FastPacketData, Server,
+    SteadyPacket, SteadyPacketData,
+};
+use crate::worldmachine::components::COMPONENT_TYPE_PLAYER;
+use crate::worldmachine::ecs::ParameterValue;
+use crate::worldmachine::navmesh::NavMesh;
+use crate::worldmachine::player::MovementInfo;
+use crate::worldmachine::prediction::InputCommand;
+use crate::worldmachine::EntityId;
+use gfx_maths::*;
+use halfbrown::HashMap;
+use rand::Rng;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+pub const BOT_TICK_RATE: f32 = 20.0;
+pub const BOT_PING_INTERVAL: f32 = 5.0;
+pub const BOT_WANDER_INTERVAL: f32 = 3.0;
+pub const BOT_THROW_RANGE: f32 = 20.0;
+pub const BOT_THROW_COOLDOWN: f32 = 1.5;
+pub const BOT_CHASE_DISTANCE: f32 = 4.0;
+pub const BOT_FLEE_DISTANCE: f32 = 15.0;
+pub const BOT_MAX_PACKETS_PER_TICK: usize = 32;
+pub const BOT_REPATH_INTERVAL: f32 = 1.0;
+pub const BOT_WAYPOINT_RADIUS: f32 = 0.75;
+
+static BOT_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BotBehaviour {
+    Wander,
+    Chase,
+    Flee,
+}
+
+impl BotBehaviour {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "wander" => Some(BotBehaviour::Wander),
+            "chase" => Some(BotBehaviour::Chase),
+            "flee" => Some(BotBehaviour::Flee),
+            _ => None,
+        }
+    }
+
+    pub fn random() -> Self {
+        match rand::thread_rng().gen_range(0..3) {
+            0 => BotBehaviour::Wander,
+            1 => BotBehaviour::Chase,
+            _ => BotBehaviour::Flee,
+        }
+    }
+}
+
+impl Display for BotBehaviour {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotBehaviour::Wander => write!(f, "wander"),
+            BotBehaviour::Chase => write!(f, "chase"),
+            BotBehaviour::Flee => write!(f, "flee"),
+        }
+    }
+}
+
+// local bots skip the network, the others exercise the same path as real players
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BotTransport {
+    #[default]
+    Local,
+    Lan,
+    WebSocket,
+}
+
+impl BotTransport {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "local" => Some(BotTransport::Local),
+            "lan" => Some(BotTransport::Lan),
+            "websocket" | "ws" => Some(BotTransport::WebSocket),
+            _ => None,
+        }
+    }
+}
+
+impl Display for BotTransport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotTransport::Local => write!(f, "local"),
+            BotTransport::Lan => write!(f, "lan"),
+            BotTransport::WebSocket => write!(f, "websocket"),
+        }
+    }
+}
+
+// a listener bound to every interface is reached through loopback
+fn loopback_for(hostname: &str) -> &str {
+    match hostname {
+        "0.0.0.0" => "127.0.0.1",
+        "::" | "[::]" => "::1",
+        hostname => hostname,
+    }
+}
+
+#[derive(Clone)]
+pub struct BotHandle {
+    pub name: String,
+    pub uuid: ConnectionUUID,
+    pub behaviour: BotBehaviour,
+    pub transport: BotTransport,
+    pub running: Arc<AtomicBool>,
+}
+
+pub struct Bot {
+    pub name: String,
+    pub behaviour: BotBehaviour,
+    connection: ConnectionClientside,
+    running: Arc<AtomicBool>,
+    tasks: Vec<JoinHandle<()>>,
+    uuid: Option<ConnectionUUID>,
+    entity_id: Option<EntityId>,
+    position: Vec3,
+    yaw: f32,
+    wander_direction: Vec3,
+    last_wander_change: Instant,
+    last_throw: Instant,
+    last_ping: Instant,
+    players: HashMap<EntityId, Vec3>,
+    snapshot_receiver: SnapshotReceiver,
+    input_sequence: u32,
+    navmesh: Option<Arc<NavMesh>>,
+    path: Vec<Vec3>,
+    last_path_query: Instant,
+}
+
+impl Bot {
+    pub fn new(
+        name: &str,
+        behaviour: BotBehaviour,
+        connection: ConnectionClientside,
+        running: Arc<AtomicBool>,
+        tasks: Vec<JoinHandle<()>>,
+        navmesh: Option<Arc<NavMesh>>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            behaviour,
+            connection,
+            running,
+            tasks,
+            uuid: None,
+            entity_id: None,
+            position: Vec3::zero(),
+            yaw: 0.0,
+            wander_direction: Vec3::zero(),
+            last_wander_change: Instant::now(),
+            last_throw: Instant::now(),
+            last_ping: Instant::now(),
+            players: HashMap::new(),
+            snapshot_receiver: SnapshotReceiver::default(),
+            input_sequence: 0,
+            navmesh,
+            path: Vec::new(),
+            last_path_query: Instant::now(),
+        }
+    }
+
+    async fn send_fast(&self, packet: FastPacket) -> bool {
+        let packet = FastPacketData {
+            packet: Some(packet),
+        };
+        match &self.connection {
+            ConnectionClientside::Local(connection) => {
+                let connection = connection.lock().await;
+                connection.fast_update_sender.send(packet).await.is_ok()
+            }
+            ConnectionClientside::Lan(connection) => {
+                connection.send_fast_and_serialise(packet).await;
+                true
+            }
+            ConnectionClientside::WebSocket(connection) => {
+                connection.send_fast_and_serialise(packet).await.is_ok()
+            }
+        }
+    }
+
+    async fn flush_fast(&self) -> bool {
+        match &self.connection {
+            ConnectionClientside::Lan(connection) => connection.flush_fast().await.is_ok(),
+            _ => true,
+        }
+    }
+
+    async fn send_steady(&self, packet: SteadyPacket) -> bool {
+        let packet = SteadyPacketData {
+            packet,
+            uuid: generate_uuid(),
+        };
+        match &self.connection {
+            ConnectionClientside::Local(connection) => {
+                let connection = connection.lock().await;
+                connection.steady_update_sender.send(packet).await.is_ok()
+            }
+            ConnectionClientside::Lan(connection) => {
+                connection.send_steady_and_serialise(packet).await.is_ok()
+            }
+            ConnectionClientside::WebSocket(connection) => {
+                connection.send_steady_and_serialise(packet).await.is_ok()
+            }
+        }
+    }
+
+    async fn receive_steady(&self) -> Result<Option<SteadyPacket>, TryRecvError> {
+        match &self.connection {
+            ConnectionClientside::Local(connection) => {
+                let mut connection = connection.lock().await;
+                match connection.steady_update_receiver.try_recv() {
+                    Ok(packet) => Ok(Some(packet.packet)),
+                    Err(TryRecvError::Empty) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            ConnectionClientside::Lan(connection) => Ok(connection
+                .attempt_receive_steady_and_deserialise()
+                .await
+                .map(|packet| packet.packet)),
+            ConnectionClientside::WebSocket(connection) => Ok(connection
+                .attempt_receive_steady_and_deserialise()
+                .await
+                .map(|packet| packet.packet)),
+        }
+    }
+
+    async fn receive_fast(&self) -> Result<Option<FastPacket>, TryRecvError> {
+        match &self.connection {
+            ConnectionClientside::Local(connection) => {
+                let mut connection = connection.lock().await;
+                match connection.fast_update_receiver.try_recv() {
+                    Ok(packet) => Ok(packet.packet),
+                    Err(TryRecvError::Empty) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            ConnectionClientside::Lan(connection) => Ok(connection
+                .attempt_receive_fast_and_deserialise()
+                .await
+                .and_then(|packet| packet.packet)),
+            ConnectionClientside::WebSocket(connection) => Ok(connection
+                .attempt_receive_fast_and_deserialise()
+                .await
+                .and_then(|packet| packet.packet)),
+        }
+    }
+
+    async fn handle_steady_packet(&mut self, packet: SteadyPacket) {
+        match packet {
+            SteadyPacket::InitialisePlayer(uuid, entity_id, _name, position, _rotation, _scale) => {
+                self.uuid = Some(uuid);
+                self.entity_id = Some(entity_id);
+                self.position = position;
+                self.send_steady(SteadyPacket::SetName(String::new(), self.name.clone()))
+                    .await;
+                debug!("bot {} joined as entity {}", self.name, entity_id);
+            }
+            SteadyPacket::InitialiseEntity(entity_id, entity) => {
+                if Some(entity_id) == self.entity_id {
+                    return;
+                }
+                if let Some(player) = entity.get_component(COMPONENT_TYPE_PLAYER.clone()) {
+                    if let ParameterValue::Vec3(position) = player.get_parameter("position").value {
+                        self.players.insert(entity_id, position);
+                    }
+                }
+            }
+            SteadyPacket::RemoveEntity(entity_id) => {
+                self.players.remove(&entity_id);
+            }
+            SteadyPacket::Respawn(position) => {
+                self.position = position;
+            }
+            SteadyPacket::NameRejected(reason) => {
+                warn!("bot {}: name rejected ({:?})", self.name, reason);
+            }
+            SteadyPacket::Disconnect(reason) => {
+                info!("bot {} was disconnected: {}", self.name, reason);
+                self.running.store(false, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_fast_packet(&mut self, packet: FastPacket) {
+        match packet {
+            FastPacket::Snapshot(delta) => {
+                if let Some(applied) = self.snapshot_receiver.receive(delta) {
+                    self.send_fast(FastPacket::SnapshotAck(applied.tick)).await;
+                    for (entity_id, state) in applied.changed {
+                        if state.head_rotation.is_some() && Some(entity_id) != self.entity_id {
+                            self.players.insert(entity_id, state.position.to_vec3());
+                        }
+                    }
+                }
+            }
+            FastPacket::PlayerInputAck(ack) => {
+                self.position = ack.position;
+            }
+            _ => {}
+        }
+    }
+
+    async fn process_packets(&mut self) -> bool {
+        for _ in 0..BOT_MAX_PACKETS_PER_TICK {
+            match self.receive_steady().await {
+                Ok(Some(packet)) => self.handle_steady_packet(packet).await,
+                Ok(None) => break,
+                Err(_) => return false,
+            }
+        }
+        for _ in 0..BOT_MAX_PACKETS_PER_TICK {
+            match self.receive_fast().await {
+                Ok(Some(packet)) => self.handle_fast_packet(packet).await,
+                Ok(None) => break,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    fn nearest_player(&self) -> Option<(Vec3, f32)> {
+        self.players
+            .values()
+            .map(|position| (*position, helpers::distance(*position, self.position)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    fn flat_direction(from: Vec3, to: Vec3) -> Vec3 {
+        let direction = Vec3::new(to.x - from.x, 0.0, to.z - from.z);
+        if direction.magnitude() > 0.0 {
+            direction.normalized()
+        } else {
+            Vec3::zero()
+        }
+    }
+
+    fn steer_towards(&mut self, target: Vec3) -> Vec3 {
+        let navmesh = match &self.navmesh {
+            Some(navmesh) => navmesh.clone(),
+            None => return Self::flat_direction(self.position, target),
+        };
+        if self.path.is_empty() || self.last_path_query.elapsed().as_secs_f32() > BOT_REPATH_INTERVAL
+        {
+            self.path = navmesh.find_path(self.position, target).unwrap_or_default();
+            self.last_path_query = Instant::now();
+        }
+        while let Some(waypoint) = self.path.first() {
+            let offset = Vec2::new(waypoint.x - self.position.x, waypoint.z - self.position.z);
+            if offset.magnitude() < BOT_WAYPOINT_RADIUS {
+                self.path.remove(0);
+            } else {
+                break;
+            }
+        }
+        match self.path.first() {
+            Some(waypoint) => Self::flat_direction(self.position, *waypoint),
+            None => Self::flat_direction(self.position, target),
+        }
+    }
+
+    fn wander(&mut self) -> Vec3 {
+        if self.last_wander_change.elapsed().as_secs_f32() > BOT_WANDER_INTERVAL {
+            let mut rng = rand::thread_rng();
+            self.wander_direction = if rng.gen_bool(0.25) {
+                Vec3::zero()
+            } else {
+                let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+                Vec3::new(angle.sin(), 0.0, angle.cos())
+            };
+            self.last_wander_change = Instant::now();
+        }
+        self.wander_direction
+    }
+
+    fn choose_direction(&mut self) -> Vec3 {
+        let nearest = self.nearest_player();
+        match (self.behaviour, nearest) {
+            (BotBehaviour::Chase, Some((target, distance))) => {
+                if distance > BOT_CHASE_DISTANCE {
+                    self.steer_towards(target)
+                } else {
+                    Vec3::zero()
+                }
+            }
+            (BotBehaviour::Flee, Some((target, distance))) if distance < BOT_FLEE_DISTANCE => {
+                Self::flat_direction(target, self.position)
+            }
+            _ => self.wander(),
+        }
+    }
+
+    fn face(&mut self, direction: Vec3) {
+        if direction.magnitude() > 0.0 {
+            self.yaw = -direction.x.atan2(direction.z);
+        }
+    }
+
+    async fn act(&mut self, delta: f32) -> bool {
+        let uuid = match &self.uuid {
+            Some(uuid) => uuid.clone(),
+            None => return true,
+        };
+
+        let direction = self.choose_direction();
+        self.face(direction);
+
+        let mut throw = false;
+        if self.behaviour != BotBehaviour::Flee {
+            if let Some((target, distance)) = self.nearest_player() {
+                if distance < BOT_THROW_RANGE
+                    && self.last_throw.elapsed().as_secs_f32() > BOT_THROW_COOLDOWN
+                {
+                    self.face(Self::flat_direction(self.position, target));
+                    throw = true;
+                }
+            }
+        }
+
+        let velocity = direction * DEFAULT_MOVESPEED;
+        self.position += velocity * delta;
+        let rotation = Quaternion::from_euler_radians_zyx(&Vec3::new(0.0, self.yaw, 0.0));
+        let movement_info = MovementInfo {
+            jumped: false,
+            sprinting: false,
+            speed: if direction.magnitude() > 0.0 { 1.0 } else { 0.0 },
+            strafe: 0.0,
+        };
+        let command = InputCommand {
+            sequence: self.input_sequence,
+            movement: velocity * delta,
+            jump: false,
+            delta_time: delta,
+            frame_delta: delta,
+            rotation,
+            head_rotation: rotation,
+            movement_info,
+            position: self.position,
+        };
+        self.input_sequence = self.input_sequence.wrapping_add(1);
+        let sent = self
+            .send_fast(FastPacket::PlayerInput(uuid, vec![command]))
+            .await;
+        if !sent {
+            return false;
+        }
+
+        if throw {
+            self.last_throw = Instant::now();
+            return self
+                .send_steady(SteadyPacket::ThrowThrowAballll(
+                    String::new(),
+                    Vec3::default(),
+                    Vec3::default(),
+                ))
+                .await;
+        }
+        true
+    }
+
+    pub async fn run(mut self) {
+        let tick = Duration::from_secs_f32(1.0 / BOT_TICK_RATE);
+        let mut last_tick = Instant::now();
+        while self.running.load(Ordering::Relaxed) {
+            if !self.process_packets().await {
+                break;
+            }
+            let delta = last_tick.elapsed().as_secs_f32();
+            last_tick = Instant::now();
+            if !self.act(delta).await {
+                break;
+            }
+            if !self.flush_fast().await {
+                break;
+            }
+            if self.last_ping.elapsed().as_secs_f32() > BOT_PING_INTERVAL {
+                if !self.send_steady(SteadyPacket::Ping).await {
+                    break;
+                }
+                self.last_ping = Instant::now();
+            }
+            tokio::time::sleep(tick).await;
+        }
+        self.running.store(false, Ordering::Relaxed);
+        self.send_steady(SteadyPacket::Disconnect(DisconnectReason::Quit))
+            .await;
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+        debug!("bot {} stopped", self.name);
+    }
+}
+
+impl Server {
+    async fn connect_bot(
+        &mut self,
+        transport: BotTransport,
+    ) -> Result<(ConnectionClientside, ConnectionUUID, Vec<JoinHandle<()>>), String> {
+        if transport != BotTransport::Local {
+            // bots have no accounts to log in with
+            if self.authenticator().is_some_and(|authenticator| authenticator.required) {
+                return Err("this server requires login, only local bots can join".to_string());
+            }
+        }
+        let listeners = self.listeners.read().unwrap().clone();
+        match transport {
+            BotTransport::Local => {
+                let connection = self.join_local_server(false).await;
+                let uuid = connection.lock().await.uuid.clone();
+                Ok((ConnectionClientside::Local(connection), uuid, Vec::new()))
+            }
+            BotTransport::Lan => {
+                let (hostname, tcp_port, udp_port) = listeners
+                    .lan
+                    .ok_or_else(|| "this server isn't hosting over lan".to_string())?;
+                let trust = ServerTrust::Expected(self.identity.public_key());
+                let (connection, tcpstream, tcpreceiver) = ClientLanConnection::connect(
+                    loopback_for(&hostname),
+                    tcp_port,
+                    udp_port,
+                    &trust,
+                    None,
+                    None,
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+                let the_clone = connection.clone();
+                let udp_task = tokio::spawn(async move {
+                    the_clone.udp_listener_thread().await;
+                });
+                let the_clone = connection.clone();
+                let tcp_task = tokio::spawn(async move {
+                    the_clone.tcp_listener_thread(tcpstream, tcpreceiver).await;
+                });
+                let uuid = connection.uuid.clone();
+                Ok((
+                    ConnectionClientside::Lan(connection),
+                    uuid,
+                    vec![udp_task, tcp_task],
+                ))
+            }
+            BotTransport::WebSocket => {
+                let (hostname, port) = listeners
+                    .websocket
+                    .ok_or_else(|| "this server isn't hosting over websocket".to_string())?;
+                let url = format!("ws://{}:{}", loopback_for(&hostname), port);
+                let (connection, socket, receiver) =
+                    ClientWebSocketConnection::connect(&url, None, None)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                let the_clone = connection.clone();
+                let socket_task = tokio::spawn(async move {
+                    the_clone.socket_thread(socket, receiver).await;
+                });
+                let uuid = connection.uuid.clone();
+                Ok((
+                    ConnectionClientside::WebSocket(connection),
+                    uuid,
+                    vec![socket_task],
+                ))
+            }
+        }
+    }
+
+    pub async fn spawn_bots(
+        &mut self,
+        count: usize,
+        behaviour: Option<BotBehaviour>,
+        transport: BotTransport,
+    ) -> usize {
+        let mut spawned = 0;
+        for _ in 0..count {
+            let name = format!("bot_{}", BOT_COUNTER.fetch_add(1, Ordering::Relaxed));
+            let (connection, uuid, tasks) = match self.connect_bot(transport).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("spawn_bots: failed to connect bot over {}: {}", transport, e);
+                    break;
+                }
+            };
+            let behaviour = behaviour.unwrap_or_else(BotBehaviour::random);
+            let running = Arc::new(AtomicBool::new(true));
+            let navmesh = self.worldmachine.lock().await.navmesh.clone();
+            let bot = Bot::new(&name, behaviour, connection, running.clone(), tasks, navmesh);
+            tokio::spawn(bot.run());
+
+            let mut bots = self.bots.lock().await;
+            bots.retain(|bot| bot.running.load(Ordering::Relaxed));
+            bots.push(BotHandle {
+                name,
+                uuid,
+                behaviour,
+                transport,
+                running,
+            });
+            spawned += 1;
+        }
+        info!("spawned {} bots", spawned);
+        spawned
+    }
+
+    pub async fn remove_bots(&self, count: Option<usize>) -> usize {
+        let mut bots = self.bots.lock().await;
+        bots.retain(|bot| bot.running.load(Ordering::Relaxed));
+        let count = count.unwrap_or(bots.len()).min(bots.len());
+        let remaining = bots.len() - count;
+        let removed = bots.split_off(remaining);
+        for bot in removed.iter() {
+            bot.running.store(false, Ordering::Relaxed);
+        }
+        info!("removed {} bots", removed.len());
+        removed.len()
+    }
+
+    pub async fn list_bots(&self) -> Vec<BotHandle> {
+        let mut bots = self.bots.lock().await;
+        bots.retain(|bot| bot.running.load(Ordering::Relaxed));
+        bots.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connections::SteadyMessageQueue;
+    use crate::server::LocalConnectionClientSide;
+    use crate::worldmachine::components::{Transform, COMPONENT_TYPE_BOX_COLLIDER};
+    use crate::worldmachine::ecs::{Component, Entity, Parameter};
+    use crate::worldmachine::player::PlayerComponent;
+    use crate::worldmachine::prediction::InputAck;
+    use crate::worldmachine::GAME_DATA_PATH;
+    use mutex_timeouts::tokio::MutexWithTimeoutAuto as Mutex;
+    use std::collections::BTreeMap;
+    use tokio::sync::mpsc;
+
+    // the server's end of a local bot connection
+    struct FakeServer {
+        fast: mpsc::Sender<FastPacketData>,
+        steady: mpsc::Sender<SteadyPacketData>,
+        fast_from_bot: mpsc::Receiver<FastPacketData>,
+        steady_from_bot: mpsc::Receiver<SteadyPacketData>,
+    }
+
+    impl FakeServer {
+        async fn send_steady(&self, packet: SteadyPacket) {
+            let packet = SteadyPacketData {
+                packet,
+                uuid: generate_uuid(),
+            };
+            self.steady.send(packet).await.unwrap();
+        }
+
+        fn steady_sent(&mut self) -> Vec<SteadyPacket> {
+            std::iter::from_fn(|| self.steady_from_bot.try_recv().ok())
+                .map(|packet| packet.packet)
+                .collect()
+        }
+
+        fn fast_sent(&mut self) -> Vec<FastPacket> {
+            std::iter::from_fn(|| self.fast_from_bot.try_recv().ok())
+                .filter_map(|packet| packet.packet)
+                .collect()
+        }
+    }
+
+    fn local_bot(behaviour: BotBehaviour, navmesh: Option<Arc<NavMesh>>) -> (Bot, FakeServer) {
+        let (fast, fast_update_receiver) = mpsc::channel(64);
+        let (steady, steady_update_receiver) = mpsc::channel(64);
+        let (fast_update_sender, fast_from_bot) = mpsc::channel(64);
+        let (steady_update_sender, steady_from_bot) = mpsc::channel(64);
+        let connection = LocalConnectionClientSide {
+            uuid: "bot".to_string(),
+            fast_update_sender,
+            steady_update_sender,
+            steady_sender_queue: Arc::new(Mutex::new(SteadyMessageQueue::new())),
+            fast_update_receiver,
+            steady_update_receiver,
+        };
+        let bot = Bot::new(
+            "bot_test",
+            behaviour,
+            ConnectionClientside::Local(Arc::new(Mutex::new(connection))),
+            Arc::new(AtomicBool::new(true)),
+            Vec::new(),
+            navmesh,
+        );
+        let server = FakeServer {
+            fast,
+            steady,
+            fast_from_bot,
+            steady_from_bot,
+        };
+        (bot, server)
+    }
+
+    fn other_player(position: Vec3) -> Entity {
+        let mut entity = Entity::new("someone");
+        entity.add_component(PlayerComponent::new(
+            "someone",
+            "someone".to_string(),
+            position,
+            Quaternion::identity(),
+            Vec3::new(1.0, 1.0, 1.0),
+        ));
+        entity
+    }
+
+    fn box_entity(position: Vec3, scale: Vec3) -> Entity {
+        let mut parameters = BTreeMap::new();
+        parameters.insert(
+            "position".to_string(),
+            Parameter::new("position", ParameterValue::Vec3(Vec3::zero())),
+        );
+        parameters.insert(
+            "scale".to_string(),
+            Parameter::new("scale", ParameterValue::Vec3(scale)),
+        );
+        let mut entity = Entity::new("box");
+        entity.add_component(Transform::new(
+            position,
+            Quaternion::identity(),
+            Vec3::new(1.0, 1.0, 1.0),
+        ));
+        entity.add_component(Component {
+            name: "BoxCollider".to_string(),
+            parameters,
+            component_type: COMPONENT_TYPE_BOX_COLLIDER.clone(),
+        });
+        entity
+    }
+
+    #[test]
+    fn names_parse_whatever_the_case() {
+        for behaviour in [BotBehaviour::Wander, BotBehaviour::Chase, BotBehaviour::Flee] {
+            let name = behaviour.to_string();
+            assert_eq!(BotBehaviour::from_name(&name), Some(behaviour));
+            assert_eq!(BotBehaviour::from_name(&name.to_uppercase()), Some(behaviour));
+        }
+        assert_eq!(BotBehaviour::from_name("dance"), None);
+
+        for transport in [
+            BotTransport::Local,
+            BotTransport::Lan,
+            BotTransport::WebSocket,
+        ] {
+            assert_eq!(BotTransport::from_name(&transport.to_string()), Some(transport));
+        }
+        assert_eq!(BotTransport::from_name("WS"), Some(BotTransport::WebSocket));
+        assert_eq!(BotTransport::from_name("carrier pigeon"), None);
+
+        assert_eq!(loopback_for("0.0.0.0"), "127.0.0.1");
+        assert_eq!(loopback_for("[::]"), "::1");
+        assert_eq!(loopback_for("192.168.1.20"), "192.168.1.20");
+    }
+
+    #[tokio::test]
+    async fn bots_join_and_keep_track_of_other_players() {
+        let (mut bot, mut server) = local_bot(BotBehaviour::Wander, None);
+        let spawn = Vec3::new(1.0, 2.0, 3.0);
+        server
+            .send_steady(SteadyPacket::InitialisePlayer(
+                "bot uuid".to_string(),
+                7,
+                String::new(),
+                spawn,
+                Quaternion::identity(),
+                Vec3::new(1.0, 1.0, 1.0),
+            ))
+            .await;
+        let someone = other_player(Vec3::new(5.0, 0.0, 0.0));
+        let someone_id = someone.uid;
+        server
+            .send_steady(SteadyPacket::InitialiseEntity(someone_id, someone))
+            .await;
+        assert!(bot.process_packets().await);
+
+        assert_eq!(bot.uuid.as_deref(), Some("bot uuid"));
+        assert_eq!(bot.entity_id, Some(7));
+        assert_eq!(bot.position, spawn);
+        assert!(matches!(
+            server.steady_sent().as_slice(),
+            [SteadyPacket::SetName(_, name)] if name == "bot_test"
+        ));
+        assert_eq!(bot.players.get(&someone_id), Some(&Vec3::new(5.0, 0.0, 0.0)));
+
+        // the server's word on where the bot is wins
+        let ack = InputAck {
+            sequence: 0,
+            position: Vec3::new(2.0, 2.0, 2.0),
+            vertical_velocity: 0.0,
+        };
+        server
+            .fast
+            .send(FastPacketData {
+                packet: Some(FastPacket::PlayerInputAck(ack)),
+            })
+            .await
+            .unwrap();
+        server
+            .send_steady(SteadyPacket::RemoveEntity(someone_id))
+            .await;
+        server
+            .send_steady(SteadyPacket::Disconnect(DisconnectReason::Quit))
+            .await;
+        assert!(bot.process_packets().await);
+        assert!(bot.players.is_empty());
+        assert_eq!(bot.position, Vec3::new(2.0, 2.0, 2.0));
+        assert!(!bot.running.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn chasers_close_in_and_fleers_run_away() {
+        let (mut chaser, _server) = local_bot(BotBehaviour::Chase, None);
+        chaser.players.insert(1, Vec3::new(10.0, 0.0, 0.0));
+        assert_eq!(chaser.choose_direction(), Vec3::new(1.0, 0.0, 0.0));
+        chaser.players.insert(1, Vec3::new(BOT_CHASE_DISTANCE / 2.0, 0.0, 0.0));
+        assert_eq!(chaser.choose_direction(), Vec3::zero());
+
+        let (mut fleer, _server) = local_bot(BotBehaviour::Flee, None);
+        fleer.players.insert(1, Vec3::new(0.0, 0.0, 5.0));
+        assert_eq!(fleer.choose_direction(), Vec3::new(0.0, 0.0, -1.0));
+        // far enough away that it goes back to wandering
+        fleer.players.insert(1, Vec3::new(0.0, 0.0, BOT_FLEE_DISTANCE * 2.0));
+        fleer.wander_direction = Vec3::new(1.0, 0.0, 0.0);
+        assert_eq!(fleer.choose_direction(), Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn chasers_follow_the_navmesh_around_walls() {
+        let floor = box_entity(Vec3::new(0.0, -0.5, 0.0), Vec3::new(12.0, 1.0, 12.0));
+        let wall = box_entity(Vec3::new(0.0, 1.0, -2.0), Vec3::new(0.5, 2.0, 8.0));
+        let navmesh = NavMesh::bake(&[floor, wall], GAME_DATA_PATH, [0; 32]).unwrap();
+        let (mut bot, _server) = local_bot(BotBehaviour::Chase, Some(Arc::new(navmesh)));
+        bot.position = Vec3::new(-3.0, 0.0, -3.0);
+        bot.players.insert(1, Vec3::new(3.0, 0.0, -3.0));
+
+        // straight at the target would walk into the wall, the path heads for the gap past its end
+        let direction = bot.choose_direction();
+        assert!(direction.z > 0.5, "went {:?}", direction);
+        assert!(!bot.path.is_empty());
+        assert!(bot.path.iter().any(|waypoint| waypoint.z > 2.0));
+    }
+
+    #[tokio::test]
+    async fn bots_send_numbered_inputs_and_throw_when_close() {
+        let (mut bot, mut server) = local_bot(BotBehaviour::Chase, None);
+        // nothing to do before the server has told it who it is
+        assert!(bot.act(0.05).await);
+        assert!(server.fast_sent().is_empty());
+
+        bot.uuid = Some("bot uuid".to_string());
+        bot.players.insert(1, Vec3::new(10.0, 0.0, 0.0));
+        bot.last_throw = Instant::now() - Duration::from_secs_f32(BOT_THROW_COOLDOWN * 2.0);
+        assert!(bot.act(0.05).await);
+        assert!(bot.act(0.05).await);
+        let sequences = server
+            .fast_sent()
+            .into_iter()
+            .map(|packet| match packet {
+                FastPacket::PlayerInput(uuid, commands) => {
+                    assert_eq!(uuid, "bot uuid");
+                    commands[0].sequence
+                }
+                other => panic!("expected an input, got {:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sequences, vec![0, 1]);
+        assert!(bot.position.x > 0.0);
+        // the cooldown holds back the second throw
+        let throws = server
+            .steady_sent()
+            .into_iter()
+            .filter(|packet| matches!(packet, SteadyPacket::ThrowThrowAballll(..)))
+            .count();
+        assert_eq!(throws, 1);
+
+        bot.behaviour = BotBehaviour::Flee;
+        bot.last_throw = Instant::now() - Duration::from_secs_f32(BOT_THROW_COOLDOWN * 2.0);
+        assert!(bot.act(0.05).await);
+        assert!(server.steady_sent().is_empty());
+    }
+}
@@ -0,0 +1,527 @@
+use crate::server::Server;
+use rand::rngs::StdRng;
+use rand::{ Rng, SeedableRng };
+use serde::{ Deserialize, Serialize };
+use std::fmt::{ Display, Formatter };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+// a packet picked for reordering is held back this long so the ones sent after it overtake it
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+// like a router's buffer, datagrams that would wait longer than this for bandwidth are dropped
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(500);
+const MAX_LATENCY_MS: u64 = 10_000;
+
+// latency, jitter and bandwidth apply to each direction on its own
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkConditions {
+    pub latency_ms: u64,
+    pub jitter_ms: u64,
+    pub loss_percent: f32,
+    pub duplicate_percent: f32,
+    pub reorder_percent: f32,
+    // 0 is unlimited
+    pub bandwidth_kbps: u64,
+    pub seed: u64,
+}
+
+impl LinkConditions {
+    pub fn is_active(&self) -> bool {
+        self.latency_ms > 0 ||
+            self.jitter_ms > 0 ||
+            self.loss_percent > 0.0 ||
+            self.duplicate_percent > 0.0 ||
+            self.reorder_percent > 0.0 ||
+            self.bandwidth_kbps > 0
+    }
+
+    // "off", or any of latency=, jitter=, loss=, dup=, reorder=, bandwidth= and seed=
+    pub fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut conditions = Self::default();
+        if args == ["off"] {
+            return Ok(conditions);
+        }
+        if args.is_empty() {
+            return Err("expected off or name=value pairs".to_string());
+        }
+        for arg in args {
+            let (name, value) = arg.split_once('=').ok_or(format!("expected name=value, got {}", arg))?;
+            let integer = || value.parse::<u64>().map_err(|_| format!("{} must be a whole number", name));
+            let percent = || value.parse::<f32>().map_err(|_| format!("{} must be a percentage", name));
+            match name {
+                "latency" => {
+                    conditions.latency_ms = integer()?;
+                }
+                "jitter" => {
+                    conditions.jitter_ms = integer()?;
+                }
+                "loss" => {
+                    conditions.loss_percent = percent()?;
+                }
+                "dup" => {
+                    conditions.duplicate_percent = percent()?;
+                }
+                "reorder" => {
+                    conditions.reorder_percent = percent()?;
+                }
+                "bandwidth" => {
+                    conditions.bandwidth_kbps = integer()?;
+                }
+                "seed" => {
+                    conditions.seed = integer()?;
+                }
+                _ => {
+                    return Err(format!("unknown condition {}", name));
+                }
+            }
+        }
+        conditions.validate()?;
+        Ok(conditions)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("latency_ms", self.latency_ms),
+            ("jitter_ms", self.jitter_ms),
+        ] {
+            if value > MAX_LATENCY_MS {
+                return Err(format!("{} must not be above {}", name, MAX_LATENCY_MS));
+            }
+        }
+        for (name, value) in [
+            ("loss_percent", self.loss_percent),
+            ("duplicate_percent", self.duplicate_percent),
+            ("reorder_percent", self.reorder_percent),
+        ] {
+            if !(0.0..=100.0).contains(&value) {
+                return Err(format!("{} must be between 0 and 100", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for LinkConditions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !self.is_active() {
+            return write!(f, "off");
+        }
+        write!(
+            f,
+            "latency {}ms, jitter {}ms, loss {}%, duplicate {}%, reorder {}%, bandwidth ",
+            self.latency_ms,
+            self.jitter_ms,
+            self.loss_percent,
+            self.duplicate_percent,
+            self.reorder_percent
+        )?;
+        match self.bandwidth_kbps {
+            0 => write!(f, "unlimited")?,
+            kbps => write!(f, "{}kbps", kbps)?,
+        }
+        write!(f, ", seed {}", self.seed)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkDirection {
+    ToServer,
+    ToClient,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkStats {
+    pub delayed: u64,
+    pub lost: u64,
+    pub overflowed: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+impl Display for LinkStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} delayed, {} lost, {} over bandwidth, {} duplicated, {} reordered",
+            self.delayed,
+            self.lost,
+            self.overflowed,
+            self.duplicated,
+            self.reordered
+        )
+    }
+}
+
+struct DirectionState {
+    rng: StdRng,
+    link_free_at: Instant,
+    ordered_until: Instant,
+}
+
+impl DirectionState {
+    fn new(seed: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            link_free_at: now,
+            ordered_until: now,
+        }
+    }
+
+    fn roll(&mut self, percent: f32) -> bool {
+        percent > 0.0 && self.rng.gen_range(0.0..100.0) < percent
+    }
+
+    // when the last bit of a packet leaves, or None if the queue in front of it is too long
+    fn transmit(&mut self, conditions: &LinkConditions, size: usize, can_drop: bool) -> Option<Instant> {
+        let now = Instant::now();
+        if conditions.bandwidth_kbps == 0 {
+            return Some(now);
+        }
+        let start = self.link_free_at.max(now);
+        if can_drop && start - now > MAX_QUEUE_DELAY {
+            return None;
+        }
+        let bits = (size as f64) * 8.0;
+        self.link_free_at = start + Duration::from_secs_f64(bits / ((conditions.bandwidth_kbps as f64) * 1000.0));
+        Some(self.link_free_at)
+    }
+
+    fn delay(&mut self, conditions: &LinkConditions) -> Duration {
+        let jitter = match conditions.jitter_ms {
+            0 => 0,
+            jitter => self.rng.gen_range(0..=jitter),
+        };
+        Duration::from_millis(conditions.latency_ms + jitter)
+    }
+}
+
+struct ConditionerState {
+    conditions: LinkConditions,
+    to_server: DirectionState,
+    to_client: DirectionState,
+    stats: LinkStats,
+}
+
+impl ConditionerState {
+    fn new(conditions: LinkConditions) -> Self {
+        // the same seed gives the same drops and delays in each direction every run
+        Self {
+            to_server: DirectionState::new(conditions.seed),
+            to_client: DirectionState::new(conditions.seed.wrapping_add(1)),
+            conditions,
+            stats: LinkStats::default(),
+        }
+    }
+
+    fn split(&mut self, direction: LinkDirection) -> (&LinkConditions, &mut DirectionState, &mut LinkStats) {
+        let state = match direction {
+            LinkDirection::ToServer => &mut self.to_server,
+            LinkDirection::ToClient => &mut self.to_client,
+        };
+        (&self.conditions, state, &mut self.stats)
+    }
+}
+
+#[derive(Clone)]
+pub struct LinkConditioner {
+    state: Arc<Mutex<ConditionerState>>,
+}
+
+impl Default for LinkConditioner {
+    fn default() -> Self {
+        Self::new(LinkConditions::default())
+    }
+}
+
+impl LinkConditioner {
+    pub fn new(conditions: LinkConditions) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ConditionerState::new(conditions))),
+        }
+    }
+
+    pub fn conditions(&self) -> LinkConditions {
+        self.state.lock().unwrap().conditions.clone()
+    }
+
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        *self.state.lock().unwrap() = ConditionerState::new(conditions);
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state.lock().unwrap().conditions.is_active()
+    }
+
+    // when each copy of an unreliable packet arrives, empty if it was lost
+    pub fn schedule(&self, direction: LinkDirection, size: usize) -> Vec<Instant> {
+        let mut state = self.state.lock().unwrap();
+        let (conditions, state, stats) = state.split(direction);
+        if state.roll(conditions.loss_percent) {
+            stats.lost += 1;
+            return Vec::new();
+        }
+        let sent_at = match state.transmit(conditions, size, true) {
+            Some(sent_at) => sent_at,
+            None => {
+                stats.overflowed += 1;
+                return Vec::new();
+            }
+        };
+        let copies = if state.roll(conditions.duplicate_percent) {
+            stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let mut arrivals = Vec::with_capacity(copies);
+        for _ in 0..copies {
+            let mut arrival = sent_at + state.delay(conditions);
+            if state.roll(conditions.reorder_percent) {
+                stats.reordered += 1;
+                arrival += REORDER_DELAY;
+            }
+            arrivals.push(arrival);
+        }
+        stats.delayed += 1;
+        arrivals
+    }
+
+    // for streams that promise delivery in order, only latency, jitter and bandwidth apply
+    pub fn schedule_ordered(&self, direction: LinkDirection, size: usize) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let (conditions, state, stats) = state.split(direction);
+        let sent_at = state.transmit(conditions, size, false).unwrap_or_else(Instant::now);
+        let arrival = (sent_at + state.delay(conditions)).max(state.ordered_until);
+        state.ordered_until = arrival;
+        stats.delayed += 1;
+        arrival
+    }
+}
+
+fn serialised_size<T: Serialize>(packet: &T) -> usize {
+    rmp_serde::to_vec(packet).map_or(0, |data| data.len())
+}
+
+// an mpsc channel whose packets cross the conditioned link on their way through
+pub fn conditioned_channel<T>(
+    buffer: usize,
+    conditioner: &LinkConditioner,
+    direction: LinkDirection,
+    ordered: bool
+) -> (mpsc::Sender<T>, mpsc::Receiver<T>)
+    where T: Clone + Serialize + Send + 'static
+{
+    let (input, mut receiver) = mpsc::channel(buffer);
+    let (sender, output) = mpsc::channel(buffer);
+    let conditioner = conditioner.clone();
+    tokio::spawn(async move {
+        let (in_flight, mut arriving) = mpsc::unbounded_channel::<(Instant, T)>();
+        if ordered {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                while let Some((arrival, packet)) = arriving.recv().await {
+                    tokio::time::sleep_until(arrival).await;
+                    if sender.send(packet).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        loop {
+            // stop as soon as the far end goes away so the near end notices the disconnect
+            let packet = tokio::select! {
+                packet = receiver.recv() => packet,
+                _ = sender.closed() => None,
+            };
+            let packet = match packet {
+                Some(packet) => packet,
+                None => {
+                    break;
+                }
+            };
+            if ordered {
+                // still queued so it can't overtake packets sent while the link was conditioned
+                let arrival = if conditioner.is_active() {
+                    conditioner.schedule_ordered(direction, serialised_size(&packet))
+                } else {
+                    Instant::now()
+                };
+                in_flight.send((arrival, packet)).ok();
+                continue;
+            }
+            if !conditioner.is_active() {
+                if sender.send(packet).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            for arrival in conditioner.schedule(direction, serialised_size(&packet)) {
+                let sender = sender.clone();
+                let packet = packet.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep_until(arrival).await;
+                    sender.send(packet).await.ok();
+                });
+            }
+        }
+    });
+    (input, output)
+}
+
+impl Server {
+    pub async fn netsim(&self, args: &[&str]) -> String {
+        let (target, conditions) = match args.split_first() {
+            Some((target, conditions)) => (*target, conditions),
+            None => {
+                let connections = self.get_all_connections().await;
+                if connections.is_empty() {
+                    return "no clients connected".to_string();
+                }
+                let mut response = format!("{} clients:", connections.len());
+                for connection in connections {
+                    match connection.link_conditioner() {
+                        Some(conditioner) =>
+                            response.push_str(
+                                &format!("\n  {:?}: {} ({})", connection, conditioner.conditions(), conditioner.stats())
+                            ),
+                        None => response.push_str(&format!("\n  {:?}: can't be conditioned", connection)),
+                    }
+                }
+                return response;
+            }
+        };
+        let conditions = match LinkConditions::parse(conditions) {
+            Ok(conditions) => conditions,
+            Err(e) => {
+                return format!("netsim: {}", e);
+            }
+        };
+        let connections = if target == "all" {
+            self.get_all_connections().await
+        } else {
+            match self.resolve_target(target).await {
+                Some(target) => self.find_connections(&target).await,
+                None => {
+                    return format!("netsim: invalid target {}", target);
+                }
+            }
+        };
+        let mut conditioned = 0;
+        for connection in connections.iter() {
+            if let Some(conditioner) = connection.link_conditioner() {
+                conditioner.set_conditions(conditions.clone());
+                conditioned += 1;
+            }
+        }
+        match (connections.len(), conditioned) {
+            (0, _) => format!("netsim: no client matches {}", target),
+            (_, 0) => "netsim: none of those connections can be conditioned".to_string(),
+            (_, conditioned) => format!("set {} links to {}", conditioned, conditions),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(conditions: LinkConditions, packets: usize) -> (Vec<usize>, LinkStats) {
+        let conditioner = LinkConditioner::new(conditions);
+        let copies = (0..packets)
+            .map(|_| conditioner.schedule(LinkDirection::ToClient, 100).len())
+            .collect();
+        (copies, conditioner.stats())
+    }
+
+    #[test]
+    fn conditions_parse_and_reject_bad_values() {
+        assert_eq!(LinkConditions::parse(&["off"]), Ok(LinkConditions::default()));
+        let conditions = LinkConditions::parse(
+            &["latency=100", "jitter=20", "loss=5", "dup=1.5", "reorder=2", "bandwidth=64", "seed=7"]
+        ).unwrap();
+        assert_eq!(conditions, LinkConditions {
+            latency_ms: 100,
+            jitter_ms: 20,
+            loss_percent: 5.0,
+            duplicate_percent: 1.5,
+            reorder_percent: 2.0,
+            bandwidth_kbps: 64,
+            seed: 7,
+        });
+        assert!(conditions.is_active());
+        assert!(!LinkConditions::parse(&["seed=7"]).unwrap().is_active());
+
+        for bad in [
+            &[][..],
+            &["latency"],
+            &["latency=-1"],
+            &["latency=10001"],
+            &["jitter=1.5"],
+            &["loss=101"],
+            &["loss=NaN"],
+            &["dup=lots"],
+            &["reorder=-0.5"],
+            &["speed=1"],
+        ] {
+            assert!(LinkConditions::parse(bad).is_err(), "{:?} was accepted", bad);
+        }
+        let out_of_range = LinkConditions {
+            duplicate_percent: 150.0,
+            ..Default::default()
+        };
+        assert!(out_of_range.validate().is_err());
+    }
+
+    #[test]
+    fn loss_and_duplicates_follow_the_seed() {
+        let conditions = LinkConditions {
+            loss_percent: 20.0,
+            duplicate_percent: 10.0,
+            seed: 42,
+            ..Default::default()
+        };
+        let (copies, stats) = run(conditions.clone(), 10_000);
+        let lost = copies.iter().filter(|copies| **copies == 0).count();
+        let duplicated = copies.iter().filter(|copies| **copies == 2).count();
+        assert_eq!(stats.lost as usize, lost);
+        assert_eq!(stats.duplicated as usize, duplicated);
+        assert_eq!(stats.delayed as usize, 10_000 - lost);
+        assert!((1_800..2_200).contains(&lost), "{} lost", lost);
+        assert!((600..1_000).contains(&duplicated), "{} duplicated", duplicated);
+
+        // the same seed drops and duplicates exactly the same packets
+        assert_eq!(run(conditions.clone(), 10_000).0, copies);
+        let reseeded = LinkConditions { seed: 43, ..conditions };
+        assert_ne!(run(reseeded, 10_000).0, copies);
+    }
+
+    #[test]
+    fn packets_queued_too_long_for_bandwidth_are_dropped() {
+        // 100 bytes take 100ms at 8kbps, so only the first six fit in the queue
+        let conditions = LinkConditions {
+            bandwidth_kbps: 8,
+            ..Default::default()
+        };
+        let (copies, stats) = run(conditions.clone(), 20);
+        assert_eq!(copies.iter().filter(|copies| **copies == 1).count(), 6);
+        assert!(copies[6..].iter().all(|copies| *copies == 0));
+        assert_eq!(stats.overflowed, 14);
+
+        // ordered streams wait their turn instead
+        let conditioner = LinkConditioner::new(conditions);
+        let arrivals = (0..20)
+            .map(|_| conditioner.schedule_ordered(LinkDirection::ToServer, 100))
+            .collect::<Vec<_>>();
+        assert!(arrivals.windows(2).all(|pair| pair[1] > pair[0]));
+        assert!(arrivals[19] - arrivals[0] >= Duration::from_millis(1_900));
+        assert_eq!(conditioner.stats().overflowed, 0);
+    }
+}
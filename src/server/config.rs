@@ -0,0 +1,355 @@
+use crate::server::conditioner::LinkConditions;
+use crate::server::discovery::DISCOVERY_PORT;
+use crate::server::ratelimit::RateLimitConfig;
+use crate::server::replication::MAX_SNAPSHOT_PAYLOAD;
+use crate::server::server_player::{ DEFAULT_MOVESPEED, DEFAULT_SPRINTSPEED };
+use crate::server::websocket::DEFAULT_WEBSOCKET_PORT;
+use crate::worldmachine::GAME_DATA_PATH;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use std::fmt::{ Display, Formatter };
+use std::net::IpAddr;
+use std::path::Path;
+
+pub const SERVER_CONFIG_FILE: &str = "server.toml";
+// physics refuses steps of a millisecond or less
+const MAX_TICK_RATE: u32 = 500;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub server_name: String,
+    pub discoverable: bool,
+    pub bind_address: String,
+    pub lan_tcp_port: u16,
+    pub lan_udp_port: u16,
+    pub websocket_port: u16,
+    pub max_players: usize,
+    pub client_timeout_secs: u64,
+    // how long a dropped player's entity waits for them to reconnect, 0 removes it straight away
+    pub resume_grace_secs: u64,
+    // snapshot bytes sent per replication round, past it the lowest priority clients wait a round, 0 is unlimited
+    pub replication_budget_bytes: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            server_name: "mutEngine19 server".to_string(),
+            discoverable: true,
+            bind_address: "0.0.0.0".to_string(),
+            lan_tcp_port: 25566,
+            lan_udp_port: 25567,
+            websocket_port: DEFAULT_WEBSOCKET_PORT,
+            max_players: 32,
+            client_timeout_secs: 60,
+            resume_grace_secs: 30,
+            replication_budget_bytes: 256 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapConfig {
+    pub rotation: Vec<String>,
+    // 0 keeps the first map forever
+    pub rotate_after_secs: u64,
+}
+
+impl Default for MapConfig {
+    fn default() -> Self {
+        Self {
+            rotation: vec!["lava".to_string()],
+            rotate_after_secs: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    pub tick_rate: u32,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self { tick_rate: 100 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameplayConfig {
+    pub move_speed: f32,
+    pub sprint_speed: f32,
+    pub snowball_cooldown_secs: f32,
+    pub kill_height: f32,
+}
+
+impl Default for GameplayConfig {
+    fn default() -> Self {
+        Self {
+            move_speed: DEFAULT_MOVESPEED,
+            sprint_speed: DEFAULT_SPRINTSPEED,
+            snowball_cooldown_secs: 0.5,
+            kill_height: -20.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub network: NetworkConfig,
+    pub maps: MapConfig,
+    pub simulation: SimulationConfig,
+    pub gameplay: GameplayConfig,
+    pub rate_limits: RateLimitConfig,
+    // applied to every new lan and local connection, the console can change it per connection
+    pub link_conditioner: LinkConditions,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to read {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "failed to parse {}: {}", path, e),
+            ConfigError::Invalid(reason) => write!(f, "invalid server config: {}", reason),
+        }
+    }
+}
+
+fn invalid(reason: String) -> Result<(), ConfigError> {
+    Err(ConfigError::Invalid(reason))
+}
+
+fn flatten_fields(prefix: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(children) => {
+            for (name, value) in children {
+                let path = if prefix.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", prefix, name)
+                };
+                flatten_fields(&path, value, fields);
+            }
+        }
+        value => fields.push((prefix.to_string(), value.to_string())),
+    }
+}
+
+impl ServerConfig {
+    // an explicitly given file has to exist, the default one is optional
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (SERVER_CONFIG_FILE, false),
+        };
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(e) => {
+                return Err(ConfigError::Io(path.to_string(), e));
+            }
+        };
+        let config = toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_string(), e))?;
+        info!("loaded server config from {}", path);
+        Ok(config)
+    }
+
+    pub fn fields(&self) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        if let Ok(value) = serde_json::to_value(self) {
+            flatten_fields("", &value, &mut fields);
+        }
+        fields
+    }
+
+    pub fn with_field(&self, path: &str, value: &str) -> Result<Self, String> {
+        let mut root = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        let mut field = &mut root;
+        for name in path.split('.') {
+            field = field.get_mut(name).ok_or(format!("unknown setting {}", path))?;
+        }
+        *field = match field {
+            Value::Bool(_) => Value::Bool(value.parse().map_err(|_| "expected true or false")?),
+            Value::Number(_) => {
+                let number = value.parse::<f64>().map_err(|_| "expected a number")?;
+                match value.parse::<u64>() {
+                    Ok(integer) => Value::from(integer),
+                    Err(_) => Value::from(number),
+                }
+            }
+            Value::String(_) => Value::String(value.to_string()),
+            Value::Array(_) => {
+                Value::Array(
+                    value
+                        .split(',')
+                        .map(|item| Value::String(item.trim().to_string()))
+                        .collect()
+                )
+            }
+            _ => {
+                return Err(format!("{} is not a single value", path));
+            }
+        };
+        serde_json::from_value(root).map_err(|e| e.to_string())
+    }
+
+    pub fn apply_overrides(mut self, overrides: &[(String, String)]) -> Result<Self, ConfigError> {
+        for (name, value) in overrides {
+            self = self
+                .with_field(name, value)
+                .map_err(|e| ConfigError::Invalid(format!("can't set {} to {}: {}", name, value, e)))?;
+        }
+        Ok(self)
+    }
+
+    pub fn starting_map(&self) -> &str {
+        &self.maps.rotation[0]
+    }
+
+    pub fn tick_interval(&self) -> f32 {
+        1.0 / (self.simulation.tick_rate as f32)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let network = &self.network;
+        if network.server_name.trim().is_empty() {
+            return invalid("network.server_name must not be empty".to_string());
+        }
+        if network.bind_address.parse::<IpAddr>().is_err() {
+            return invalid(format!("network.bind_address {} is not an ip address", network.bind_address));
+        }
+        for (name, port) in [
+            ("lan_tcp_port", network.lan_tcp_port),
+            ("lan_udp_port", network.lan_udp_port),
+            ("websocket_port", network.websocket_port),
+        ] {
+            if port == 0 {
+                return invalid(format!("network.{} must not be 0", name));
+            }
+        }
+        if network.discoverable && network.lan_udp_port == DISCOVERY_PORT {
+            return invalid(format!("network.lan_udp_port can't be {}, discovery uses it", DISCOVERY_PORT));
+        }
+        if network.lan_tcp_port == network.websocket_port {
+            return invalid("network.lan_tcp_port and network.websocket_port must differ".to_string());
+        }
+        if network.max_players == 0 {
+            return invalid("network.max_players must be at least 1".to_string());
+        }
+        if network.client_timeout_secs == 0 {
+            return invalid("network.client_timeout_secs must be at least 1".to_string());
+        }
+        // anything smaller lets a single client through each round
+        if network.replication_budget_bytes != 0 && network.replication_budget_bytes < MAX_SNAPSHOT_PAYLOAD {
+            return invalid(
+                format!("network.replication_budget_bytes must be 0 or at least {}", MAX_SNAPSHOT_PAYLOAD)
+            );
+        }
+
+        if self.maps.rotation.is_empty() {
+            return invalid("maps.rotation must list at least one map".to_string());
+        }
+        for map in &self.maps.rotation {
+            if !Path::new(&format!("{}/maps/{}", GAME_DATA_PATH, map)).is_dir() {
+                return invalid(format!("maps.rotation lists {}, which is not in {}/maps", map, GAME_DATA_PATH));
+            }
+        }
+
+        if !(1..=MAX_TICK_RATE).contains(&self.simulation.tick_rate) {
+            return invalid(
+                format!("simulation.tick_rate must be between 1 and {}, got {}", MAX_TICK_RATE, self.simulation.tick_rate)
+            );
+        }
+
+        let gameplay = &self.gameplay;
+        if [gameplay.move_speed, gameplay.sprint_speed].iter().any(|speed| speed.is_nan() || *speed <= 0.0) {
+            return invalid("gameplay.move_speed and gameplay.sprint_speed must be positive".to_string());
+        }
+        if !(0.0..).contains(&gameplay.snowball_cooldown_secs) {
+            return invalid("gameplay.snowball_cooldown_secs must not be negative".to_string());
+        }
+        if !gameplay.kill_height.is_finite() {
+            return invalid("gameplay.kill_height must be a number".to_string());
+        }
+
+        if let Err(e) = self.rate_limits.validate() {
+            return invalid(format!("rate_limits.{}", e));
+        }
+
+        if let Err(e) = self.link_conditioner.validate() {
+            return invalid(format!("link_conditioner.{}", e));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ratelimit::BucketLimit;
+
+    fn rejects(config: ServerConfig, field: &str) {
+        match config.validate() {
+            Err(ConfigError::Invalid(reason)) => assert!(reason.starts_with(field), "{}", reason),
+            other => panic!("expected {} to be rejected, got {:?}", field, other),
+        }
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(ServerConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_bucket_limits_that_never_let_a_packet_through() {
+        let mut config = ServerConfig::default();
+        config.rate_limits.chat.burst = 0.5;
+        rejects(config, "rate_limits.chat.burst");
+
+        let mut config = ServerConfig::default();
+        config.rate_limits.fast.per_second = 0.0;
+        rejects(config, "rate_limits.fast.per_second");
+
+        let mut config = ServerConfig::default();
+        config.rate_limits.movement = Some(BucketLimit { burst: 10.0, per_second: f32::NAN });
+        rejects(config, "rate_limits.movement.per_second");
+
+        let mut config = ServerConfig::default();
+        config.rate_limits.movement = Some(BucketLimit { burst: 10.0, per_second: 5.0 });
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_violation_thresholds_out_of_order() {
+        let mut config = ServerConfig::default();
+        config.rate_limits.kick_after = config.rate_limits.warn_after - 1.0;
+        rejects(config, "rate_limits.kick_after");
+
+        let mut config = ServerConfig::default();
+        config.rate_limits.violations_forgiven_per_second = -1.0;
+        rejects(config, "rate_limits.violations_forgiven_per_second");
+    }
+
+    #[test]
+    fn replication_budget_is_unlimited_or_fits_a_snapshot() {
+        let mut config = ServerConfig::default();
+        config.network.replication_budget_bytes = 0;
+        assert!(config.validate().is_ok());
+        config.network.replication_budget_bytes = MAX_SNAPSHOT_PAYLOAD - 1;
+        rejects(config, "network.replication_budget_bytes");
+    }
+}
@@ -0,0 +1,348 @@
+use crate::server::bots::{BotBehaviour, BotTransport};
+use crate::server::interest::{InterestArea, DEFAULT_INTEREST_PRIORITY};
+use crate::server::savegame::QUICKSAVE_NAME;
+use crate::server::Server;
+use crate::worldmachine::snapshot::WorldSnapshot;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+// their arguments are credentials, so only the command name goes in the log
+const SENSITIVE_COMMANDS: &[&str] = &["register", "password", "login"];
+
+const CONSOLE_HELP: &str = "commands:
+  help                          show this message
+  bots                          list running bots
+  addbots [count] [behaviour] [transport]
+                                spawn bots (behaviour: wander, chase, flee; random if omitted,
+                                transport: local, lan, websocket; local if omitted)
+  kickbots [count]              remove bots (all if count is omitted)
+  save [name]                   write a save game (quicksave if name is omitted)
+  snapshot [name]               dump a world snapshot (named after the tick if omitted)
+  restore <name>                restore a dumped world snapshot
+  diff <from> [to]              compare two dumped snapshots, or one against the live world
+  accounts                      list registered accounts
+  register <name> <password>    create an account
+  unregister <name>             delete an account
+  interest                      list each client's interest radius and priority
+  interest <uuid> <radius> [priority]
+                                change a client's interest radius and priority
+  status                        show the map, tick and number of clients
+  players                       list connected players
+  say <message>                 send a message to every client
+  changemap <map>               load another map (clients have to reconnect)
+  cvars                         list settings that can be changed with set
+  set <cvar> <value>            change a setting
+  shutdown                      stop the server
+  netstats                      show packet counters for each connection
+  tickstats                     show server tick durations and overruns
+  netsim                        show each connection's simulated link conditions
+  netsim <target|all> <off|name=value...>
+                                simulate a bad link (latency, jitter, loss, dup, reorder,
+                                bandwidth in kbps, seed)
+  ratelimits                    show rate limit violations, kicks and bans
+  mute <target> [minutes]       stop a client from chatting
+  unmute <target>               let a muted client chat again
+  kick <target> [reason]        disconnect a client
+  ban <target> [minutes] [reason]
+                                disconnect a client and refuse them until the ban expires
+  unban <target>                lift a ban
+  bans                          list active bans
+                                (targets are account:<name>, uuid:<uuid>, ip:<address>,
+                                or a connected player's name or uuid)";
+
+fn redact(command: &str) -> String {
+    match command.split_whitespace().next() {
+        Some(name) if SENSITIVE_COMMANDS.contains(&name) && name.len() < command.len() => {
+            format!("{} <redacted>", name)
+        }
+        _ => command.to_string(),
+    }
+}
+
+impl Server {
+    pub async fn console_thread(&self) {
+        let mut the_clone = self.clone();
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while self.running.load(Ordering::Relaxed) {
+            let line = tokio::select! {
+                line = lines.next_line() => line,
+                _ = tokio::time::sleep(Duration::from_millis(250)) => continue,
+            };
+            let line = match line {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    debug!("console_thread: stdin closed");
+                    return;
+                }
+                Err(e) => {
+                    error!("console_thread: failed to read stdin: {:?}", e);
+                    return;
+                }
+            };
+            let response = the_clone.run_console_command("stdin", line.trim()).await;
+            if !response.is_empty() {
+                println!("{}", response);
+            }
+        }
+    }
+
+    pub async fn run_console_command(&mut self, source: &str, command: &str) -> String {
+        if command.is_empty() {
+            return String::new();
+        }
+        info!("console command from {}: {}", source, redact(command));
+        let response = self.execute_console_command(command).await;
+        debug!("console response to {}: {}", source, response);
+        response
+    }
+
+    pub async fn execute_console_command(&mut self, command: &str) -> String {
+        let mut args = command.split_whitespace();
+        let name = match args.next() {
+            Some(name) => name,
+            None => return String::new(),
+        };
+        match name {
+            "help" => CONSOLE_HELP.to_string(),
+            "status" => self.status().await,
+            "players" => self.list_players().await,
+            "say" => {
+                let message = args.collect::<Vec<_>>().join(" ");
+                if message.is_empty() {
+                    return "say: expected a message".to_string();
+                }
+                self.say(&message).await;
+                format!("said: {}", message)
+            }
+            "changemap" => {
+                let map_name = match args.next() {
+                    Some(map_name) => map_name,
+                    None => return "changemap: expected a map name".to_string(),
+                };
+                match self.change_map(map_name).await {
+                    Ok(kicked) => format!("changed map to {}, {} clients must reconnect", map_name, kicked),
+                    Err(e) => format!("changemap: {}", e),
+                }
+            }
+            "cvars" => {
+                let mut response = String::new();
+                for (name, value) in self.cvars() {
+                    response.push_str(&format!("{} = {}\n", name, value));
+                }
+                response.trim_end().to_string()
+            }
+            "set" => {
+                let (cvar, value) = match (args.next(), args.next()) {
+                    (Some(cvar), Some(value)) => (cvar, value),
+                    _ => return "set: expected a cvar and a value".to_string(),
+                };
+                match self.set_cvar(cvar, value) {
+                    Ok(response) => response,
+                    Err(e) => format!("set: {}", e),
+                }
+            }
+            "shutdown" => {
+                self.shutdown();
+                "shutting down".to_string()
+            }
+            "bots" => {
+                let bots = self.list_bots().await;
+                if bots.is_empty() {
+                    return "no bots running".to_string();
+                }
+                let mut response = format!("{} bots running:", bots.len());
+                for bot in bots {
+                    response.push_str(&format!(
+                        "\n  {} ({}, {})",
+                        bot.name, bot.behaviour, bot.transport
+                    ));
+                }
+                response
+            }
+            "addbots" => {
+                let count = match args.next().map(|count| count.parse::<usize>()) {
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => return "addbots: count must be a number".to_string(),
+                    None => 1,
+                };
+                let mut behaviour = None;
+                let mut transport = BotTransport::default();
+                for arg in args {
+                    if let Some(parsed) = BotBehaviour::from_name(arg) {
+                        behaviour = Some(parsed);
+                    } else if let Some(parsed) = BotTransport::from_name(arg) {
+                        transport = parsed;
+                    } else {
+                        return format!("addbots: unknown behaviour or transport {}", arg);
+                    }
+                }
+                let spawned = self.spawn_bots(count, behaviour, transport).await;
+                format!("spawned {} of {} bots", spawned, count)
+            }
+            "kickbots" => {
+                let count = match args.next().map(|count| count.parse::<usize>()) {
+                    Some(Ok(count)) => Some(count),
+                    Some(Err(_)) => return "kickbots: count must be a number".to_string(),
+                    None => None,
+                };
+                let removed = self.remove_bots(count).await;
+                format!("removed {} bots", removed)
+            }
+            "save" => {
+                let save_name = args.next().unwrap_or(QUICKSAVE_NAME);
+                match self.save_game(save_name).await {
+                    Ok(path) => format!("saved game to {}", path),
+                    Err(e) => format!("save: failed to save game: {:?}", e),
+                }
+            }
+            "snapshot" => {
+                let snapshot = self.worldmachine.lock().await.snapshot().await;
+                let snapshot_name = match args.next() {
+                    Some(snapshot_name) => snapshot_name.to_string(),
+                    None => format!("tick_{}", snapshot.tick),
+                };
+                match snapshot.write(&snapshot_name) {
+                    Ok(path) => format!("wrote snapshot of tick {} to {}", snapshot.tick, path),
+                    Err(e) => format!("snapshot: failed to write snapshot: {:?}", e),
+                }
+            }
+            "restore" => {
+                let snapshot_name = match args.next() {
+                    Some(snapshot_name) => snapshot_name,
+                    None => return "restore: expected a snapshot name".to_string(),
+                };
+                let snapshot = match WorldSnapshot::read(snapshot_name) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => return format!("restore: failed to read snapshot: {:?}", e),
+                };
+                match self.worldmachine.lock().await.restore_snapshot(&snapshot).await {
+                    Ok(()) => format!("restored snapshot of tick {}", snapshot.tick),
+                    Err(e) => format!("restore: failed to restore snapshot: {:?}", e),
+                }
+            }
+            "diff" => {
+                let from = match args.next().map(WorldSnapshot::read) {
+                    Some(Ok(from)) => from,
+                    Some(Err(e)) => return format!("diff: failed to read snapshot: {:?}", e),
+                    None => return "diff: expected a snapshot name".to_string(),
+                };
+                let to = match args.next().map(WorldSnapshot::read) {
+                    Some(Ok(to)) => to,
+                    Some(Err(e)) => return format!("diff: failed to read snapshot: {:?}", e),
+                    None => self.worldmachine.lock().await.snapshot().await,
+                };
+                from.diff(&to).to_string()
+            }
+            "accounts" | "register" | "unregister" => {
+                let authenticator = match self.authenticator() {
+                    Some(authenticator) => authenticator,
+                    None => return format!("{}: accounts are not enabled on this server", name),
+                };
+                let username = args.next().map(|username| username.to_string());
+                let password = args.next().map(|password| password.to_string());
+                let command = name.to_string();
+                let result = tokio::task::spawn_blocking(move || {
+                    match (command.as_str(), username, password) {
+                        ("accounts", _, _) => authenticator.list().map(|usernames| {
+                            if usernames.is_empty() {
+                                "no accounts registered".to_string()
+                            } else {
+                                format!("{} accounts: {}", usernames.len(), usernames.join(", "))
+                            }
+                        }),
+                        ("register", Some(username), Some(password)) => authenticator
+                            .register(&username, &password)
+                            .map(|_| format!("registered {}", username)),
+                        ("unregister", Some(username), _) => authenticator
+                            .remove(&username)
+                            .map(|removed| match removed {
+                                true => format!("removed {}", username),
+                                false => format!("no account named {}", username),
+                            }),
+                        (command, _, _) => Ok(format!("{}: missing arguments (try help)", command)),
+                    }
+                })
+                .await;
+                match result {
+                    Ok(Ok(response)) => response,
+                    Ok(Err(e)) => format!("{}: {}", name, e),
+                    Err(e) => format!("{}: {:?}", name, e),
+                }
+            }
+            "interest" => {
+                let uuid = match args.next() {
+                    Some(uuid) => uuid.to_string(),
+                    None => {
+                        let areas = self.list_interest_areas().await;
+                        if areas.is_empty() {
+                            return "no clients connected".to_string();
+                        }
+                        let mut response = format!("{} clients:", areas.len());
+                        for (uuid, area, known) in areas {
+                            response.push_str(&format!(
+                                "\n  {} radius {} priority {} ({} entities in view)",
+                                uuid, area.radius, area.priority, known
+                            ));
+                        }
+                        return response;
+                    }
+                };
+                let radius = match args.next().map(|radius| radius.parse::<f32>()) {
+                    Some(Ok(radius)) if radius > 0.0 => radius,
+                    Some(_) => return "interest: radius must be a positive number".to_string(),
+                    None => return "interest: expected a radius".to_string(),
+                };
+                let priority = match args.next().map(|priority| priority.parse::<u8>()) {
+                    Some(Ok(priority)) => priority,
+                    Some(Err(_)) => return "interest: priority must be 0-255".to_string(),
+                    None => DEFAULT_INTEREST_PRIORITY,
+                };
+                let area = InterestArea { radius, priority };
+                match self.set_interest_area(&uuid, area).await {
+                    true => format!("{} now sees {} units at priority {}", uuid, radius, priority),
+                    false => format!("interest: no client {}", uuid),
+                }
+            }
+            "netstats" => {
+                let connections = self.get_all_connections().await;
+                if connections.is_empty() {
+                    return "no clients connected".to_string();
+                }
+                let mut response = format!("{} clients:", connections.len());
+                for connection in connections {
+                    match connection.counters().await {
+                        Some(counters) => {
+                            response.push_str(&format!("\n  {:?}: {}", connection, counters))
+                        }
+                        None => response.push_str(&format!("\n  {:?}: no counters", connection)),
+                    }
+                }
+                response
+            }
+            "tickstats" => self.tick_stats().await.to_string(),
+            "netsim" => {
+                let args = args.collect::<Vec<_>>();
+                self.netsim(&args).await
+            }
+            "ratelimits" => self.offences.lock().await.stats.to_string(),
+            "mute" | "unmute" | "kick" | "ban" | "unban" | "bans" => {
+                let args = args.collect::<Vec<_>>();
+                self.moderate(name, &args, "console").await
+            }
+            _ => format!("unknown command: {} (try help)", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_left_out_of_the_log() {
+        assert_eq!(redact("register alice hunter22"), "register <redacted>");
+        assert_eq!(redact("register"), "register");
+        assert_eq!(redact("kick alice being rude"), "kick alice being rude");
+    }
+}
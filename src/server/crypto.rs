@@ -0,0 +1,252 @@
+use chacha20poly1305::aead::{ Aead, KeyInit, Payload };
+use chacha20poly1305::{ ChaCha20Poly1305, Key, Nonce };
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use serde::{ Deserialize, Serialize };
+use sha2::Sha256;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+use x25519_dalek::{ EphemeralSecret, PublicKey };
+
+pub type PublicKeyBytes = [u8; 32];
+
+// tag, counter and msgpack framing added around every sealed payload
+pub const SEALING_OVERHEAD: usize = 32;
+const REPLAY_WINDOW: u64 = 64;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sealed {
+    pub counter: u64,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::from(nonce)
+}
+
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    counter: AtomicU64,
+}
+
+impl Sealer {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Sealed {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+        let ciphertext = self.cipher
+            .encrypt(&nonce(counter), Payload { msg: plaintext, aad })
+            .expect("failed to seal packet");
+        Sealed { counter, ciphertext }
+    }
+}
+
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) => {
+                let age = highest - counter;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn mark(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                self.seen |= 1 << (highest - counter);
+            }
+            Some(highest) => {
+                let shift = counter - highest;
+                self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+                self.seen |= 1;
+                self.highest = Some(counter);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    window: Mutex<ReplayWindow>,
+}
+
+impl Opener {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            window: Mutex::new(ReplayWindow::default()),
+        }
+    }
+
+    pub fn open(&self, aad: &[u8], sealed: &Sealed) -> Option<Vec<u8>> {
+        if !self.window.lock().unwrap().is_fresh(sealed.counter) {
+            return None;
+        }
+        let plaintext = self.cipher
+            .decrypt(&nonce(sealed.counter), Payload { msg: &sealed.ciphertext, aad })
+            .ok()?;
+        // only authentic packets may move the window, and a racing duplicate loses here
+        let mut window = self.window.lock().unwrap();
+        if !window.is_fresh(sealed.counter) {
+            return None;
+        }
+        window.mark(sealed.counter);
+        Some(plaintext)
+    }
+}
+
+pub struct ChannelKeys {
+    pub steady_sealer: Sealer,
+    pub steady_opener: Opener,
+    pub datagram_sealer: Sealer,
+    pub datagram_opener: Opener,
+}
+
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> PublicKeyBytes {
+        self.public.to_bytes()
+    }
+
+    pub fn complete(self, peer: PublicKeyBytes, side: Side) -> Option<ChannelKeys> {
+        let (client, server) = match side {
+            Side::Client => (self.public.to_bytes(), peer),
+            Side::Server => (peer, self.public.to_bytes()),
+        };
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return None;
+        }
+        let mut salt = Vec::with_capacity(64);
+        salt.extend_from_slice(&client);
+        salt.extend_from_slice(&server);
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let derive = |info: &[u8]| {
+            let mut key = [0; 32];
+            hkdf.expand(info, &mut key).expect("failed to derive key");
+            key
+        };
+        let client_steady = derive(b"client steady");
+        let client_datagram = derive(b"client datagram");
+        let server_steady = derive(b"server steady");
+        let server_datagram = derive(b"server datagram");
+        Some(match side {
+            Side::Client =>
+                ChannelKeys {
+                    steady_sealer: Sealer::new(client_steady),
+                    steady_opener: Opener::new(server_steady),
+                    datagram_sealer: Sealer::new(client_datagram),
+                    datagram_opener: Opener::new(server_datagram),
+                },
+            Side::Server =>
+                ChannelKeys {
+                    steady_sealer: Sealer::new(server_steady),
+                    steady_opener: Opener::new(client_steady),
+                    datagram_sealer: Sealer::new(server_datagram),
+                    datagram_opener: Opener::new(client_datagram),
+                },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel_pair() -> (ChannelKeys, ChannelKeys) {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_key = client.public_key();
+        let server_key = server.public_key();
+        (
+            client.complete(server_key, Side::Client).unwrap(),
+            server.complete(client_key, Side::Server).unwrap(),
+        )
+    }
+
+    #[test]
+    fn replay_window_accepts_each_counter_once() {
+        let mut window = ReplayWindow::default();
+        assert!(window.is_fresh(5));
+        window.mark(5);
+        assert!(!window.is_fresh(5));
+        // late but inside the window
+        assert!(window.is_fresh(3));
+        window.mark(3);
+        assert!(!window.is_fresh(3));
+        assert!(window.is_fresh(4));
+        assert!(window.is_fresh(6));
+    }
+
+    #[test]
+    fn replay_window_forgets_counters_that_fall_behind() {
+        let mut window = ReplayWindow::default();
+        window.mark(0);
+        window.mark(REPLAY_WINDOW);
+        assert!(!window.is_fresh(0));
+        assert!(window.is_fresh(1));
+        window.mark(REPLAY_WINDOW * 3);
+        assert!(!window.is_fresh(REPLAY_WINDOW));
+        assert!(!window.is_fresh(REPLAY_WINDOW * 2));
+        assert!(window.is_fresh(REPLAY_WINDOW * 2 + 1));
+    }
+
+    #[test]
+    fn opener_rejects_replays_and_tampering() {
+        let (client, server) = channel_pair();
+        let sealed = client.datagram_sealer.seal(b"channel", b"hello");
+        assert_eq!(server.datagram_opener.open(b"channel", &sealed), Some(b"hello".to_vec()));
+        assert_eq!(server.datagram_opener.open(b"channel", &sealed), None);
+
+        let sealed = client.datagram_sealer.seal(b"channel", b"hello");
+        assert_eq!(server.datagram_opener.open(b"another channel", &sealed), None);
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(server.datagram_opener.open(b"channel", &tampered), None);
+        // a forgery mustn't burn the counter for the real packet
+        assert_eq!(server.datagram_opener.open(b"channel", &sealed), Some(b"hello".to_vec()));
+    }
+}
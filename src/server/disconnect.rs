@@ -0,0 +1,316 @@
+use crate::server::server_player::ServerPlayerContainer;
+use crate::server::transport::Connection;
+use crate::server::{ Server, SteadyPacket };
+use crate::worldmachine::components::COMPONENT_TYPE_PLAYER;
+use crate::worldmachine::ecs::ParameterValue;
+use crate::worldmachine::WorldUpdate;
+use gfx_maths::Vec3;
+use mutex_timeouts::tokio::MutexWithTimeoutAuto as Mutex;
+use serde::{ Deserialize, Serialize };
+use std::fmt::{ Display, Formatter };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+use tokio::sync::Notify;
+
+// long enough for the connection tasks to send their goodbyes before the process exits
+pub const SHUTDOWN_GRACE: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    Quit,
+    Kicked {
+        by: String,
+        reason: String,
+    },
+    Banned {
+        by: String,
+        reason: String,
+    },
+    Timeout,
+    ServerShutdown,
+    MapChange(String),
+    ServerFull(usize),
+    ConnectionLost,
+}
+
+impl DisconnectReason {
+    // the player's entity waits for them to come back instead of leaving with them
+    pub fn is_resumable(&self) -> bool {
+        matches!(self, DisconnectReason::Timeout | DisconnectReason::ConnectionLost)
+    }
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::Quit => write!(f, "quit"),
+            DisconnectReason::Kicked { by, reason } => write!(f, "kicked by {}: {}", by, reason),
+            DisconnectReason::Banned { by, reason } => write!(f, "banned by {}: {}", by, reason),
+            DisconnectReason::Timeout => write!(f, "timed out"),
+            DisconnectReason::ServerShutdown => write!(f, "the server shut down"),
+            DisconnectReason::MapChange(map) => write!(f, "the server changed map to {}", map),
+            DisconnectReason::ServerFull(max_players) =>
+                write!(f, "the server is full ({} players)", max_players),
+            DisconnectReason::ConnectionLost => write!(f, "lost connection"),
+        }
+    }
+}
+
+pub struct DetachedPlayer {
+    pub player: ServerPlayerContainer,
+    pub detached_at: Instant,
+}
+
+impl ServerPlayerContainer {
+    // the token alone isn't enough, it has to come back on the account it left on
+    fn resumable_by(&self, token: &str, identity: &Option<String>) -> bool {
+        self.player.resume_token.as_str() == token && self.connection.identity() == *identity
+    }
+}
+
+impl Server {
+    // tells the client why and ends its connection on the next pass of its connection task
+    pub async fn disconnect(&self, connection: &Connection, reason: DisconnectReason) {
+        info!("disconnecting {:?}: {}", connection, reason);
+        self.send_steady_packet(connection, SteadyPacket::Disconnect(reason.clone())).await;
+        let players = self.worldmachine.lock().await.players.clone();
+        if let Some(players) = players {
+            let player = players.lock().await.get(&connection.uuid()).cloned();
+            if let Some(player) = player {
+                player.player.request_disconnect(reason).await;
+            }
+        }
+    }
+
+    pub(super) async fn end_connection(&self, player: ServerPlayerContainer, reason: DisconnectReason) {
+        let uuid = player.connection.uuid();
+        let entity_id = match player.entity_id {
+            Some(entity_id) => entity_id,
+            None => {
+                return;
+            }
+        };
+        // a resumed player has already moved on to a new connection
+        let players = self.worldmachine.lock().await.players.clone();
+        let still_playing = match players {
+            Some(players) => players.lock().await.contains_key(&uuid),
+            None => false,
+        };
+        if !still_playing {
+            self.connections.lock().await.retain(|connection| connection.uuid() != uuid);
+            return;
+        }
+        if reason.is_resumable() && self.config().network.resume_grace_secs > 0 {
+            info!("{:?} dropped ({}), holding on to their player", player.connection, reason);
+            self.detach_player(player).await;
+        } else {
+            info!("{:?} left: {}", player.connection, reason);
+            self.disconnect_player(uuid, entity_id).await;
+        }
+    }
+
+    async fn detach_player(&self, player: ServerPlayerContainer) {
+        let uuid = player.connection.uuid();
+        self.connections.lock().await.retain(|connection| connection.uuid() != uuid);
+        let players = self.worldmachine.lock().await.players.clone();
+        if let Some(players) = players {
+            players.lock().await.remove(&uuid);
+        }
+        let token = player.player.resume_token.to_string();
+        self.detached.lock().await.insert(token, DetachedPlayer {
+            player,
+            detached_at: Instant::now(),
+        });
+    }
+
+    pub(super) async fn expire_detached_players(&self, grace: Duration) {
+        let expired = {
+            let mut detached = self.detached.lock().await;
+            let expired = detached
+                .iter()
+                .filter(|(_, detached)| detached.detached_at.elapsed() >= grace)
+                .map(|(token, _)| token.clone())
+                .collect::<Vec<_>>();
+            expired
+                .into_iter()
+                .filter_map(|token| detached.remove(&token))
+                .collect::<Vec<_>>()
+        };
+        for detached in expired {
+            info!("{:?} didn't come back in time, removing their player", detached.player.connection);
+            if let Some(entity_id) = detached.player.entity_id {
+                self.disconnect_player(detached.player.connection.uuid(), entity_id).await;
+            }
+        }
+    }
+
+    // finds the player a resume token belongs to, even if we haven't noticed their old connection die yet
+    async fn take_resumable_player(&self, connection: &Connection, token: &str) -> Option<ServerPlayerContainer> {
+        let identity = connection.identity();
+        {
+            let mut detached = self.detached.lock().await;
+            let matches = detached
+                .get(token)
+                .is_some_and(|detached| detached.player.resumable_by(token, &identity));
+            if matches {
+                return detached.remove(token).map(|detached| detached.player);
+            }
+        }
+        let players = self.worldmachine.lock().await.players.clone()?;
+        let mut players = players.lock().await;
+        let uuid = players
+            .iter()
+            .find(|(_, player)| player.resumable_by(token, &identity))
+            .map(|(uuid, _)| uuid.clone())?;
+        let player = players.remove(&uuid)?;
+        drop(players);
+        self.connections.lock().await.retain(|connection| connection.uuid() != uuid);
+        player.player.request_disconnect(DisconnectReason::ConnectionLost).await;
+        Some(player)
+    }
+
+    pub(super) async fn resume_player(&self, connection: &Connection) -> Option<ServerPlayerContainer> {
+        let token = connection.resume_token()?;
+        let previous = match self.take_resumable_player(connection, &token).await {
+            Some(previous) => previous,
+            None => {
+                debug!("{:?} tried to resume an unknown session", connection);
+                return None;
+            }
+        };
+        let entity_id = previous.entity_id?;
+        let uuid = connection.uuid();
+
+        let mut player = previous.player.clone();
+        player.uuid = Arc::new(uuid.clone());
+        player.disconnect_reason = Arc::new(Mutex::new(None));
+        player.disconnect_requested = Arc::new(Notify::new());
+        let resumed = ServerPlayerContainer {
+            player,
+            entity_id: Some(entity_id),
+            connection: connection.clone(),
+        };
+
+        {
+            let mut worldmachine = self.worldmachine.lock().await;
+            let entity = worldmachine.world.entities.iter_mut().find(|entity| entity.uid == entity_id);
+            let entity = match entity {
+                Some(entity) => {
+                    entity.set_component_parameter(
+                        COMPONENT_TYPE_PLAYER.clone(),
+                        "uuid",
+                        ParameterValue::String(uuid.clone())
+                    );
+                    entity.clone()
+                }
+                None => {
+                    return None;
+                }
+            };
+            worldmachine.queue_update(WorldUpdate::InitEntity(entity_id, entity)).await;
+        }
+
+        if !self.send_initial_entities(connection).await {
+            self.detach_player(resumed).await;
+            return None;
+        }
+        let name = resumed.player.name.lock().await.clone();
+        let position = resumed.player.get_position(None, None).await;
+        let rotation = resumed.player.get_rotation(None, None).await;
+        let packet = SteadyPacket::InitialisePlayer(
+            uuid.clone(),
+            entity_id,
+            name,
+            position,
+            rotation,
+            Vec3::new(1.0, 1.0, 1.0)
+        );
+        if !self.send_steady_packet(connection, packet).await {
+            self.detach_player(resumed).await;
+            return None;
+        }
+        let players = self.worldmachine.lock().await.players.clone()?;
+        players.lock().await.insert(uuid, resumed.clone());
+        if !self.send_steady_packet(connection, SteadyPacket::FinaliseMapLoad).await {
+            self.detach_player(resumed).await;
+            return None;
+        }
+        info!("{:?} resumed their session as entity {}", connection, entity_id);
+        Some(resumed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::transport::{ unix_time, ConnectionError, Transport, TransportFuture };
+    use crate::server::server_player::ServerPlayer;
+    use crate::server::{ ConnectionUUID, FastPacketData, SteadyPacketData };
+
+    struct AccountConnection(Option<String>);
+
+    impl Transport for AccountConnection {
+        fn kind(&self) -> &'static str {
+            "AccountConnection"
+        }
+
+        fn uuid(&self) -> ConnectionUUID {
+            "test".to_string()
+        }
+
+        fn identity(&self) -> Option<String> {
+            self.0.clone()
+        }
+
+        fn last_seen(&self) -> u64 {
+            unix_time()
+        }
+
+        fn mark_alive(&self) {}
+
+        fn send_steady(&self, _packet: SteadyPacketData) -> TransportFuture<'_, bool> {
+            Box::pin(async { true })
+        }
+
+        fn send_fast(&self, _packet: FastPacketData) -> TransportFuture<'_, bool> {
+            Box::pin(async { true })
+        }
+
+        fn receive_steady(
+            &self
+        ) -> TransportFuture<'_, Result<Option<SteadyPacketData>, ConnectionError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn receive_fast(&self) -> TransportFuture<'_, Result<Option<FastPacketData>, ConnectionError>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn readable(&self) -> TransportFuture<'_, ()> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    fn player_on(account: Option<&str>) -> ServerPlayerContainer {
+        ServerPlayerContainer {
+            player: ServerPlayer::default(),
+            entity_id: None,
+            connection: Arc::new(AccountConnection(account.map(|account| account.to_string()))),
+        }
+    }
+
+    #[test]
+    fn resuming_needs_the_token_and_the_same_account() {
+        let player = player_on(Some("alice"));
+        let token = player.player.resume_token.to_string();
+        assert!(player.resumable_by(&token, &Some("alice".to_string())));
+        assert!(!player.resumable_by(&token, &Some("bob".to_string())));
+        assert!(!player.resumable_by(&token, &None));
+        assert!(!player.resumable_by("someone else's token", &Some("alice".to_string())));
+
+        let guest = player_on(None);
+        let token = guest.player.resume_token.to_string();
+        assert!(guest.resumable_by(&token, &None));
+        assert!(!guest.resumable_by(&token, &Some("alice".to_string())));
+    }
+}
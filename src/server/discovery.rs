@@ -0,0 +1,161 @@
+use crate::server::lan::PROTOCOL_VERSION;
+use crate::server::Server;
+use serde::{ Deserialize, Serialize };
+use std::net::{ IpAddr, Ipv4Addr };
+use std::sync::atomic::Ordering;
+use std::time::{ Duration, Instant };
+use tokio::net::UdpSocket;
+
+pub const DISCOVERY_PORT: u16 = 25569;
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_millis(750);
+// queries are tiny, anything bigger isn't worth parsing
+const MAX_QUERY_SIZE: usize = 64;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub name: String,
+    pub map: String,
+    pub players: usize,
+    pub max_players: usize,
+    pub protocol_version: u32,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum DiscoveryPacket {
+    Query {
+        nonce: u64,
+    },
+    Info {
+        nonce: u64,
+        instance: u64,
+        info: ServerInfo,
+    },
+}
+
+fn serialise(packet: &DiscoveryPacket) -> Vec<u8> {
+    let mut serialiser = rmp_serde::Serializer::new(Vec::new());
+    packet.serialize(&mut serialiser).unwrap();
+    serialiser.into_inner()
+}
+
+fn deserialise(data: &[u8]) -> Option<DiscoveryPacket> {
+    let mut deserialiser = rmp_serde::Deserializer::new(data);
+    DiscoveryPacket::deserialize(&mut deserialiser).ok()
+}
+
+#[derive(Clone, Debug)]
+pub struct DiscoveredServer {
+    pub address: IpAddr,
+    pub info: ServerInfo,
+    pub ping: Duration,
+    instance: u64,
+}
+
+impl DiscoveredServer {
+    pub fn is_compatible(&self) -> bool {
+        self.info.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+pub async fn discover_servers(timeout: Duration) -> std::io::Result<Vec<DiscoveredServer>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let nonce = rand::random::<u64>();
+    let query = serialise(&DiscoveryPacket::Query { nonce });
+    let sent_at = Instant::now();
+    // loopback too, broadcasts don't reach servers on this machine without a network
+    let mut sent = false;
+    for target in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+        match socket.send_to(&query, (target, DISCOVERY_PORT)).await {
+            Ok(_) => {
+                sent = true;
+            }
+            Err(e) => debug!("failed to send discovery query to {}: {:?}", target, e),
+        }
+    }
+    if !sent {
+        return Err(std::io::Error::other("couldn't send a discovery query"));
+    }
+
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    let mut buffer = [0; 1024];
+    let deadline = tokio::time::Instant::now() + timeout;
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (len, from) = received?;
+        let (instance, info) = match deserialise(&buffer[..len]) {
+            Some(DiscoveryPacket::Info { nonce: reply_nonce, instance, info }) if reply_nonce == nonce => {
+                (instance, info)
+            }
+            _ => {
+                continue;
+            }
+        };
+        // the same server can answer both the broadcast and the loopback query
+        if servers.iter().any(|server| server.instance == instance) {
+            continue;
+        }
+        servers.push(DiscoveredServer {
+            address: from.ip(),
+            info,
+            ping: sent_at.elapsed(),
+            instance,
+        });
+    }
+    servers.sort_by_key(|server| server.ping);
+    Ok(servers)
+}
+
+impl Server {
+    pub async fn server_info(&self, tcp_port: u16, udp_port: u16) -> ServerInfo {
+        let config = self.config();
+        let map = self.worldmachine.lock().await.current_map();
+        ServerInfo {
+            name: config.network.server_name,
+            map,
+            players: self.get_all_connections().await.len(),
+            max_players: config.network.max_players,
+            protocol_version: PROTOCOL_VERSION,
+            tcp_port,
+            udp_port,
+        }
+    }
+
+    pub async fn host_discovery(&self, tcp_port: u16, udp_port: u16) {
+        // broadcasts only reach sockets bound to the wildcard address
+        let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("failed to bind discovery port {}, this server won't show up in browsers: {:?}", DISCOVERY_PORT, e);
+                return;
+            }
+        };
+        let instance = rand::random::<u64>();
+        let the_clone = self.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0; MAX_QUERY_SIZE];
+            while the_clone.running.load(Ordering::Relaxed) {
+                let (len, from) = match socket.recv_from(&mut buffer).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!("discovery receive failed: {:?}", e);
+                        continue;
+                    }
+                };
+                let nonce = match deserialise(&buffer[..len]) {
+                    Some(DiscoveryPacket::Query { nonce }) => nonce,
+                    _ => {
+                        continue;
+                    }
+                };
+                let info = the_clone.server_info(tcp_port, udp_port).await;
+                let reply = serialise(&DiscoveryPacket::Info { nonce, instance, info });
+                if let Err(e) = socket.send_to(&reply, from).await {
+                    debug!("failed to answer discovery query from {}: {:?}", from, e);
+                }
+            }
+        });
+        info!("answering discovery queries on port {}", DISCOVERY_PORT);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::server::crypto::PublicKeyBytes;
+use ed25519_dalek::{ Signature, Signer, SigningKey, Verifier, VerifyingKey };
+use rand::rngs::OsRng;
+use serde::{ Deserialize, Serialize };
+use std::collections::HashMap;
+use std::fmt::{ Display, Formatter };
+
+pub const SERVER_IDENTITY_FILE: &str = "server_identity.key";
+pub const KNOWN_SERVERS_FILE: &str = "known_servers.json";
+const KEY_EXCHANGE_CONTEXT: &[u8] = b"mutengine lan key exchange";
+
+pub type IdentityKeyBytes = [u8; 32];
+
+#[derive(Clone, Debug)]
+pub enum IdentityError {
+    Io(String),
+    Malformed,
+}
+
+impl Display for IdentityError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityError::Io(e) => write!(f, "io error: {}", e),
+            IdentityError::Malformed => write!(f, "the identity file isn't a signing key"),
+        }
+    }
+}
+
+pub fn fingerprint(identity: &IdentityKeyBytes) -> String {
+    identity
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// covers both ephemeral keys, so a signature can't be replayed into someone else's handshake
+fn transcript(client_key: &PublicKeyBytes, server_key: &PublicKeyBytes) -> Vec<u8> {
+    let mut transcript = Vec::with_capacity(KEY_EXCHANGE_CONTEXT.len() + 64);
+    transcript.extend_from_slice(KEY_EXCHANGE_CONTEXT);
+    transcript.extend_from_slice(client_key);
+    transcript.extend_from_slice(server_key);
+    transcript
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedKeyExchange {
+    pub key: PublicKeyBytes,
+    pub identity: IdentityKeyBytes,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedKeyExchange {
+    pub fn verify(&self, client_key: &PublicKeyBytes) -> bool {
+        let identity = match VerifyingKey::from_bytes(&self.identity) {
+            Ok(identity) => identity,
+            Err(_) => {
+                return false;
+            }
+        };
+        let signature = match Signature::from_slice(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => {
+                return false;
+            }
+        };
+        identity.verify(&transcript(client_key, &self.key), &signature).is_ok()
+    }
+}
+
+pub struct ServerIdentity {
+    signing_key: SigningKey,
+}
+
+impl ServerIdentity {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn open(path: &str) -> Result<Self, IdentityError> {
+        if std::path::Path::new(path).exists() {
+            let data = std::fs::read(path).map_err(|e| IdentityError::Io(e.to_string()))?;
+            let secret: [u8; 32] = data.as_slice().try_into().map_err(|_| IdentityError::Malformed)?;
+            return Ok(Self {
+                signing_key: SigningKey::from_bytes(&secret),
+            });
+        }
+        let identity = Self::generate();
+        std::fs::write(path, identity.signing_key.to_bytes()).map_err(|e| IdentityError::Io(e.to_string()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(0o600);
+            std::fs::set_permissions(path, permissions).map_err(|e| IdentityError::Io(e.to_string()))?;
+        }
+        info!("generated a new server identity {}", identity.fingerprint());
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> IdentityKeyBytes {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+
+    pub fn sign_exchange(&self, client_key: &PublicKeyBytes, server_key: PublicKeyBytes) -> SignedKeyExchange {
+        let signature = self.signing_key.sign(&transcript(client_key, &server_key));
+        SignedKeyExchange {
+            key: server_key,
+            identity: self.public_key(),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+}
+
+fn load_known_servers() -> HashMap<String, String> {
+    std::fs
+        ::read_to_string(KNOWN_SERVERS_FILE)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+pub fn known_server(address: &str) -> Option<String> {
+    load_known_servers().remove(address)
+}
+
+fn pin_server(address: &str, fingerprint: &str) {
+    let mut known = load_known_servers();
+    known.insert(address.to_string(), fingerprint.to_string());
+    let result = serde_json
+        ::to_string_pretty(&known)
+        .map_err(|e| e.to_string())
+        .and_then(|data| std::fs::write(KNOWN_SERVERS_FILE, data).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        warn!("failed to save {}: {}", KNOWN_SERVERS_FILE, e);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerTrust {
+    // whatever answers first at this address is who we expect there from then on
+    Pinned(String),
+    Expected(IdentityKeyBytes),
+}
+
+impl ServerTrust {
+    // hands back the fingerprint we expected if the server isn't who we think it is
+    pub fn check(&self, identity: &IdentityKeyBytes) -> Result<(), String> {
+        let presented = fingerprint(identity);
+        match self {
+            ServerTrust::Expected(expected) if expected == identity => Ok(()),
+            ServerTrust::Expected(expected) => Err(fingerprint(expected)),
+            ServerTrust::Pinned(address) =>
+                match known_server(address) {
+                    Some(expected) if expected == presented => Ok(()),
+                    Some(expected) => Err(expected),
+                    None => {
+                        info!("trusting {} as {} from now on", address, presented);
+                        pin_server(address, &presented);
+                        Ok(())
+                    }
+                }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::crypto::KeyExchange;
+
+    #[test]
+    fn signed_key_exchange_only_verifies_for_its_own_handshake() {
+        let identity = ServerIdentity::generate();
+        let client_key = KeyExchange::new().public_key();
+        let signed = identity.sign_exchange(&client_key, KeyExchange::new().public_key());
+        assert!(signed.verify(&client_key));
+        assert!(!signed.verify(&KeyExchange::new().public_key()));
+
+        let mut swapped = signed.clone();
+        swapped.key = KeyExchange::new().public_key();
+        assert!(!swapped.verify(&client_key));
+
+        let mut impostor = signed;
+        impostor.identity = ServerIdentity::generate().public_key();
+        assert!(!impostor.verify(&client_key));
+    }
+
+    #[test]
+    fn expected_trust_rejects_other_identities() {
+        let identity = ServerIdentity::generate();
+        let trust = ServerTrust::Expected(identity.public_key());
+        assert!(trust.check(&identity.public_key()).is_ok());
+        assert_eq!(trust.check(&ServerIdentity::generate().public_key()), Err(identity.fingerprint()));
+    }
+}
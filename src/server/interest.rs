@@ -0,0 +1,291 @@
+use crate::server::replication::ReplicationSnapshot;
+use crate::server::transport::Connection;
+use crate::server::{ ConnectionUUID, Server };
+use crate::worldmachine::components::{
+    COMPONENT_TYPE_BOX_COLLIDER,
+    COMPONENT_TYPE_JUKEBOX,
+    COMPONENT_TYPE_LIGHT,
+    COMPONENT_TYPE_PLAYER,
+    COMPONENT_TYPE_TERRAIN,
+    COMPONENT_TYPE_TRANSFORM,
+    COMPONENT_TYPE_TRIGGER,
+};
+use crate::worldmachine::ecs::Entity;
+use crate::worldmachine::EntityId;
+use gfx_maths::Vec3;
+use halfbrown::HashMap;
+use std::collections::HashSet;
+
+pub const DEFAULT_INTEREST_RADIUS: f32 = 150.0;
+pub const DEFAULT_INTEREST_PRIORITY: u8 = 0;
+pub const INTEREST_CELL_SIZE: f32 = 32.0;
+
+type Cell = (i32, i32, i32);
+
+fn cell_of(position: Vec3) -> Cell {
+    (
+        (position.x / INTEREST_CELL_SIZE).floor() as i32,
+        (position.y / INTEREST_CELL_SIZE).floor() as i32,
+        (position.z / INTEREST_CELL_SIZE).floor() as i32,
+    )
+}
+
+pub struct SpatialIndex<K> {
+    cells: HashMap<Cell, Vec<(K, Vec3)>>,
+}
+
+impl<K: Clone> SpatialIndex<K> {
+    pub fn new() -> Self {
+        Self { cells: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, key: K, position: Vec3) {
+        self.cells.entry(cell_of(position)).or_default().push((key, position));
+    }
+
+    pub fn query(&self, position: Vec3, radius: f32) -> Vec<(K, Vec3)> {
+        let min = cell_of(position - Vec3::new(radius, radius, radius));
+        let max = cell_of(position + Vec3::new(radius, radius, radius));
+        let mut found = Vec::new();
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    if let Some(cell) = self.cells.get(&(x, y, z)) {
+                        for (key, other) in cell {
+                            if (*other - position).magnitude() <= radius {
+                                found.push((key.clone(), *other));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+impl<K: Clone> Default for SpatialIndex<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InterestArea {
+    pub radius: f32,
+    pub priority: u8,
+}
+
+impl Default for InterestArea {
+    fn default() -> Self {
+        Self {
+            radius: DEFAULT_INTEREST_RADIUS,
+            priority: DEFAULT_INTEREST_PRIORITY,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ClientInterest {
+    pub area: InterestArea,
+    pub known: HashSet<EntityId>,
+}
+
+pub fn is_interest_managed(entity: &Entity) -> bool {
+    if entity.parent.is_some() || entity.has_component(COMPONENT_TYPE_PLAYER.clone()) {
+        return true;
+    }
+    let structural = [
+        &*COMPONENT_TYPE_TERRAIN,
+        &*COMPONENT_TYPE_BOX_COLLIDER,
+        &*COMPONENT_TYPE_LIGHT,
+        &*COMPONENT_TYPE_TRIGGER,
+        &*COMPONENT_TYPE_JUKEBOX,
+    ];
+    entity.has_component(COMPONENT_TYPE_TRANSFORM.clone()) &&
+        !structural.iter().any(|component_type| entity.has_component((*component_type).clone()))
+}
+
+pub struct Relevancy {
+    index: SpatialIndex<EntityId>,
+    managed: HashSet<EntityId>,
+    parents: HashMap<EntityId, EntityId>,
+}
+
+impl Relevancy {
+    pub fn build(entities: &[Entity], snapshot: &ReplicationSnapshot) -> Self {
+        let mut relevancy = Self {
+            index: SpatialIndex::new(),
+            managed: HashSet::new(),
+            parents: HashMap::new(),
+        };
+        for entity in entities {
+            if !is_interest_managed(entity) {
+                continue;
+            }
+            relevancy.managed.insert(entity.uid);
+            if let Some(parent) = entity.parent {
+                relevancy.parents.insert(entity.uid, parent);
+            } else if let Some(state) = snapshot.entities.get(&entity.uid) {
+                relevancy.index.insert(entity.uid, state.position.to_vec3());
+            }
+        }
+        relevancy
+    }
+
+    pub fn is_managed(&self, entity: EntityId) -> bool {
+        self.managed.contains(&entity)
+    }
+
+    pub fn relevant_to(&self, position: Vec3, radius: f32) -> HashSet<EntityId> {
+        let mut relevant = self.index
+            .query(position, radius)
+            .into_iter()
+            .map(|(entity, _)| entity)
+            .collect::<HashSet<_>>();
+        // attachments follow their parent, which may itself be attached
+        loop {
+            let attached = self.parents
+                .iter()
+                .filter(|(child, parent)| {
+                    !relevant.contains(*child) &&
+                        (relevant.contains(*parent) || !self.managed.contains(*parent))
+                })
+                .map(|(child, _)| *child)
+                .collect::<Vec<_>>();
+            if attached.is_empty() {
+                break;
+            }
+            relevant.extend(attached);
+        }
+        relevant
+    }
+}
+
+impl Server {
+    pub async fn get_connections_affected_from_position(&self, position: Vec3) -> Vec<Connection> {
+        let replication = self.replication.lock().await;
+        let radius = replication.clients
+            .values()
+            .map(|client| client.interest.area.radius)
+            .fold(DEFAULT_INTEREST_RADIUS, f32::max);
+        let affected = replication.viewers
+            .query(position, radius)
+            .into_iter()
+            .filter(|(uuid, viewer)| {
+                let radius = replication.clients
+                    .get(uuid)
+                    .map_or(DEFAULT_INTEREST_RADIUS, |client| client.interest.area.radius);
+                (*viewer - position).magnitude() <= radius
+            })
+            .map(|(uuid, _)| uuid)
+            .collect::<HashSet<_>>();
+        drop(replication);
+        self.get_all_connections().await
+            .into_iter()
+            .filter(|connection| affected.contains(&connection.uuid()))
+            .collect()
+    }
+
+    pub async fn get_connections_aware_of(&self, entity: EntityId) -> Vec<Connection> {
+        let replication = self.replication.lock().await;
+        let aware = replication.clients
+            .iter()
+            .filter(|(_, client)| client.interest.known.contains(&entity))
+            .map(|(uuid, _)| uuid.clone())
+            .collect::<HashSet<_>>();
+        drop(replication);
+        self.get_all_connections().await
+            .into_iter()
+            .filter(|connection| aware.contains(&connection.uuid()))
+            .collect()
+    }
+
+    pub async fn forget_entity(&self, entity: EntityId) {
+        let mut replication = self.replication.lock().await;
+        for client in replication.clients.values_mut() {
+            client.interest.known.remove(&entity);
+        }
+    }
+
+    pub async fn set_interest_area(&self, uuid: &ConnectionUUID, area: InterestArea) -> bool {
+        match self.replication.lock().await.clients.get_mut(uuid) {
+            Some(client) => {
+                client.interest.area = area;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn list_interest_areas(&self) -> Vec<(ConnectionUUID, InterestArea, usize)> {
+        self.replication
+            .lock().await
+            .clients.iter()
+            .map(|(uuid, client)| (uuid.clone(), client.interest.area, client.interest.known.len()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worldmachine::components::Transform;
+    use gfx_maths::Quaternion;
+
+    fn entity(uid: EntityId, position: Vec3, parent: Option<EntityId>) -> Entity {
+        Entity {
+            name: format!("entity {}", uid),
+            uid,
+            components: vec![Transform::new(position, Quaternion::identity(), Vec3::new(1.0, 1.0, 1.0))],
+            children: Vec::new(),
+            parent,
+        }
+    }
+
+    fn found(index: &SpatialIndex<u32>, position: Vec3, radius: f32) -> Vec<u32> {
+        let mut keys = index
+            .query(position, radius)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn queries_reach_across_cell_boundaries() {
+        let mut index = SpatialIndex::new();
+        index.insert(1, Vec3::new(INTEREST_CELL_SIZE - 0.1, 0.0, 0.0));
+        index.insert(2, Vec3::new(INTEREST_CELL_SIZE, 0.0, 0.0));
+        index.insert(3, Vec3::new(-0.1, 0.0, 0.0));
+        index.insert(4, Vec3::new(INTEREST_CELL_SIZE * 2.0, 0.0, 0.0));
+
+        assert_eq!(found(&index, Vec3::new(INTEREST_CELL_SIZE, 0.0, 0.0), 0.5), vec![1, 2]);
+        assert_eq!(found(&index, Vec3::zero(), 0.2), vec![3]);
+        // exactly on the radius still counts
+        assert_eq!(found(&index, Vec3::new(INTEREST_CELL_SIZE, 0.0, 0.0), INTEREST_CELL_SIZE), vec![1, 2, 4]);
+        assert_eq!(found(&index, Vec3::new(INTEREST_CELL_SIZE, 0.0, 0.0), INTEREST_CELL_SIZE - 0.01), vec![1, 2]);
+    }
+
+    #[test]
+    fn relevancy_follows_entities_that_moved() {
+        let mut entities = vec![
+            entity(1, Vec3::zero(), None),
+            entity(2, Vec3::new(500.0, 0.0, 0.0), None),
+            entity(3, Vec3::zero(), Some(2)),
+        ];
+        let snapshot = ReplicationSnapshot::capture(1, &entities);
+        let relevancy = Relevancy::build(&entities, &snapshot);
+        assert!(relevancy.is_managed(3));
+        assert_eq!(relevancy.relevant_to(Vec3::zero(), 10.0), HashSet::from([1]));
+
+        entities[1] = entity(2, Vec3::new(5.0, 0.0, 0.0), None);
+        entities[0] = entity(1, Vec3::new(500.0, 0.0, 0.0), None);
+        let snapshot = ReplicationSnapshot::capture(2, &entities);
+        let relevancy = Relevancy::build(&entities, &snapshot);
+        // the attachment comes along with its parent
+        assert_eq!(relevancy.relevant_to(Vec3::zero(), 10.0), HashSet::from([2, 3]));
+        assert_eq!(relevancy.relevant_to(Vec3::new(500.0, 0.0, 0.0), 10.0), HashSet::from([1]));
+    }
+}
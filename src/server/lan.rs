@@ -1,4 +1,18 @@
+use crate::server::accounts::{ authenticate_join, Authenticator, Credentials, SessionGrant };
+use crate::server::moderation::{ check_bans, BanList };
+use crate::server::conditioner::{ conditioned_channel, LinkConditioner, LinkDirection };
 use crate::server::connections::SteadyMessageQueue;
+use crate::server::crypto::{ ChannelKeys, KeyExchange, PublicKeyBytes, Sealed, Side, SEALING_OVERHEAD };
+use crate::server::identity::{ fingerprint, IdentityKeyBytes, ServerIdentity, ServerTrust, SignedKeyExchange };
+use crate::server::reliable::{ ReliableChannel, ReliableSegment, RELIABLE_TICK };
+use crate::server::transport::{
+    unix_time,
+    wake_on_arrival,
+    ConnectionError,
+    Transport,
+    TransportCounters,
+    TransportFuture,
+};
 use crate::server::{
     generate_uuid,
     ConnectionUUID,
@@ -9,84 +23,204 @@ use crate::server::{
     SteadyPacketData,
 };
 use bytes::{ Bytes, BytesMut };
-use fyrox_sound::futures::{ SinkExt, TryStreamExt };
+use futures::{ SinkExt, TryStreamExt };
 use halfbrown::HashMap;
 use mutex_timeouts::tokio::MutexWithTimeoutAuto as Mutex;
 use serde::{ Deserialize, Serialize };
 use std::cell::UnsafeCell;
 use std::collections::VecDeque;
-use std::fmt::format;
-use std::net::SocketAddr;
+use std::fmt::{ format, Display, Formatter };
+use std::net::{ IpAddr, SocketAddr };
 use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
-use std::sync::Arc;
+use std::sync::{ Arc, RwLock };
 use std::time::Duration;
 use tokio::io::{ AsyncReadExt, AsyncWriteExt };
 use tokio::net::{ TcpListener, TcpSocket, TcpStream, UdpSocket };
-use tokio::sync::mpsc;
+use tokio::sync::{ mpsc, Notify };
 use tokio::sync::mpsc::error::SendError;
 use tokio::time::Instant;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{ BytesCodec, Decoder, Encoder, Framed, LengthDelimitedCodec };
 
-pub const FAST_QUEUE_LIMIT: usize = 4;
-pub const FAKE_LAG: bool = false;
-pub const FAKE_LAG_TIME: u64 = 10;
-
-#[derive(Default, Debug)]
+pub const FAST_QUEUE_LIMIT: usize = 32;
+pub const RELIABLE_QUEUE_LIMIT: usize = 1024;
+pub const MAX_PENDING_FAST_PACKETS: usize = 128;
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+pub const MAX_OVERSIZED_DATAGRAM_SIZE: usize = 65507;
+pub const RECEIVE_BUFFER_SIZE: usize = 65536;
+pub const MAX_PENDING_UDP_HANDSHAKES: usize = 64;
+// a client that got our key exchange answers straight away, no need to hold its slot for long
+pub const UDP_AUTHENTICATE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const PROTOCOL_VERSION: u32 = 12;
+pub const BUILD_ID: &str = concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION"));
+pub const SUPPORTED_FEATURES: &[&str] = &["bots", "savegames", "snapshots", "reliable-udp", "encryption"];
+pub const REQUIRED_FEATURES: &[&str] = &[];
+
+// routes datagrams to a session, unlike the connection uuid nobody else in the game ever sees it
+pub type ChannelId = String;
+
+#[derive(Debug)]
 pub struct FastUpdateQueue<T> {
     pub queue: VecDeque<T>,
+    pub peer: Option<SocketAddr>,
+    pub counters: TransportCounters,
+    last_sequence: Option<u32>,
 }
 
-impl FastUpdateQueue<FastPacketData> {
-    pub fn new(packet: Option<FastPacketData>) -> Self {
+impl<T> FastUpdateQueue<T> {
+    pub fn new(packet: Option<T>) -> Self {
         let mut queue = VecDeque::new();
         if let Some(packet) = packet {
             queue.push_back(packet);
         }
-        Self { queue }
+        Self {
+            queue,
+            peer: None,
+            counters: TransportCounters::default(),
+            last_sequence: None,
+        }
     }
 
-    pub fn push(&mut self, packet: FastPacketData) {
+    pub fn push(&mut self, packet: T) {
         if self.queue.len() >= FAST_QUEUE_LIMIT {
             self.queue.pop_front();
+            self.counters.dropped += 1;
         }
         self.queue.push_back(packet);
+        self.counters.packets_received += 1;
     }
 
-    pub fn pop(&mut self) -> Option<FastPacketData> {
+    pub fn pop(&mut self) -> Option<T> {
         self.queue.pop_front()
     }
+
+    pub fn record_datagram(&mut self, sequence: Option<u32>) {
+        self.counters.datagrams_received += 1;
+        if let Some(sequence) = sequence {
+            match self.last_sequence {
+                Some(last) if sequence <= last => {}
+                Some(last) => {
+                    self.counters.lost += (sequence - last - 1) as u64;
+                    self.last_sequence = Some(sequence);
+                }
+                None => {
+                    self.last_sequence = Some(sequence);
+                }
+            }
+        }
+    }
 }
 
-impl FastUpdateQueue<FastPacketLan> {
-    pub fn new(packet: Option<FastPacketLan>) -> Self {
-        let mut queue = VecDeque::new();
-        if let Some(packet) = packet {
-            queue.push_back(packet);
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FastBatch {
+    pub sequence: u32,
+    pub packets: Vec<FastPacketData>,
+}
+
+pub struct FastBatcher {
+    channel: ChannelId,
+    keys: Arc<ChannelKeys>,
+    sequence: u32,
+    pending: Vec<FastPacketData>,
+    header_size: usize,
+    pub counters: TransportCounters,
+}
+
+impl FastBatcher {
+    pub fn new(channel: ChannelId, keys: Arc<ChannelKeys>) -> Self {
+        let empty = FastBatch {
+            sequence: u32::MAX,
+            packets: Vec::new(),
+        };
+        // the array length prefix grows by a few bytes once packets are added
+        let header_size =
+            serialise_lan_packet(&channel, FastPacketPotentials::Batch(empty)).len() +
+            SEALING_OVERHEAD +
+            4;
+        Self {
+            channel,
+            keys,
+            sequence: 0,
+            pending: Vec::new(),
+            header_size,
+            counters: TransportCounters::default(),
         }
-        Self { queue }
     }
 
-    pub fn push(&mut self, packet: FastPacketLan) {
-        if self.queue.len() >= FAST_QUEUE_LIMIT {
-            self.queue.pop_front();
+    pub fn push(&mut self, packet: FastPacketData) {
+        if self.pending.len() >= MAX_PENDING_FAST_PACKETS {
+            self.pending.remove(0);
+            self.counters.dropped += 1;
         }
-        self.queue.push_back(packet);
+        self.pending.push(packet);
     }
 
-    pub fn pop(&mut self) -> Option<FastPacketLan> {
-        self.queue.pop_front()
+    fn seal(&mut self, packets: Vec<FastPacketData>) -> Vec<u8> {
+        let batch = FastBatch {
+            sequence: self.sequence,
+            packets,
+        };
+        self.sequence += 1;
+        self.counters.datagrams_sent += 1;
+        self.counters.packets_sent += batch.packets.len() as u64;
+        seal_lan_packet(&self.channel, FastPacketPotentials::Batch(batch), &self.keys)
+    }
+
+    pub fn drain(&mut self) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        let mut batch = Vec::new();
+        let mut batch_size = self.header_size;
+        for packet in std::mem::take(&mut self.pending) {
+            let size = match rmp_serde::to_vec(&packet) {
+                Ok(data) => data.len(),
+                Err(e) => {
+                    warn!("failed to serialise fast packet: {:?}", e);
+                    self.counters.dropped += 1;
+                    continue;
+                }
+            };
+            if self.header_size + size > MAX_DATAGRAM_SIZE {
+                if self.header_size + size > MAX_OVERSIZED_DATAGRAM_SIZE {
+                    warn!("dropping fast packet of {} bytes, too large for a datagram", size);
+                    self.counters.dropped += 1;
+                    continue;
+                }
+                // too big to share, send it alone and let ip fragment it
+                self.counters.oversized += 1;
+                datagrams.push(self.seal(vec![packet]));
+                continue;
+            }
+            if batch_size + size > MAX_DATAGRAM_SIZE {
+                let full = std::mem::take(&mut batch);
+                datagrams.push(self.seal(full));
+                batch_size = self.header_size;
+            }
+            batch_size += size;
+            batch.push(packet);
+        }
+        if !batch.is_empty() {
+            datagrams.push(self.seal(batch));
+        }
+        datagrams
     }
 }
 
 #[derive(Clone)]
 pub struct LanConnection {
+    listener: LanListener,
     pub steady_update: mpsc::Sender<SteadyPacketData>,
-    pub steady_receiver_passthrough: Arc<Mutex<Option<mpsc::Receiver<SteadyPacketData>>>>,
+    steady_receiver: Arc<Mutex<mpsc::Receiver<SteadyPacketData>>>,
     pub is_connected: Arc<AtomicBool>,
     pub remote_addr: SocketAddr,
     pub uuid: ConnectionUUID,
+    channel: ChannelId,
+    pub identity: Option<String>,
+    pub resume_token: Option<String>,
     pub last_successful_ping: Arc<AtomicU64>,
+    fast_batcher: Arc<Mutex<FastBatcher>>,
+    keys: Arc<ChannelKeys>,
+    pub conditioner: LinkConditioner,
+    arrived: Arc<Notify>,
 }
 
 unsafe impl Send for LanConnection {}
@@ -99,7 +233,13 @@ pub struct ClientLanConnection {
     pub fast_update_queue: Arc<Mutex<FastUpdateQueue<FastPacketData>>>,
     pub steady_sender_queue: mpsc::Sender<SteadyPacketData>,
     pub steady_receiver_queue: Arc<Mutex<SteadyMessageQueue>>,
+    reliable_segments: Option<mpsc::Sender<ReliableSegment>>,
+    fast_batcher: Arc<Mutex<FastBatcher>>,
+    keys: Arc<ChannelKeys>,
     pub uuid: ConnectionUUID,
+    channel: ChannelId,
+    pub server_identity: IdentityKeyBytes,
+    pub session: Option<SessionGrant>,
 }
 
 unsafe impl Send for ClientLanConnection {}
@@ -108,7 +248,7 @@ unsafe impl Sync for ClientLanConnection {}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FastPacketLan {
-    pub uuid: ConnectionUUID,
+    pub channel: ChannelId,
     pub socket_addr: Option<SocketAddr>,
     pub data: FastPacketPotentials,
 }
@@ -121,46 +261,259 @@ unsafe impl Sync for FastPacketLan {}
 pub enum FastPacketPotentials {
     FastPacket(FastPacketData),
     ConnectionHandshake(ConnectionHandshakePacket),
+    Reliable(ReliableSegment),
+    Batch(FastBatch),
+    Sealed(Sealed),
+}
+
+fn serialise_lan_packet(channel: &ChannelId, data: FastPacketPotentials) -> Vec<u8> {
+    let packet = FastPacketLan {
+        channel: channel.clone(),
+        socket_addr: None,
+        data,
+    };
+    let mut serialiser = rmp_serde::Serializer::new(Vec::new());
+    packet.serialize(&mut serialiser).unwrap();
+    serialiser.into_inner()
+}
+
+fn seal_lan_packet(channel: &ChannelId, data: FastPacketPotentials, keys: &ChannelKeys) -> Vec<u8> {
+    let mut serialiser = rmp_serde::Serializer::new(Vec::new());
+    data.serialize(&mut serialiser).unwrap();
+    let sealed = keys.datagram_sealer.seal(channel.as_bytes(), &serialiser.into_inner());
+    serialise_lan_packet(channel, FastPacketPotentials::Sealed(sealed))
+}
+
+fn open_lan_packet(packet: FastPacketLan, keys: &ChannelKeys) -> Option<FastPacketLan> {
+    let sealed = match &packet.data {
+        FastPacketPotentials::Sealed(sealed) => sealed,
+        _ => {
+            return None;
+        }
+    };
+    let plaintext = keys.datagram_opener.open(packet.channel.as_bytes(), sealed)?;
+    let mut deserialiser = rmp_serde::Deserializer::new(&plaintext[..]);
+    match FastPacketPotentials::deserialize(&mut deserialiser).ok()? {
+        FastPacketPotentials::Sealed(_) => None,
+        data =>
+            Some(FastPacketLan {
+                channel: packet.channel,
+                socket_addr: packet.socket_addr,
+                data,
+            }),
+    }
+}
+
+fn seal_steady(plaintext: &[u8], keys: &ChannelKeys) -> Bytes {
+    let mut serialiser = rmp_serde::Serializer::new(Vec::new());
+    keys.steady_sealer.seal(&[], plaintext).serialize(&mut serialiser).unwrap();
+    Bytes::from(serialiser.into_inner())
+}
+
+fn open_steady(frame: &[u8], keys: &ChannelKeys) -> Option<Vec<u8>> {
+    let mut deserialiser = rmp_serde::Deserializer::new(frame);
+    let sealed = Sealed::deserialize(&mut deserialiser).ok()?;
+    keys.steady_opener.open(&[], &sealed)
+}
+
+pub enum PendingLanConnection {
+    Tcp(TcpStream),
+    Udp(FastPacketLan),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolInfo {
+    pub protocol_version: u32,
+    pub build_id: String,
+    pub features: Vec<String>,
+}
+
+impl ProtocolInfo {
+    pub fn current() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build_id: BUILD_ID.to_string(),
+            features: SUPPORTED_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    pub fn check_compatible(&self) -> Result<(), RejectionReason> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Err(RejectionReason::ProtocolMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: self.protocol_version,
+            });
+        }
+        let missing = REQUIRED_FEATURES.iter()
+            .filter(|feature| !self.features.iter().any(|other| other == *feature))
+            .map(|feature| feature.to_string())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(RejectionReason::MissingFeatures(missing));
+        }
+        if self.build_id != BUILD_ID {
+            info!("peer is running build {} (we are {})", self.build_id, BUILD_ID);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RejectionReason {
+    ProtocolMismatch {
+        ours: u32,
+        theirs: u32,
+    },
+    MissingFeatures(Vec<String>),
+    MalformedHandshake,
+    AuthenticationRequired,
+    AuthenticationFailed(String),
+    Banned {
+        reason: String,
+        expires_at: Option<u64>,
+    },
+}
+
+impl Display for RejectionReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectionReason::ProtocolMismatch { ours, theirs } =>
+                write!(f, "protocol version mismatch (expected {}, got {})", ours, theirs),
+            RejectionReason::MissingFeatures(features) =>
+                write!(f, "missing required features: {}", features.join(", ")),
+            RejectionReason::MalformedHandshake => write!(f, "malformed handshake"),
+            RejectionReason::AuthenticationRequired =>
+                write!(f, "this server requires you to log in"),
+            RejectionReason::AuthenticationFailed(reason) =>
+                write!(f, "authentication failed: {}", reason),
+            RejectionReason::Banned { reason, expires_at: Some(expires_at) } =>
+                write!(
+                    f,
+                    "banned from this server for another {} seconds: {}",
+                    expires_at.saturating_sub(unix_time()),
+                    reason
+                ),
+            RejectionReason::Banned { reason, expires_at: None } =>
+                write!(f, "banned from this server: {}", reason),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ClientConnectError {
+    Io(String),
+    Rejected(RejectionReason),
+    IncompatibleServer(RejectionReason),
+    UnexpectedPacket,
+    VerificationFailed,
+    UntrustedServer {
+        expected: String,
+        presented: String,
+    },
+}
+
+impl Display for ClientConnectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientConnectError::Io(e) => write!(f, "connection failed: {}", e),
+            ClientConnectError::Rejected(reason) => write!(f, "rejected by server: {}", reason),
+            ClientConnectError::IncompatibleServer(reason) =>
+                write!(f, "incompatible server: {}", reason),
+            ClientConnectError::UnexpectedPacket => write!(f, "unexpected handshake packet"),
+            ClientConnectError::VerificationFailed =>
+                write!(f, "could not establish a secure channel with the server"),
+            ClientConnectError::UntrustedServer { expected, presented } =>
+                write!(
+                    f,
+                    "the server's identity has changed (expected {}, got {}), it may be an impostor",
+                    expected,
+                    presented
+                ),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ConnectionHandshakePacket {
-    JoinRequest,
+    JoinRequest(ProtocolInfo, Option<Credentials>, Option<String>),
+
+    Hello(ProtocolInfo, PublicKeyBytes),
+
+    KeyExchange(SignedKeyExchange),
+
+    // the token resumes a player the client lost its connection to
+    Authenticate(Option<Credentials>, Option<String>),
 
-    PleaseConnectUDPNow(ConnectionUUID),
+    Accepted(ProtocolInfo, Option<SessionGrant>),
 
-    IconnectedUDP(ConnectionUUID),
+    Rejected(RejectionReason),
+
+    PleaseConnectUDPNow(ConnectionUUID, ChannelId),
+
+    IconnectedUDP(ChannelId),
 
     YoureReady(ConnectionUUID),
 }
 
+struct PendingUdpHandshake {
+    addr: SocketAddr,
+    // what we answered their hello with, sent again once for every hello they repeat
+    reply: Option<Vec<u8>>,
+}
+
 #[derive(Clone)]
 pub struct LanListener {
     pub fast_update: Arc<UdpSocket>,
     steady_update: Arc<Mutex<TcpListener>>,
-    fast_update_map: Arc<Mutex<HashMap<ConnectionUUID, FastUpdateQueue<FastPacketLan>>>>,
+    fast_update_map: Arc<Mutex<HashMap<ChannelId, FastUpdateQueue<FastPacketLan>>>>,
+    reliable_map: Arc<Mutex<HashMap<ChannelId, mpsc::Sender<ReliableSegment>>>>,
+    udp_handshakes: Arc<Mutex<HashMap<ChannelId, PendingUdpHandshake>>>,
+    keys: Arc<Mutex<HashMap<ChannelId, Arc<ChannelKeys>>>>,
+    conditioners: Arc<Mutex<HashMap<ChannelId, LinkConditioner>>>,
+    wakers: Arc<Mutex<HashMap<ChannelId, Arc<Notify>>>>,
+    udp_join_sender: mpsc::Sender<FastPacketLan>,
+    udp_joins: Arc<Mutex<mpsc::Receiver<FastPacketLan>>>,
+    authenticator: Arc<RwLock<Option<Arc<Authenticator>>>>,
+    bans: Arc<RwLock<BanList>>,
+    identity: Arc<ServerIdentity>,
+    pub tcp_port: u16,
+    pub udp_port: u16,
 }
 
 unsafe impl Send for LanListener {}
 
 unsafe impl Sync for LanListener {}
 
-#[derive(Clone, Copy, Debug)]
-pub enum ConnectionError {
-    ConnectionClosed,
-    ConnectionError,
-}
-
 impl LanListener {
-    pub async fn new(hostname: &str, tcp_port: u16, udp_port: u16) -> Self {
+    pub async fn new(
+        hostname: &str,
+        tcp_port: u16,
+        udp_port: u16,
+        authenticator: Arc<RwLock<Option<Arc<Authenticator>>>>,
+        bans: Arc<RwLock<BanList>>,
+        identity: Arc<ServerIdentity>
+    ) -> Self {
         let tcp_listener = TcpListener::bind(format!("{}:{}", hostname, tcp_port)).await.unwrap();
         let udp_socket = UdpSocket::bind(format!("{}:{}", hostname, udp_port)).await.unwrap();
         let fast_update_map = Arc::new(Mutex::new(HashMap::new()));
+        let (udp_join_sender, udp_joins) = mpsc::channel(MAX_PENDING_UDP_HANDSHAKES);
 
         let the_self = Self {
             fast_update: Arc::new(udp_socket),
             steady_update: Arc::new(Mutex::new(tcp_listener)),
             fast_update_map,
+            reliable_map: Arc::new(Mutex::new(HashMap::new())),
+            udp_handshakes: Arc::new(Mutex::new(HashMap::new())),
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            conditioners: Arc::new(Mutex::new(HashMap::new())),
+            wakers: Arc::new(Mutex::new(HashMap::new())),
+            udp_join_sender,
+            udp_joins: Arc::new(Mutex::new(udp_joins)),
+            authenticator,
+            bans,
+            identity,
+            tcp_port,
+            udp_port,
         };
 
         let the_clone = the_self.clone();
@@ -171,114 +524,386 @@ impl LanListener {
         the_self
     }
 
-    pub async fn poll_new_connection(&self) -> Option<TcpStream> {
-        let mut steady_update = self.steady_update.lock().await;
+    pub fn authenticator(&self) -> Option<Arc<Authenticator>> {
+        self.authenticator.read().unwrap().clone()
+    }
 
-        let new_connection = steady_update.accept().await;
+    pub async fn poll_new_connection(&self) -> Option<PendingLanConnection> {
+        let steady_update = self.steady_update.lock().await;
+        let mut udp_joins = self.udp_joins.lock().await;
 
-        if new_connection.is_err() {
-            return None;
-        } else {
-            return Some(new_connection.unwrap().0);
+        tokio::select! {
+            new_connection = steady_update.accept() => {
+                new_connection.ok().map(|(stream, _)| PendingLanConnection::Tcp(stream))
+            }
+            join = udp_joins.recv() => join.map(PendingLanConnection::Udp),
         }
     }
 
-    pub async fn init_new_connection(&self, steady_update: TcpStream) -> Option<LanConnection> {
+    pub async fn init_new_connection(&self, pending: PendingLanConnection) -> Option<LanConnection> {
+        match pending {
+            PendingLanConnection::Tcp(stream) => self.init_tcp_connection(stream).await,
+            PendingLanConnection::Udp(join) => self.init_udp_connection(join).await,
+        }
+    }
+
+    async fn init_tcp_connection(&self, steady_update: TcpStream) -> Option<LanConnection> {
         debug!("new connection");
-        let mut steady_update = steady_update;
         let mut reader = Framed::new(steady_update, LengthDelimitedCodec::new());
 
-        let buffer = StreamExt::next(&mut reader).await
-            .expect("failed to read result from server")
-            .expect("error reading result from server");
-        let mut deserialiser = rmp_serde::Deserializer::new(buffer.as_ref());
-        let handshake_packet = ConnectionHandshakePacket::deserialize(&mut deserialiser);
-        if let Err(_) = handshake_packet {
-            warn!("handshake packet error");
+        let (client_info, client_key) = match Self::read_handshake(&mut reader, None).await {
+            Some(ConnectionHandshakePacket::Hello(client_info, client_key)) => (client_info, client_key),
+            _ => {
+                warn!("handshake packet error");
+                Self::reject_connection(&mut reader, RejectionReason::MalformedHandshake, None).await;
+                return None;
+            }
+        };
+        debug!("got first handshake packet");
+        if let Err(reason) = client_info.check_compatible() {
+            warn!("rejecting client running build {}: {}", client_info.build_id, reason);
+            Self::reject_connection(&mut reader, reason, None).await;
             return None;
         }
-        let handshake_packet = handshake_packet.unwrap();
-        if matches!(handshake_packet, ConnectionHandshakePacket::JoinRequest) {
-            debug!("got first handshake packet");
-            let uuid_real = generate_uuid();
-            let mut serialiser = rmp_serde::Serializer::new(Vec::new());
-            let packet = ConnectionHandshakePacket::PleaseConnectUDPNow(uuid_real.clone());
-            packet.serialize(&mut serialiser).unwrap();
-            let data = serialiser.into_inner();
-            let n = reader.send(Bytes::from(data)).await;
-            if let Err(_) = n {
-                warn!("handshake packet error");
+        let exchange = KeyExchange::new();
+        let signed = self.identity.sign_exchange(&client_key, exchange.public_key());
+        let keys = match exchange.complete(client_key, Side::Server) {
+            Some(keys) => Arc::new(keys),
+            None => {
+                warn!("rejecting client: unusable public key");
+                Self::reject_connection(&mut reader, RejectionReason::MalformedHandshake, None).await;
                 return None;
             }
-            debug!("sent second handshake packet");
+        };
+        let packet = ConnectionHandshakePacket::KeyExchange(signed);
+        if !Self::send_handshake(&mut reader, packet, None).await {
+            warn!("handshake packet error");
+            return None;
+        }
 
-            let mut peer_addr = None;
+        let (credentials, resume_token) = match Self::read_handshake(&mut reader, Some(&keys)).await {
+            Some(ConnectionHandshakePacket::Authenticate(credentials, resume_token)) => (credentials, resume_token),
+            _ => {
+                warn!("handshake packet error: expected credentials");
+                let reason = RejectionReason::MalformedHandshake;
+                Self::reject_connection(&mut reader, reason, Some(&keys)).await;
+                return None;
+            }
+        };
+        let remote_ip = reader.get_ref().peer_addr().ok().map(|addr| addr.ip());
+        let session = match authenticate_join(self.authenticator(), credentials, remote_ip).await {
+            Ok(session) => session,
+            Err(reason) => {
+                warn!("rejecting client: {}", reason);
+                Self::reject_connection(&mut reader, reason, Some(&keys)).await;
+                return None;
+            }
+        };
+        let identity = session.as_ref().map(|session| session.username.clone());
+        if let Some(identity) = &identity {
+            info!("client authenticated as {}", identity);
+        }
+        if let Err(reason) = check_bans(&self.bans, identity.as_ref(), remote_ip) {
+            warn!("rejecting client: {}", reason);
+            Self::reject_connection(&mut reader, reason, Some(&keys)).await;
+            return None;
+        }
+        let packet = ConnectionHandshakePacket::Accepted(ProtocolInfo::current(), session);
+        if !Self::send_handshake(&mut reader, packet, Some(&keys)).await {
+            warn!("handshake packet error");
+            return None;
+        }
 
-            let starting_time = Instant::now();
-            const TIMEOUT_SECS: u64 = 20;
-            const RETRY_SECS: u64 = 5;
-            loop {
-                let packet = self.check_for_fast_update(&uuid_real).await;
-                if let Some(packet) = packet {
-                    debug!("got a packet, checking if it's the right one");
-                    if
-                        let FastPacketPotentials::ConnectionHandshake(handshake_packet) =
-                            packet.data
-                    {
-                        if let ConnectionHandshakePacket::IconnectedUDP(uuid) = handshake_packet {
-                            if uuid == uuid_real {
-                                peer_addr = packet.socket_addr;
-                                break;
-                            }
+        let uuid_real = generate_uuid();
+        let channel = generate_uuid();
+        self.register_keys(&channel, keys.clone()).await;
+        let packet = ConnectionHandshakePacket::PleaseConnectUDPNow(uuid_real.clone(), channel.clone());
+        if !Self::send_handshake(&mut reader, packet, Some(&keys)).await {
+            warn!("handshake packet error");
+            self.forget_keys(&channel).await;
+            return None;
+        }
+        debug!("sent second handshake packet");
+
+        let starting_time = Instant::now();
+        const TIMEOUT_SECS: u64 = 20;
+        const RETRY_MILLIS: u64 = 500;
+        let mut last_sent = Instant::now();
+        let peer_addr = loop {
+            if let Some(packet) = self.check_for_fast_update(&channel).await {
+                debug!("got a packet, checking if it's the right one");
+                if
+                    let FastPacketPotentials::ConnectionHandshake(
+                        ConnectionHandshakePacket::IconnectedUDP(confirmed),
+                    ) = packet.data
+                {
+                    if confirmed == channel {
+                        if let Some(peer_addr) = packet.socket_addr {
+                            break peer_addr;
                         }
                     }
                 }
+            }
 
-                if starting_time.elapsed().as_secs() % RETRY_SECS == 0 {
-                    let mut serialiser = rmp_serde::Serializer::new(Vec::new());
-                    let packet = ConnectionHandshakePacket::PleaseConnectUDPNow(uuid_real.clone());
-                    packet.serialize(&mut serialiser).unwrap();
-                    let data = serialiser.into_inner();
-                    let n = reader.send(Bytes::from(data)).await;
-                    if let Err(_) = n {
-                        warn!("handshake packet error");
-                        return None;
-                    }
-                    debug!("resent second handshake packet");
+            // every resend is a fresh nonce and another udp confirmation from the client, so pace them
+            if last_sent.elapsed() >= Duration::from_millis(RETRY_MILLIS) {
+                let packet = ConnectionHandshakePacket::PleaseConnectUDPNow(uuid_real.clone(), channel.clone());
+                if !Self::send_handshake(&mut reader, packet, Some(&keys)).await {
+                    warn!("handshake packet error");
+                    self.forget_keys(&channel).await;
+                    return None;
                 }
+                last_sent = Instant::now();
+                debug!("resent second handshake packet");
+            }
 
-                if starting_time.elapsed().as_secs() > TIMEOUT_SECS {
-                    warn!("handshake packet error: timed out");
-                    return None;
+            if starting_time.elapsed().as_secs() > TIMEOUT_SECS {
+                warn!("handshake packet error: timed out");
+                self.forget_keys(&channel).await;
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        debug!("got third handshake packet");
+
+        let packet = ConnectionHandshakePacket::YoureReady(uuid_real.clone());
+        if !Self::send_handshake(&mut reader, packet, Some(&keys)).await {
+            warn!("handshake packet error");
+            self.forget_keys(&channel).await;
+            return None;
+        }
+
+        debug!("sent fourth handshake packet");
+
+        Some(LanConnection::new(uuid_real, channel, identity, resume_token, self.clone(), reader, peer_addr, keys))
+    }
+
+    async fn read_handshake(
+        reader: &mut Framed<TcpStream, LengthDelimitedCodec>,
+        keys: Option<&ChannelKeys>
+    ) -> Option<ConnectionHandshakePacket> {
+        let buffer = StreamExt::next(reader).await?.ok()?;
+        let plaintext = match keys {
+            Some(keys) => open_steady(&buffer, keys)?,
+            None => buffer.to_vec(),
+        };
+        let mut deserialiser = rmp_serde::Deserializer::new(&plaintext[..]);
+        ConnectionHandshakePacket::deserialize(&mut deserialiser).ok()
+    }
+
+    async fn send_handshake(
+        reader: &mut Framed<TcpStream, LengthDelimitedCodec>,
+        packet: ConnectionHandshakePacket,
+        keys: Option<&ChannelKeys>
+    ) -> bool {
+        let mut serialiser = rmp_serde::Serializer::new(Vec::new());
+        packet.serialize(&mut serialiser).unwrap();
+        let data = serialiser.into_inner();
+        let frame = match keys {
+            Some(keys) => seal_steady(&data, keys),
+            None => Bytes::from(data),
+        };
+        reader.send(frame).await.is_ok()
+    }
+
+    async fn init_udp_connection(&self, join: FastPacketLan) -> Option<LanConnection> {
+        let handshake_id = join.channel.clone();
+        let connection = self.handshake_udp_connection(join).await;
+        self.udp_handshakes.lock().await.remove(&handshake_id);
+        connection
+    }
+
+    async fn handshake_udp_connection(&self, join: FastPacketLan) -> Option<LanConnection> {
+        debug!("new udp connection");
+        let handshake_addr = join.socket_addr?;
+        let (client_info, client_key) = match join.data {
+            FastPacketPotentials::ConnectionHandshake(
+                ConnectionHandshakePacket::Hello(client_info, client_key),
+            ) => (client_info, client_key),
+            _ => {
+                return None;
+            }
+        };
+        if let Err(reason) = client_info.check_compatible() {
+            warn!("rejecting client running build {}: {}", client_info.build_id, reason);
+            let packet = ConnectionHandshakePacket::Rejected(reason);
+            self.send_udp_handshake(&join.channel, packet, handshake_addr, None).await;
+            return None;
+        }
+        let exchange = KeyExchange::new();
+        let key_exchange = ConnectionHandshakePacket::KeyExchange(
+            self.identity.sign_exchange(&client_key, exchange.public_key())
+        );
+        let keys = match exchange.complete(client_key, Side::Server) {
+            Some(keys) => Arc::new(keys),
+            None => {
+                warn!("rejecting client: unusable public key");
+                let packet = ConnectionHandshakePacket::Rejected(RejectionReason::MalformedHandshake);
+                self.send_udp_handshake(&join.channel, packet, handshake_addr, None).await;
+                return None;
+            }
+        };
+        self.register_keys(&join.channel, keys.clone()).await;
+        let reply = serialise_lan_packet(&join.channel, FastPacketPotentials::ConnectionHandshake(key_exchange));
+        if let Some(pending) = self.udp_handshakes.lock().await.get_mut(&join.channel) {
+            pending.reply = Some(reply.clone());
+        }
+        if let Err(e) = self.fast_update.send_to(&reply, handshake_addr).await {
+            debug!("failed to send handshake: {:?}", e);
+        }
+        let connection = self.finish_udp_connection(&join.channel, handshake_addr, keys).await;
+        self.forget_keys(&join.channel).await;
+        connection
+    }
+
+    async fn finish_udp_connection(
+        &self,
+        handshake_id: &ChannelId,
+        handshake_addr: SocketAddr,
+        keys: Arc<ChannelKeys>
+    ) -> Option<LanConnection> {
+        const TIMEOUT_SECS: u64 = 20;
+        const RETRY_MILLIS: u64 = 500;
+        let starting_time = Instant::now();
+        // the client repeats its hello or its credentials until it hears from us
+        let (credentials, resume_token) = loop {
+            if let Some(packet) = self.check_for_fast_update(handshake_id).await {
+                if
+                    let FastPacketPotentials::ConnectionHandshake(
+                        ConnectionHandshakePacket::Authenticate(credentials, resume_token),
+                    ) = packet.data
+                {
+                    break (credentials, resume_token);
                 }
             }
 
-            debug!("got third handshake packet");
+            if starting_time.elapsed() > UDP_AUTHENTICATE_TIMEOUT {
+                warn!("handshake packet error: timed out waiting for credentials");
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
 
-            let peer_addr = peer_addr;
-            if peer_addr.is_none() {
-                warn!("handshake packet error");
+        let address = Some(handshake_addr.ip());
+        let session = match authenticate_join(self.authenticator(), credentials, address).await {
+            Ok(session) => session,
+            Err(reason) => {
+                warn!("rejecting client: {}", reason);
+                let packet = ConnectionHandshakePacket::Rejected(reason);
+                self.send_udp_handshake(handshake_id, packet, handshake_addr, Some(&keys)).await;
                 return None;
             }
-            let peer_addr = peer_addr.unwrap();
+        };
+        let identity = session.as_ref().map(|session| session.username.clone());
+        if let Some(identity) = &identity {
+            info!("client authenticated as {}", identity);
+        }
+        if let Err(reason) = check_bans(&self.bans, identity.as_ref(), Some(handshake_addr.ip())) {
+            warn!("rejecting client: {}", reason);
+            let packet = ConnectionHandshakePacket::Rejected(reason);
+            self.send_udp_handshake(handshake_id, packet, handshake_addr, Some(&keys)).await;
+            return None;
+        }
+
+        let uuid_real = generate_uuid();
+        let channel = generate_uuid();
+        let accepted = ConnectionHandshakePacket::Accepted(ProtocolInfo::current(), session);
+        let connect = ConnectionHandshakePacket::PleaseConnectUDPNow(uuid_real.clone(), channel.clone());
+        self.register_keys(&channel, keys.clone()).await;
+
+        // their credentials were sealed with our keys, so from here on we know they're really at that address
+        let mut last_sent: Option<Instant> = None;
+        let peer_addr = loop {
+            if last_sent.is_none_or(|sent| sent.elapsed() >= Duration::from_millis(RETRY_MILLIS)) {
+                let (accepted, connect) = (accepted.clone(), connect.clone());
+                self.send_udp_handshake(handshake_id, accepted, handshake_addr, Some(&keys)).await;
+                self.send_udp_handshake(handshake_id, connect, handshake_addr, Some(&keys)).await;
+                last_sent = Some(Instant::now());
+            }
 
-            let mut serialiser = rmp_serde::Serializer::new(Vec::new());
-            let packet = ConnectionHandshakePacket::YoureReady(uuid_real.clone());
-            packet.serialize(&mut serialiser).unwrap();
-            let data = serialiser.into_inner();
+            if let Some(packet) = self.check_for_fast_update(&channel).await {
+                if
+                    let FastPacketPotentials::ConnectionHandshake(
+                        ConnectionHandshakePacket::IconnectedUDP(confirmed),
+                    ) = packet.data
+                {
+                    if confirmed == channel {
+                        if let Some(peer_addr) = packet.socket_addr {
+                            break peer_addr;
+                        }
+                    }
+                }
+            }
 
-            let successful_write = reader.send(Bytes::from(data)).await;
-            if successful_write.is_err() {
-                warn!("handshake packet error");
+            if starting_time.elapsed().as_secs() > TIMEOUT_SECS {
+                warn!("handshake packet error: timed out");
+                self.forget_keys(&channel).await;
                 return None;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        debug!("udp client confirmed, switching to reliable channel");
 
-            debug!("sent fourth handshake packet");
+        Some(LanConnection::new_reliable(uuid_real, channel, identity, resume_token, self.clone(), peer_addr, keys).await)
+    }
 
-            return Some(LanConnection::new(uuid_real, reader, peer_addr));
+    async fn send_udp_handshake(
+        &self,
+        channel: &ChannelId,
+        packet: ConnectionHandshakePacket,
+        addr: SocketAddr,
+        keys: Option<&ChannelKeys>
+    ) {
+        let data = FastPacketPotentials::ConnectionHandshake(packet);
+        let data = match keys {
+            Some(keys) => seal_lan_packet(channel, data, keys),
+            None => serialise_lan_packet(channel, data),
+        };
+        if let Err(e) = self.fast_update.send_to(&data, addr).await {
+            debug!("failed to send handshake: {:?}", e);
         }
+    }
 
-        None
+    async fn register_keys(&self, channel: &ChannelId, keys: Arc<ChannelKeys>) {
+        self.keys.lock().await.insert(channel.clone(), keys);
+    }
+
+    async fn forget_keys(&self, channel: &ChannelId) {
+        self.keys.lock().await.remove(channel);
+        self.fast_update_map.lock().await.remove(channel);
+        self.conditioners.lock().await.remove(channel);
+        self.wakers.lock().await.remove(channel);
+    }
+
+    async fn register_conditioner(&self, channel: &ChannelId, conditioner: LinkConditioner) {
+        self.conditioners.lock().await.insert(channel.clone(), conditioner);
+    }
+
+    async fn register_waker(&self, channel: &ChannelId, arrived: Arc<Notify>) {
+        self.wakers.lock().await.insert(channel.clone(), arrived);
+    }
+
+    async fn register_reliable(&self, channel: &ChannelId) -> mpsc::Receiver<ReliableSegment> {
+        let (sender, receiver) = mpsc::channel(RELIABLE_QUEUE_LIMIT);
+        self.reliable_map.lock().await.insert(channel.clone(), sender);
+        receiver
+    }
+
+    async fn unregister_reliable(&self, channel: &ChannelId) {
+        self.reliable_map.lock().await.remove(channel);
+    }
+
+    async fn reject_connection(
+        reader: &mut Framed<TcpStream, LengthDelimitedCodec>,
+        reason: RejectionReason,
+        keys: Option<&ChannelKeys>
+    ) {
+        let packet = ConnectionHandshakePacket::Rejected(reason);
+        if !Self::send_handshake(reader, packet, keys).await {
+            debug!("failed to send rejection");
+        }
     }
 
     async fn send_fast_update(
@@ -286,44 +911,147 @@ impl LanListener {
         connection: LanConnection,
         data: &[u8]
     ) -> std::io::Result<usize> {
-        if FAKE_LAG {
-            tokio::time::sleep(Duration::from_millis(FAKE_LAG_TIME)).await;
+        if !connection.conditioner.is_active() {
+            return self.fast_update.send_to(data, connection.remote_addr).await;
+        }
+        for arrival in connection.conditioner.schedule(LinkDirection::ToClient, data.len()) {
+            let socket = self.fast_update.clone();
+            let data = data.to_vec();
+            tokio::spawn(async move {
+                tokio::time::sleep_until(arrival).await;
+                if let Err(e) = socket.send_to(&data, connection.remote_addr).await {
+                    debug!("failed to send delayed datagram to {}: {:?}", connection.remote_addr, e);
+                }
+            });
         }
-        self.fast_update.send_to(data, connection.remote_addr).await
+        Ok(data.len())
     }
 
     pub async fn udp_thread(&self) {
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
         loop {
-            let mut buf = [0; 4096];
-            let fast_update = &self.fast_update;
-            if FAKE_LAG {
-                tokio::time::sleep(Duration::from_millis(FAKE_LAG_TIME)).await;
-            }
-            let (len, addr) = fast_update
+            let (len, addr) = self.fast_update
                 .recv_from(&mut buf).await
                 .expect("failed to receive from udp socket");
             let mut deserialiser = rmp_serde::Deserializer::new(&buf[..len]);
             let packet = FastPacketLan::deserialize(&mut deserialiser);
             if let Err(e) = packet {
                 debug!("failed to deserialise packet: {:?}", e);
+                let mut fast_update_map = self.fast_update_map.lock().await;
+                if let Some(updates) = fast_update_map.values_mut().find(|updates| updates.peer == Some(addr)) {
+                    updates.counters.truncated += 1;
+                }
                 continue;
             }
             let mut packet = packet.unwrap();
-            let mut fast_update_map = self.fast_update_map.lock().await;
             packet.socket_addr = Some(addr);
-            if let Some(updates) = fast_update_map.get_mut(&packet.uuid) {
-                updates.push(packet);
-            } else {
-                let new_queue = FastUpdateQueue::<FastPacketLan>::new(Some(packet.clone()));
-                fast_update_map.insert(packet.clone().uuid, new_queue);
+            let conditioner = self.conditioners.lock().await.get(&packet.channel).cloned();
+            let conditioner = match conditioner {
+                Some(conditioner) if conditioner.is_active() => conditioner,
+                _ => {
+                    self.receive_datagram(packet, addr).await;
+                    continue;
+                }
+            };
+            for arrival in conditioner.schedule(LinkDirection::ToServer, len) {
+                let the_clone = self.clone();
+                let packet = packet.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep_until(arrival).await;
+                    the_clone.receive_datagram(packet, addr).await;
+                });
             }
-            drop(fast_update_map);
         }
     }
 
-    pub async fn check_for_fast_update(&self, uuid: &ConnectionUUID) -> Option<FastPacketLan> {
+    async fn receive_datagram(&self, packet: FastPacketLan, addr: SocketAddr) {
+        if let FastPacketPotentials::ConnectionHandshake(ConnectionHandshakePacket::Hello(..)) = &packet.data {
+            self.receive_hello(packet, addr).await;
+            return;
+        }
+        let channel = packet.channel.clone();
+        let keys = self.keys.lock().await.get(&channel).cloned();
+        let packet = keys.and_then(|keys| open_lan_packet(packet, &keys));
+        let packet = match packet {
+            Some(packet) => packet,
+            None => {
+                debug!("rejecting datagram from {} that failed verification", addr);
+                if let Some(updates) = self.fast_update_map.lock().await.get_mut(&channel) {
+                    updates.counters.rejected += 1;
+                }
+                return;
+            }
+        };
+        if let FastPacketPotentials::Reliable(segment) = &packet.data {
+            if let Some(segments) = self.reliable_map.lock().await.get(&packet.channel) {
+                if segments.try_send(segment.clone()).is_err() {
+                    debug!("reliable queue for {} is full, dropping segment", addr);
+                }
+            }
+            return;
+        }
         let mut fast_update_map = self.fast_update_map.lock().await;
-        let update = fast_update_map.get_mut(uuid);
+        let updates = fast_update_map
+            .entry(packet.channel.clone())
+            .or_insert_with(|| FastUpdateQueue::<FastPacketLan>::new(None));
+        updates.peer = Some(addr);
+        if let FastPacketPotentials::Batch(batch) = packet.data {
+            updates.record_datagram(Some(batch.sequence));
+            for data in batch.packets {
+                updates.push(FastPacketLan {
+                    channel: packet.channel.clone(),
+                    socket_addr: Some(addr),
+                    data: FastPacketPotentials::FastPacket(data),
+                });
+            }
+        } else {
+            updates.record_datagram(None);
+            updates.push(packet);
+        }
+        drop(fast_update_map);
+        if let Some(arrived) = self.wakers.lock().await.get(&channel) {
+            arrived.notify_one();
+        }
+    }
+
+    // one datagram back for every hello, so a spoofed hello can't make us flood someone else
+    async fn receive_hello(&self, packet: FastPacketLan, addr: SocketAddr) {
+        let mut handshakes = self.udp_handshakes.lock().await;
+        if let Some(pending) = handshakes.get(&packet.channel) {
+            let reply = pending.reply.clone().filter(|_| pending.addr == addr);
+            drop(handshakes);
+            if let Some(reply) = reply {
+                if let Err(e) = self.fast_update.send_to(&reply, addr).await {
+                    debug!("failed to send handshake: {:?}", e);
+                }
+            }
+            return;
+        }
+        if handshakes.len() >= MAX_PENDING_UDP_HANDSHAKES {
+            debug!("too many udp handshakes in progress, ignoring hello from {}", addr);
+            return;
+        }
+        let handshake_id = packet.channel.clone();
+        match self.udp_join_sender.try_send(packet) {
+            Ok(()) => {
+                handshakes.insert(handshake_id, PendingUdpHandshake { addr, reply: None });
+            }
+            Err(_) => {
+                debug!("udp join queue is full, ignoring hello from {}", addr);
+            }
+        }
+    }
+
+    pub async fn receive_counters(&self, channel: &ChannelId) -> TransportCounters {
+        self.fast_update_map
+            .lock().await
+            .get(channel)
+            .map_or(TransportCounters::default(), |updates| updates.counters)
+    }
+
+    pub async fn check_for_fast_update(&self, channel: &ChannelId) -> Option<FastPacketLan> {
+        let mut fast_update_map = self.fast_update_map.lock().await;
+        let update = fast_update_map.get_mut(channel);
         if let Some(updates) = update {
             let update = updates.pop();
             if let Some(update) = update {
@@ -337,47 +1065,120 @@ impl LanListener {
 }
 
 impl LanConnection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         uuid: ConnectionUUID,
+        channel: ChannelId,
+        identity: Option<String>,
+        resume_token: Option<String>,
+        listener: LanListener,
         steady_update: Framed<TcpStream, LengthDelimitedCodec>,
-        peer_addr: SocketAddr
+        peer_addr: SocketAddr,
+        keys: Arc<ChannelKeys>
     ) -> Self {
-        let (steady_sender_to_client, steady_receiver_at_tcpthread) = mpsc::channel(100);
-        let (steady_sender_at_tcpthread, steady_receiver_from_tcpthread) = mpsc::channel(100);
-        let the_self = Self {
-            steady_update: steady_sender_to_client,
-            steady_receiver_passthrough: Arc::new(Mutex::new(Some(steady_receiver_from_tcpthread))),
-            is_connected: Arc::new(AtomicBool::new(true)),
-            remote_addr: peer_addr,
+        let (the_self, sender, receiver) = Self::with_queues(
             uuid,
-            last_successful_ping: Arc::new(AtomicU64::new(0)),
-        };
+            channel,
+            identity,
+            resume_token,
+            listener,
+            peer_addr,
+            keys,
+            true
+        );
         let the_clone = the_self.clone();
         tokio::spawn(async move {
-            the_clone.tcp_thread(
-                steady_sender_at_tcpthread,
-                steady_receiver_at_tcpthread,
-                steady_update
-            ).await;
+            the_clone.listener.register_conditioner(&the_clone.channel, the_clone.conditioner.clone()).await;
+            the_clone.listener.register_waker(&the_clone.channel, the_clone.arrived.clone()).await;
+            the_clone.tcp_thread(sender, receiver, steady_update).await;
         });
         the_self
     }
 
-    pub async fn serialise_and_send_fast(
-        &self,
-        to_uuid: ConnectionUUID,
+    pub async fn new_reliable(
+        uuid: ConnectionUUID,
+        channel: ChannelId,
+        identity: Option<String>,
+        resume_token: Option<String>,
         listener: LanListener,
-        packet: FastPacketData
-    ) -> std::io::Result<usize> {
-        let mut buffer = Vec::new();
-        let packet = FastPacketLan {
-            uuid: to_uuid,
-            socket_addr: None,
-            data: FastPacketPotentials::FastPacket(packet),
+        peer_addr: SocketAddr,
+        keys: Arc<ChannelKeys>
+    ) -> Self {
+        let segments = listener.register_reliable(&channel).await;
+        let (the_self, sender, receiver) = Self::with_queues(
+            uuid,
+            channel,
+            identity,
+            resume_token,
+            listener,
+            peer_addr,
+            keys,
+            false
+        );
+        let the_clone = the_self.clone();
+        tokio::spawn(async move {
+            the_clone.listener.register_conditioner(&the_clone.channel, the_clone.conditioner.clone()).await;
+            the_clone.listener.register_waker(&the_clone.channel, the_clone.arrived.clone()).await;
+            the_clone.reliable_thread(sender, receiver, segments).await;
+        });
+        the_self
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn with_queues(
+        uuid: ConnectionUUID,
+        channel: ChannelId,
+        identity: Option<String>,
+        resume_token: Option<String>,
+        listener: LanListener,
+        peer_addr: SocketAddr,
+        keys: Arc<ChannelKeys>,
+        condition_steady: bool
+    ) -> (Self, mpsc::Sender<SteadyPacketData>, mpsc::Receiver<SteadyPacketData>) {
+        let conditioner = LinkConditioner::default();
+        // tcp carries steady packets outside the conditioned datagrams, so delay them on the way
+        let (to_client, to_server) = if condition_steady {
+            (
+                conditioned_channel(100, &conditioner, LinkDirection::ToClient, true),
+                conditioned_channel(100, &conditioner, LinkDirection::ToServer, true),
+            )
+        } else {
+            (mpsc::channel(100), mpsc::channel(100))
         };
-        let mut serialiser = rmp_serde::Serializer::new(&mut buffer);
-        packet.serialize(&mut serialiser).unwrap();
-        listener.send_fast_update(self.clone(), &buffer).await
+        let (steady_sender_to_client, steady_receiver_at_thread) = to_client;
+        let (steady_sender_at_thread, steady_receiver_from_thread) = to_server;
+        let fast_batcher = Arc::new(Mutex::new(FastBatcher::new(channel.clone(), keys.clone())));
+        let arrived = Arc::new(Notify::new());
+        let steady_receiver_from_thread = wake_on_arrival(steady_receiver_from_thread, 100, arrived.clone());
+        let the_self = Self {
+            listener,
+            steady_update: steady_sender_to_client,
+            steady_receiver: Arc::new(Mutex::new(steady_receiver_from_thread)),
+            is_connected: Arc::new(AtomicBool::new(true)),
+            remote_addr: peer_addr,
+            uuid,
+            channel,
+            identity,
+            resume_token,
+            last_successful_ping: Arc::new(AtomicU64::new(unix_time())),
+            fast_batcher,
+            keys,
+            conditioner,
+            arrived,
+        };
+        (the_self, steady_sender_at_thread, steady_receiver_at_thread)
+    }
+
+    pub async fn serialise_and_send_fast(&self, packet: FastPacketData) {
+        self.fast_batcher.lock().await.push(packet);
+    }
+
+    pub async fn flush_fast(&self) -> std::io::Result<()> {
+        let datagrams = self.fast_batcher.lock().await.drain();
+        for datagram in datagrams {
+            self.listener.send_fast_update(self.clone(), &datagram).await?;
+        }
+        Ok(())
     }
 
     pub async fn serialise_and_send_steady(
@@ -387,11 +1188,8 @@ impl LanConnection {
         self.steady_update.send(packet).await
     }
 
-    pub async fn attempt_receive_fast_and_deserialise(
-        &self,
-        listener: LanListener
-    ) -> Option<FastPacketData> {
-        let last_fast_update_received = listener.check_for_fast_update(&self.uuid).await;
+    pub async fn attempt_receive_fast_and_deserialise(&self) -> Option<FastPacketData> {
+        let last_fast_update_received = self.listener.check_for_fast_update(&self.channel).await;
         if let Some(last_fast_update_received) = last_fast_update_received {
             if let FastPacketPotentials::FastPacket(packet) = last_fast_update_received.data {
                 return Some(packet);
@@ -401,11 +1199,10 @@ impl LanConnection {
     }
 
     pub async fn attempt_receive_steady_and_deserialise(
-        &self,
-        steady_receiver: &mut mpsc::Receiver<SteadyPacketData>
+        &self
     ) -> Result<Option<SteadyPacketData>, ConnectionError> {
-        let packet = steady_receiver.recv().await;
-        if let Some(packet) = packet {
+        let packet = self.steady_receiver.lock().await.try_recv();
+        if let Ok(packet) = packet {
             Ok(Some(packet))
         } else if !self.is_connected.load(Ordering::Relaxed) {
             Err(ConnectionError::ConnectionClosed)
@@ -425,6 +1222,13 @@ impl LanConnection {
                 attempt = StreamExt::next(&mut reader) => {
                     if let Some(packet) = attempt {
                         if let Ok(packet) = packet {
+                            let packet = match open_steady(&packet, &self.keys) {
+                                Some(packet) => packet,
+                                None => {
+                                    warn!("closing connection to {}, steady packet failed verification", self.remote_addr);
+                                    break;
+                                }
+                            };
                             let mut deserialiser = rmp_serde::Deserializer::new(&packet[..]);
                             let packet = SteadyPacketData::deserialize(&mut deserialiser);
                             if let Ok(packet) = packet {
@@ -434,8 +1238,6 @@ impl LanConnection {
                             }
                         }
                     } else {
-
-                        self.is_connected.store(false, Ordering::Relaxed);
                         break;
                     }
                 }
@@ -445,94 +1247,436 @@ impl LanConnection {
                         let mut buffer = Vec::new();
                         let mut serialiser = rmp_serde::Serializer::new(&mut buffer);
                         packet.serialize(&mut serialiser).unwrap();
-                        reader.send(Bytes::from(buffer)).await.unwrap();
+                        reader.send(seal_steady(&buffer, &self.keys)).await.unwrap();
+                    }
+                }
+            }
+        }
+        self.is_connected.store(false, Ordering::Relaxed);
+        self.listener.forget_keys(&self.channel).await;
+    }
+
+    pub async fn reliable_thread(
+        &self,
+        sender: mpsc::Sender<SteadyPacketData>,
+        mut receiver: mpsc::Receiver<SteadyPacketData>,
+        mut segments: mpsc::Receiver<ReliableSegment>
+    ) {
+        let mut channel = ReliableChannel::default();
+        let mut interval = tokio::time::interval(RELIABLE_TICK);
+        loop {
+            tokio::select! {
+                attempt = receiver.recv() => {
+                    if let Some(packet) = attempt {
+                        debug!("sending steady packet: {:?}", packet);
+                        let mut buffer = Vec::new();
+                        let mut serialiser = rmp_serde::Serializer::new(&mut buffer);
+                        packet.serialize(&mut serialiser).unwrap();
+                        channel.send(&buffer);
                     }
                 }
+                attempt = segments.recv() => {
+                    if let Some(segment) = attempt {
+                        channel.receive(segment);
+                    } else {
+                        break;
+                    }
+                }
+                _ = interval.tick() => {}
+            }
+
+            while let Some(payload) = channel.deliver() {
+                let mut deserialiser = rmp_serde::Deserializer::new(&payload[..]);
+                if let Ok(packet) = SteadyPacketData::deserialize(&mut deserialiser) {
+                    debug!("received steady packet: {:?}", packet);
+                    sender.send(packet).await.unwrap();
+                }
+            }
+            for segment in channel.poll() {
+                let segment = FastPacketPotentials::Reliable(segment);
+                let data = seal_lan_packet(&self.channel, segment, &self.keys);
+                if let Err(e) = self.listener.send_fast_update(self.clone(), &data).await {
+                    debug!("failed to send reliable segment: {:?}", e);
+                }
+            }
+            if channel.has_failed() {
+                warn!(
+                    "reliable channel to {} failed after {} retransmissions (rtt {:?})",
+                    self.remote_addr,
+                    channel.retransmissions(),
+                    channel.rtt()
+                );
+                break;
             }
         }
+        self.is_connected.store(false, Ordering::Relaxed);
+        self.listener.unregister_reliable(&self.channel).await;
+        self.listener.forget_keys(&self.channel).await;
+    }
+}
+
+impl Transport for LanConnection {
+    fn kind(&self) -> &'static str {
+        "LanConnection"
+    }
+
+    fn uuid(&self) -> ConnectionUUID {
+        self.uuid.clone()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.identity.clone()
+    }
+
+    fn resume_token(&self) -> Option<String> {
+        self.resume_token.clone()
+    }
+
+    fn remote_ip(&self) -> Option<IpAddr> {
+        Some(self.remote_addr.ip())
+    }
+
+    fn last_seen(&self) -> u64 {
+        self.last_successful_ping.load(Ordering::Relaxed)
+    }
+
+    fn mark_alive(&self) {
+        self.last_successful_ping.store(unix_time(), Ordering::Relaxed);
+    }
+
+    fn send_steady(&self, packet: SteadyPacketData) -> TransportFuture<'_, bool> {
+        Box::pin(async move { self.serialise_and_send_steady(packet).await.is_ok() })
+    }
+
+    fn send_fast(&self, packet: FastPacketData) -> TransportFuture<'_, bool> {
+        Box::pin(async move {
+            self.serialise_and_send_fast(packet).await;
+            true
+        })
+    }
+
+    fn receive_steady(
+        &self
+    ) -> TransportFuture<'_, Result<Option<SteadyPacketData>, ConnectionError>> {
+        Box::pin(self.attempt_receive_steady_and_deserialise())
+    }
+
+    fn receive_fast(&self) -> TransportFuture<'_, Result<Option<FastPacketData>, ConnectionError>> {
+        Box::pin(async move { Ok(self.attempt_receive_fast_and_deserialise().await) })
+    }
+
+    fn flush(&self) -> TransportFuture<'_, bool> {
+        Box::pin(async move { self.flush_fast().await.is_ok() })
+    }
+
+    fn counters(&self) -> TransportFuture<'_, Option<TransportCounters>> {
+        Box::pin(async move {
+            let sent = self.fast_batcher.lock().await.counters;
+            Some(sent.combined(&self.listener.receive_counters(&self.channel).await))
+        })
+    }
+
+    fn readable(&self) -> TransportFuture<'_, ()> {
+        Box::pin(self.arrived.notified())
     }
 }
 
 impl ClientLanConnection {
+    async fn read_handshake(
+        reader: &mut Framed<TcpStream, LengthDelimitedCodec>,
+        keys: Option<&ChannelKeys>
+    ) -> Result<ConnectionHandshakePacket, ClientConnectError> {
+        let buffer = StreamExt::next(reader).await
+            .ok_or(ClientConnectError::Io("server closed the connection".to_string()))?
+            .map_err(|e| ClientConnectError::Io(e.to_string()))?;
+        let plaintext = match keys {
+            Some(keys) => open_steady(&buffer, keys).ok_or(ClientConnectError::VerificationFailed)?,
+            None => buffer.to_vec(),
+        };
+        let mut deserialiser = rmp_serde::Deserializer::new(&plaintext[..]);
+        ConnectionHandshakePacket::deserialize(&mut deserialiser).map_err(
+            |_| ClientConnectError::UnexpectedPacket
+        )
+    }
+
+    async fn write_handshake(
+        reader: &mut Framed<TcpStream, LengthDelimitedCodec>,
+        packet: ConnectionHandshakePacket,
+        keys: Option<&ChannelKeys>
+    ) -> Result<(), ClientConnectError> {
+        let mut serialiser = rmp_serde::Serializer::new(Vec::new());
+        packet.serialize(&mut serialiser).unwrap();
+        let data = serialiser.into_inner();
+        let frame = match keys {
+            Some(keys) => seal_steady(&data, keys),
+            None => Bytes::from(data),
+        };
+        reader.send(frame).await.map_err(|e| ClientConnectError::Io(e.to_string()))
+    }
+
+    // nothing goes to the server, credentials least of all, until it has proven who it is
+    fn accept_key_exchange(
+        exchange: KeyExchange,
+        signed: &SignedKeyExchange,
+        trust: &ServerTrust
+    ) -> Result<ChannelKeys, ClientConnectError> {
+        if !signed.verify(&exchange.public_key()) {
+            return Err(ClientConnectError::VerificationFailed);
+        }
+        trust.check(&signed.identity).map_err(|expected| ClientConnectError::UntrustedServer {
+            expected,
+            presented: fingerprint(&signed.identity),
+        })?;
+        exchange.complete(signed.key, Side::Client).ok_or(ClientConnectError::VerificationFailed)
+    }
+
+    async fn bind_udp(hostname: &str, udp_port: u16) -> Result<UdpSocket, ClientConnectError> {
+        let remote_addr: SocketAddr = format!("{}:{}", hostname, udp_port)
+            .parse()
+            .map_err(|e: std::net::AddrParseError| ClientConnectError::Io(e.to_string()))?;
+        let local_addr: SocketAddr = (
+            if remote_addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            }
+        )
+            .parse()
+            .unwrap();
+        let socket = UdpSocket::bind(local_addr).await.map_err(|e|
+            ClientConnectError::Io(e.to_string())
+        )?;
+        socket.connect(remote_addr).await.map_err(|e| ClientConnectError::Io(e.to_string()))?;
+        debug!("connected to udp");
+        Ok(socket)
+    }
+
+    pub async fn connect_udp(
+        hostname: &str,
+        udp_port: u16,
+        trust: &ServerTrust,
+        credentials: Option<Credentials>,
+        resume_token: Option<String>
+    ) -> Result<
+        (Self, mpsc::Receiver<SteadyPacketData>, mpsc::Receiver<ReliableSegment>),
+        ClientConnectError
+    > {
+        let socket = Self::bind_udp(hostname, udp_port).await?;
+        let handshake_id = generate_uuid();
+        let mut exchange = Some(KeyExchange::new());
+        let hello = serialise_lan_packet(
+            &handshake_id,
+            FastPacketPotentials::ConnectionHandshake(
+                ConnectionHandshakePacket::Hello(
+                    ProtocolInfo::current(),
+                    exchange.as_ref().unwrap().public_key()
+                )
+            )
+        );
+
+        const TIMEOUT_SECS: u64 = 20;
+        const RETRY_MILLIS: u64 = 500;
+        let starting_time = Instant::now();
+        let mut keys: Option<Arc<ChannelKeys>> = None;
+        let mut server_identity = None;
+        let mut authenticate: Option<Vec<u8>> = None;
+        let mut session = None;
+        let mut buffer = [0; 2048];
+        socket.send(&hello).await.map_err(|e| ClientConnectError::Io(e.to_string()))?;
+        debug!("sent hello");
+        let (uuid, channel) = loop {
+            if starting_time.elapsed().as_secs() > TIMEOUT_SECS {
+                return Err(ClientConnectError::Io("timed out waiting for the server".to_string()));
+            }
+            let attempt = tokio::time::timeout(
+                Duration::from_millis(RETRY_MILLIS),
+                socket.recv(&mut buffer)
+            ).await;
+            let len = match attempt {
+                Ok(len) => len.map_err(|e| ClientConnectError::Io(e.to_string()))?,
+                Err(_) => {
+                    // the server only ever answers, so whatever we last said has to be repeated
+                    let packet = authenticate.as_ref().unwrap_or(&hello);
+                    socket.send(packet).await.map_err(|e| ClientConnectError::Io(e.to_string()))?;
+                    debug!("resent handshake");
+                    continue;
+                }
+            };
+            let mut deserialiser = rmp_serde::Deserializer::new(&buffer[..len]);
+            let packet = match FastPacketLan::deserialize(&mut deserialiser) {
+                Ok(packet) => packet,
+                Err(_) => {
+                    continue;
+                }
+            };
+            let (data, sealed) = match &packet.data {
+                FastPacketPotentials::Sealed(_) => {
+                    match keys.as_ref().and_then(|keys| open_lan_packet(packet, keys)) {
+                        Some(packet) => (packet.data, true),
+                        None => {
+                            continue;
+                        }
+                    }
+                }
+                _ => (packet.data, false),
+            };
+            match data {
+                FastPacketPotentials::ConnectionHandshake(
+                    ConnectionHandshakePacket::KeyExchange(signed),
+                ) if !sealed => {
+                    if let Some(exchange) = exchange.take() {
+                        let channel_keys = Self::accept_key_exchange(exchange, &signed, trust)?;
+                        keys = Some(Arc::new(channel_keys));
+                        server_identity = Some(signed.identity);
+                    }
+                    // a repeated key exchange means our credentials got lost
+                    let packet = authenticate.get_or_insert_with(|| {
+                        seal_lan_packet(
+                            &handshake_id,
+                            FastPacketPotentials::ConnectionHandshake(
+                                ConnectionHandshakePacket::Authenticate(credentials.clone(), resume_token.clone())
+                            ),
+                            keys.as_ref().unwrap()
+                        )
+                    });
+                    socket
+                        .send(packet).await
+                        .map_err(|e| ClientConnectError::Io(e.to_string()))?;
+                }
+                FastPacketPotentials::ConnectionHandshake(
+                    ConnectionHandshakePacket::Rejected(reason),
+                ) if sealed || keys.is_none() => {
+                    return Err(ClientConnectError::Rejected(reason));
+                }
+                FastPacketPotentials::ConnectionHandshake(
+                    ConnectionHandshakePacket::Accepted(server_info, grant),
+                ) if sealed => {
+                    server_info.check_compatible().map_err(ClientConnectError::IncompatibleServer)?;
+                    session = Some(grant);
+                }
+                FastPacketPotentials::ConnectionHandshake(
+                    ConnectionHandshakePacket::PleaseConnectUDPNow(uuid, channel),
+                ) if sealed && session.is_some() => {
+                    break (uuid, channel);
+                }
+                _ => {}
+            }
+        };
+        let keys = keys.unwrap();
+        debug!("our uuid is {}", uuid);
+        socket
+            .send(&Self::connected_packet(&channel, &keys)).await
+            .map_err(|e| ClientConnectError::Io(e.to_string()))?;
+        debug!("told the server we're ready to receive udp");
+
+        let (sender, receiver) = mpsc::channel(100);
+        let (segment_sender, segment_receiver) = mpsc::channel(RELIABLE_QUEUE_LIMIT);
+
+        Ok((
+            ClientLanConnection {
+                fast_update: Arc::new(socket),
+                fast_update_queue: Arc::new(
+                    Mutex::new(FastUpdateQueue::<FastPacketData>::new(None))
+                ),
+                steady_sender_queue: sender,
+                steady_receiver_queue: Arc::new(Mutex::new(SteadyMessageQueue::new())),
+                reliable_segments: Some(segment_sender),
+                fast_batcher: Arc::new(Mutex::new(FastBatcher::new(channel.clone(), keys.clone()))),
+                keys,
+                uuid,
+                channel,
+                server_identity: server_identity.unwrap(),
+                session: session.flatten(),
+            },
+            receiver,
+            segment_receiver,
+        ))
+    }
+
+    fn connected_packet(channel: &ChannelId, keys: &ChannelKeys) -> Vec<u8> {
+        seal_lan_packet(
+            channel,
+            FastPacketPotentials::ConnectionHandshake(
+                ConnectionHandshakePacket::IconnectedUDP(channel.clone())
+            ),
+            keys
+        )
+    }
+
     pub async fn connect(
         hostname: &str,
         tcp_port: u16,
-        udp_port: u16
-    ) -> Option<(Self, Framed<TcpStream, LengthDelimitedCodec>, mpsc::Receiver<SteadyPacketData>)> {
-        let stream = TcpStream::connect(format!("{}:{}", hostname, tcp_port)).await.ok()?;
+        udp_port: u16,
+        trust: &ServerTrust,
+        credentials: Option<Credentials>,
+        resume_token: Option<String>
+    ) -> Result<
+        (Self, Framed<TcpStream, LengthDelimitedCodec>, mpsc::Receiver<SteadyPacketData>),
+        ClientConnectError
+    > {
+        let stream = TcpStream::connect(format!("{}:{}", hostname, tcp_port)).await.map_err(|e|
+            ClientConnectError::Io(e.to_string())
+        )?;
         let mut reader = Framed::new(stream, LengthDelimitedCodec::new());
         debug!("connected to server");
-        let mut serialiser = rmp_serde::Serializer::new(Vec::new());
-        let packet = ConnectionHandshakePacket::JoinRequest;
-        packet.serialize(&mut serialiser).unwrap();
-        let data = serialiser.into_inner();
-        reader.send(Bytes::from(data)).await.ok()?;
+        let exchange = KeyExchange::new();
+        let packet = ConnectionHandshakePacket::Hello(ProtocolInfo::current(), exchange.public_key());
+        Self::write_handshake(&mut reader, packet, None).await?;
+        debug!("sent hello");
+        let (keys, server_identity) = match Self::read_handshake(&mut reader, None).await? {
+            ConnectionHandshakePacket::KeyExchange(signed) => {
+                let keys = Self::accept_key_exchange(exchange, &signed, trust)?;
+                (Arc::new(keys), signed.identity)
+            }
+            ConnectionHandshakePacket::Rejected(reason) => {
+                return Err(ClientConnectError::Rejected(reason));
+            }
+            _ => {
+                return Err(ClientConnectError::UnexpectedPacket);
+            }
+        };
+        let packet = ConnectionHandshakePacket::Authenticate(credentials, resume_token);
+        Self::write_handshake(&mut reader, packet, Some(&keys)).await?;
         debug!("sent join request");
-        let buffer = StreamExt::next(&mut reader).await
-            .expect("failed to read result from server")
-            .expect("error reading result from server");
-        let mut deserialiser = rmp_serde::Deserializer::new(buffer.as_ref());
-        let packet = ConnectionHandshakePacket::deserialize(&mut deserialiser).unwrap();
-        if let ConnectionHandshakePacket::PleaseConnectUDPNow(uuid) = packet {
+        let session = match Self::read_handshake(&mut reader, Some(&keys)).await? {
+            ConnectionHandshakePacket::Accepted(server_info, session) => {
+                server_info.check_compatible().map_err(ClientConnectError::IncompatibleServer)?;
+                session
+            }
+            ConnectionHandshakePacket::Rejected(reason) => {
+                return Err(ClientConnectError::Rejected(reason));
+            }
+            _ => {
+                return Err(ClientConnectError::UnexpectedPacket);
+            }
+        };
+        let packet = Self::read_handshake(&mut reader, Some(&keys)).await?;
+        if let ConnectionHandshakePacket::PleaseConnectUDPNow(uuid, channel) = packet {
             debug!("received join response");
             debug!("our uuid is {}", uuid);
-            let remote_addr: SocketAddr = format!("{}:{}", hostname, udp_port).parse().unwrap();
-            let local_addr: SocketAddr = (
-                if remote_addr.is_ipv4() {
-                    "0.0.0.0:0"
-                } else {
-                    "[::]:0"
-                }
-            )
-                .parse()
-                .unwrap();
-            let socket = UdpSocket::bind(local_addr).await.ok()?;
-            socket.connect(remote_addr).await.ok()?;
-            debug!("connected to udp");
-
-            let packet = FastPacketLan {
-                uuid: uuid.clone(),
-                socket_addr: Some(socket.local_addr().unwrap()),
-                data: FastPacketPotentials::ConnectionHandshake(
-                    ConnectionHandshakePacket::IconnectedUDP(uuid.clone())
-                ),
-            };
-            let mut serialiser = rmp_serde::Serializer::new(Vec::new());
-            packet.serialize(&mut serialiser).unwrap();
-            let data = serialiser.into_inner();
+            let socket = Self::bind_udp(hostname, udp_port).await?;
+
+            let data = Self::connected_packet(&channel, &keys);
             debug!("told the server we're ready to receive udp");
-            socket.send(&data.clone()).await.ok()?;
+            socket.send(&data).await.map_err(|e| ClientConnectError::Io(e.to_string()))?;
 
             loop {
-                let buffer = StreamExt::next(&mut reader).await
-                    .expect("failed to read result from server")
-                    .expect("error reading result from server");
-                let mut deserialiser = rmp_serde::Deserializer::new(buffer.as_ref());
-                let packet = ConnectionHandshakePacket::deserialize(&mut deserialiser).unwrap();
-                if let ConnectionHandshakePacket::YoureReady(_) = packet {
+                let packet = Self::read_handshake(&mut reader, Some(&keys)).await?;
+                if let ConnectionHandshakePacket::YoureReady(..) = packet {
                     debug!("received YoureReady packet");
                     break;
                 }
 
-                if let ConnectionHandshakePacket::PleaseConnectUDPNow(_) = packet {
-                    let packet = FastPacketLan {
-                        uuid: uuid.clone(),
-                        socket_addr: Some(socket.local_addr().unwrap()),
-                        data: FastPacketPotentials::ConnectionHandshake(
-                            ConnectionHandshakePacket::IconnectedUDP(uuid.clone())
-                        ),
-                    };
-                    let mut serialiser = rmp_serde::Serializer::new(Vec::new());
-                    packet.serialize(&mut serialiser).unwrap();
-                    let data = serialiser.into_inner();
+                if let ConnectionHandshakePacket::PleaseConnectUDPNow(..) = packet {
+                    let data = Self::connected_packet(&channel, &keys);
                     debug!("told the server we're ready to receive udp (again)");
-                    socket.send(&data.clone()).await.ok()?;
+                    socket.send(&data).await.map_err(|e| ClientConnectError::Io(e.to_string()))?;
                 }
             }
 
             let (sender, receiver) = mpsc::channel(100);
 
-            return Some((
+            return Ok((
                 ClientLanConnection {
                     fast_update: Arc::new(socket),
                     fast_update_queue: Arc::new(
@@ -540,14 +1684,25 @@ impl ClientLanConnection {
                     ),
                     steady_sender_queue: sender,
                     steady_receiver_queue: Arc::new(Mutex::new(SteadyMessageQueue::new())),
+                    reliable_segments: None,
+                    fast_batcher: Arc::new(
+                        Mutex::new(FastBatcher::new(channel.clone(), keys.clone()))
+                    ),
+                    keys,
                     uuid,
+                    channel,
+                    server_identity,
+                    session,
                 },
                 reader,
                 receiver,
             ));
         }
 
-        None
+        if let ConnectionHandshakePacket::Rejected(reason) = packet {
+            return Err(ClientConnectError::Rejected(reason));
+        }
+        Err(ClientConnectError::UnexpectedPacket)
     }
 
     async fn send_fast_update(&self, data: &[u8]) -> std::io::Result<usize> {
@@ -558,20 +1713,25 @@ impl ClientLanConnection {
         self.fast_update.recv(data).await
     }
 
-    pub async fn send_fast_and_serialise(&self, packet: FastPacketData) -> std::io::Result<usize> {
-        let mut serialiser = rmp_serde::Serializer::new(Vec::new());
-        let packet = FastPacketLan {
-            uuid: self.uuid.clone(),
-            socket_addr: None,
-            data: FastPacketPotentials::FastPacket(packet),
-        };
-        packet.serialize(&mut serialiser).unwrap();
-        let data = serialiser.into_inner();
-        self.send_fast_update(&data).await
+    pub async fn send_fast_and_serialise(&self, packet: FastPacketData) {
+        self.fast_batcher.lock().await.push(packet);
+    }
+
+    pub async fn flush_fast(&self) -> std::io::Result<()> {
+        let datagrams = self.fast_batcher.lock().await.drain();
+        for datagram in datagrams {
+            self.send_fast_update(&datagram).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn counters(&self) -> TransportCounters {
+        let sent = self.fast_batcher.lock().await.counters;
+        sent.combined(&self.fast_update_queue.lock().await.counters)
     }
 
     pub async fn udp_listener_thread(&self) {
-        let mut buffer = [0; 2048];
+        let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
         loop {
             let attempt = self.block_receive_fast_update(&mut buffer).await;
             if attempt.is_err() {
@@ -583,11 +1743,103 @@ impl ClientLanConnection {
             let packet = FastPacketLan::deserialize(&mut deserialiser);
             if packet.is_err() {
                 warn!("failed to deserialise fast update: {:?}", packet);
+                self.fast_update_queue.lock().await.counters.truncated += 1;
                 continue;
             }
-            let packet = packet.unwrap();
-            if let FastPacketPotentials::FastPacket(packet) = packet.data {
-                self.fast_update_queue.lock().await.push(packet);
+            let packet = match open_lan_packet(packet.unwrap(), &self.keys) {
+                Some(packet) => packet,
+                None => {
+                    debug!("dropping unsealed or forged fast update");
+                    self.fast_update_queue.lock().await.counters.rejected += 1;
+                    continue;
+                }
+            };
+            match packet.data {
+                FastPacketPotentials::FastPacket(packet) => {
+                    let mut fast_update_queue = self.fast_update_queue.lock().await;
+                    fast_update_queue.record_datagram(None);
+                    fast_update_queue.push(packet);
+                }
+                FastPacketPotentials::Batch(batch) => {
+                    let mut fast_update_queue = self.fast_update_queue.lock().await;
+                    fast_update_queue.record_datagram(Some(batch.sequence));
+                    for packet in batch.packets {
+                        fast_update_queue.push(packet);
+                    }
+                }
+                FastPacketPotentials::Reliable(segment) => {
+                    if let Some(segments) = &self.reliable_segments {
+                        if segments.try_send(segment).is_err() {
+                            debug!("reliable queue is full, dropping segment");
+                        }
+                    }
+                }
+                FastPacketPotentials::ConnectionHandshake(
+                    ConnectionHandshakePacket::PleaseConnectUDPNow(..),
+                ) => {
+                    // the server never heard our confirmation and is still asking
+                    let data = Self::connected_packet(&self.channel, &self.keys);
+                    if let Err(e) = self.send_fast_update(&data).await {
+                        warn!("failed to confirm connection: {:?}", e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn reliable_thread(
+        &self,
+        mut receiver: mpsc::Receiver<SteadyPacketData>,
+        mut segments: mpsc::Receiver<ReliableSegment>
+    ) {
+        let mut channel = ReliableChannel::default();
+        let mut interval = tokio::time::interval(RELIABLE_TICK);
+        loop {
+            tokio::select! {
+                attempt = receiver.recv() => {
+                    if let Some(attempt) = attempt {
+                        debug!("sending steady update: {:?}", attempt);
+                        let mut buffer = Vec::new();
+                        let mut serialiser = rmp_serde::Serializer::new(&mut buffer);
+                        attempt.serialize(&mut serialiser).unwrap();
+                        channel.send(&buffer);
+                    }
+                }
+                attempt = segments.recv() => {
+                    if let Some(segment) = attempt {
+                        channel.receive(segment);
+                    } else {
+                        break;
+                    }
+                }
+                _ = interval.tick() => {}
+            }
+
+            while let Some(payload) = channel.deliver() {
+                let mut deserialiser = rmp_serde::Deserializer::new(&payload[..]);
+                match SteadyPacketData::deserialize(&mut deserialiser) {
+                    Ok(packet) => self.steady_receiver_queue.lock().await.push(packet),
+                    Err(e) => warn!("failed to deserialise steady update: {:?}", e),
+                }
+            }
+            for segment in channel.poll() {
+                let data = seal_lan_packet(
+                    &self.channel,
+                    FastPacketPotentials::Reliable(segment),
+                    &self.keys
+                );
+                if let Err(e) = self.send_fast_update(&data).await {
+                    warn!("failed to send steady update: {:?}", e);
+                }
+            }
+            if channel.has_failed() {
+                error!(
+                    "connection closed, reliable channel failed after {} retransmissions (rtt {:?})",
+                    channel.retransmissions(),
+                    channel.rtt()
+                );
+                break;
             }
         }
     }
@@ -602,14 +1854,19 @@ impl ClientLanConnection {
                 attempt = StreamExt::next(&mut reader) => {
                     if let Some(attempt) = attempt {
                         if let Ok(attempt) = attempt {
-                            let mut deserialiser = rmp_serde::Deserializer::new(attempt.as_ref());
+                            let plaintext = match open_steady(&attempt, &self.keys) {
+                                Some(plaintext) => plaintext,
+                                None => {
+                                    error!("connection closed, steady update failed verification");
+                                    break;
+                                }
+                            };
+                            let mut deserialiser = rmp_serde::Deserializer::new(&plaintext[..]);
                             let packet = SteadyPacketData::deserialize(&mut deserialiser);
                             if packet.is_err() {
                                 warn!("failed to deserialise steady update: {:?}", packet);
                             } else {
-                                let packet = packet.unwrap();
-                                let uuid = packet.uuid.clone();
-                                self.steady_receiver_queue.lock().await.push(packet);
+                                self.steady_receiver_queue.lock().await.push(packet.unwrap());
                             }
                         }
                     } else {
@@ -624,7 +1881,7 @@ impl ClientLanConnection {
                         let mut buffer = Vec::new();
                         let mut serialiser = rmp_serde::Serializer::new(&mut buffer);
                         attempt.serialize(&mut serialiser).unwrap();
-                        let attempt = reader.send(Bytes::from(buffer)).await;
+                        let attempt = reader.send(seal_steady(&buffer, &self.keys)).await;
                         if attempt.is_err() {
                             warn!("failed to send steady update: {:?}", attempt);
                         }
@@ -664,3 +1921,97 @@ impl ClientLanConnection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel_pair() -> (Arc<ChannelKeys>, ChannelKeys) {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_key = client.public_key();
+        let server_key = server.public_key();
+        (
+            Arc::new(server.complete(client_key, Side::Server).unwrap()),
+            client.complete(server_key, Side::Client).unwrap(),
+        )
+    }
+
+    fn jump(size: usize) -> FastPacketData {
+        FastPacketData {
+            packet: Some(FastPacket::PlayerJump("x".repeat(size))),
+        }
+    }
+
+    fn open_batch(datagram: &[u8], keys: &ChannelKeys) -> FastBatch {
+        let packet = rmp_serde::from_slice::<FastPacketLan>(datagram).unwrap();
+        match open_lan_packet(packet, keys).unwrap().data {
+            FastPacketPotentials::Batch(batch) => batch,
+            other => panic!("expected a batch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn batches_never_go_over_the_datagram_size() {
+        let (keys, peer) = channel_pair();
+        let mut batcher = FastBatcher::new("channel".to_string(), keys);
+        for i in 0..MAX_PENDING_FAST_PACKETS {
+            batcher.push(jump((i * 37) % 400));
+        }
+        let datagrams = batcher.drain();
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= MAX_DATAGRAM_SIZE));
+
+        let batches = datagrams
+            .iter()
+            .map(|datagram| open_batch(datagram, &peer))
+            .collect::<Vec<_>>();
+        let sequences = batches.iter().map(|batch| batch.sequence).collect::<Vec<_>>();
+        assert_eq!(sequences, (0..datagrams.len() as u32).collect::<Vec<_>>());
+        let delivered = batches.iter().map(|batch| batch.packets.len()).sum::<usize>();
+        assert_eq!(delivered, MAX_PENDING_FAST_PACKETS);
+        assert_eq!(batcher.counters.packets_sent as usize, MAX_PENDING_FAST_PACKETS);
+        assert_eq!(batcher.counters.datagrams_sent as usize, datagrams.len());
+        assert_eq!(batcher.counters.dropped, 0);
+        assert!(batcher.drain().is_empty());
+    }
+
+    #[test]
+    fn packets_too_big_for_any_datagram_are_dropped() {
+        let (keys, peer) = channel_pair();
+        let mut batcher = FastBatcher::new("channel".to_string(), keys);
+        batcher.push(jump(10));
+        batcher.push(jump(MAX_OVERSIZED_DATAGRAM_SIZE));
+        batcher.push(jump(4000));
+        batcher.push(jump(10));
+        let datagrams = batcher.drain();
+        assert_eq!(batcher.counters.dropped, 1);
+        // too big to batch but small enough for ip to fragment, so it goes alone
+        assert_eq!(batcher.counters.oversized, 1);
+        assert_eq!(datagrams.len(), 2);
+        assert!(datagrams[0].len() > MAX_DATAGRAM_SIZE);
+        assert_eq!(open_batch(&datagrams[0], &peer).packets.len(), 1);
+        assert_eq!(open_batch(&datagrams[1], &peer).packets.len(), 2);
+
+        for _ in 0..MAX_PENDING_FAST_PACKETS + 3 {
+            batcher.push(jump(10));
+        }
+        assert_eq!(batcher.counters.dropped, 4);
+    }
+
+    #[test]
+    fn full_queues_drop_the_oldest_packet() {
+        let mut queue = FastUpdateQueue::new(None);
+        for i in 0..FAST_QUEUE_LIMIT + 2 {
+            queue.push(i);
+        }
+        assert_eq!(queue.counters.dropped, 2);
+        assert_eq!(queue.pop(), Some(2));
+
+        queue.record_datagram(Some(0));
+        queue.record_datagram(Some(3));
+        queue.record_datagram(Some(1));
+        assert_eq!(queue.counters.datagrams_received, 3);
+        assert_eq!(queue.counters.lost, 2);
+    }
+}
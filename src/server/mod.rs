@@ -1,9 +1,26 @@
 use crate::physics::PhysicsSystem;
+use crate::server::bots::BotHandle;
+use crate::server::conditioner::{conditioned_channel, LinkConditioner, LinkDirection};
+use crate::server::config::ServerConfig;
 use crate::server::connections::SteadyMessageQueue;
-use crate::server::lan::{ClientLanConnection, LanConnection, LanListener};
-use crate::server::server_player::{ServerPlayer, ServerPlayerContainer};
+use crate::server::disconnect::{DetachedPlayer, DisconnectReason};
+use crate::server::identity::{ServerIdentity, SERVER_IDENTITY_FILE};
+use crate::server::interest::is_interest_managed;
+use crate::server::accounts::Authenticator;
+use crate::server::lan::{ClientLanConnection, LanListener, PendingLanConnection};
+use crate::server::moderation::{BanList, Moderation, BAN_LIST_FILE};
+use crate::server::ratelimit::{
+    flooding_kick, Offences, PacketKind, RateLimiter, Verdict,
+};
+use crate::server::replication::{ReplicationState, SnapshotDelta};
+use crate::server::savegame::{PlayerSave, PlayerSaveOwner};
+use crate::server::server_player::{InputOutcome, ServerPlayer, ServerPlayerContainer};
+use crate::server::tick::TickStats;
+use crate::server::transport::{wake_on_arrival, Connection};
+use crate::server::websocket::{ClientWebSocketConnection, WebSocketListener};
 use crate::worldmachine::ecs::{ComponentType, Entity, ParameterValue};
-use crate::worldmachine::player::{MovementInfo, PlayerComponent};
+use crate::worldmachine::player::PlayerComponent;
+use crate::worldmachine::prediction::{InputAck, InputCommand};
 use crate::worldmachine::throwballs::ThrowingBall;
 use crate::worldmachine::{EntityId, WorldMachine, WorldUpdate};
 use async_recursion::async_recursion;
@@ -12,64 +29,66 @@ use halfbrown::HashMap;
 use mutex_timeouts::tokio::MutexWithTimeoutAuto as Mutex;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
-use std::fmt::{Debug, Formatter};
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc, watch};
-use tokio::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Notify};
 use tokio_util::codec::Encoder;
 
+pub mod accounts;
+pub mod admin;
+pub mod bots;
+pub mod conditioner;
+pub mod config;
 pub mod connections;
+pub mod console;
+pub mod crypto;
+pub mod disconnect;
+pub mod discovery;
+pub mod identity;
+pub mod interest;
 pub mod lan;
+pub mod moderation;
+pub mod ratelimit;
+pub mod reliable;
+pub mod replication;
+pub mod savegame;
 pub mod server_player;
+pub mod tick;
+pub mod transport;
+pub mod websocket;
 
 pub type PacketUUID = String;
 pub type ConnectionUUID = String;
 
-#[derive(Clone)]
-pub enum Connection {
-    Local(Arc<LocalConnection>),
-    Lan(LanListener, LanConnection),
-}
-
-impl Debug for Connection {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Connection::Local(_) => write!(f, "LocalConnection"),
-            Connection::Lan(_, _) => write!(f, "LanConnection"),
-        }
-    }
-}
-
 #[derive(Clone)]
 pub enum ConnectionClientside {
     Local(Arc<Mutex<LocalConnectionClientSide>>),
     Lan(ClientLanConnection),
+    WebSocket(ClientWebSocketConnection),
+}
+
+// where a client is connected, so it can come back after losing the connection
+#[derive(Clone, Debug)]
+pub enum ServerAddress {
+    Lan {
+        ip: String,
+        tcp_port: u16,
+        udp_port: u16,
+        reliable_udp: bool,
+    },
+    WebSocket(String),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum FastPacket {
-    ChangePosition(EntityId, Vec3),
-    ChangeRotation(EntityId, Quaternion),
-    ChangeScale(EntityId, Vec3),
-    PlayerMoved(EntityId, Vec3, Quaternion, Quaternion),
+    Snapshot(SnapshotDelta),
+    SnapshotAck(u64),
     EntitySetParameter(EntityId, ComponentType, String, ParameterValue),
-    PlayerMove(
-        ConnectionUUID,
-        Vec3,
-        Vec3,
-        Quaternion,
-        Quaternion,
-        Option<MovementInfo>,
-    ),
+    PlayerInput(ConnectionUUID, Vec<InputCommand>),
+    PlayerInputAck(InputAck),
 
     PlayerJump(ConnectionUUID),
-    PlayerFuckYouMoveHere(Vec3),
-
-    PlayerCheckPosition(ConnectionUUID, Vec3),
-
     PlayerFuckYouSetRotation(Quaternion),
 }
 
@@ -86,6 +105,7 @@ unsafe impl Sync for FastPacketData {}
 pub enum NameRejectionReason {
     IllegalWord,
     Taken,
+    BoundToAccount,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -103,6 +123,9 @@ pub enum SteadyPacket {
     ThrowThrowAballll(String, Vec3, Vec3),
 
     Ping,
+    Disconnect(DisconnectReason),
+    ResumeToken(String),
+    TickRate(u32),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -119,10 +142,14 @@ pub struct LocalConnection {
     steady_update_receiver: Arc<Mutex<mpsc::Receiver<SteadyPacketData>>>,
     clientside_steady_update_sender: mpsc::Sender<SteadyPacketData>,
     pub consume_receiver_queue: Arc<Mutex<SteadyMessageQueue>>,
+    pub conditioner: LinkConditioner,
     pub uuid: ConnectionUUID,
+    host: bool,
+    arrived: Arc<Notify>,
 }
 
 pub struct LocalConnectionClientSide {
+    pub uuid: ConnectionUUID,
     pub fast_update_sender: mpsc::Sender<FastPacketData>,
     pub steady_update_sender: mpsc::Sender<SteadyPacketData>,
     pub steady_sender_queue: Arc<Mutex<SteadyMessageQueue>>,
@@ -130,17 +157,45 @@ pub struct LocalConnectionClientSide {
     pub steady_update_receiver: mpsc::Receiver<SteadyPacketData>,
 }
 
-#[derive(Clone)]
-pub enum Connections {
-    Local(Arc<Mutex<Vec<Arc<LocalConnection>>>>),
-    Lan(LanListener, Arc<Mutex<Vec<LanConnection>>>),
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PacketOutcome {
+    Idle,
+    Handled,
+    Disconnect(DisconnectReason),
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum PendingConnection {
+    Lan(LanListener, PendingLanConnection),
+    WebSocket(WebSocketListener, TcpStream),
+}
+
+// where our own listeners can be reached, for bots that join over the network
+#[derive(Clone, Debug, Default)]
+pub struct HostedListeners {
+    pub lan: Option<(String, u16, u16)>,
+    pub websocket: Option<(String, u16)>,
 }
 
 #[derive(Clone)]
 pub struct Server {
-    pub connections: Connections,
-    pub connections_incoming: Arc<Mutex<VecDeque<TcpStream>>>,
+    pub connections: Arc<Mutex<Vec<Connection>>>,
+    pub connections_incoming: Arc<Mutex<VecDeque<PendingConnection>>>,
+    pub authenticator: Arc<RwLock<Option<Arc<Authenticator>>>>,
     pub worldmachine: Arc<Mutex<WorldMachine>>,
+    pub bots: Arc<Mutex<Vec<BotHandle>>>,
+    pub pending_player_states: Arc<Mutex<HashMap<PlayerSaveOwner, PlayerSave>>>,
+    pub replication: Arc<Mutex<ReplicationState>>,
+    pub config: Arc<RwLock<ServerConfig>>,
+    pub offences: Arc<Mutex<Offences>>,
+    pub bans: Arc<RwLock<BanList>>,
+    pub moderation: Arc<Mutex<Moderation>>,
+    pub tick_stats: Arc<Mutex<TickStats>>,
+    pub detached: Arc<Mutex<HashMap<String, DetachedPlayer>>>,
+    pub listeners: Arc<RwLock<HostedListeners>>,
+    pub identity: Arc<ServerIdentity>,
+    pub running: Arc<AtomicBool>,
+    stopping: Arc<Notify>,
 }
 
 pub fn generate_uuid() -> PacketUUID {
@@ -153,17 +208,61 @@ impl Server {
         worldmachine.initialise(physics, true);
         worldmachine.load_map(map_name).expect("failed to load map");
 
+        Self::from_worldmachine(worldmachine)
+    }
+
+    fn from_worldmachine(mut worldmachine: WorldMachine) -> Self {
         worldmachine.players = Some(Arc::new(Mutex::new(HashMap::new())));
 
+        let bans = BanList::open(BAN_LIST_FILE).unwrap_or_else(|e| {
+            error!("failed to load {}, bans will not be saved: {:?}", BAN_LIST_FILE, e);
+            BanList::in_memory()
+        });
+        let identity = ServerIdentity::open(SERVER_IDENTITY_FILE).unwrap_or_else(|e| {
+            error!(
+                "failed to load {}, clients will see a new identity every restart: {}",
+                SERVER_IDENTITY_FILE, e
+            );
+            ServerIdentity::generate()
+        });
+
         info!("server started");
 
         Self {
-            connections: Connections::Local(Arc::new(Mutex::new(Vec::new()))),
+            connections: Arc::new(Mutex::new(Vec::new())),
             connections_incoming: Arc::new(Mutex::new(VecDeque::new())),
+            authenticator: Arc::new(RwLock::new(None)),
             worldmachine: Arc::new(Mutex::new(worldmachine)),
+            bots: Arc::new(Mutex::new(Vec::new())),
+            pending_player_states: Arc::new(Mutex::new(HashMap::new())),
+            replication: Arc::new(Mutex::new(ReplicationState::default())),
+            config: Arc::new(RwLock::new(ServerConfig::default())),
+            offences: Arc::new(Mutex::new(Offences::default())),
+            bans: Arc::new(RwLock::new(bans)),
+            moderation: Arc::new(Mutex::new(Moderation::load())),
+            tick_stats: Arc::new(Mutex::new(TickStats::default())),
+            detached: Arc::new(Mutex::new(HashMap::new())),
+            listeners: Arc::new(RwLock::new(HostedListeners::default())),
+            identity: Arc::new(identity),
+            running: Arc::new(AtomicBool::new(true)),
+            stopping: Arc::new(Notify::new()),
         }
     }
 
+    pub fn config(&self) -> ServerConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: ServerConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    pub fn shutdown(&self) {
+        info!("server shutting down");
+        self.running.store(false, Ordering::Relaxed);
+        self.stopping.notify_waiters();
+    }
+
     pub async fn new_host_lan_server(
         map_name: &str,
         physics: PhysicsSystem,
@@ -171,210 +270,219 @@ impl Server {
         udp_port: u16,
         hostname: &str,
     ) -> Self {
-        let mut worldmachine = WorldMachine::default();
-        worldmachine.initialise(physics, true);
-        worldmachine.load_map(map_name).expect("failed to load map");
-
-        worldmachine.players = Some(Arc::new(Mutex::new(HashMap::new())));
-
-        let listener = LanListener::new(hostname, tcp_port, udp_port).await;
+        let the_self = Self::new(map_name, physics);
+        the_self.host_lan(hostname, tcp_port, udp_port).await;
+        the_self
+    }
 
-        let the_self = Self {
-            connections: Connections::Lan(listener.clone(), Arc::new(Mutex::new(Vec::new()))),
-            connections_incoming: Arc::new(Mutex::new(VecDeque::new())),
-            worldmachine: Arc::new(Mutex::new(worldmachine)),
-        };
-        let the_clone = the_self.clone();
-        let listener_clone = listener;
+    pub async fn host_lan(&self, hostname: &str, tcp_port: u16, udp_port: u16) {
+        let listener = LanListener::new(
+            hostname,
+            tcp_port,
+            udp_port,
+            self.authenticator.clone(),
+            self.bans.clone(),
+            self.identity.clone(),
+        )
+        .await;
+        let the_clone = self.clone();
         tokio::spawn(async move {
             loop {
-                the_clone
-                    .connection_listening_thread(listener_clone.clone())
-                    .await;
+                let new_connection = listener.poll_new_connection().await;
+                if let Some(new_connection) = new_connection {
+                    the_clone
+                        .connections_incoming
+                        .lock()
+                        .await
+                        .push_back(PendingConnection::Lan(listener.clone(), new_connection));
+                }
             }
         });
+        self.listeners.write().unwrap().lan = Some((hostname.to_string(), tcp_port, udp_port));
+        info!(
+            "accepting lan connections on {}:{} as {}",
+            hostname,
+            tcp_port,
+            self.identity.fingerprint()
+        );
+    }
 
-        info!("server started");
+    pub async fn new_host_websocket_server(
+        map_name: &str,
+        physics: PhysicsSystem,
+        port: u16,
+        hostname: &str,
+    ) -> Self {
+        let the_self = Self::new(map_name, physics);
+        the_self.host_websocket(hostname, port).await;
         the_self
     }
 
-    async fn connection_listening_thread(&self, listener: LanListener) {
-        loop {
-            let new_connection = listener.poll_new_connection().await;
-            if let Some(new_connection) = new_connection {
-                self.connections_incoming
-                    .lock()
-                    .await
-                    .push_back(new_connection);
+    pub async fn host_websocket(&self, hostname: &str, port: u16) {
+        let listener = WebSocketListener::new(
+            hostname,
+            port,
+            self.authenticator.clone(),
+            self.bans.clone(),
+        )
+        .await;
+        let the_clone = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let new_connection = listener.poll_new_connection().await;
+                if let Some(new_connection) = new_connection {
+                    the_clone
+                        .connections_incoming
+                        .lock()
+                        .await
+                        .push_back(PendingConnection::WebSocket(listener.clone(), new_connection));
+                }
             }
-        }
+        });
+        self.listeners.write().unwrap().websocket = Some((hostname.to_string(), port));
+        info!("accepting websocket connections on {}:{}", hostname, port);
     }
 
-    pub async fn listen_for_lan_connections(&mut self) {
-        if let Connections::Lan(listener, _connections_raw) = self.connections.clone() {
-            let mut connections_incoming = self.connections_incoming.lock().await;
-            while let Some(connection) = connections_incoming.pop_front() {
-                let the_clone = self.clone();
-                let listener_clone = listener.clone();
-                tokio::spawn(async move {
-                    let connection = listener_clone.clone().init_new_connection(connection).await;
-                    if connection.is_none() {
-                        return;
-                    }
-                    let connection = connection.unwrap();
-                    let the_listener_clone = listener_clone.clone();
-                    the_clone
-                        .new_connection(Connection::Lan(
-                            the_listener_clone.clone(),
-                            connection.clone(),
-                        ))
-                        .await;
-                });
-                debug!("spawned new connection thread");
-            }
+    pub async fn listen_for_connections(&mut self) {
+        let mut connections_incoming = self.connections_incoming.lock().await;
+        while let Some(pending) = connections_incoming.pop_front() {
+            let the_clone = self.clone();
+            tokio::spawn(async move {
+                let connection: Option<Connection> = match pending {
+                    PendingConnection::Lan(listener, stream) => listener
+                        .init_new_connection(stream)
+                        .await
+                        .map(|connection| Arc::new(connection) as Connection),
+                    PendingConnection::WebSocket(listener, stream) => listener
+                        .init_new_connection(stream)
+                        .await
+                        .map(|connection| Arc::new(connection) as Connection),
+                };
+                if let Some(connection) = connection {
+                    the_clone.new_connection(connection).await;
+                }
+            });
+            debug!("spawned new connection thread");
         }
     }
 
-    async fn get_connection_uuid(&self, connection: &Connection) -> ConnectionUUID {
-        match connection {
-            Connection::Local(local_connection) => local_connection.uuid.clone(),
-            Connection::Lan(_, lan_connection) => lan_connection.uuid.clone(),
-        }
+    async fn send_steady_packet(&self, connection: &Connection, packet: SteadyPacket) -> bool {
+        let packet_data = SteadyPacketData {
+            packet,
+            uuid: generate_uuid(),
+        };
+        connection.send_steady(packet_data).await
     }
 
-    async unsafe fn send_steady_packet_unsafe(
-        &self,
-        connection_og: &Connection,
-        packet: SteadyPacketData,
-    ) -> bool {
-        match connection_og.clone() {
-            Connection::Local(connection) => {
-                let sus = connection.steady_update_sender.clone();
-                sus.send(packet.clone()).await.unwrap();
-                drop(sus);
-                debug!("sent packet to local connection");
-            }
-            Connection::Lan(_, connection) => {
-                let res = connection.serialise_and_send_steady(packet.clone()).await;
-                return res.is_ok();
-            }
+    async fn broadcast_steady_packet(&self, packet: SteadyPacket) {
+        for connection in self.get_all_connections().await {
+            self.send_steady_packet(&connection, packet.clone()).await;
         }
-        true
     }
 
-    async fn send_steady_packet(&self, connection: &Connection, packet: SteadyPacket) -> bool {
-        match connection.clone() {
-            Connection::Local(connection) => {
-                let uuid = generate_uuid();
-                let packet_data = SteadyPacketData {
-                    packet,
-                    uuid: uuid.clone(),
-                };
-                unsafe {
-                    self.send_steady_packet_unsafe(&Connection::Local(connection), packet_data)
-                        .await
-                }
-            }
-            Connection::Lan(listener, connection) => {
-                let uuid = generate_uuid();
-                let packet_data = SteadyPacketData {
-                    packet,
-                    uuid: uuid.clone(),
-                };
-                unsafe {
-                    self.send_steady_packet_unsafe(
-                        &Connection::Lan(listener, connection),
-                        packet_data,
-                    )
-                    .await
-                }
+    async fn flush_connections(&self) {
+        for connection in self.get_all_connections().await {
+            if !connection.flush().await {
+                debug!("failed to flush fast packets to {:?}", connection);
             }
         }
     }
 
     pub async fn send_fast_packet(&self, connection: &Connection, packet: FastPacket) {
-        match connection.clone() {
-            Connection::Local(connection) => {
-                let fus = connection.fast_update_sender.clone();
-                let packet_data = FastPacketData {
-                    packet: Some(packet),
-                };
-                fus.send(packet_data).await.unwrap();
-            }
-            Connection::Lan(listener, connection) => {
-                let packet_data = FastPacketData {
-                    packet: Some(packet),
-                };
-                connection
-                    .serialise_and_send_fast(connection.uuid.clone(), listener.clone(), packet_data)
-                    .await
-                    .unwrap();
-            }
+        let packet_data = FastPacketData {
+            packet: Some(packet),
+        };
+        if !connection.send_fast(packet_data).await {
+            debug!("failed to send fast packet to {:?}", connection);
         }
     }
 
     pub async fn try_receive_fast_packet(&mut self, connection: &Connection) -> Option<FastPacket> {
-        match connection.clone() {
-            Connection::Local(connection) => {
-                let mut fur = connection.fast_update_receiver.lock().await;
-                if let Ok(packet) = fur.try_recv() {
-                    return Some(packet.packet.unwrap());
-                } else {
-                    return None;
-                }
-            }
-            Connection::Lan(listener, connection) => {
-                let packet = connection
-                    .attempt_receive_fast_and_deserialise(listener)
-                    .await;
-                if let Some(packet) = packet {
-                    return Some(packet.packet.unwrap());
-                }
-            }
+        match connection.receive_fast().await {
+            Ok(Some(packet)) => packet.packet,
+            _ => None,
         }
-        None
     }
 
-    pub async fn begin_connection(&self, connection: Connection) -> Option<ServerPlayerContainer> {
-        let worldmachine = self.worldmachine.lock().await;
-
-        let world_clone = worldmachine.world.clone();
-        let physics = worldmachine.physics.lock().unwrap().clone().unwrap();
-
-        drop(worldmachine);
+    async fn send_initial_entities(&self, connection: &Connection) -> bool {
+        let world_clone = self.worldmachine.lock().await.world.clone();
         for entity in world_clone.entities.iter() {
+            if is_interest_managed(entity) {
+                continue;
+            }
             let res = self
                 .send_steady_packet(
-                    &connection,
+                    connection,
                     SteadyPacket::InitialiseEntity(entity.uid, entity.clone()),
                 )
                 .await;
             if !res {
-                return None;
+                return false;
             }
         }
         debug!("sent all entity initialise packets");
-        let uuid = self.get_connection_uuid(&connection).await;
+        true
+    }
+
+    pub async fn begin_connection(&self, connection: Connection) -> Option<ServerPlayerContainer> {
+        let physics = self
+            .worldmachine
+            .lock()
+            .await
+            .physics
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap();
 
-        let name = "hardcoded muten";
+        if !self.send_initial_entities(&connection).await {
+            return None;
+        }
+        let uuid = connection.uuid();
 
-        let position = Vec3::new(0.0, 2.0, 0.0);
-        let rotation = Quaternion::identity();
+        let identity = connection.identity();
+        let saved_state = match (&identity, connection.is_host()) {
+            (Some(identity), _) => {
+                let owner = PlayerSaveOwner::Account(identity.clone());
+                self.pending_player_states.lock().await.remove(&owner)
+            }
+            (None, true) => self.pending_player_states.lock().await.remove(&PlayerSaveOwner::Host),
+            // players without an account can only claim theirs once they've taken a name
+            (None, false) => None,
+        };
+        let name = match (&identity, &saved_state) {
+            (Some(identity), _) => identity.as_str(),
+            (None, Some(saved_state)) => saved_state.name.as_str(),
+            (None, None) => "hardcoded muten",
+        };
+
+        let position = match &saved_state {
+            Some(saved_state) => saved_state.position,
+            None => Vec3::new(0.0, 2.0, 0.0),
+        };
+        let rotation = match &saved_state {
+            Some(saved_state) => saved_state.rotation,
+            None => Quaternion::identity(),
+        };
         let scale = Vec3::new(1.0, 1.0, 1.0);
 
         let mut player = ServerPlayer::new(uuid.as_str(), name, position, rotation, scale);
 
         player.init(physics.clone()).await;
+        if let Some(saved_state) = &saved_state {
+            let mut worldmachine = self.worldmachine.lock().await;
+            player.set_position(position, None, &mut worldmachine).await;
+            player
+                .set_head_rotation(saved_state.head_rotation, None, &mut worldmachine)
+                .await;
+            drop(worldmachine);
+        }
 
         let mut player_entity = Entity::new(player.name.lock().await.as_str());
         let entity_uuid = player_entity.uid;
         let player_component = PlayerComponent::new(name, uuid.clone(), position, rotation, scale);
         player_entity.add_component(player_component);
 
-        let mut worldmachine = self.worldmachine.lock().await;
-        worldmachine.world.entities.push(player_entity.clone());
-
-        drop(worldmachine);
         let res = self
             .send_steady_packet(
                 &connection,
@@ -433,91 +541,72 @@ impl Server {
             SteadyPacket::FinaliseMapLoad => {}
             SteadyPacket::RemoveEntity(_) => {}
             SteadyPacket::ChatMessage(_who_sent, message) => {
-                let who_sent = match player.connection.clone() {
-                    Connection::Local(local_connection) => local_connection.uuid.clone(),
-                    Connection::Lan(listener, connection) => connection.uuid.clone(),
-                };
-                let packet = SteadyPacket::ChatMessage(who_sent, message);
-                match &self.connections {
-                    Connections::Local(local_connections) => {
-                        let cons = local_connections.lock().await.clone();
-                        for connection in cons.iter() {
-                            self.send_steady_packet(
-                                &Connection::Local(connection.clone()),
-                                packet.clone(),
-                            )
-                            .await;
-                        }
-                    }
-                    Connections::Lan(listener, connections) => {
-                        let cons = connections.lock().await.clone();
-                        for a_connection in cons.iter() {
-                            self.send_steady_packet(
-                                &Connection::Lan(listener.clone(), a_connection.clone()),
-                                packet.clone(),
-                            )
-                            .await;
-                        }
-                    }
+                if self.chat_command(&player.connection, &message).await {
+                    return true;
                 }
+                let mut moderation = self.moderation.lock().await;
+                if moderation.is_muted(&player.connection) {
+                    drop(moderation);
+                    let message = "you are muted".to_string();
+                    self.send_steady_packet(&player.connection, SteadyPacket::Message(message))
+                        .await;
+                    return true;
+                }
+                let message = moderation.filter.censor(&message);
+                drop(moderation);
+                let who_sent = player.connection.uuid();
+                self.broadcast_steady_packet(SteadyPacket::ChatMessage(who_sent, message))
+                    .await;
             }
             SteadyPacket::SetName(_who_sent, new_name) => {
-                let who_sent = match player.connection.clone() {
-                    Connection::Local(local_connection) => local_connection.uuid.clone(),
-                    Connection::Lan(listener, connection) => connection.uuid.clone(),
-                };
-                let packet = SteadyPacket::SetName(who_sent, new_name.clone());
-                match &self.connections {
-                    Connections::Local(local_connections) => {
-                        let cons = local_connections.lock().await.clone();
-                        for connection in cons.iter() {
-                            self.send_steady_packet(
-                                &Connection::Local(connection.clone()),
-                                packet.clone(),
-                            )
-                            .await;
-                        }
+                if player.connection.identity().is_some() {
+                    self.send_steady_packet(
+                        &player.connection,
+                        SteadyPacket::NameRejected(NameRejectionReason::BoundToAccount),
+                    )
+                    .await;
+                    return true;
+                }
+                if !self.moderation.lock().await.filter.is_clean(&new_name) {
+                    self.send_steady_packet(
+                        &player.connection,
+                        SteadyPacket::NameRejected(NameRejectionReason::IllegalWord),
+                    )
+                    .await;
+                    return true;
+                }
+                let mut name_taken = false;
+                let mut wm = self.worldmachine.lock().await;
+                let players = wm.players.as_mut().unwrap().lock().await;
+                for player in players.values() {
+                    if *player.player.name.lock().await == new_name {
+                        name_taken = true;
+                        break;
                     }
-                    Connections::Lan(listener, connections) => {
-                        let mut name_taken = false;
-                        let mut wm = self.worldmachine.lock().await;
-                        let players = wm.players.as_mut().unwrap().lock().await;
-                        for player in players.values() {
-                            if *player.player.name.lock().await == new_name {
-                                name_taken = true;
-                                break;
-                            }
-                        }
-                        drop(players);
-                        drop(wm);
-                        let connection = match &player.connection {
-                            Connection::Local(_) => unreachable!(),
-                            Connection::Lan(_, connection) => connection,
-                        };
-                        if name_taken {
-                            self.send_steady_packet(
-                                &Connection::Lan(listener.clone(), connection.clone()),
-                                SteadyPacket::NameRejected(NameRejectionReason::Taken),
-                            )
-                            .await;
-                        } else {
-                            let mut wm = self.worldmachine.lock().await;
-                            let mut players = wm.players.as_mut().unwrap().lock().await;
-                            let player = players.get_mut(&connection.uuid).unwrap();
-                            *player.player.name.lock().await = new_name.clone();
-                            drop(players);
-                            drop(wm);
-
-                            let packet = SteadyPacket::SetName(connection.uuid.clone(), new_name);
-                            let cons = connections.lock().await.clone();
-                            for a_connection in cons {
-                                self.send_steady_packet(
-                                    &Connection::Lan(listener.clone(), a_connection.clone()),
-                                    packet.clone(),
-                                )
-                                .await;
-                            }
-                        }
+                }
+                drop(players);
+                drop(wm);
+                let uuid = player.connection.uuid();
+                if name_taken {
+                    self.send_steady_packet(
+                        &player.connection,
+                        SteadyPacket::NameRejected(NameRejectionReason::Taken),
+                    )
+                    .await;
+                } else {
+                    let mut wm = self.worldmachine.lock().await;
+                    let mut players = wm.players.as_mut().unwrap().lock().await;
+                    let renamed = players.get_mut(&uuid).unwrap();
+                    *renamed.player.name.lock().await = new_name.clone();
+                    drop(players);
+                    drop(wm);
+
+                    self.broadcast_steady_packet(SteadyPacket::SetName(uuid, new_name.clone()))
+                        .await;
+                    let owner = PlayerSaveOwner::of(&player.connection, &new_name);
+                    let saved_state = self.pending_player_states.lock().await.remove(&owner);
+                    if let Some(saved_state) = saved_state {
+                        self.restore_player_state(player, &saved_state).await;
                     }
                 }
             }
@@ -525,7 +614,8 @@ impl Server {
                 debug!("player threw snowball");
                 let tball_cooldown = *player.player.tball_cooldown.lock().await;
                 if tball_cooldown <= 0.0 {
-                    *player.player.tball_cooldown.lock().await = 0.5;
+                    *player.player.tball_cooldown.lock().await =
+                        self.config().gameplay.snowball_cooldown_secs;
                     let position = player.player.get_position(None, None).await;
                     let mut rotation = player.player.get_head_rotation(None, None).await;
                     rotation.w = -rotation.w;
@@ -549,46 +639,19 @@ impl Server {
                     let mut worldmachine = self.worldmachine.lock().await;
                     worldmachine.tballs.push(snowball);
                     drop(worldmachine);
-                    match &self.connections {
-                        Connections::Local(local_connections) => {
-                            let cons = local_connections.lock().await.clone();
-                            for connection in cons.iter() {
-                                self.send_steady_packet(
-                                    &Connection::Local(connection.clone()),
-                                    packet.clone(),
-                                )
-                                .await;
-                            }
-                        }
-                        Connections::Lan(listener, connections) => {
-                            let cons = connections.lock().await.clone();
-                            for a_connection in cons.iter() {
-                                self.send_steady_packet(
-                                    &Connection::Lan(listener.clone(), a_connection.clone()),
-                                    packet.clone(),
-                                )
-                                .await;
-                            }
-                        }
+                    for connection in self.get_connections_affected_from_position(position).await {
+                        self.send_steady_packet(&connection, packet.clone()).await;
                     }
                 }
             }
             SteadyPacket::Ping => {
-                match &player.connection {
-                    Connection::Local(local_connection) => {}
-                    Connection::Lan(_, connection) => {
-                        let unix_time = SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-                        connection
-                            .last_successful_ping
-                            .store(unix_time, Ordering::Relaxed);
-                    }
-                };
+                player.connection.mark_alive();
             }
             SteadyPacket::NameRejected(_) => {}
             SteadyPacket::Respawn(_) => {}
+            SteadyPacket::Disconnect(_) => {}
+            SteadyPacket::ResumeToken(_) => {}
+            SteadyPacket::TickRate(_) => {}
         }
         true
     }
@@ -596,209 +659,155 @@ impl Server {
     async fn handle_steady_packets(
         &self,
         player: &ServerPlayerContainer,
-        tcp_receiver: &mut Option<mpsc::Receiver<SteadyPacketData>>,
-    ) -> bool {
-        match player.connection.clone() {
-            Connection::Local(local_connection) => {
-                let sur = local_connection.steady_update_receiver.clone();
-                let mut sur = sur.lock().await;
-                if let Ok(packet) = sur.try_recv() {
-                    drop(sur);
-                    if !self.steady_packet(&player, packet.packet).await {
-                        return false;
-                    }
-                }
-            }
-            Connection::Lan(_, lan_connection) => {
-                let packet = lan_connection
-                    .attempt_receive_steady_and_deserialise(tcp_receiver.as_mut().unwrap())
-                    .await;
-                if let Err(e) = packet {
-                    debug!("error receiving steady packet: {:?}", e);
-                    return false;
-                }
-                let packet_og = packet.unwrap();
-                if let Some(packet) = packet_og.clone() {
-                    if !self.steady_packet(&player, packet.packet).await {
-                        return false;
-                    }
-                }
+        limiter: &mut RateLimiter,
+    ) -> PacketOutcome {
+        let packet = match player.connection.receive_steady().await {
+            Ok(Some(packet)) => packet.packet,
+            Ok(None) => return PacketOutcome::Idle,
+            Err(e) => {
+                debug!("error receiving steady packet: {:?}", e);
+                return PacketOutcome::Disconnect(DisconnectReason::ConnectionLost);
             }
+        };
+        // whatever reason the client gives, leaving on purpose doesn't keep their player around
+        if let SteadyPacket::Disconnect(reason) = packet {
+            debug!("{:?} is leaving: {}", player.connection, reason);
+            return PacketOutcome::Disconnect(DisconnectReason::Quit);
         }
-        true
-    }
-
-    async fn player_move(&self, player: &ServerPlayerContainer, packet: FastPacket) {
-        if let FastPacket::PlayerMove(
-            uuid,
-            position,
-            displacement_vector,
-            rotation,
-            head_rotation,
-            movement_info,
-        ) = packet
+        let kind = PacketKind::of_steady(&packet);
+        match self
+            .enforce_rate_limit(&player.connection, limiter, kind)
+            .await
         {
-            let (success, correct_position) = {
-                player
-                    .player
-                    .attempt_position_change(
-                        position,
-                        displacement_vector,
-                        rotation,
-                        head_rotation,
-                        movement_info.unwrap_or_default(),
-                        player.entity_id,
-                        self.worldmachine.clone(),
-                    )
-                    .await
-            };
-            if success {
-            } else {
-                self.send_fast_packet(
-                    &player.connection,
-                    FastPacket::PlayerFuckYouMoveHere(correct_position.unwrap()),
-                )
-                .await
-            }
+            Verdict::Allow => {}
+            Verdict::Kick => return PacketOutcome::Disconnect(flooding_kick()),
+            Verdict::Drop | Verdict::Warn => return PacketOutcome::Handled,
+        }
+        match self.steady_packet(player, packet).await {
+            true => PacketOutcome::Handled,
+            false => PacketOutcome::Disconnect(DisconnectReason::ConnectionLost),
         }
     }
 
-    async fn player_check_position(&self, player: &ServerPlayerContainer, packet: FastPacket) {
-        if let FastPacket::PlayerCheckPosition(uuid, position) = packet {
-            let worldmachine = self.worldmachine.clone();
-            let mut worldmachine = worldmachine.lock().await;
-            if !player.player.respawning.load(Ordering::Relaxed) {
-                let server_position = player
-                    .player
-                    .get_position(player.entity_id, Some(&mut worldmachine))
-                    .await;
-                let success = server_position == position;
-                if success {
-                } else {
-                    let position = player
-                        .player
-                        .get_position(player.entity_id, Some(&mut worldmachine))
-                        .await;
-                    drop(worldmachine);
-                    self.send_fast_packet(
-                        &player.connection,
-                        FastPacket::PlayerFuckYouMoveHere(position),
-                    )
-                    .await
+    async fn player_input(&self, player: &ServerPlayerContainer, mut commands: Vec<InputCommand>) {
+        commands.sort_by_key(|command| command.sequence);
+        let mut latest = None;
+        let mut corrected = false;
+        let gameplay = self.config().gameplay;
+        for command in commands {
+            let outcome = player
+                .player
+                .apply_input(command, player.entity_id, self.worldmachine.clone(), &gameplay)
+                .await;
+            match outcome {
+                InputOutcome::Skipped => {}
+                InputOutcome::Accepted(ack) => latest = Some(ack),
+                InputOutcome::Corrected(ack) => {
+                    latest = Some(ack);
+                    corrected = true;
                 }
             }
         }
+        if let Some(ack) = latest {
+            if player.player.should_acknowledge(corrected).await {
+                self.send_fast_packet(&player.connection, FastPacket::PlayerInputAck(ack))
+                    .await;
+            }
+        }
     }
 
-    async fn handle_fast_packets(&self, player: &ServerPlayerContainer) {
-        match &player.connection {
-            Connection::Local(local_connection) => {
-                let mut fur = local_connection.fast_update_receiver.lock().await;
-                if let Ok(packet) = fur.try_recv() {
-                    drop(fur);
-                    if let Some(fast_packet) = packet.packet {
-                        match fast_packet.clone() {
-                            FastPacket::PlayerMove(_, _, _, _, _, _) => {
-                                self.player_move(player, fast_packet).await;
-                            }
-
-                            FastPacket::PlayerCheckPosition(_, _) => {
-                                self.player_check_position(player, fast_packet).await;
-                            }
-
-                            FastPacket::PlayerJump(uuid) => {}
-
-                            FastPacket::ChangePosition(_, _) => {}
-                            FastPacket::ChangeRotation(_, _) => {}
-                            FastPacket::ChangeScale(_, _) => {}
-                            FastPacket::PlayerFuckYouMoveHere(_) => {}
-                            FastPacket::PlayerFuckYouSetRotation(_) => {}
-                            FastPacket::EntitySetParameter(_, _, _, _) => {}
-                            FastPacket::PlayerMoved(_, _, _, _) => {}
-                        }
-                    }
-                }
+    async fn fast_packet(&self, player: &ServerPlayerContainer, packet: FastPacket) {
+        match packet.clone() {
+            FastPacket::PlayerInput(_, commands) => {
+                player.player.queue_inputs(commands).await;
             }
-            Connection::Lan(listener, lan_connection) => {
-                let listener = listener.clone();
-                let packet = lan_connection
-                    .attempt_receive_fast_and_deserialise(listener)
+
+            FastPacket::PlayerJump(uuid) => {}
+
+            FastPacket::SnapshotAck(tick) => {
+                self.acknowledge_snapshot(&player.connection.uuid(), tick)
                     .await;
-                if let Some(packet) = packet {
-                    match packet.clone().packet.unwrap() {
-                        FastPacket::PlayerMove(_, _, _, _, _, _) => {
-                            self.player_move(&player, packet.clone().packet.unwrap())
-                                .await;
-                        }
-
-                        FastPacket::PlayerCheckPosition(_, _) => {
-                            self.player_check_position(&player, packet.clone().packet.unwrap())
-                                .await;
-                        }
-
-                        FastPacket::PlayerJump(uuid) => {}
-
-                        FastPacket::ChangePosition(_, _) => {}
-                        FastPacket::ChangeRotation(_, _) => {}
-                        FastPacket::ChangeScale(_, _) => {}
-                        FastPacket::PlayerFuckYouMoveHere(_) => {}
-                        FastPacket::PlayerFuckYouSetRotation(_) => {}
-                        FastPacket::EntitySetParameter(_, _, _, _) => {}
-                        FastPacket::PlayerMoved(_, _, _, _) => {}
-                    }
-                }
             }
+
+            FastPacket::Snapshot(_) => {}
+            FastPacket::PlayerInputAck(_) => {}
+            FastPacket::PlayerFuckYouSetRotation(_) => {}
+            FastPacket::EntitySetParameter(_, _, _, _) => {}
         }
     }
 
-    pub async fn handle_connection(
+    async fn handle_fast_packets(
         &self,
-        connection: Connection,
-        player: ServerPlayerContainer,
-    ) -> bool {
-        let mut tcp_receiver = match &connection {
-            Connection::Local(_) => None,
-            Connection::Lan(_, connection) => {
-                connection.steady_receiver_passthrough.lock().await.take()
+        player: &ServerPlayerContainer,
+        limiter: &mut RateLimiter,
+    ) -> PacketOutcome {
+        let packet = match player.connection.receive_fast().await {
+            Ok(Some(packet)) => packet.packet,
+            Ok(None) => return PacketOutcome::Idle,
+            Err(e) => {
+                debug!("error receiving fast packet: {:?}", e);
+                return PacketOutcome::Disconnect(DisconnectReason::ConnectionLost);
             }
         };
-        loop {
-            match connection.clone() {
-                Connection::Local(local_connection) => {
-                    self.handle_fast_packets(&player).await;
-                    self.handle_steady_packets(&player, &mut None).await;
-                }
-                Connection::Lan(listener, lan_connection) => {
-                    self.handle_fast_packets(&player).await;
-                    let connection = self.handle_steady_packets(&player, &mut tcp_receiver).await;
-                    if !connection {
-                        return false;
-                    }
-                }
+        if let Some(fast_packet) = packet {
+            let kind = PacketKind::of_fast(&fast_packet);
+            match self
+                .enforce_rate_limit(&player.connection, limiter, kind)
+                .await
+            {
+                Verdict::Allow => self.fast_packet(player, fast_packet).await,
+                Verdict::Kick => return PacketOutcome::Disconnect(flooding_kick()),
+                Verdict::Drop | Verdict::Warn => {}
             }
         }
+        PacketOutcome::Handled
     }
 
-    async fn assert_connection_type_allowed(&self, connection: Connection) -> bool {
-        match connection {
-            Connection::Local(_) => {
-                matches!(self.connections.clone(), Connections::Local(_))
+    pub async fn handle_connection(&self, player: &ServerPlayerContainer) -> DisconnectReason {
+        let mut limiter =
+            RateLimiter::new(self.rate_limit_config(), self.config().simulation.tick_rate);
+        let stopping = self.stopping.notified();
+        tokio::pin!(stopping);
+        stopping.as_mut().enable();
+        while self.running.load(Ordering::Relaxed) {
+            if let Some(reason) = player.player.disconnect_reason().await {
+                return reason;
+            }
+            // bounded so a flooding client can't hold on to this task
+            let mut handled = 0;
+            while handled < limiter.packets_per_iteration() {
+                let fast = self.handle_fast_packets(player, &mut limiter).await;
+                if let PacketOutcome::Disconnect(reason) = fast {
+                    // a kick or ban asked for first says more than how the connection ended
+                    return player.player.disconnect_reason().await.unwrap_or(reason);
+                }
+                let steady = self.handle_steady_packets(player, &mut limiter).await;
+                if let PacketOutcome::Disconnect(reason) = steady {
+                    return player.player.disconnect_reason().await.unwrap_or(reason);
+                }
+                if fast == PacketOutcome::Idle && steady == PacketOutcome::Idle {
+                    break;
+                }
+                handled += 1;
             }
-            Connection::Lan(_, _) => {
-                matches!(self.connections.clone(), Connections::Lan(_, _))
+            if handled == 0 {
+                tokio::select! {
+                    _ = player.connection.readable() => {}
+                    _ = player.player.disconnect_requested.notified() => {}
+                    _ = &mut stopping => {}
+                }
+            } else {
+                tokio::task::yield_now().await;
             }
         }
+        let reason = DisconnectReason::ServerShutdown;
+        self.disconnect(&player.connection, reason.clone()).await;
+        reason
     }
 
     async fn disconnect_player(&self, uuid: ConnectionUUID, player_entity_id: EntityId) {
-        let connections = match self.connections.clone() {
-            Connections::Lan(_, connections) => connections.clone(),
-            _ => {
-                panic!("assert_connection_type_allowed failed");
-            }
-        };
-        let mut connections = connections.lock().await;
-        connections.retain(|x| x.uuid != uuid);
+        let mut connections = self.connections.lock().await;
+        connections.retain(|x| x.uuid() != uuid);
         debug!("connections: {:?}", connections.len());
         drop(connections);
 
@@ -827,67 +836,60 @@ impl Server {
     }
 
     async fn new_connection(&self, connection: Connection) {
-        if self
-            .assert_connection_type_allowed(connection.clone())
-            .await
-        {
-            match connection.clone() {
-                Connection::Local(local_connection) => {
-                    let _connection_index = match self.connections.clone() {
-                        Connections::Local(connections) => {
-                            let mut connections = connections.lock().await;
-                            connections.push(local_connection.clone());
-                            connections.len() - 1
-                        }
-                        _ => {
-                            panic!("assert_connection_type_allowed failed");
-                        }
-                    };
-                    let player = self.begin_connection(connection.clone()).await;
-                    self.handle_connection(connection, player.unwrap()).await;
-                }
-                Connection::Lan(_, lan_connection) => {
-                    let _connection_index = match self.connections.clone() {
-                        Connections::Lan(_, connections) => {
-                            let mut connections = connections.lock().await;
-                            connections.push(lan_connection.clone());
-                            connections.len() - 1
-                        }
-                        _ => {
-                            panic!("assert_connection_type_allowed failed");
-                        }
-                    };
-                    let player = self.begin_connection(connection.clone()).await;
-                    if player.is_none() {
-                        let connections = match self.connections.clone() {
-                            Connections::Lan(_, connections) => connections.clone(),
-                            _ => {
-                                panic!("assert_connection_type_allowed failed");
-                            }
-                        };
-                        let mut connections = connections.lock().await;
-                        connections.retain(|x| x.uuid != lan_connection.uuid);
-                        debug!("connections: {:?}", connections.len());
-                        return;
-                    }
-                    let player = player.unwrap();
-                    let entity_id = player.entity_id.unwrap();
-                    let connected = self.handle_connection(connection, player).await;
-                    if !connected {
-                        self.disconnect_player(lan_connection.uuid, entity_id).await;
-                    }
-                }
+        let uuid = connection.uuid();
+        let max_players = self.config().network.max_players;
+        if self.connections.lock().await.len() >= max_players {
+            info!("turning away {:?}, the server is full", connection);
+            self.disconnect(&connection, DisconnectReason::ServerFull(max_players))
+                .await;
+            return;
+        }
+        if let Some(conditioner) = connection.link_conditioner() {
+            conditioner.set_conditions(self.config().link_conditioner);
+        }
+        self.connections.lock().await.push(connection.clone());
+        let player = match self.resume_player(&connection).await {
+            Some(player) => Some(player),
+            None => self.begin_connection(connection.clone()).await,
+        };
+        let player = match player {
+            Some(player) => player,
+            None => {
+                let mut connections = self.connections.lock().await;
+                connections.retain(|x| x.uuid() != uuid);
+                debug!("connections: {:?}", connections.len());
+                return;
             }
+        };
+        if self.config().network.resume_grace_secs > 0 {
+            let token = player.player.resume_token.to_string();
+            self.send_steady_packet(&connection, SteadyPacket::ResumeToken(token))
+                .await;
         }
+        let tick_rate = self.config().simulation.tick_rate;
+        self.send_steady_packet(&connection, SteadyPacket::TickRate(tick_rate))
+            .await;
+        let reason = self.handle_connection(&player).await;
+        self.end_connection(player, reason).await;
     }
 
-    pub async fn join_local_server(&mut self) -> Arc<Mutex<LocalConnectionClientSide>> {
+    pub async fn join_local_server(&mut self, host: bool) -> Arc<Mutex<LocalConnectionClientSide>> {
         info!("joining local server");
-        let (fast_update_sender_client, fast_update_receiver_server) = mpsc::channel(100);
-        let (steady_update_sender_client, steady_update_receiver_server) = mpsc::channel(100);
-        let (fast_update_sender_server, fast_update_receiver_client) = mpsc::channel(100);
-        let (steady_update_sender_server, steady_update_receiver_client) = mpsc::channel(100);
+        let conditioner = LinkConditioner::default();
+        let (fast_update_sender_client, fast_update_receiver_server) =
+            conditioned_channel(100, &conditioner, LinkDirection::ToServer, false);
+        let (steady_update_sender_client, steady_update_receiver_server) =
+            conditioned_channel(100, &conditioner, LinkDirection::ToServer, true);
+        let (fast_update_sender_server, fast_update_receiver_client) =
+            conditioned_channel(100, &conditioner, LinkDirection::ToClient, false);
+        let (steady_update_sender_server, steady_update_receiver_client) =
+            conditioned_channel(100, &conditioner, LinkDirection::ToClient, true);
         let uuid = generate_uuid();
+        let arrived = Arc::new(Notify::new());
+        let fast_update_receiver_server =
+            wake_on_arrival(fast_update_receiver_server, 100, arrived.clone());
+        let steady_update_receiver_server =
+            wake_on_arrival(steady_update_receiver_server, 100, arrived.clone());
         let local_connection = LocalConnection {
             fast_update_sender: fast_update_sender_server,
             steady_update_sender: steady_update_sender_server,
@@ -895,9 +897,13 @@ impl Server {
             steady_update_receiver: Arc::new(Mutex::new(steady_update_receiver_server)),
             clientside_steady_update_sender: steady_update_sender_client.clone(),
             consume_receiver_queue: Arc::new(Mutex::new(SteadyMessageQueue::new())),
-            uuid,
+            conditioner,
+            uuid: uuid.clone(),
+            host,
+            arrived,
         };
         let local_connection_client_side = LocalConnectionClientSide {
+            uuid,
             fast_update_sender: fast_update_sender_client,
             steady_update_sender: steady_update_sender_client,
             steady_sender_queue: Arc::new(Mutex::new(SteadyMessageQueue::new())),
@@ -906,254 +912,47 @@ impl Server {
         };
         struct ThreadData {
             server: Server,
-            connection: Arc<Mutex<LocalConnectionClientSide>>,
         }
         let connection = Arc::new(Mutex::new(local_connection_client_side));
         let thread_data = ThreadData {
             server: self.clone(),
-            connection: connection.clone(),
         };
         tokio::spawn(async move {
             let thread_data = thread_data;
             let connection = Arc::new(local_connection);
-            thread_data
-                .server
-                .new_connection(Connection::Local(connection))
-                .await;
+            thread_data.server.new_connection(connection).await;
         });
         connection
     }
 
-    async fn get_connections_affected_from_position(&mut self, position: Vec3) -> Vec<Connection> {
-        let mut connections_affected = Vec::new();
-        match self.connections.clone() {
-            Connections::Local(connections) => {
-                let connections = connections.lock().await;
-                for connection in connections.iter() {
-                    connections_affected.push(Connection::Local(connection.clone()));
-                }
-            }
-            Connections::Lan(listener, connections) => {
-                let connections = connections.lock().await;
-                for connection in connections.iter() {
-                    connections_affected
-                        .push(Connection::Lan(listener.clone(), connection.clone()));
-                }
-            }
-        }
-        connections_affected
-    }
-
-    async fn get_all_connections(&mut self) -> Vec<Connection> {
-        let mut connections_final = Vec::new();
-        match self.connections.clone() {
-            Connections::Local(connections) => {
-                let connections = connections.lock().await;
-                for connection in connections.iter() {
-                    connections_final.push(Connection::Local(connection.clone()));
-                }
-            }
-            Connections::Lan(listener, connections) => {
-                let connections = connections.lock().await;
-                for connection in connections.iter() {
-                    connections_final.push(Connection::Lan(listener.clone(), connection.clone()));
-                }
-            }
-        }
-        connections_final
+    async fn get_all_connections(&self) -> Vec<Connection> {
+        self.connections.lock().await.clone()
     }
 
     pub async fn handle_world_updates(&mut self, updates: Vec<WorldUpdate>) {
-        let mut player_entity_movement_stack: HashMap<
-            EntityId,
-            Vec<(Vec3, Quaternion, Quaternion)>,
-        > = HashMap::new();
         for update in updates {
             match update {
-                WorldUpdate::SetPosition(entity_id, vec3) => {
-                    let connections = self.get_connections_affected_from_position(vec3).await;
-                    for connection in connections {
-                        self.send_fast_packet(
-                            &connection,
-                            FastPacket::ChangePosition(entity_id, vec3),
-                        )
-                        .await;
-                    }
-                }
-                WorldUpdate::SetRotation(entity_id, quat) => {
-                    let connections = self
-                        .get_connections_affected_from_position(Vec3::new(0.0, 0.0, 0.0))
-                        .await;
-                    for connection in connections {
-                        self.send_fast_packet(
-                            &connection,
-                            FastPacket::ChangeRotation(entity_id, quat),
-                        )
-                        .await;
-                    }
-                }
-                WorldUpdate::SetScale(entity_id, vec3) => {
-                    let connections = self
-                        .get_connections_affected_from_position(Vec3::new(0.0, 0.0, 0.0))
-                        .await;
-                    for connection in connections {
-                        self.send_fast_packet(
-                            &connection,
-                            FastPacket::ChangeScale(entity_id, vec3),
-                        )
-                        .await;
-                    }
-                }
                 WorldUpdate::InitEntity(entity_id, entity_data) => {
-                    let connections = self.get_all_connections().await;
-                    for connection in connections {
-                        self.send_steady_packet(
-                            &connection,
-                            SteadyPacket::InitialiseEntity(entity_id, entity_data.clone()),
-                        )
-                        .await;
-                    }
-                }
-                WorldUpdate::EntityNoLongerExists(entity_id) => {
-                    let connections = self.get_all_connections().await;
-                    for connection in connections {
-                        self.send_steady_packet(&connection, SteadyPacket::RemoveEntity(entity_id))
-                            .await;
-                    }
-                }
-                WorldUpdate::MovePlayerEntity(entity_id, position, rotation, head_rotation) => {
-                    player_entity_movement_stack
-                        .entry(entity_id)
-                        .or_insert(Vec::new())
-                        .push((position, rotation, head_rotation));
-                }
-            }
-        }
-
-        for (entity_id, movement_stack) in player_entity_movement_stack {
-            let movement = movement_stack.last().unwrap();
-            let connections = self
-                .get_connections_affected_from_position(movement.0)
-                .await;
-            for connection in connections {
-                self.send_fast_packet(
-                    &connection,
-                    FastPacket::PlayerMoved(entity_id, movement.0, movement.1, movement.2),
-                )
-                .await;
-            }
-        }
-    }
-
-    pub async fn physics_thread(&self) {
-        loop {}
-    }
-
-    pub async fn player_and_physics_tick_thread(&self) {
-        let mut compensation_delta = 0.0;
-        let mut delta;
-        loop {
-            {
-                let worldmachine = self.worldmachine.lock().await;
-                let last_physics_tick = worldmachine.last_physics_update;
-                drop(worldmachine);
-                let current_time = std::time::Instant::now();
-                delta = (current_time - last_physics_tick).as_secs_f32();
-                if delta > 0.01 {
-                    let mut worldmachine = self.worldmachine.lock().await;
-                    let res = worldmachine
-                        .physics
-                        .lock()
-                        .unwrap()
-                        .as_mut()
-                        .unwrap()
-                        .tick(delta + compensation_delta);
-                    if let Some(delta) = res {
-                        compensation_delta += delta;
+                    let connections = if is_interest_managed(&entity_data) {
+                        self.get_connections_aware_of(entity_id).await
                     } else {
-                        compensation_delta = 0.0;
-                        worldmachine.last_physics_update = current_time;
-                    }
-                }
-            }
-            {
-                let worldmachine = self.worldmachine.lock().await;
-                let players = worldmachine.players.clone().unwrap();
-                drop(worldmachine);
-                let mut players_to_disconnect = Vec::new();
-                let mut players = players.lock().await.clone();
-                for (_uuid, player) in players.iter_mut() {
-                    let last_ping = match &player.connection {
-                        Connection::Local(_) => SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
-                        Connection::Lan(_, con) => con.last_successful_ping.load(Ordering::Relaxed),
+                        self.get_all_connections().await
                     };
-                    let current_time = SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-                    if current_time - last_ping > 60 {
-                        players_to_disconnect
-                            .push((player.connection.clone(), player.entity_id.unwrap()));
-                        continue;
-                    }
-                    if player.player.gravity_tick().await {
-                        let mut worldmachine = self.worldmachine.lock().await;
-                        let _pos = player
-                            .player
-                            .get_position(player.entity_id, Some(&mut worldmachine))
-                            .await;
-                        drop(worldmachine);
-                    }
-                    *player.player.tball_cooldown.lock().await -= delta;
-                    let position = player.player.get_position(None, None).await;
-                    if position.y < -20.0 {
-                        player.player.respawning.store(true, Ordering::Relaxed);
-                        let respawning = player.player.respawning.clone();
-                        let packet = SteadyPacket::Respawn(Vec3::new(0.0, 0.0, 0.0));
-                        self.send_steady_packet(&player.connection, packet).await;
-                        let mut worldmachine = self.worldmachine.lock().await;
-                        player
-                            .player
-                            .set_position(
-                                Vec3::new(0.0, 0.0, 0.0),
-                                player.entity_id,
-                                &mut worldmachine,
-                            )
-                            .await;
-                        drop(worldmachine);
-                        respawning.store(false, Ordering::Relaxed);
-                    }
-                }
-                drop(players);
-                for player in players_to_disconnect {
-                    match player.0 {
-                        Connection::Local(con) => {
-                            self.disconnect_player(con.uuid.clone(), player.1).await;
-                        }
-                        Connection::Lan(_, con) => {
-                            self.disconnect_player(con.uuid.clone(), player.1).await;
-                        }
+                    let packet = SteadyPacket::InitialiseEntity(entity_id, entity_data);
+                    for connection in connections {
+                        self.send_steady_packet(&connection, packet.clone()).await;
                     }
                 }
-            }
-        }
-    }
-
-    pub async fn run(&mut self) {
-        loop {
-            {
-                let mut worldmachine = self.worldmachine.lock().await;
-                let updates = { worldmachine.server_tick().await };
-                drop(worldmachine);
-                if let Some(updates) = updates {
-                    self.handle_world_updates(updates).await;
+                WorldUpdate::EntityNoLongerExists(entity_id) => {
+                    self.forget_entity(entity_id).await;
+                    self.broadcast_steady_packet(SteadyPacket::RemoveEntity(entity_id))
+                        .await;
                 }
+                WorldUpdate::SetPosition(_, _)
+                | WorldUpdate::SetRotation(_, _)
+                | WorldUpdate::SetScale(_, _)
+                | WorldUpdate::MovePlayerEntity(_, _, _, _) => {}
             }
-
-            self.listen_for_lan_connections().await;
         }
     }
 }
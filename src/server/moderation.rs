@@ -0,0 +1,507 @@
+use crate::server::disconnect::DisconnectReason;
+use crate::server::lan::RejectionReason;
+use crate::server::ratelimit::offender_targets;
+use crate::server::transport::{ unix_time, Connection };
+use crate::server::{ ConnectionUUID, Server, SteadyPacket };
+use serde::{ Deserialize, Serialize };
+use std::collections::HashMap;
+use std::fmt::{ Display, Formatter };
+use std::net::IpAddr;
+use std::sync::{ Arc, RwLock };
+use std::time::{ Duration, Instant };
+
+pub const BAN_LIST_FILE: &str = "bans.json";
+pub const WORD_FILTER_FILE: &str = "word_filter.txt";
+pub const MODERATORS_FILE: &str = "moderators.txt";
+
+pub const MODERATION_HELP: &str = "moderation commands:
+  mute <target> [minutes]           stop a client from chatting (until they leave if minutes is omitted)
+  unmute <target>                   let a muted client chat again
+  kick <target> [reason]            disconnect a client
+  ban <target> [minutes] [reason]   disconnect a client and refuse them until the ban expires (forever if minutes is omitted)
+  unban <target>                    lift a ban
+  bans                              list active bans
+targets are account:<name>, uuid:<uuid>, ip:<address>, or a connected player's name or uuid";
+
+#[derive(Clone, Debug)]
+pub enum ModerationError {
+    Io(String),
+    Serialisation(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BanTarget {
+    Account(String),
+    Uuid(ConnectionUUID),
+    Ip(IpAddr),
+}
+
+impl BanTarget {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.split_once(':') {
+            Some(("account", name)) if !name.is_empty() => Some(BanTarget::Account(name.to_string())),
+            Some(("uuid", uuid)) if !uuid.is_empty() => Some(BanTarget::Uuid(uuid.to_string())),
+            Some(("ip", address)) => address.parse().ok().map(BanTarget::Ip),
+            _ => text.parse().ok().map(BanTarget::Ip),
+        }
+    }
+
+    pub fn of_connection(connection: &Connection) -> Vec<Self> {
+        let mut targets = vec![BanTarget::Uuid(connection.uuid())];
+        targets.extend(offender_targets(connection));
+        targets
+    }
+
+    pub fn matches(&self, connection: &Connection) -> bool {
+        match self {
+            BanTarget::Account(name) => connection.identity().as_ref() == Some(name),
+            BanTarget::Uuid(uuid) => connection.uuid() == *uuid,
+            BanTarget::Ip(address) => connection.remote_ip() == Some(*address),
+        }
+    }
+}
+
+impl Display for BanTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::Account(name) => write!(f, "account:{}", name),
+            BanTarget::Uuid(uuid) => write!(f, "uuid:{}", uuid),
+            BanTarget::Ip(address) => write!(f, "ip:{}", address),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    pub banned_by: String,
+    pub banned_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    pub fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > unix_time())
+    }
+
+    pub fn rejection(&self) -> RejectionReason {
+        RejectionReason::Banned {
+            reason: self.reason.clone(),
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+impl Display for Ban {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} by {}: {}", self.target, self.banned_by, self.reason)?;
+        match self.expires_at {
+            Some(expires_at) => {
+                write!(f, " ({} seconds left)", expires_at.saturating_sub(unix_time()))
+            }
+            None => write!(f, " (permanent)"),
+        }
+    }
+}
+
+pub struct BanList {
+    path: Option<String>,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    pub fn in_memory() -> Self {
+        Self { path: None, bans: Vec::new() }
+    }
+
+    pub fn open(path: &str) -> Result<Self, ModerationError> {
+        let bans = if std::path::Path::new(path).exists() {
+            let data = std::fs::read(path).map_err(|e| ModerationError::Io(e.to_string()))?;
+            serde_json
+                ::from_slice(&data)
+                .map_err(|e| ModerationError::Serialisation(e.to_string()))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { path: Some(path.to_string()), bans })
+    }
+
+    fn flush(&mut self) -> Result<(), ModerationError> {
+        self.bans.retain(|ban| ban.is_active());
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                return Ok(());
+            }
+        };
+        let data = serde_json
+            ::to_vec_pretty(&self.bans)
+            .map_err(|e| ModerationError::Serialisation(e.to_string()))?;
+        let temp_path = format!("{}.tmp", path);
+        std::fs::write(&temp_path, data).map_err(|e| ModerationError::Io(e.to_string()))?;
+        std::fs::rename(&temp_path, path).map_err(|e| ModerationError::Io(e.to_string()))
+    }
+
+    pub fn find(&self, targets: &[BanTarget]) -> Option<&Ban> {
+        self.bans.iter().find(|ban| ban.is_active() && targets.contains(&ban.target))
+    }
+
+    pub fn ban(&mut self, ban: Ban) -> Result<(), ModerationError> {
+        self.bans.retain(|other| other.target != ban.target);
+        self.bans.push(ban);
+        self.flush()
+    }
+
+    pub fn unban(&mut self, target: &BanTarget) -> Result<bool, ModerationError> {
+        let before = self.bans.len();
+        self.bans.retain(|ban| ban.target != *target);
+        let removed = self.bans.len() != before;
+        self.flush()?;
+        Ok(removed)
+    }
+
+    pub fn active(&self) -> Vec<Ban> {
+        self.bans
+            .iter()
+            .filter(|ban| ban.is_active())
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn check_bans(
+    bans: &Arc<RwLock<BanList>>,
+    identity: Option<&String>,
+    address: Option<IpAddr>
+) -> Result<(), RejectionReason> {
+    let mut targets = Vec::new();
+    if let Some(identity) = identity {
+        targets.push(BanTarget::Account(identity.clone()));
+    }
+    if let Some(address) = address {
+        targets.push(BanTarget::Ip(address));
+    }
+    match bans.read().unwrap().find(&targets) {
+        Some(ban) => Err(ban.rejection()),
+        None => Ok(()),
+    }
+}
+
+fn read_word_list(path: &str) -> Result<Vec<String>, ModerationError> {
+    if !std::path::Path::new(path).exists() {
+        return Ok(Vec::new());
+    }
+    let text = std::fs::read_to_string(path).map_err(|e| ModerationError::Io(e.to_string()))?;
+    Ok(
+        text
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect()
+    )
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WordFilter {
+    words: Vec<String>,
+}
+
+impl WordFilter {
+    pub fn new(words: Vec<String>) -> Self {
+        Self {
+            words: words
+                .into_iter()
+                .map(|word| word.to_ascii_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, ModerationError> {
+        read_word_list(path).map(Self::new)
+    }
+
+    pub fn is_clean(&self, text: &str) -> bool {
+        let text = text.to_ascii_lowercase();
+        !self.words.iter().any(|word| text.contains(word.as_str()))
+    }
+
+    pub fn censor(&self, text: &str) -> String {
+        // ascii lowercasing keeps byte offsets, so matches line up with the original text
+        let lowered = text.to_ascii_lowercase();
+        let mut hidden = vec![false; text.len()];
+        for word in &self.words {
+            for (start, matched) in lowered.match_indices(word.as_str()) {
+                hidden[start..start + matched.len()].iter_mut().for_each(|byte| {
+                    *byte = true;
+                });
+            }
+        }
+        text.char_indices()
+            .map(|(index, c)| if hidden[index] { '*' } else { c })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct Moderation {
+    pub filter: WordFilter,
+    pub moderators: Vec<String>,
+    mutes: HashMap<BanTarget, Option<Instant>>,
+}
+
+impl Moderation {
+    pub fn load() -> Self {
+        let filter = WordFilter::load(WORD_FILTER_FILE).unwrap_or_else(|e| {
+            error!("failed to load {}: {:?}", WORD_FILTER_FILE, e);
+            WordFilter::default()
+        });
+        let moderators = read_word_list(MODERATORS_FILE).unwrap_or_else(|e| {
+            error!("failed to load {}: {:?}", MODERATORS_FILE, e);
+            Vec::new()
+        });
+        Self {
+            filter,
+            moderators,
+            mutes: HashMap::new(),
+        }
+    }
+
+    pub fn is_moderator(&self, connection: &Connection) -> bool {
+        connection.identity().is_some_and(|identity| self.moderators.contains(&identity))
+    }
+
+    pub fn is_muted(&mut self, connection: &Connection) -> bool {
+        let now = Instant::now();
+        self.mutes.retain(|_, until| until.is_none_or(|until| until > now));
+        self.mutes.keys().any(|target| target.matches(connection))
+    }
+
+    pub fn mute(&mut self, target: BanTarget, duration: Option<Duration>) {
+        self.mutes.insert(
+            target,
+            duration.map(|duration| Instant::now() + duration)
+        );
+    }
+
+    pub fn unmute(&mut self, target: &BanTarget) -> bool {
+        self.mutes.remove(target).is_some()
+    }
+}
+
+impl Server {
+    pub async fn find_connections(&self, target: &BanTarget) -> Vec<Connection> {
+        self.get_all_connections().await
+            .into_iter()
+            .filter(|connection| target.matches(connection))
+            .collect()
+    }
+
+    pub async fn resolve_target(&self, text: &str) -> Option<BanTarget> {
+        if let Some(target) = BanTarget::parse(text) {
+            return Some(target);
+        }
+        if text.contains(':') {
+            return None;
+        }
+        let target = BanTarget::Uuid(text.to_string());
+        if !self.find_connections(&target).await.is_empty() {
+            return Some(target);
+        }
+        let players = self.worldmachine.lock().await.players.clone()?;
+        let players = players.lock().await.clone();
+        for player in players.values() {
+            if *player.player.name.lock().await == text {
+                return Some(BanTarget::Uuid(player.connection.uuid()));
+            }
+        }
+        Some(BanTarget::Account(text.to_string()))
+    }
+
+    pub async fn kick(&self, target: &BanTarget, reason: DisconnectReason) -> usize {
+        let connections = self.find_connections(target).await;
+        for connection in connections.iter() {
+            self.disconnect(connection, reason.clone()).await;
+        }
+        connections.len()
+    }
+
+    pub async fn ban(
+        &self,
+        target: BanTarget,
+        duration: Option<Duration>,
+        reason: &str,
+        banned_by: &str
+    ) -> Result<usize, ModerationError> {
+        // a player found by name or uuid is banned by everything we know about them
+        let mut targets = vec![target.clone()];
+        for connection in self.find_connections(&target).await {
+            for target in BanTarget::of_connection(&connection) {
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+        let now = unix_time();
+        let bans = targets.into_iter().map(|target| Ban {
+            target,
+            reason: reason.to_string(),
+            banned_by: banned_by.to_string(),
+            banned_at: now,
+            expires_at: duration.map(|duration| now + duration.as_secs()),
+        });
+        let saved = {
+            let mut ban_list = self.bans.write().unwrap();
+            bans.map(|ban| ban_list.ban(ban)).collect::<Result<Vec<_>, _>>()
+        };
+        saved?;
+        let reason = DisconnectReason::Banned {
+            by: banned_by.to_string(),
+            reason: reason.to_string(),
+        };
+        Ok(self.kick(&target, reason).await)
+    }
+
+    pub async fn moderate(&self, command: &str, args: &[&str], moderator: &str) -> String {
+        if command == "bans" {
+            let bans = self.bans.read().unwrap().active();
+            if bans.is_empty() {
+                return "no active bans".to_string();
+            }
+            let mut response = format!("{} active bans:", bans.len());
+            for ban in bans {
+                response.push_str(&format!("\n  {}", ban));
+            }
+            return response;
+        }
+
+        let target = match args.first() {
+            Some(target) =>
+                match self.resolve_target(target).await {
+                    Some(target) => target,
+                    None => {
+                        return format!("{}: invalid target {}", command, target);
+                    }
+                }
+            None => {
+                return format!("{}: expected a target", command);
+            }
+        };
+        let minutes = args.get(1).and_then(|minutes| minutes.parse::<u64>().ok());
+        let reason_start = if minutes.is_some() { 2 } else { 1 };
+        let reason = match args.get(reason_start..) {
+            Some(words) if !words.is_empty() => words.join(" "),
+            _ => "no reason given".to_string(),
+        };
+        let duration = minutes.map(|minutes| Duration::from_secs(minutes * 60));
+
+        match command {
+            "mute" => {
+                self.moderation.lock().await.mute(target.clone(), duration);
+                for connection in self.find_connections(&target).await {
+                    let message = format!("you have been muted by {}", moderator);
+                    self.send_steady_packet(&connection, SteadyPacket::Message(message)).await;
+                }
+                format!("muted {}", target)
+            }
+            "unmute" => {
+                match self.moderation.lock().await.unmute(&target) {
+                    true => format!("unmuted {}", target),
+                    false => format!("unmute: {} is not muted", target),
+                }
+            }
+            "kick" => {
+                let reason = DisconnectReason::Kicked {
+                    by: moderator.to_string(),
+                    reason,
+                };
+                match self.kick(&target, reason).await {
+                    0 => format!("kick: no client matches {}", target),
+                    kicked => format!("kicked {} clients matching {}", kicked, target),
+                }
+            }
+            "ban" => {
+                match self.ban(target.clone(), duration, &reason, moderator).await {
+                    Ok(kicked) => format!("banned {} ({} clients disconnected)", target, kicked),
+                    Err(e) => format!("ban: failed to save the ban list: {:?}", e),
+                }
+            }
+            "unban" => {
+                match self.bans.write().unwrap().unban(&target) {
+                    Ok(true) => format!("unbanned {}", target),
+                    Ok(false) => format!("unban: {} is not banned", target),
+                    Err(e) => format!("unban: failed to save the ban list: {:?}", e),
+                }
+            }
+            _ => format!("unknown moderation command: {}", command),
+        }
+    }
+
+    // returns true if the message was a command and should not be relayed
+    pub async fn chat_command(&self, connection: &Connection, message: &str) -> bool {
+        let command = match message.strip_prefix('/') {
+            Some(command) => command,
+            None => {
+                return false;
+            }
+        };
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args = words.collect::<Vec<_>>();
+        let response = if !self.moderation.lock().await.is_moderator(connection) {
+            "only moderators can use chat commands".to_string()
+        } else if name == "help" {
+            MODERATION_HELP.to_string()
+        } else {
+            let moderator = connection.identity().unwrap_or_default();
+            info!("{} ran /{}", moderator, command);
+            self.moderate(name, &args, &moderator).await
+        };
+        self.send_steady_packet(connection, SteadyPacket::Message(response)).await;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(target: BanTarget, expires_at: Option<u64>) -> Ban {
+        Ban {
+            target,
+            reason: "testing".to_string(),
+            banned_by: "console".to_string(),
+            banned_at: unix_time(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn bans_stop_matching_once_they_expire() {
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut bans = BanList::in_memory();
+        bans.ban(ban(BanTarget::Ip(address), Some(unix_time() + 60))).unwrap();
+        bans.ban(ban(BanTarget::Account("alice".to_string()), None)).unwrap();
+        assert!(bans.find(&[BanTarget::Ip(address)]).is_some());
+        assert!(bans.find(&[BanTarget::Account("alice".to_string())]).is_some());
+
+        bans.bans[0].expires_at = Some(unix_time() - 1);
+        assert!(bans.find(&[BanTarget::Ip(address)]).is_none());
+        assert_eq!(bans.active().len(), 1);
+
+        let bans = Arc::new(RwLock::new(bans));
+        assert!(check_bans(&bans, None, Some(address)).is_ok());
+        assert!(check_bans(&bans, Some(&"alice".to_string()), Some(address)).is_err());
+    }
+
+    #[test]
+    fn expired_bans_are_dropped_when_the_list_is_saved() {
+        let mut bans = BanList::in_memory();
+        bans.ban(ban(BanTarget::Uuid("expired".to_string()), Some(unix_time() - 1))).unwrap();
+        bans.ban(ban(BanTarget::Uuid("current".to_string()), Some(unix_time() + 60))).unwrap();
+        assert_eq!(bans.bans.len(), 1);
+        assert_eq!(bans.bans[0].target, BanTarget::Uuid("current".to_string()));
+    }
+}
@@ -0,0 +1,493 @@
+use crate::server::disconnect::DisconnectReason;
+use crate::server::moderation::BanTarget;
+use crate::server::transport::Connection;
+use crate::server::{ FastPacket, Server, SteadyPacket };
+use halfbrown::HashMap;
+use serde::{ Deserialize, Serialize };
+use std::fmt::{ Display, Formatter };
+use std::net::IpAddr;
+use std::time::{ Duration, Instant };
+
+// clients send their inputs once a tick, the rest is room for jumps and a frame that ran late
+pub const MOVEMENT_PACKETS_PER_TICK: f32 = 2.0;
+pub const MOVEMENT_BURST_SECS: f32 = 1.0;
+pub const LOGIN_FREE_ATTEMPTS: u32 = 3;
+pub const LOGIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const LOGIN_MAX_BACKOFF: Duration = Duration::from_secs(60 * 5);
+pub const LOGIN_FAILURE_MEMORY: Duration = Duration::from_secs(60 * 15);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PacketKind {
+    Chat,
+    SetName,
+    Throw,
+    Movement,
+    Steady,
+    Fast,
+}
+
+impl PacketKind {
+    pub const ALL: [PacketKind; 6] = [
+        PacketKind::Chat,
+        PacketKind::SetName,
+        PacketKind::Throw,
+        PacketKind::Movement,
+        PacketKind::Steady,
+        PacketKind::Fast,
+    ];
+
+    pub fn of_steady(packet: &SteadyPacket) -> Self {
+        match packet {
+            SteadyPacket::ChatMessage(_, _) => PacketKind::Chat,
+            SteadyPacket::SetName(_, _) => PacketKind::SetName,
+            SteadyPacket::ThrowThrowAballll(_, _, _) => PacketKind::Throw,
+            _ => PacketKind::Steady,
+        }
+    }
+
+    pub fn of_fast(packet: &FastPacket) -> Self {
+        match packet {
+            FastPacket::PlayerInput(_, _) | FastPacket::PlayerJump(_) => PacketKind::Movement,
+            _ => PacketKind::Fast,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PacketKind::Chat => "chat",
+            PacketKind::SetName => "set name",
+            PacketKind::Throw => "throw",
+            PacketKind::Movement => "movement",
+            PacketKind::Steady => "other steady",
+            PacketKind::Fast => "other fast",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BucketLimit {
+    pub burst: f32,
+    pub per_second: f32,
+}
+
+impl BucketLimit {
+    pub fn for_tick_rate(tick_rate: u32) -> Self {
+        let tick_rate = tick_rate as f32;
+        Self {
+            burst: tick_rate * MOVEMENT_PACKETS_PER_TICK * MOVEMENT_BURST_SECS,
+            per_second: tick_rate * MOVEMENT_PACKETS_PER_TICK,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub chat: BucketLimit,
+    pub set_name: BucketLimit,
+    pub throw: BucketLimit,
+    // left out, it follows simulation.tick_rate
+    pub movement: Option<BucketLimit>,
+    pub steady: BucketLimit,
+    pub fast: BucketLimit,
+    pub warn_after: f32,
+    pub kick_after: f32,
+    pub violations_forgiven_per_second: f32,
+    pub kicks_before_ban: u32,
+    pub kick_memory_secs: u64,
+    pub ban_secs: u64,
+    pub packets_per_iteration: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            chat: BucketLimit { burst: 5.0, per_second: 1.0 },
+            set_name: BucketLimit { burst: 2.0, per_second: 0.2 },
+            throw: BucketLimit { burst: 4.0, per_second: 2.0 },
+            movement: None,
+            steady: BucketLimit { burst: 60.0, per_second: 30.0 },
+            fast: BucketLimit { burst: 240.0, per_second: 200.0 },
+            warn_after: 10.0,
+            kick_after: 50.0,
+            violations_forgiven_per_second: 1.0,
+            kicks_before_ban: 3,
+            kick_memory_secs: 60 * 10,
+            ban_secs: 60 * 10,
+            packets_per_iteration: 32,
+        }
+    }
+}
+
+impl BucketLimit {
+    fn validate(&self) -> Result<(), String> {
+        // a burst under one would never let a single packet through
+        if !(1.0..).contains(&self.burst) {
+            return Err("burst must be at least 1".to_string());
+        }
+        if self.per_second <= 0.0 || !self.per_second.is_finite() {
+            return Err("per_second must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = [
+            ("chat", Some(self.chat)),
+            ("set_name", Some(self.set_name)),
+            ("throw", Some(self.throw)),
+            ("movement", self.movement),
+            ("steady", Some(self.steady)),
+            ("fast", Some(self.fast)),
+        ];
+        for (name, limit) in limits {
+            if let Some(Err(e)) = limit.map(|limit| limit.validate()) {
+                return Err(format!("{}.{}", name, e));
+            }
+        }
+        if !(1.0..).contains(&self.warn_after) {
+            return Err("warn_after must be at least 1".to_string());
+        }
+        if !(self.warn_after..).contains(&self.kick_after) || !self.kick_after.is_finite() {
+            return Err("kick_after must not be below warn_after".to_string());
+        }
+        if !(0.0..).contains(&self.violations_forgiven_per_second) || !self.violations_forgiven_per_second.is_finite() {
+            return Err("violations_forgiven_per_second must not be negative".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn limit(&self, kind: PacketKind, tick_rate: u32) -> BucketLimit {
+        match kind {
+            PacketKind::Chat => self.chat,
+            PacketKind::SetName => self.set_name,
+            PacketKind::Throw => self.throw,
+            PacketKind::Movement => self.movement.unwrap_or_else(|| BucketLimit::for_tick_rate(tick_rate)),
+            PacketKind::Steady => self.steady,
+            PacketKind::Fast => self.fast,
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: BucketLimit,
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: BucketLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    Warn,
+    Kick,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<PacketKind, TokenBucket>,
+    violations: f32,
+    last_violation: Instant,
+    warned: bool,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, tick_rate: u32) -> Self {
+        let mut buckets = HashMap::new();
+        for kind in PacketKind::ALL {
+            buckets.insert(kind, TokenBucket::new(config.limit(kind, tick_rate)));
+        }
+        Self {
+            config,
+            buckets,
+            violations: 0.0,
+            last_violation: Instant::now(),
+            warned: false,
+        }
+    }
+
+    pub fn packets_per_iteration(&self) -> usize {
+        self.config.packets_per_iteration.max(1)
+    }
+
+    pub fn check(&mut self, kind: PacketKind) -> Verdict {
+        if self.buckets.get_mut(&kind).is_none_or(|bucket| bucket.take()) {
+            return Verdict::Allow;
+        }
+        let now = Instant::now();
+        let forgiven =
+            now.duration_since(self.last_violation).as_secs_f32() *
+            self.config.violations_forgiven_per_second;
+        self.last_violation = now;
+        self.violations = (self.violations - forgiven).max(0.0) + 1.0;
+        if self.violations < self.config.warn_after {
+            self.warned = false;
+        }
+
+        if self.violations >= self.config.kick_after {
+            Verdict::Kick
+        } else if self.violations >= self.config.warn_after && !self.warned {
+            self.warned = true;
+            Verdict::Warn
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitStats {
+    pub violations: HashMap<PacketKind, u64>,
+    pub warnings: u64,
+    pub kicks: u64,
+    pub bans: u64,
+}
+
+impl Display for RateLimitStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} warnings, {} kicks, {} bans", self.warnings, self.kicks, self.bans)?;
+        for kind in PacketKind::ALL {
+            let violations = self.violations.get(&kind).copied().unwrap_or(0);
+            write!(f, "\n  {}: {} packets over the limit", kind.name(), violations)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Offences {
+    kicks: HashMap<BanTarget, Vec<Instant>>,
+    pub stats: RateLimitStats,
+}
+
+impl Offences {
+    // returns how long the offender should be banned for, if this kick was one too many
+    pub fn record_kick(&mut self, targets: &[BanTarget], config: &RateLimitConfig) -> Option<Duration> {
+        self.stats.kicks += 1;
+        let now = Instant::now();
+        let memory = Duration::from_secs(config.kick_memory_secs);
+        let mut kicks = 0;
+        for target in targets {
+            let history = self.kicks.entry(target.clone()).or_default();
+            history.retain(|kicked_at| now.duration_since(*kicked_at) < memory);
+            history.push(now);
+            kicks = kicks.max(history.len());
+        }
+        if config.kicks_before_ban == 0 || kicks < (config.kicks_before_ban as usize) {
+            return None;
+        }
+        self.stats.bans += 1;
+        Some(Duration::from_secs(config.ban_secs))
+    }
+}
+
+pub fn offender_targets(connection: &Connection) -> Vec<BanTarget> {
+    let mut targets = Vec::new();
+    if let Some(identity) = connection.identity() {
+        targets.push(BanTarget::Account(identity));
+    }
+    if let Some(address) = connection.remote_ip() {
+        targets.push(BanTarget::Ip(address));
+    }
+    targets
+}
+
+struct LoginAttempts {
+    count: u32,
+    last_attempt: Instant,
+}
+
+// every attempt past the free ones doubles the wait before the next, until one succeeds
+#[derive(Default)]
+pub struct LoginThrottle {
+    attempts: HashMap<IpAddr, LoginAttempts>,
+}
+
+impl LoginThrottle {
+    fn backoff(count: u32) -> Duration {
+        if count < LOGIN_FREE_ATTEMPTS {
+            return Duration::ZERO;
+        }
+        let doublings = (count - LOGIN_FREE_ATTEMPTS).min(16);
+        (LOGIN_BACKOFF * (1 << doublings)).min(LOGIN_MAX_BACKOFF)
+    }
+
+    // counts the attempt straight away so parallel attempts can't all get through, or says how long to wait
+    pub fn attempt(&mut self, address: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.attempts.retain(|_, attempts| now.duration_since(attempts.last_attempt) < LOGIN_FAILURE_MEMORY);
+        let attempts = self.attempts.entry(address).or_insert(LoginAttempts {
+            count: 0,
+            last_attempt: now,
+        });
+        let wait = Self::backoff(attempts.count);
+        let waited = now.duration_since(attempts.last_attempt);
+        if attempts.count > 0 && waited < wait {
+            return Err(wait - waited);
+        }
+        attempts.count += 1;
+        attempts.last_attempt = now;
+        Ok(())
+    }
+
+    pub fn succeeded(&mut self, address: IpAddr) {
+        self.attempts.remove(&address);
+    }
+}
+
+pub fn flooding_kick() -> DisconnectReason {
+    DisconnectReason::Kicked {
+        by: "rate limiter".to_string(),
+        reason: "flooding the server".to_string(),
+    }
+}
+
+impl Server {
+    pub fn rate_limit_config(&self) -> RateLimitConfig {
+        self.config.read().unwrap().rate_limits.clone()
+    }
+
+    pub async fn enforce_rate_limit(
+        &self,
+        connection: &Connection,
+        limiter: &mut RateLimiter,
+        kind: PacketKind
+    ) -> Verdict {
+        let verdict = limiter.check(kind);
+        if verdict == Verdict::Allow {
+            return verdict;
+        }
+        let mut offences = self.offences.lock().await;
+        *offences.stats.violations.entry(kind).or_insert(0) += 1;
+        match verdict {
+            Verdict::Allow | Verdict::Drop => {}
+            Verdict::Warn => {
+                offences.stats.warnings += 1;
+                drop(offences);
+                warn!("{:?} is flooding {} packets", connection, kind.name());
+                let message = "you are sending too many messages, slow down".to_string();
+                self.send_steady_packet(connection, SteadyPacket::Message(message)).await;
+            }
+            Verdict::Kick => {
+                let banned = offences.record_kick(&offender_targets(connection), &limiter.config);
+                drop(offences);
+                match banned {
+                    Some(duration) => {
+                        warn!("banning {:?} for {} seconds for flooding", connection, duration.as_secs());
+                        let target = BanTarget::Uuid(connection.uuid());
+                        let reason = "flooding the server";
+                        if let Err(e) = self.ban(target, Some(duration), reason, "rate limiter").await {
+                            error!("failed to save the ban list: {:?}", e);
+                        }
+                    }
+                    None => {
+                        warn!("kicking {:?} for flooding {} packets", connection, kind.name());
+                        self.disconnect(connection, flooding_kick()).await;
+                    }
+                }
+            }
+        }
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_throttle_backs_off_after_the_free_attempts() {
+        let mut throttle = LoginThrottle::default();
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        for _ in 0..LOGIN_FREE_ATTEMPTS {
+            assert!(throttle.attempt(address).is_ok());
+        }
+        let wait = throttle.attempt(address).unwrap_err();
+        assert!(wait <= LOGIN_BACKOFF && wait > Duration::ZERO);
+
+        // someone else on another address isn't affected
+        assert!(throttle.attempt("10.0.0.2".parse().unwrap()).is_ok());
+
+        throttle.succeeded(address);
+        assert!(throttle.attempt(address).is_ok());
+    }
+
+    #[test]
+    fn login_backoff_doubles_up_to_the_maximum() {
+        assert_eq!(LoginThrottle::backoff(0), Duration::ZERO);
+        assert_eq!(LoginThrottle::backoff(LOGIN_FREE_ATTEMPTS), LOGIN_BACKOFF);
+        assert_eq!(LoginThrottle::backoff(LOGIN_FREE_ATTEMPTS + 1), LOGIN_BACKOFF * 2);
+        assert_eq!(LoginThrottle::backoff(LOGIN_FREE_ATTEMPTS + 100), LOGIN_MAX_BACKOFF);
+    }
+
+    #[test]
+    fn token_bucket_refills_over_time_up_to_its_burst() {
+        let mut bucket = TokenBucket::new(BucketLimit { burst: 3.0, per_second: 4.0 });
+        for _ in 0..3 {
+            assert!(bucket.take());
+        }
+        assert!(!bucket.take());
+
+        bucket.last_refill -= Duration::from_millis(500);
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+
+        bucket.last_refill -= Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take());
+        }
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn movement_limit_follows_the_tick_rate_unless_configured() {
+        let mut config = RateLimitConfig::default();
+        let slow = config.limit(PacketKind::Movement, 20);
+        let fast = config.limit(PacketKind::Movement, 240);
+        assert_eq!(slow.per_second, 20.0 * MOVEMENT_PACKETS_PER_TICK);
+        assert_eq!(fast.per_second, 240.0 * MOVEMENT_PACKETS_PER_TICK);
+
+        let fixed = BucketLimit { burst: 10.0, per_second: 5.0 };
+        config.movement = Some(fixed);
+        assert_eq!(config.limit(PacketKind::Movement, 240), fixed);
+    }
+
+    #[test]
+    fn limiter_keeps_up_with_a_client_sending_every_tick() {
+        let tick_rate = 60;
+        let mut limiter = RateLimiter::new(RateLimitConfig::default(), tick_rate);
+        let tick = Duration::from_secs_f32(1.0 / (tick_rate as f32));
+        for _ in 0..tick_rate * 10 {
+            limiter.buckets.get_mut(&PacketKind::Movement).unwrap().last_refill -= tick;
+            // an input and a jump in the same tick
+            assert_eq!(limiter.check(PacketKind::Movement), Verdict::Allow);
+            assert_eq!(limiter.check(PacketKind::Movement), Verdict::Allow);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use serde::{ Deserialize, Serialize };
+use std::collections::{ BTreeMap, VecDeque };
+use std::time::{ Duration, Instant };
+
+pub const MAX_FRAGMENT_SIZE: usize = 512;
+// bigger payloads are refused on the way out and end the connection on the way in
+pub const MAX_PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
+pub const MAX_IN_FLIGHT: usize = 256;
+pub const MAX_RETRANSMISSIONS: u32 = 10;
+pub const INITIAL_RTO: Duration = Duration::from_millis(500);
+pub const MIN_RTO: Duration = Duration::from_millis(50);
+pub const MAX_RTO: Duration = Duration::from_secs(2);
+pub const RELIABLE_TICK: Duration = Duration::from_millis(10);
+const ACK_BITS: u32 = 32;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReliableFragment {
+    pub sequence: u32,
+    pub index: u16,
+    pub count: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ReliableSegment {
+    Data(ReliableFragment),
+    Ack {
+        next_expected: u32,
+        received: u32,
+    },
+}
+
+struct InFlight {
+    fragment: ReliableFragment,
+    sent_at: Instant,
+    retransmissions: u32,
+}
+
+pub struct ReliableChannel {
+    next_sequence: u32,
+    queued: VecDeque<ReliableFragment>,
+    in_flight: BTreeMap<u32, InFlight>,
+    next_expected: u32,
+    out_of_order: BTreeMap<u32, ReliableFragment>,
+    assembling: Vec<u8>,
+    // index and count of the next fragment of the payload being assembled
+    assembling_next: Option<(u16, u16)>,
+    delivered: VecDeque<Vec<u8>>,
+    ack_pending: bool,
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+    rto: Duration,
+    retransmissions: u64,
+    failed: bool,
+}
+
+impl Default for ReliableChannel {
+    fn default() -> Self {
+        Self {
+            next_sequence: 0,
+            queued: VecDeque::new(),
+            in_flight: BTreeMap::new(),
+            next_expected: 0,
+            out_of_order: BTreeMap::new(),
+            assembling: Vec::new(),
+            assembling_next: None,
+            delivered: VecDeque::new(),
+            ack_pending: false,
+            smoothed_rtt: None,
+            rtt_variance: Duration::ZERO,
+            rto: INITIAL_RTO,
+            retransmissions: 0,
+            failed: false,
+        }
+    }
+}
+
+impl ReliableChannel {
+    pub fn send(&mut self, payload: &[u8]) {
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            warn!("dropping reliable payload of {} bytes, too big to send", payload.len());
+            return;
+        }
+        let chunks = payload.chunks(MAX_FRAGMENT_SIZE).collect::<Vec<_>>();
+        let count = chunks.len().max(1);
+        for index in 0..count {
+            self.queued.push_back(ReliableFragment {
+                sequence: self.next_sequence,
+                index: index as u16,
+                count: count as u16,
+                data: chunks.get(index).map_or(Vec::new(), |chunk| chunk.to_vec()),
+            });
+            self.next_sequence += 1;
+        }
+    }
+
+    pub fn receive(&mut self, segment: ReliableSegment) {
+        match segment {
+            ReliableSegment::Data(fragment) => self.receive_fragment(fragment),
+            ReliableSegment::Ack { next_expected, received } => {
+                self.receive_ack(next_expected, received)
+            }
+        }
+    }
+
+    // a peer that sends something we'd never send is broken or hostile, either way we're done with it
+    fn violation(&mut self, reason: &str) {
+        warn!("reliable channel failed: {}", reason);
+        self.failed = true;
+        self.out_of_order.clear();
+        self.assembling = Vec::new();
+    }
+
+    fn receive_fragment(&mut self, fragment: ReliableFragment) {
+        if self.failed {
+            return;
+        }
+        if fragment.count == 0 || fragment.index >= fragment.count || fragment.data.len() > MAX_FRAGMENT_SIZE {
+            self.violation("malformed fragment");
+            return;
+        }
+        // always ack, the previous ack may have been the one that was lost
+        self.ack_pending = true;
+        if fragment.sequence < self.next_expected {
+            return;
+        }
+        if fragment.sequence - self.next_expected >= (MAX_IN_FLIGHT as u32) * 2 {
+            debug!("dropping reliable fragment {} outside of the window", fragment.sequence);
+            return;
+        }
+        self.out_of_order.insert(fragment.sequence, fragment);
+        while let Some(fragment) = self.out_of_order.remove(&self.next_expected) {
+            self.next_expected += 1;
+            let expected = self.assembling_next.unwrap_or((0, fragment.count));
+            if (fragment.index, fragment.count) != expected {
+                self.violation("fragment out of place in its payload");
+                return;
+            }
+            if self.assembling.len() + fragment.data.len() > MAX_PAYLOAD_SIZE {
+                self.violation("payload too big to reassemble");
+                return;
+            }
+            self.assembling.extend_from_slice(&fragment.data);
+            if fragment.index == fragment.count - 1 {
+                self.assembling_next = None;
+                self.delivered.push_back(std::mem::take(&mut self.assembling));
+            } else {
+                self.assembling_next = Some((fragment.index + 1, fragment.count));
+            }
+        }
+    }
+
+    fn receive_ack(&mut self, next_expected: u32, received: u32) {
+        let now = Instant::now();
+        let mut acknowledged = self.in_flight
+            .range(..next_expected)
+            .map(|(sequence, _)| *sequence)
+            .collect::<Vec<_>>();
+        for bit in 0..ACK_BITS {
+            if received & (1 << bit) != 0 {
+                acknowledged.push(next_expected + 1 + bit);
+            }
+        }
+        for sequence in acknowledged {
+            if let Some(in_flight) = self.in_flight.remove(&sequence) {
+                // karn's algorithm, retransmitted fragments give ambiguous samples
+                if in_flight.retransmissions == 0 {
+                    self.sample_rtt(now.duration_since(in_flight.sent_at));
+                }
+            }
+        }
+    }
+
+    fn sample_rtt(&mut self, sample: Duration) {
+        match self.smoothed_rtt {
+            None => {
+                self.smoothed_rtt = Some(sample);
+                self.rtt_variance = sample / 2;
+            }
+            Some(smoothed) => {
+                let deviation = smoothed.abs_diff(sample);
+                self.rtt_variance = (self.rtt_variance * 3 + deviation) / 4;
+                self.smoothed_rtt = Some((smoothed * 7 + sample) / 8);
+            }
+        }
+        let smoothed = self.smoothed_rtt.unwrap_or(INITIAL_RTO);
+        self.rto = (smoothed + self.rtt_variance * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    pub fn poll(&mut self) -> Vec<ReliableSegment> {
+        let now = Instant::now();
+        let mut segments = Vec::new();
+        for in_flight in self.in_flight.values_mut() {
+            let timeout = (self.rto * (1 << in_flight.retransmissions.min(5))).min(MAX_RTO * 4);
+            if now.duration_since(in_flight.sent_at) < timeout {
+                continue;
+            }
+            if in_flight.retransmissions >= MAX_RETRANSMISSIONS {
+                self.failed = true;
+                continue;
+            }
+            in_flight.retransmissions += 1;
+            in_flight.sent_at = now;
+            self.retransmissions += 1;
+            segments.push(ReliableSegment::Data(in_flight.fragment.clone()));
+        }
+        while self.in_flight.len() < MAX_IN_FLIGHT {
+            let fragment = match self.queued.pop_front() {
+                Some(fragment) => fragment,
+                None => {
+                    break;
+                }
+            };
+            segments.push(ReliableSegment::Data(fragment.clone()));
+            self.in_flight.insert(fragment.sequence, InFlight {
+                fragment,
+                sent_at: now,
+                retransmissions: 0,
+            });
+        }
+        if self.ack_pending {
+            self.ack_pending = false;
+            let mut received = 0;
+            for bit in 0..ACK_BITS {
+                if self.out_of_order.contains_key(&(self.next_expected + 1 + bit)) {
+                    received |= 1 << bit;
+                }
+            }
+            segments.push(ReliableSegment::Ack {
+                next_expected: self.next_expected,
+                received,
+            });
+        }
+        segments
+    }
+
+    pub fn deliver(&mut self) -> Option<Vec<u8>> {
+        self.delivered.pop_front()
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragments(channel: &mut ReliableChannel) -> Vec<ReliableFragment> {
+        channel
+            .poll()
+            .into_iter()
+            .filter_map(|segment| {
+                match segment {
+                    ReliableSegment::Data(fragment) => Some(fragment),
+                    ReliableSegment::Ack { .. } => None,
+                }
+            })
+            .collect()
+    }
+
+    fn fragment(sequence: u32, index: u16, count: u16, size: usize) -> ReliableSegment {
+        ReliableSegment::Data(ReliableFragment {
+            sequence,
+            index,
+            count,
+            data: vec![0; size],
+        })
+    }
+
+    #[test]
+    fn reassembles_fragments_that_arrive_out_of_order() {
+        let payload = (0..MAX_FRAGMENT_SIZE * 5 + 17).map(|i| i as u8).collect::<Vec<_>>();
+        let mut sender = ReliableChannel::default();
+        sender.send(&payload);
+        sender.send(b"second");
+        let mut fragments = fragments(&mut sender);
+        assert_eq!(fragments.len(), 7);
+        fragments.reverse();
+
+        let mut receiver = ReliableChannel::default();
+        for fragment in fragments.iter().cloned() {
+            receiver.receive(ReliableSegment::Data(fragment));
+        }
+        // duplicates of already delivered fragments are ignored
+        receiver.receive(ReliableSegment::Data(fragments[0].clone()));
+        assert_eq!(receiver.deliver(), Some(payload));
+        assert_eq!(receiver.deliver(), Some(b"second".to_vec()));
+        assert_eq!(receiver.deliver(), None);
+        assert!(!receiver.has_failed());
+    }
+
+    #[test]
+    fn sends_an_empty_payload_as_one_fragment() {
+        let mut sender = ReliableChannel::default();
+        sender.send(&[]);
+        let mut receiver = ReliableChannel::default();
+        for fragment in fragments(&mut sender) {
+            receiver.receive(ReliableSegment::Data(fragment));
+        }
+        assert_eq!(receiver.deliver(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_malformed_fragments() {
+        for segment in [
+            fragment(0, 0, 0, 1),
+            fragment(0, 2, 2, 1),
+            fragment(0, u16::MAX, u16::MAX, 1),
+            fragment(0, 0, 1, MAX_FRAGMENT_SIZE + 1),
+        ] {
+            let mut receiver = ReliableChannel::default();
+            receiver.receive(segment);
+            assert!(receiver.has_failed());
+            assert_eq!(receiver.deliver(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_fragments_out_of_place_in_their_payload() {
+        let mut receiver = ReliableChannel::default();
+        receiver.receive(fragment(0, 1, 2, 1));
+        assert!(receiver.has_failed());
+
+        let mut receiver = ReliableChannel::default();
+        receiver.receive(fragment(0, 0, 3, 1));
+        receiver.receive(fragment(1, 1, 2, 1));
+        assert!(receiver.has_failed());
+    }
+
+    #[test]
+    fn gives_up_on_payloads_over_the_size_cap() {
+        let mut receiver = ReliableChannel::default();
+        let fragments_allowed = (MAX_PAYLOAD_SIZE / MAX_FRAGMENT_SIZE) as u32;
+        for sequence in 0..fragments_allowed {
+            receiver.receive(fragment(sequence, sequence as u16, u16::MAX, MAX_FRAGMENT_SIZE));
+        }
+        assert!(!receiver.has_failed());
+        receiver.receive(fragment(fragments_allowed, fragments_allowed as u16, u16::MAX, MAX_FRAGMENT_SIZE));
+        assert!(receiver.has_failed());
+        assert_eq!(receiver.deliver(), None);
+    }
+}
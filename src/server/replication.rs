@@ -0,0 +1,672 @@
+use crate::server::interest::{ ClientInterest, Relevancy, SpatialIndex };
+use crate::server::{ ConnectionUUID, FastPacket, Server, SteadyPacket };
+use crate::worldmachine::components::{ COMPONENT_TYPE_PLAYER, COMPONENT_TYPE_TRANSFORM };
+use crate::worldmachine::ecs::{ Component, Entity, ParameterValue };
+use crate::worldmachine::EntityId;
+use gfx_maths::{ Quaternion, Vec3 };
+use halfbrown::HashMap;
+use serde::{ Deserialize, Serialize };
+use std::collections::{ BTreeMap, VecDeque };
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use tokio::time::Duration;
+
+pub const REPLICATION_INTERVAL: Duration = Duration::from_millis(33);
+pub const SNAPSHOT_HISTORY: usize = 32;
+pub const MAX_SNAPSHOT_PAYLOAD: usize = 1100;
+pub const POSITION_SCALE: f32 = 256.0;
+const ROTATION_BITS: u32 = 10;
+const ROTATION_MAX: u32 = (1 << ROTATION_BITS) - 1;
+const SNAPSHOT_HEADER_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuantisedVec3(i32, i32, i32);
+
+impl QuantisedVec3 {
+    pub fn new(vector: Vec3) -> Self {
+        let quantise = |value: f32| (value * POSITION_SCALE).round() as i32;
+        Self(quantise(vector.x), quantise(vector.y), quantise(vector.z))
+    }
+
+    pub fn to_vec3(self) -> Vec3 {
+        Vec3::new(
+            (self.0 as f32) / POSITION_SCALE,
+            (self.1 as f32) / POSITION_SCALE,
+            (self.2 as f32) / POSITION_SCALE
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuantisedQuaternion(u32);
+
+impl QuantisedQuaternion {
+    pub fn new(rotation: Quaternion) -> Self {
+        let components = [rotation.x, rotation.y, rotation.z, rotation.w];
+        let length = components
+            .iter()
+            .map(|component| component * component)
+            .sum::<f32>()
+            .sqrt();
+        if length <= f32::EPSILON {
+            return Self::new(Quaternion::identity());
+        }
+        let mut largest = 0;
+        for i in 1..4 {
+            if components[i].abs() > components[largest].abs() {
+                largest = i;
+            }
+        }
+        let sign = if components[largest] < 0.0 { -1.0 } else { 1.0 };
+        let mut packed = largest as u32;
+        for (i, component) in components.iter().enumerate() {
+            if i == largest {
+                continue;
+            }
+            let normalised = ((component * sign) / length / FRAC_1_SQRT_2).clamp(-1.0, 1.0);
+            let quantised = (((normalised + 1.0) * 0.5 * (ROTATION_MAX as f32)).round() as u32).min(
+                ROTATION_MAX
+            );
+            packed = (packed << ROTATION_BITS) | quantised;
+        }
+        Self(packed)
+    }
+
+    pub fn to_quaternion(self) -> Quaternion {
+        let largest = (self.0 >> (ROTATION_BITS * 3)) as usize;
+        let mut components = [0.0; 4];
+        let mut sum = 0.0;
+        let mut shift = ROTATION_BITS * 3;
+        for (i, component) in components.iter_mut().enumerate() {
+            if i == largest {
+                continue;
+            }
+            shift -= ROTATION_BITS;
+            let quantised = (self.0 >> shift) & ROTATION_MAX;
+            *component = (((quantised as f32) / (ROTATION_MAX as f32)) * 2.0 - 1.0) * FRAC_1_SQRT_2;
+            sum += *component * *component;
+        }
+        components[largest] = (1.0 - sum).max(0.0).sqrt();
+        Quaternion::new(components[0], components[1], components[2], components[3])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntityState {
+    pub position: QuantisedVec3,
+    pub rotation: QuantisedQuaternion,
+    pub scale: QuantisedVec3,
+    pub head_rotation: Option<QuantisedQuaternion>,
+}
+
+impl EntityState {
+    pub fn capture(entity: &Entity) -> Option<Self> {
+        let player = entity.get_component(COMPONENT_TYPE_PLAYER.clone());
+        let component = player.or_else(|| entity.get_component(COMPONENT_TYPE_TRANSFORM.clone()))?;
+        Some(Self {
+            position: QuantisedVec3::new(vec3_parameter(component, "position")?),
+            rotation: QuantisedQuaternion::new(quaternion_parameter(component, "rotation")?),
+            scale: QuantisedVec3::new(
+                vec3_parameter(component, "scale").unwrap_or(Vec3::new(1.0, 1.0, 1.0))
+            ),
+            head_rotation: player
+                .and_then(|player| quaternion_parameter(player, "head_rotation"))
+                .map(QuantisedQuaternion::new),
+        })
+    }
+
+    fn delta_from(&self, entity: EntityId, baseline: Option<&EntityState>) -> Option<EntityDelta> {
+        fn changed<T: PartialEq>(ours: T, theirs: Option<T>) -> Option<T> {
+            if theirs.as_ref() == Some(&ours) { None } else { Some(ours) }
+        }
+        let delta = EntityDelta {
+            entity,
+            position: changed(self.position, baseline.map(|state| state.position)),
+            rotation: changed(self.rotation, baseline.map(|state| state.rotation)),
+            scale: changed(self.scale, baseline.map(|state| state.scale)),
+            head_rotation: match (self.head_rotation, baseline) {
+                (Some(ours), Some(baseline)) if baseline.head_rotation == Some(ours) => None,
+                (ours, _) => ours,
+            },
+        };
+        if baseline.is_some() && delta.is_empty() {
+            None
+        } else {
+            Some(delta)
+        }
+    }
+}
+
+fn vec3_parameter(component: &Component, name: &str) -> Option<Vec3> {
+    match component.parameters.get(name).map(|parameter| &parameter.value) {
+        Some(ParameterValue::Vec3(vector)) => Some(*vector),
+        _ => None,
+    }
+}
+
+fn quaternion_parameter(component: &Component, name: &str) -> Option<Quaternion> {
+    match component.parameters.get(name).map(|parameter| &parameter.value) {
+        Some(ParameterValue::Quaternion(rotation)) => Some(*rotation),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct EntityDelta {
+    pub entity: EntityId,
+    pub position: Option<QuantisedVec3>,
+    pub rotation: Option<QuantisedQuaternion>,
+    pub scale: Option<QuantisedVec3>,
+    pub head_rotation: Option<QuantisedQuaternion>,
+}
+
+impl EntityDelta {
+    fn is_empty(&self) -> bool {
+        self.position.is_none() &&
+            self.rotation.is_none() &&
+            self.scale.is_none() &&
+            self.head_rotation.is_none()
+    }
+
+    fn apply(&self, baseline: Option<&EntityState>) -> Option<EntityState> {
+        Some(EntityState {
+            position: self.position.or(baseline.map(|state| state.position))?,
+            rotation: self.rotation.or(baseline.map(|state| state.rotation))?,
+            scale: self.scale.or(baseline.map(|state| state.scale))?,
+            head_rotation: self.head_rotation.or(baseline.and_then(|state| state.head_rotation)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotDelta {
+    pub tick: u64,
+    pub time: f64,
+    pub baseline: Option<u64>,
+    pub part: u16,
+    pub parts: u16,
+    pub entities: Vec<EntityDelta>,
+    pub removed: Vec<EntityId>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplicationSnapshot {
+    pub tick: u64,
+    pub time: f64,
+    pub entities: BTreeMap<EntityId, EntityState>,
+}
+
+impl ReplicationSnapshot {
+    pub fn capture(tick: u64, entities: &[Entity]) -> Self {
+        let mut states = BTreeMap::new();
+        for entity in entities {
+            if let Some(state) = EntityState::capture(entity) {
+                states.entry(entity.uid).or_insert(state);
+            }
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |time| time.as_secs_f64());
+        Self { tick, time, entities: states }
+    }
+
+    pub fn filtered(&self, keep: impl Fn(EntityId) -> bool) -> Self {
+        Self {
+            tick: self.tick,
+            time: self.time,
+            entities: self.entities
+                .iter()
+                .filter(|(entity, _)| keep(**entity))
+                .map(|(entity, state)| (*entity, *state))
+                .collect(),
+        }
+    }
+
+    pub fn delta_from(
+        &self,
+        baseline: Option<&ReplicationSnapshot>,
+        viewer: Option<Vec3>
+    ) -> Vec<SnapshotDelta> {
+        let baseline_entities = baseline.map(|baseline| &baseline.entities);
+        let mut entities = self.entities
+            .iter()
+            .filter_map(|(entity, state)| {
+                state.delta_from(
+                    *entity,
+                    baseline_entities.and_then(|entities| entities.get(entity))
+                )
+            })
+            .collect::<Vec<_>>();
+        let removed = baseline_entities
+            .map(|entities| {
+                entities
+                    .keys()
+                    .filter(|entity| !self.entities.contains_key(entity))
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if baseline.is_some() && entities.is_empty() && removed.is_empty() {
+            return Vec::new();
+        }
+        if let Some(viewer) = viewer {
+            let distance = |delta: &EntityDelta| {
+                self.entities
+                    .get(&delta.entity)
+                    .map_or(f32::MAX, |state| (state.position.to_vec3() - viewer).magnitude())
+            };
+            entities.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+        }
+
+        // removals go in first, then the changes, and either may start a new part
+        let mut chunks: Vec<(Vec<EntityDelta>, Vec<EntityId>)> = vec![Default::default()];
+        let mut chunk_size = SNAPSHOT_HEADER_SIZE;
+        let mut make_room = |chunks: &mut Vec<(Vec<EntityDelta>, Vec<EntityId>)>, size: usize| {
+            let (entities, removed) = chunks.last().unwrap();
+            if chunk_size + size > MAX_SNAPSHOT_PAYLOAD && !(entities.is_empty() && removed.is_empty()) {
+                chunks.push(Default::default());
+                chunk_size = SNAPSHOT_HEADER_SIZE;
+            }
+            chunk_size += size;
+        };
+        for entity in removed {
+            make_room(&mut chunks, rmp_serde::to_vec(&entity).map_or(0, |data| data.len()));
+            chunks.last_mut().unwrap().1.push(entity);
+        }
+        for delta in entities {
+            make_room(&mut chunks, rmp_serde::to_vec(&delta).map_or(0, |data| data.len()));
+            chunks.last_mut().unwrap().0.push(delta);
+        }
+        let parts = chunks.len() as u16;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(part, (entities, removed))| SnapshotDelta {
+                tick: self.tick,
+                time: self.time,
+                baseline: baseline.map(|baseline| baseline.tick),
+                part: part as u16,
+                parts,
+                entities,
+                removed,
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub(super) struct ClientReplication {
+    sent: VecDeque<ReplicationSnapshot>,
+    acked: Option<u64>,
+    // rounds in a row this client was skipped for being over budget
+    skipped: u32,
+    pub(super) interest: ClientInterest,
+}
+
+impl ClientReplication {
+    // waiting raises a client's priority so a busy server can't starve it for good
+    fn effective_priority(&self) -> u32 {
+        (self.interest.area.priority as u32) + self.skipped
+    }
+}
+
+fn snapshot_size(deltas: &[SnapshotDelta]) -> usize {
+    deltas
+        .iter()
+        .map(|delta| rmp_serde::to_vec(delta).map_or(0, |data| data.len()))
+        .sum()
+}
+
+#[derive(Default)]
+pub struct ReplicationState {
+    tick: u64,
+    pub(super) clients: HashMap<ConnectionUUID, ClientReplication>,
+    pub(super) viewers: SpatialIndex<ConnectionUUID>,
+}
+
+impl Server {
+    pub async fn replicate(&self) {
+        let budget = self.config().network.replication_budget_bytes;
+        let worldmachine = self.worldmachine.lock().await;
+        let mut viewer_entities = HashMap::new();
+        if let Some(players) = &worldmachine.players {
+            for (uuid, player) in players.lock().await.iter() {
+                if let Some(entity_id) = player.entity_id {
+                    viewer_entities.insert(uuid.clone(), entity_id);
+                }
+            }
+        }
+        let mut replication = self.replication.lock().await;
+        replication.tick += 1;
+        let snapshot = ReplicationSnapshot::capture(replication.tick, &worldmachine.world.entities);
+        let relevancy = Relevancy::build(&worldmachine.world.entities, &snapshot);
+
+        let mut connections = self.get_all_connections().await;
+        replication.clients.retain(|uuid, _| {
+            connections.iter().any(|connection| connection.uuid() == *uuid)
+        });
+        connections.sort_by_key(|connection| {
+            std::cmp::Reverse(
+                replication.clients
+                    .get(&connection.uuid())
+                    .map_or(0, |client| client.effective_priority())
+            )
+        });
+        replication.viewers = SpatialIndex::new();
+        let mut outgoing = Vec::new();
+        let mut spent = 0;
+        for connection in connections {
+            let uuid = connection.uuid();
+            let viewer = viewer_entities
+                .get(&uuid)
+                .and_then(|entity_id| snapshot.entities.get(entity_id))
+                .map(|state| state.position.to_vec3());
+            if let Some(viewer) = viewer {
+                replication.viewers.insert(uuid.clone(), viewer);
+            }
+            let client = replication.clients.entry(uuid).or_default();
+            let relevant = match viewer {
+                Some(viewer) => relevancy.relevant_to(viewer, client.interest.area.radius),
+                None => Default::default(),
+            };
+
+            let mut steady = Vec::new();
+            for entity in worldmachine.world.entities.iter() {
+                if !relevancy.is_managed(entity.uid) {
+                    continue;
+                }
+                let is_relevant = relevant.contains(&entity.uid);
+                let is_known = client.interest.known.contains(&entity.uid);
+                if is_relevant && !is_known {
+                    client.interest.known.insert(entity.uid);
+                    steady.push(SteadyPacket::InitialiseEntity(entity.uid, entity.clone()));
+                } else if !is_relevant && is_known {
+                    client.interest.known.remove(&entity.uid);
+                    steady.push(SteadyPacket::RemoveEntity(entity.uid));
+                }
+            }
+
+            let visible = snapshot.filtered(|entity| {
+                !relevancy.is_managed(entity) || relevant.contains(&entity)
+            });
+            let baseline = client.acked.and_then(|acked| {
+                client.sent.iter().find(|sent| sent.tick == acked)
+            });
+            let mut deltas = visible.delta_from(baseline, viewer);
+            let size = snapshot_size(&deltas);
+            if budget > 0 && spent > 0 && spent + size > budget {
+                // it catches up from its last acked snapshot next round
+                client.skipped += 1;
+                deltas.clear();
+            } else {
+                spent += size;
+                client.skipped = 0;
+            }
+            if !deltas.is_empty() {
+                client.sent.push_back(visible);
+                if client.sent.len() > SNAPSHOT_HISTORY {
+                    client.sent.pop_front();
+                }
+            }
+            outgoing.push((connection, steady, deltas));
+        }
+        drop(replication);
+        drop(worldmachine);
+
+        for (connection, steady, deltas) in outgoing {
+            for packet in steady {
+                self.send_steady_packet(&connection, packet).await;
+            }
+            for delta in deltas {
+                self.send_fast_packet(&connection, FastPacket::Snapshot(delta)).await;
+            }
+        }
+    }
+
+    pub async fn acknowledge_snapshot(&self, uuid: &ConnectionUUID, tick: u64) {
+        let mut replication = self.replication.lock().await;
+        if let Some(client) = replication.clients.get_mut(uuid) {
+            if client.acked.is_none_or(|acked| tick > acked) {
+                client.acked = Some(tick);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AppliedSnapshot {
+    pub tick: u64,
+    pub time: f64,
+    pub changed: Vec<(EntityId, EntityState)>,
+    pub entities: BTreeMap<EntityId, EntityState>,
+}
+
+#[derive(Default)]
+pub struct SnapshotReceiver {
+    history: VecDeque<ReplicationSnapshot>,
+    partial: BTreeMap<u64, Vec<Option<SnapshotDelta>>>,
+}
+
+impl SnapshotReceiver {
+    pub fn receive(&mut self, delta: SnapshotDelta) -> Option<AppliedSnapshot> {
+        let latest = self.history.back().map(|snapshot| snapshot.tick);
+        if latest.is_some_and(|latest| delta.tick <= latest) {
+            return None;
+        }
+        if delta.parts == 0 || delta.part >= delta.parts {
+            return None;
+        }
+        let tick = delta.tick;
+        if !self.partial.contains_key(&tick) && self.partial.len() >= SNAPSHOT_HISTORY {
+            self.partial.pop_first();
+        }
+        let parts = self.partial.entry(tick).or_insert_with(|| vec![None; delta.parts as usize]);
+        if parts.len() != (delta.parts as usize) {
+            return None;
+        }
+        let part = delta.part as usize;
+        parts[part] = Some(delta);
+        if parts.iter().any(|part| part.is_none()) {
+            return None;
+        }
+        let parts = self.partial.remove(&tick)?;
+        self.partial.retain(|partial, _| *partial > tick);
+        let parts = parts.into_iter().flatten().collect::<Vec<_>>();
+        let time = parts[0].time;
+
+        let baseline = match parts[0].baseline {
+            Some(baseline) => Some(self.history.iter().find(|snapshot| snapshot.tick == baseline)?),
+            None => None,
+        };
+        let mut entities = baseline.map(|baseline| baseline.entities.clone()).unwrap_or_default();
+        for part in parts.iter() {
+            for entity in part.removed.iter() {
+                entities.remove(entity);
+            }
+            for delta in part.entities.iter() {
+                if let Some(state) = delta.apply(entities.get(&delta.entity)) {
+                    entities.insert(delta.entity, state);
+                }
+            }
+        }
+
+        let previous = self.history.back().map(|snapshot| &snapshot.entities);
+        let changed = entities
+            .iter()
+            .filter(|(entity, state)| previous.and_then(|previous| previous.get(entity)) != Some(state))
+            .map(|(entity, state)| (*entity, *state))
+            .collect();
+        self.history.push_back(ReplicationSnapshot { tick, time, entities: entities.clone() });
+        if self.history.len() > SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+        Some(AppliedSnapshot { tick, time, changed, entities })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same_rotation(a: Quaternion, b: Quaternion) -> bool {
+        // q and -q are the same rotation
+        let dot = a.x * b.x + a.y * b.y + a.z * b.z + a.w * b.w;
+        dot.abs() > 0.9999
+    }
+
+    #[test]
+    fn positions_round_trip_to_within_half_a_step() {
+        for position in [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.3, -7.25, 1000.001),
+            Vec3::new(-4096.7, 0.004, 12.5),
+        ] {
+            let restored = QuantisedVec3::new(position).to_vec3();
+            for (original, restored) in [
+                (position.x, restored.x),
+                (position.y, restored.y),
+                (position.z, restored.z),
+            ] {
+                assert!((original - restored).abs() <= 0.5 / POSITION_SCALE, "{} became {}", original, restored);
+            }
+        }
+    }
+
+    #[test]
+    fn rotations_round_trip_whichever_component_is_largest() {
+        let rotations = [
+            Quaternion::identity(),
+            Quaternion::from_euler_radians_zyx(&Vec3::new(0.3, 2.9, -1.2)),
+            Quaternion::from_euler_radians_zyx(&Vec3::new(-3.0, 0.1, 0.7)),
+            Quaternion::new(0.9, -0.1, 0.3, 0.2),
+            Quaternion::new(0.0, 0.0, -1.0, 0.0),
+        ];
+        for rotation in rotations {
+            let length = (rotation.x * rotation.x +
+                rotation.y * rotation.y +
+                rotation.z * rotation.z +
+                rotation.w * rotation.w).sqrt();
+            let normalised = Quaternion::new(
+                rotation.x / length,
+                rotation.y / length,
+                rotation.z / length,
+                rotation.w / length
+            );
+            let restored = QuantisedQuaternion::new(rotation).to_quaternion();
+            assert!(same_rotation(normalised, restored), "{:?} became {:?}", rotation, restored);
+        }
+    }
+
+    #[test]
+    fn degenerate_rotations_become_the_identity() {
+        let zero = QuantisedQuaternion::new(Quaternion::new(0.0, 0.0, 0.0, 0.0));
+        assert_eq!(zero, QuantisedQuaternion::new(Quaternion::identity()));
+        assert!(same_rotation(zero.to_quaternion(), Quaternion::identity()));
+    }
+
+    fn state(x: f32) -> EntityState {
+        EntityState {
+            position: QuantisedVec3::new(Vec3::new(x, 0.0, 0.0)),
+            rotation: QuantisedQuaternion::new(Quaternion::identity()),
+            scale: QuantisedVec3::new(Vec3::new(1.0, 1.0, 1.0)),
+            head_rotation: None,
+        }
+    }
+
+    fn snapshot(tick: u64, entities: impl IntoIterator<Item = (EntityId, EntityState)>) -> ReplicationSnapshot {
+        ReplicationSnapshot {
+            tick,
+            time: tick as f64,
+            entities: entities.into_iter().collect(),
+        }
+    }
+
+    fn fits(deltas: &[SnapshotDelta]) -> bool {
+        deltas.iter().all(|delta| rmp_serde::to_vec(delta).unwrap().len() <= MAX_SNAPSHOT_PAYLOAD)
+    }
+
+    #[test]
+    fn deltas_only_carry_what_changed_and_apply_back_to_the_full_state() {
+        let first = snapshot(1, [(1, state(0.0)), (2, state(1.0)), (3, state(2.0))]);
+        let second = snapshot(2, [(1, state(0.0)), (2, state(5.0)), (4, state(3.0))]);
+        let mut receiver = SnapshotReceiver::default();
+
+        let full = first.delta_from(None, None);
+        assert_eq!(full.len(), 1);
+        assert_eq!(full[0].entities.len(), 3);
+        assert_eq!(receiver.receive(full[0].clone()).unwrap().entities, first.entities);
+
+        let deltas = second.delta_from(Some(&first), None);
+        assert_eq!(deltas.len(), 1);
+        let changed = deltas[0].entities.iter().map(|delta| delta.entity).collect::<Vec<_>>();
+        assert_eq!(changed, vec![2, 4]);
+        assert!(deltas[0].entities[0].rotation.is_none() && deltas[0].entities[0].position.is_some());
+        assert_eq!(deltas[0].removed, vec![3]);
+        let applied = receiver.receive(deltas[0].clone()).unwrap();
+        assert_eq!(applied.entities, second.entities);
+        let changed = applied.changed.iter().map(|(entity, _)| *entity).collect::<Vec<_>>();
+        assert_eq!(changed, vec![2, 4]);
+
+        assert!(snapshot(3, second.entities.clone()).delta_from(Some(&second), None).is_empty());
+    }
+
+    #[test]
+    fn snapshots_split_into_parts_are_put_back_together() {
+        let big = snapshot(1, (0..300).map(|entity| (entity, state(entity as f32))));
+        let deltas = big.delta_from(None, None);
+        assert!(deltas.len() > 1);
+        assert!(fits(&deltas));
+
+        let mut receiver = SnapshotReceiver::default();
+        let (last, rest) = deltas.split_last().unwrap();
+        for delta in rest.iter().rev() {
+            assert!(receiver.receive(delta.clone()).is_none());
+        }
+        assert_eq!(receiver.receive(last.clone()).unwrap().entities, big.entities);
+    }
+
+    #[test]
+    fn removals_are_split_across_parts_too() {
+        let big = snapshot(1, (1000..3000).map(|entity| (entity, state(0.0))));
+        let empty = snapshot(2, []);
+        let mut receiver = SnapshotReceiver::default();
+        for delta in big.delta_from(None, None) {
+            receiver.receive(delta);
+        }
+
+        let deltas = empty.delta_from(Some(&big), None);
+        assert!(deltas.len() > 1);
+        assert!(fits(&deltas));
+        assert_eq!(deltas.iter().map(|delta| delta.removed.len()).sum::<usize>(), 2000);
+        let applied = deltas.into_iter().filter_map(|delta| receiver.receive(delta)).last().unwrap();
+        assert!(applied.entities.is_empty());
+    }
+
+    #[test]
+    fn nearer_entities_go_out_first_and_waiting_clients_move_up() {
+        let entities = snapshot(1, [(1, state(50.0)), (2, state(-2.0)), (3, state(10.0))]);
+        let deltas = entities.delta_from(None, Some(Vec3::zero()));
+        let order = deltas[0].entities.iter().map(|delta| delta.entity).collect::<Vec<_>>();
+        assert_eq!(order, vec![2, 3, 1]);
+
+        let mut urgent = ClientReplication::default();
+        urgent.interest.area.priority = 2;
+        let mut patient = ClientReplication::default();
+        assert!(urgent.effective_priority() > patient.effective_priority());
+        patient.skipped = 3;
+        assert!(patient.effective_priority() > urgent.effective_priority());
+    }
+
+    #[test]
+    fn deltas_against_an_unknown_baseline_are_dropped() {
+        let first = snapshot(1, [(1, state(0.0))]);
+        let second = snapshot(2, [(1, state(1.0))]);
+        let third = snapshot(3, [(1, state(2.0))]);
+        let mut receiver = SnapshotReceiver::default();
+        receiver.receive(first.delta_from(None, None).remove(0)).unwrap();
+
+        // the client never saw tick 2, so a delta against it can't be applied
+        assert!(receiver.receive(third.delta_from(Some(&second), None).remove(0)).is_none());
+        assert!(receiver.receive(third.delta_from(Some(&first), None).remove(0)).is_some());
+    }
+}
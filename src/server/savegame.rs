@@ -0,0 +1,279 @@
+use crate::physics::PhysicsSystem;
+use crate::server::transport::Connection;
+use crate::server::server_player::ServerPlayerContainer;
+use crate::server::{ Server, SteadyPacket };
+use crate::worldmachine::components::COMPONENT_TYPE_PLAYER;
+use crate::worldmachine::ecs::{ Entity, System };
+use crate::worldmachine::{ MapLoadError, WorldMachine };
+use gfx_maths::*;
+use halfbrown::HashMap;
+use mutex_timeouts::tokio::MutexWithTimeoutAuto as Mutex;
+use serde::{ Deserialize, Serialize };
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+pub const SAVE_GAME_VERSION: u32 = 2;
+pub const SAVES_DIR: &str = "saves";
+pub const SAVE_FILE_EXTENSION: &str = "sav";
+pub const QUICKSAVE_NAME: &str = "quicksave";
+
+lazy_static! {
+    static ref SESSION_REQUESTS: std::sync::Mutex<VecDeque<SessionRequest>> = std::sync::Mutex::new(
+        VecDeque::new()
+    );
+}
+
+#[derive(Clone, Debug)]
+pub enum SessionRequest {
+    Save(String),
+    Load(String),
+    JoinLan(IpAddr, u16, u16),
+    Reconnect,
+}
+
+pub fn request_session(request: SessionRequest) {
+    SESSION_REQUESTS.lock().unwrap().push_back(request);
+}
+
+pub fn take_session_request() -> Option<SessionRequest> {
+    SESSION_REQUESTS.lock().unwrap().pop_front()
+}
+
+#[derive(Clone, Debug)]
+pub enum SaveGameError {
+    Io(String),
+    Serialisation(String),
+    UnsupportedVersion(u32),
+    InvalidName(String),
+    MapLoad(MapLoadError),
+}
+
+// who a saved player belongs to, so whoever joins first can't walk off with someone else's save
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PlayerSaveOwner {
+    // whoever plays on the machine hosting the server
+    Host,
+    Account(String),
+    // players without an account get theirs back by taking the same name again
+    Name(String),
+}
+
+impl PlayerSaveOwner {
+    pub fn of(connection: &Connection, name: &str) -> Self {
+        match connection.identity() {
+            Some(identity) => PlayerSaveOwner::Account(identity),
+            None if connection.is_host() => PlayerSaveOwner::Host,
+            None => PlayerSaveOwner::Name(name.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerSave {
+    pub owner: PlayerSaveOwner,
+    pub name: String,
+    pub position: Vec3,
+    pub rotation: Quaternion,
+    pub head_rotation: Quaternion,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SaveGame {
+    pub version: u32,
+    pub engine_version: String,
+    pub saved_at: u64,
+    pub map_name: String,
+    pub entities: Vec<Entity>,
+    pub systems: Vec<System>,
+    pub players: Vec<PlayerSave>,
+}
+
+impl SaveGame {
+    pub fn path_for(name: &str) -> Result<String, SaveGameError> {
+        if
+            name.is_empty() ||
+            !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(SaveGameError::InvalidName(name.to_string()));
+        }
+        Ok(format!("{}/{}.{}", SAVES_DIR, name, SAVE_FILE_EXTENSION))
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, SaveGameError> {
+        rmp_serde::to_vec(self).map_err(|e| SaveGameError::Serialisation(e.to_string()))
+    }
+
+    pub fn decode(data: impl std::io::Read) -> Result<Self, SaveGameError> {
+        let mut deserializer = rmp_serde::Deserializer::new(data);
+        let save: SaveGame = Deserialize::deserialize(&mut deserializer).map_err(|e|
+            SaveGameError::Serialisation(e.to_string())
+        )?;
+        if save.version != SAVE_GAME_VERSION {
+            return Err(SaveGameError::UnsupportedVersion(save.version));
+        }
+        Ok(save)
+    }
+
+    pub fn write(&self, name: &str) -> Result<String, SaveGameError> {
+        let path = SaveGame::path_for(name)?;
+        std::fs::create_dir_all(SAVES_DIR).map_err(|e| SaveGameError::Io(e.to_string()))?;
+        let data = self.encode()?;
+        let temp_path = format!("{}.tmp", path);
+        std::fs::write(&temp_path, data).map_err(|e| SaveGameError::Io(e.to_string()))?;
+        std::fs::rename(&temp_path, &path).map_err(|e| SaveGameError::Io(e.to_string()))?;
+        Ok(path)
+    }
+
+    pub fn read(name: &str) -> Result<Self, SaveGameError> {
+        let path = SaveGame::path_for(name)?;
+        let file = std::fs::File::open(&path).map_err(|e| SaveGameError::Io(e.to_string()))?;
+        SaveGame::decode(file)
+    }
+}
+
+impl Server {
+    pub async fn capture_save_game(&self) -> SaveGame {
+        let worldmachine = self.worldmachine.lock().await;
+        let entities = worldmachine.world.entities
+            .iter()
+            .filter(|entity| !entity.has_component(COMPONENT_TYPE_PLAYER.clone()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let systems = worldmachine.world.systems.clone();
+        let map_name = worldmachine.current_map();
+        let players = worldmachine.players.clone();
+        drop(worldmachine);
+
+        let bot_uuids = self.list_bots().await
+            .into_iter()
+            .map(|bot| bot.uuid)
+            .collect::<Vec<_>>();
+        let mut player_saves = Vec::new();
+        if let Some(players) = players {
+            let players = players.lock().await.clone();
+            for (uuid, player) in players.iter() {
+                if bot_uuids.contains(uuid) {
+                    continue;
+                }
+                let name = player.player.name.lock().await.clone();
+                player_saves.push(PlayerSave {
+                    owner: PlayerSaveOwner::of(&player.connection, &name),
+                    name,
+                    position: player.player.get_position(None, None).await,
+                    rotation: player.player.get_rotation(None, None).await,
+                    head_rotation: player.player.get_head_rotation(None, None).await,
+                });
+            }
+        }
+
+        SaveGame {
+            version: SAVE_GAME_VERSION,
+            engine_version: env!("CARGO_PKG_VERSION").to_string(),
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            map_name,
+            entities,
+            systems,
+            players: player_saves,
+        }
+    }
+
+    pub async fn save_game(&self, name: &str) -> Result<String, SaveGameError> {
+        let save = self.capture_save_game().await;
+        let path = save.write(name)?;
+        info!("saved game to {}", path);
+        Ok(path)
+    }
+
+    // for players that only turn out to own a save after they've spawned
+    pub async fn restore_player_state(&self, player: &ServerPlayerContainer, saved_state: &PlayerSave) {
+        player.player.respawning.store(true, Ordering::Relaxed);
+        let packet = SteadyPacket::Respawn(saved_state.position);
+        self.send_steady_packet(&player.connection, packet).await;
+        let mut worldmachine = self.worldmachine.lock().await;
+        player.player.set_rotation(saved_state.rotation, player.entity_id, &mut worldmachine).await;
+        player.player
+            .set_head_rotation(saved_state.head_rotation, player.entity_id, &mut worldmachine).await;
+        player.player.set_position(saved_state.position, player.entity_id, &mut worldmachine).await;
+        drop(worldmachine);
+        player.player.respawning.store(false, Ordering::Relaxed);
+    }
+
+    pub fn new_from_save(save: &SaveGame, physics: PhysicsSystem) -> Result<Self, SaveGameError> {
+        let mut worldmachine = WorldMachine::default();
+        worldmachine.initialise(physics, true);
+        worldmachine
+            .load_saved_world(&save.map_name, save.entities.clone(), save.systems.clone())
+            .map_err(SaveGameError::MapLoad)?;
+
+        let mut server = Server::from_worldmachine(worldmachine);
+        let players = save.players
+            .iter()
+            .map(|player| (player.owner.clone(), player.clone()))
+            .collect::<HashMap<_, _>>();
+        server.pending_player_states = Arc::new(Mutex::new(players));
+        info!("server restored from save of {}", save.map_name);
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worldmachine::components::Transform;
+
+    fn save_game(version: u32) -> SaveGame {
+        let crate_entity = Entity {
+            name: "crate".to_string(),
+            uid: 7,
+            components: vec![
+                Transform::new(Vec3::new(1.0, 2.0, 3.0), Quaternion::identity(), Vec3::new(1.0, 1.0, 1.0))
+            ],
+            children: Vec::new(),
+            parent: None,
+        };
+        SaveGame {
+            version,
+            engine_version: env!("CARGO_PKG_VERSION").to_string(),
+            saved_at: 1_700_000_000,
+            map_name: "lava".to_string(),
+            entities: vec![crate_entity],
+            systems: vec![System {
+                name: "spinner".to_string(),
+                uid: 1,
+                affected_entities: vec![7],
+            }],
+            players: vec![PlayerSave {
+                owner: PlayerSaveOwner::Account("alice".to_string()),
+                name: "alice".to_string(),
+                position: Vec3::new(4.0, 5.0, 6.0),
+                rotation: Quaternion::identity(),
+                head_rotation: Quaternion::identity(),
+            }],
+        }
+    }
+
+    #[test]
+    fn saves_round_trip() {
+        let save = save_game(SAVE_GAME_VERSION);
+        let loaded = SaveGame::decode(&save.encode().unwrap()[..]).unwrap();
+        assert_eq!(loaded.map_name, "lava");
+        assert_eq!(loaded.saved_at, save.saved_at);
+        assert_eq!(loaded.entities.len(), 1);
+        assert_eq!(loaded.entities[0].uid, 7);
+        assert_eq!(loaded.entities[0].components, save.entities[0].components);
+        assert_eq!(loaded.systems[0].affected_entities, vec![7]);
+        assert_eq!(loaded.players.len(), 1);
+        assert_eq!(loaded.players[0].owner, PlayerSaveOwner::Account("alice".to_string()));
+        assert_eq!(loaded.players[0].position, Vec3::new(4.0, 5.0, 6.0));
+    }
+
+    #[test]
+    fn saves_from_other_versions_are_rejected() {
+        let save = save_game(SAVE_GAME_VERSION + 1);
+        let loaded = SaveGame::decode(&save.encode().unwrap()[..]);
+        assert!(matches!(loaded, Err(SaveGameError::UnsupportedVersion(version)) if version == SAVE_GAME_VERSION + 1));
+    }
+}
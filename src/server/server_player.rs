@@ -2,14 +2,19 @@ use std::sync::{ Arc };
 use mutex_timeouts::tokio::MutexWithTimeoutAuto as Mutex;
 use std::sync::atomic::AtomicBool;
 use gfx_maths::*;
+use tokio::sync::Notify;
 use tokio::time::Instant;
 use crate::helpers;
 use crate::physics::{ ClimbingMode, Materials, PhysicsCharacterController, PhysicsSystem };
-use crate::server::{ Connection, Server };
+use crate::server::config::GameplayConfig;
+use crate::server::disconnect::DisconnectReason;
+use crate::server::transport::Connection;
+use crate::server::{ generate_uuid, Server };
 use crate::worldmachine::{ EntityId, WorldMachine, WorldUpdate };
 use crate::worldmachine::components::COMPONENT_TYPE_PLAYER;
 use crate::worldmachine::ecs::ParameterValue;
-use crate::worldmachine::player::MovementInfo;
+use crate::server::replication::REPLICATION_INTERVAL;
+use crate::worldmachine::prediction::{ is_newer_sequence, InputAck, InputCommand, RECONCILE_TOLERANCE };
 
 pub const DEFAULT_MOVESPEED: f32 = 8.15;
 pub const DEFAULT_SPRINTSPEED: f32 = 14.4;
@@ -17,8 +22,10 @@ pub const DEFAULT_RADIUS: f32 = 1.3;
 pub const DEFAULT_HEIGHT: f32 = 1.7;
 pub const DEFAULT_STEPHEIGHT: f32 = 0.5;
 
-pub const ERROR_MARGIN: f32 = 5.0;
 pub const MAX_HEIGHT_BEFORE_FLIGHT: f32 = 15.0;
+pub const MAX_INPUT_DELTA: f32 = 0.1;
+pub const MAX_INPUT_BUDGET: f32 = 0.25;
+pub const MAX_PENDING_INPUTS: usize = 128;
 
 #[derive(Clone)]
 pub struct ServerPlayerContainer {
@@ -37,6 +44,16 @@ struct PlayerPhysics {
     last_move_call: Instant,
     height_gained_since_grounded: f32,
     last_height: f32,
+    last_input_sequence: Option<u32>,
+    input_budget: f32,
+    last_budget_update: Instant,
+    last_ack: Instant,
+}
+
+pub enum InputOutcome {
+    Skipped,
+    Accepted(InputAck),
+    Corrected(InputAck),
 }
 
 impl Default for PlayerPhysics {
@@ -51,6 +68,10 @@ impl Default for PlayerPhysics {
             last_move_call: Instant::now(),
             height_gained_since_grounded: 0.0,
             last_height: 0.0,
+            last_input_sequence: None,
+            input_budget: 0.0,
+            last_budget_update: Instant::now(),
+            last_ack: Instant::now(),
         }
     }
 }
@@ -61,8 +82,13 @@ pub struct ServerPlayer {
     pub name: Arc<Mutex<String>>,
     physics: Arc<Mutex<PlayerPhysics>>,
     pub tball_cooldown: Arc<Mutex<f32>>,
+    pending_inputs: Arc<Mutex<Vec<InputCommand>>>,
     pub pinging: Arc<AtomicBool>,
     pub respawning: Arc<AtomicBool>,
+    pub disconnect_reason: Arc<Mutex<Option<DisconnectReason>>>,
+    pub disconnect_requested: Arc<Notify>,
+    // lets a reconnecting client take this player back over
+    pub resume_token: Arc<String>,
 }
 
 impl Default for ServerPlayer {
@@ -72,8 +98,12 @@ impl Default for ServerPlayer {
             name: Arc::new(Mutex::new("".to_string())),
             physics: Arc::new(Mutex::new(PlayerPhysics::default())),
             tball_cooldown: Arc::new(Mutex::new(0.0)),
+            pending_inputs: Arc::new(Mutex::new(Vec::new())),
             pinging: Arc::new(AtomicBool::new(false)),
             respawning: Arc::new(AtomicBool::new(false)),
+            disconnect_reason: Arc::new(Mutex::new(None)),
+            disconnect_requested: Arc::new(Notify::new()),
+            resume_token: Arc::new(generate_uuid()),
         }
     }
 }
@@ -92,11 +122,28 @@ impl ServerPlayer {
                 })
             ),
             tball_cooldown: Arc::new(Mutex::new(0.0)),
+            pending_inputs: Arc::new(Mutex::new(Vec::new())),
             pinging: Arc::new(AtomicBool::new(false)),
             respawning: Arc::new(AtomicBool::new(false)),
+            disconnect_reason: Arc::new(Mutex::new(None)),
+            disconnect_requested: Arc::new(Notify::new()),
+            resume_token: Arc::new(generate_uuid()),
+        }
+    }
+
+    // the first reason wins, a kick shouldn't turn into a timeout before the connection task sees it
+    pub async fn request_disconnect(&self, reason: DisconnectReason) {
+        let mut disconnect_reason = self.disconnect_reason.lock().await;
+        if disconnect_reason.is_none() {
+            *disconnect_reason = Some(reason);
+            self.disconnect_requested.notify_one();
         }
     }
 
+    pub async fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect_reason.lock().await.clone()
+    }
+
     pub async fn init(&self, physics_system: PhysicsSystem) {
         let mut physics = self.physics.lock().await;
         physics.physics_controller = physics_system.create_character_controller(
@@ -110,136 +157,113 @@ impl ServerPlayer {
         }
     }
 
-    pub async fn attempt_position_change(
-        &self,
-        new_position: Vec3,
-        displacement_vector: Vec3,
-        new_rotation: Quaternion,
-        new_head_rotation: Quaternion,
-        movement_info: MovementInfo,
-        entity_id: Option<EntityId>,
-        worldmachine: Arc<mutex_timeouts::tokio::MutexWithTimeoutAuto<WorldMachine>>
-    ) -> (bool, Option<Vec3>) {
-
-        let last_position = self.get_position(None, None).await;
-
-        if new_position.x.is_nan() || new_position.y.is_nan() || new_position.z.is_nan() {
-            return (false, Some(last_position));
-        }
-
-        if
-            new_rotation.x.is_nan() ||
-            new_rotation.y.is_nan() ||
-            new_rotation.z.is_nan() ||
-            new_rotation.w.is_nan()
-        {
-            return (false, Some(last_position));
+    pub async fn queue_inputs(&self, commands: Vec<InputCommand>) {
+        let mut pending = self.pending_inputs.lock().await;
+        pending.extend(commands);
+        if pending.len() > MAX_PENDING_INPUTS {
+            let excess = pending.len() - MAX_PENDING_INPUTS;
+            pending.drain(..excess);
         }
+    }
 
-        if
-            new_head_rotation.x.is_nan() ||
-            new_head_rotation.y.is_nan() ||
-            new_head_rotation.z.is_nan() ||
-            new_head_rotation.w.is_nan()
-        {
-            return (false, Some(last_position));
-        }
+    pub async fn take_pending_inputs(&self) -> Vec<InputCommand> {
+        std::mem::take(&mut *self.pending_inputs.lock().await)
+    }
 
-        if
-            displacement_vector.x.is_nan() ||
-            displacement_vector.y.is_nan() ||
-            displacement_vector.z.is_nan()
-        {
-            return (false, Some(last_position));
+    pub async fn apply_input(
+        &self,
+        command: InputCommand,
+        entity_id: Option<EntityId>,
+        worldmachine: Arc<mutex_timeouts::tokio::MutexWithTimeoutAuto<WorldMachine>>,
+        gameplay: &GameplayConfig
+    ) -> InputOutcome {
+        let mut physics_guard = self.physics.lock().await;
+        let physics = &mut *physics_guard;
+        if physics.last_input_sequence.is_some_and(|last| !is_newer_sequence(command.sequence, last)) {
+            return InputOutcome::Skipped;
         }
+        physics.last_input_sequence = Some(command.sequence);
 
-        if movement_info.speed.is_nan() || movement_info.strafe.is_nan() {
-            return (false, Some(last_position));
-        }
+        let now = Instant::now();
+        physics.input_budget = (
+            physics.input_budget + now.duration_since(physics.last_budget_update).as_secs_f32()
+        ).min(MAX_INPUT_BUDGET);
+        physics.last_budget_update = now;
 
-        let mut physics = self.physics.lock().await;
+        let delta_time = command.delta_time.clamp(0.0, MAX_INPUT_DELTA);
+        let frame_delta = command.frame_delta.clamp(0.0, MAX_INPUT_DELTA);
+        let mut valid = command.is_finite() && frame_delta <= physics.input_budget;
 
-        if movement_info.sprinting {
-            physics.movement_speed = DEFAULT_SPRINTSPEED;
+        physics.movement_speed = if command.movement_info.sprinting {
+            gameplay.sprint_speed
         } else {
-            physics.movement_speed = DEFAULT_MOVESPEED;
+            gameplay.move_speed
+        };
+        let physics_controller = match physics.physics_controller.as_mut() {
+            Some(physics_controller) => physics_controller,
+            None => {
+                return InputOutcome::Skipped;
+            }
+        };
+        if valid {
+            physics.input_budget -= frame_delta;
+            let mut movement = command.movement;
+            movement.y = 0.0;
+            movement = helpers::clamp_magnitude(movement, physics.movement_speed * frame_delta);
+            physics_controller.move_by(
+                movement,
+                command.movement_info.jumped,
+                None,
+                false,
+                delta_time,
+                frame_delta
+            );
+            physics.last_move_call = now;
         }
 
-        let mut displacement_vector = displacement_vector;
-        displacement_vector.y = 0.0;
-        displacement_vector = helpers::clamp_magnitude(
-            displacement_vector,
-            1.0 * physics.movement_speed
-        );
-
-        let current_time = Instant::now();
-        let last_move_call = physics.last_move_call;
-        let delta = current_time.duration_since(last_move_call.clone()).as_secs_f32();
-        displacement_vector *= delta;
-        if delta >= 0.01 {
-            physics.last_move_call = current_time;
-            let _final_movement = physics.physics_controller
-                .as_mut()
-                .unwrap()
-                .move_by(
-                    displacement_vector,
-                    movement_info.jumped,
-                    Some(false),
-                    false,
-                    delta,
-                    delta
-                );
-        }
-        let current_time = Instant::now();
-        let delta = current_time
-            .duration_since(Instant::from(worldmachine.lock().await.last_physics_update))
-            .as_secs_f32();
-        if delta >= 0.01 {
-            worldmachine.lock().await.physics.lock().unwrap().as_mut().unwrap().tick(delta);
-            worldmachine.lock().await.last_physics_update = std::time::Instant::from(current_time);
-        }
-        let new_position_calculated = physics.physics_controller
-            .as_mut()
-            .unwrap()
-            .get_foot_position();
-        let distance = helpers::distance(new_position_calculated, new_position);
-        if !physics.physics_controller.as_ref().unwrap().is_on_ground() {
-            physics.height_gained_since_grounded += physics.last_height - new_position_calculated.y;
+        let mut position = physics_controller.get_foot_position();
+        if !physics_controller.is_on_ground() {
+            physics.height_gained_since_grounded += physics.last_height - position.y;
         } else {
             physics.height_gained_since_grounded = 0.0;
         }
-        physics.last_height = new_position_calculated.y;
-
+        physics.last_height = position.y;
         if physics.height_gained_since_grounded > MAX_HEIGHT_BEFORE_FLIGHT {
             warn!("player {} is flying", self.uuid);
-            return (false, Some(last_position));
+            physics_controller.set_foot_position(physics.position);
+            position = physics.position;
+            valid = false;
+        }
+        let vertical_velocity = physics_controller.get_y_velocity();
+        drop(physics_guard);
+
+        let mut wm = worldmachine.lock().await;
+        self.set_position(position, entity_id, &mut wm).await;
+        if valid {
+            self.set_rotation(command.rotation, entity_id, &mut wm).await;
+            self.set_head_rotation(command.head_rotation, entity_id, &mut wm).await;
+        }
+        drop(wm);
+
+        let ack = InputAck {
+            sequence: command.sequence,
+            position,
+            vertical_velocity,
+        };
+        if valid && helpers::distance(command.position, position) <= RECONCILE_TOLERANCE {
+            InputOutcome::Accepted(ack)
+        } else {
+            InputOutcome::Corrected(ack)
         }
+    }
 
-        drop(physics);
-
-        if distance < ERROR_MARGIN {
-            let mut wm = worldmachine.lock().await;
-            self.set_position(new_position, entity_id, &mut wm).await;
-            drop(wm);
-            let mut wm = worldmachine.lock().await;
-            self.set_rotation(new_rotation, entity_id, &mut wm).await;
-            drop(wm);
-            let mut wm = worldmachine.lock().await;
-            self.set_head_rotation(new_head_rotation, entity_id, &mut wm).await;
-            drop(wm);
-            (true, None)
+    pub async fn should_acknowledge(&self, corrected: bool) -> bool {
+        let mut physics = self.physics.lock().await;
+        if corrected || physics.last_ack.elapsed() >= REPLICATION_INTERVAL {
+            physics.last_ack = Instant::now();
+            true
         } else {
-            let mut wm = worldmachine.lock().await;
-            self.set_position(new_position_calculated, entity_id, &mut wm).await;
-            drop(wm);
-            let mut wm = worldmachine.lock().await;
-            self.set_rotation(new_rotation, entity_id, &mut wm).await;
-            drop(wm);
-            let mut wm = worldmachine.lock().await;
-            self.set_head_rotation(new_head_rotation, entity_id, &mut wm).await;
-            drop(wm);
-            let position = self.get_position(None, None).await;
-            (false, Some(position))
+            false
         }
     }
 
@@ -296,6 +320,21 @@ impl ServerPlayer {
         }
     }
 
+    pub async fn get_vertical_velocity(&self) -> f32 {
+        let physics = self.physics.lock().await;
+        match physics.physics_controller.as_ref() {
+            Some(physics_controller) => physics_controller.get_y_velocity(),
+            None => 0.0,
+        }
+    }
+
+    pub async fn set_vertical_velocity(&self, velocity: f32) {
+        let physics = self.physics.lock().await;
+        if let Some(physics_controller) = physics.physics_controller.as_ref() {
+            physics_controller.set_y_velocity(velocity);
+        }
+    }
+
     pub async fn set_rotation(
         &self,
         rotation: Quaternion,
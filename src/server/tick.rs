@@ -0,0 +1,184 @@
+use crate::server::config::ServerConfig;
+use crate::server::disconnect::{ DisconnectReason, SHUTDOWN_GRACE };
+use crate::server::replication::REPLICATION_INTERVAL;
+use crate::server::server_player::ServerPlayerContainer;
+use crate::server::transport::unix_time;
+use crate::server::{ ConnectionUUID, Server, SteadyPacket };
+use gfx_maths::Vec3;
+use halfbrown::HashMap;
+use std::fmt::{ Display, Formatter };
+use std::sync::atomic::Ordering;
+use std::time::{ Duration, Instant };
+use tokio::time::MissedTickBehavior;
+
+// weight of the newest tick in the running average
+const AVERAGE_WEIGHT: f64 = 0.05;
+
+#[derive(Clone, Debug, Default)]
+pub struct TickStats {
+    pub tick_rate: u32,
+    pub ticks: u64,
+    pub overruns: u64,
+    pub last: Duration,
+    pub average: Duration,
+    pub worst: Duration,
+}
+
+impl TickStats {
+    fn record(&mut self, duration: Duration, budget: Duration) -> bool {
+        self.ticks += 1;
+        self.last = duration;
+        self.worst = self.worst.max(duration);
+        self.average = if self.ticks == 1 {
+            duration
+        } else {
+            self.average.mul_f64(1.0 - AVERAGE_WEIGHT) + duration.mul_f64(AVERAGE_WEIGHT)
+        };
+        let overran = duration > budget;
+        if overran {
+            self.overruns += 1;
+        }
+        overran
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Display for TickStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let overrun_percent = if self.ticks == 0 {
+            0.0
+        } else {
+            ((self.overruns as f64) * 100.0) / (self.ticks as f64)
+        };
+        write!(
+            f,
+            "{} ticks at {} Hz, last {:.2}ms, average {:.2}ms, worst {:.2}ms, {} overruns ({:.1}%)",
+            self.ticks,
+            self.tick_rate,
+            millis(self.last),
+            millis(self.average),
+            millis(self.worst),
+            self.overruns,
+            overrun_percent
+        )
+    }
+}
+
+impl Server {
+    pub async fn run(&mut self) {
+        let mut tick_rate = 0;
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let mut last_replication = Instant::now();
+        while self.running.load(Ordering::Relaxed) {
+            let config = self.config();
+            let budget = Duration::from_secs_f32(config.tick_interval());
+            if config.simulation.tick_rate != tick_rate {
+                tick_rate = config.simulation.tick_rate;
+                interval = tokio::time::interval(budget);
+                // a slow tick delays the ones after it rather than causing a burst to catch up
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                self.tick_stats.lock().await.tick_rate = tick_rate;
+                info!("server ticking at {} Hz", tick_rate);
+                // clients send their inputs once a tick
+                self.broadcast_steady_packet(SteadyPacket::TickRate(tick_rate)).await;
+            }
+            interval.tick().await;
+
+            let started = Instant::now();
+            self.tick(&config).await;
+            if last_replication.elapsed() >= REPLICATION_INTERVAL {
+                last_replication = Instant::now();
+                self.replicate().await;
+            }
+            self.flush_connections().await;
+
+            let duration = started.elapsed();
+            if self.tick_stats.lock().await.record(duration, budget) {
+                debug!("server tick took {:?}, over its {:?} budget", duration, budget);
+            }
+        }
+        // the connection tasks tell their clients we're shutting down
+        tokio::time::sleep(SHUTDOWN_GRACE).await;
+        // the scene was made for this server, a server started after us gets its own
+        let physics = self.worldmachine.lock().await.physics.lock().unwrap().take();
+        if let Some(physics) = physics {
+            physics.release_scene();
+        }
+    }
+
+    pub async fn tick_stats(&self) -> TickStats {
+        self.tick_stats.lock().await.clone()
+    }
+
+    async fn tick(&mut self, config: &ServerConfig) {
+        self.listen_for_connections().await;
+
+        let players = self.worldmachine.lock().await.players.clone();
+        let players = match players {
+            Some(players) => players.lock().await.clone(),
+            None => HashMap::new(),
+        };
+        for player in players.values() {
+            let commands = player.player.take_pending_inputs().await;
+            if !commands.is_empty() {
+                self.player_input(player, commands).await;
+            }
+        }
+
+        {
+            let mut worldmachine = self.worldmachine.lock().await;
+            worldmachine.physics.lock().unwrap().as_mut().unwrap().tick(config.tick_interval());
+            worldmachine.tick += 1;
+        }
+
+        self.gameplay_tick(&players, config).await;
+
+        let updates = {
+            let mut worldmachine = self.worldmachine.lock().await;
+            worldmachine.server_tick().await
+        };
+        if let Some(updates) = updates {
+            self.handle_world_updates(updates).await;
+        }
+    }
+
+    async fn gameplay_tick(
+        &self,
+        players: &HashMap<ConnectionUUID, ServerPlayerContainer>,
+        config: &ServerConfig
+    ) {
+        let mut timed_out = Vec::new();
+        for player in players.values() {
+            let last_ping = player.connection.last_seen();
+            if unix_time().saturating_sub(last_ping) > config.network.client_timeout_secs {
+                if player.player.disconnect_reason().await.is_none() {
+                    timed_out.push(player.connection.clone());
+                }
+                continue;
+            }
+            if player.player.gravity_tick().await {
+                let mut worldmachine = self.worldmachine.lock().await;
+                player.player.get_position(player.entity_id, Some(&mut worldmachine)).await;
+            }
+            *player.player.tball_cooldown.lock().await -= config.tick_interval();
+            let position = player.player.get_position(None, None).await;
+            if position.y < config.gameplay.kill_height {
+                player.player.respawning.store(true, Ordering::Relaxed);
+                let packet = SteadyPacket::Respawn(Vec3::new(0.0, 0.0, 0.0));
+                self.send_steady_packet(&player.connection, packet).await;
+                let mut worldmachine = self.worldmachine.lock().await;
+                player.player
+                    .set_position(Vec3::new(0.0, 0.0, 0.0), player.entity_id, &mut worldmachine).await;
+                drop(worldmachine);
+                player.player.respawning.store(false, Ordering::Relaxed);
+            }
+        }
+        for connection in timed_out {
+            self.disconnect(&connection, DisconnectReason::Timeout).await;
+        }
+        self.expire_detached_players(Duration::from_secs(config.network.resume_grace_secs)).await;
+    }
+}
@@ -0,0 +1,209 @@
+use crate::server::conditioner::LinkConditioner;
+use crate::server::{ ConnectionUUID, FastPacketData, LocalConnection, SteadyPacketData };
+use std::fmt::{ Debug, Display, Formatter };
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{ mpsc, Notify };
+
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub type Connection = Arc<dyn Transport>;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ConnectionError {
+    ConnectionClosed,
+    ConnectionError,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransportCounters {
+    pub datagrams_sent: u64,
+    pub packets_sent: u64,
+    pub oversized: u64,
+    pub datagrams_received: u64,
+    pub packets_received: u64,
+    pub dropped: u64,
+    pub truncated: u64,
+    pub rejected: u64,
+    pub lost: u64,
+}
+
+impl TransportCounters {
+    pub fn combined(&self, other: &Self) -> Self {
+        Self {
+            datagrams_sent: self.datagrams_sent + other.datagrams_sent,
+            packets_sent: self.packets_sent + other.packets_sent,
+            oversized: self.oversized + other.oversized,
+            datagrams_received: self.datagrams_received + other.datagrams_received,
+            packets_received: self.packets_received + other.packets_received,
+            dropped: self.dropped + other.dropped,
+            truncated: self.truncated + other.truncated,
+            rejected: self.rejected + other.rejected,
+            lost: self.lost + other.lost,
+        }
+    }
+}
+
+impl Display for TransportCounters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sent {} packets in {} datagrams ({} oversized), received {} packets in {} datagrams, {} dropped, {} truncated, {} rejected, {} lost",
+            self.packets_sent,
+            self.datagrams_sent,
+            self.oversized,
+            self.packets_received,
+            self.datagrams_received,
+            self.dropped,
+            self.truncated,
+            self.rejected,
+            self.lost
+        )
+    }
+}
+
+pub trait Transport: Send + Sync {
+    fn kind(&self) -> &'static str;
+
+    fn uuid(&self) -> ConnectionUUID;
+
+    fn identity(&self) -> Option<String>;
+
+    fn resume_token(&self) -> Option<String> {
+        None
+    }
+
+    fn remote_ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    // the local player of whoever runs the server, never a bot or a remote client
+    fn is_host(&self) -> bool {
+        false
+    }
+
+    fn last_seen(&self) -> u64;
+
+    fn mark_alive(&self);
+
+    fn send_steady(&self, packet: SteadyPacketData) -> TransportFuture<'_, bool>;
+
+    fn send_fast(&self, packet: FastPacketData) -> TransportFuture<'_, bool>;
+
+    fn receive_steady(
+        &self
+    ) -> TransportFuture<'_, Result<Option<SteadyPacketData>, ConnectionError>>;
+
+    fn receive_fast(&self) -> TransportFuture<'_, Result<Option<FastPacketData>, ConnectionError>>;
+
+    // resolves once there may be something to receive, or the connection has closed
+    fn readable(&self) -> TransportFuture<'_, ()>;
+
+    fn flush(&self) -> TransportFuture<'_, bool> {
+        Box::pin(async { true })
+    }
+
+    fn counters(&self) -> TransportFuture<'_, Option<TransportCounters>> {
+        Box::pin(async { None })
+    }
+
+    fn link_conditioner(&self) -> Option<LinkConditioner> {
+        None
+    }
+}
+
+impl Debug for dyn Transport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({})", self.kind(), self.uuid())
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// passes packets straight on, waking whoever waits for them, and once more when the sender goes away
+pub fn wake_on_arrival<T: Send + 'static>(
+    mut receiver: mpsc::Receiver<T>,
+    buffer: usize,
+    arrived: Arc<Notify>
+) -> mpsc::Receiver<T> {
+    let (sender, output) = mpsc::channel(buffer);
+    tokio::spawn(async move {
+        while let Some(packet) = receiver.recv().await {
+            if sender.send(packet).await.is_err() {
+                break;
+            }
+            arrived.notify_one();
+        }
+        drop(sender);
+        arrived.notify_one();
+    });
+    output
+}
+
+impl Transport for LocalConnection {
+    fn kind(&self) -> &'static str {
+        "LocalConnection"
+    }
+
+    fn uuid(&self) -> ConnectionUUID {
+        self.uuid.clone()
+    }
+
+    fn identity(&self) -> Option<String> {
+        None
+    }
+
+    fn is_host(&self) -> bool {
+        self.host
+    }
+
+    fn last_seen(&self) -> u64 {
+        unix_time()
+    }
+
+    fn mark_alive(&self) {}
+
+    fn link_conditioner(&self) -> Option<LinkConditioner> {
+        Some(self.conditioner.clone())
+    }
+
+    fn send_steady(&self, packet: SteadyPacketData) -> TransportFuture<'_, bool> {
+        Box::pin(async move { self.steady_update_sender.send(packet).await.is_ok() })
+    }
+
+    fn send_fast(&self, packet: FastPacketData) -> TransportFuture<'_, bool> {
+        Box::pin(async move { self.fast_update_sender.send(packet).await.is_ok() })
+    }
+
+    fn receive_steady(
+        &self
+    ) -> TransportFuture<'_, Result<Option<SteadyPacketData>, ConnectionError>> {
+        Box::pin(async move {
+            match self.steady_update_receiver.lock().await.try_recv() {
+                Ok(packet) => Ok(Some(packet)),
+                Err(TryRecvError::Empty) => Ok(None),
+                Err(TryRecvError::Disconnected) => Err(ConnectionError::ConnectionClosed),
+            }
+        })
+    }
+
+    fn receive_fast(&self) -> TransportFuture<'_, Result<Option<FastPacketData>, ConnectionError>> {
+        Box::pin(async move {
+            match self.fast_update_receiver.lock().await.try_recv() {
+                Ok(packet) => Ok(Some(packet)),
+                Err(TryRecvError::Empty) => Ok(None),
+                Err(TryRecvError::Disconnected) => Err(ConnectionError::ConnectionClosed),
+            }
+        })
+    }
+
+    fn readable(&self) -> TransportFuture<'_, ()> {
+        Box::pin(self.arrived.notified())
+    }
+}
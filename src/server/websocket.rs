@@ -0,0 +1,516 @@
+use crate::server::accounts::{ authenticate_join, Authenticator, Credentials, SessionGrant };
+use crate::server::moderation::{ check_bans, BanList };
+use crate::server::connections::SteadyMessageQueue;
+use crate::server::lan::{
+    ClientConnectError,
+    ConnectionHandshakePacket,
+    FastUpdateQueue,
+    ProtocolInfo,
+    RejectionReason,
+};
+use crate::server::transport::{ unix_time, ConnectionError, Transport, TransportFuture };
+use crate::server::{ generate_uuid, ConnectionUUID, FastPacketData, SteadyPacketData };
+use futures::SinkExt;
+use mutex_timeouts::tokio::MutexWithTimeoutAuto as Mutex;
+use serde::{ Deserialize, Serialize };
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::sync::{ Arc, RwLock };
+use std::time::Duration;
+use tokio::net::{ TcpListener, TcpStream };
+use tokio::sync::{ mpsc, Notify };
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio_stream::StreamExt;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{ MaybeTlsStream, WebSocketStream };
+
+pub const DEFAULT_WEBSOCKET_PORT: u16 = 25568;
+pub const HANDSHAKE_TIMEOUT_SECS: u64 = 20;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WebSocketFrame {
+    Handshake(ConnectionHandshakePacket),
+    Steady(SteadyPacketData),
+    Fast(FastPacketData),
+}
+
+impl WebSocketFrame {
+    fn encode(&self) -> Message {
+        let mut serialiser = rmp_serde::Serializer::new(Vec::new());
+        self.serialize(&mut serialiser).unwrap();
+        Message::Binary(serialiser.into_inner())
+    }
+
+    fn decode(message: &Message) -> Option<Self> {
+        if let Message::Binary(data) = message {
+            let mut deserialiser = rmp_serde::Deserializer::new(data.as_slice());
+            return Self::deserialize(&mut deserialiser).ok();
+        }
+        None
+    }
+}
+
+async fn read_frame<S>(socket: &mut WebSocketStream<S>) -> Result<WebSocketFrame, ClientConnectError>
+    where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin
+{
+    loop {
+        let message = tokio::time
+            ::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), StreamExt::next(socket)).await
+            .map_err(|_| ClientConnectError::Io("handshake timed out".to_string()))?
+            .ok_or(ClientConnectError::Io("connection closed".to_string()))?
+            .map_err(|e| ClientConnectError::Io(e.to_string()))?;
+        match message {
+            Message::Binary(_) => {
+                return WebSocketFrame::decode(&message).ok_or(ClientConnectError::UnexpectedPacket);
+            }
+            Message::Close(_) => {
+                return Err(ClientConnectError::Io("connection closed".to_string()));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebSocketListener {
+    listener: Arc<Mutex<TcpListener>>,
+    authenticator: Arc<RwLock<Option<Arc<Authenticator>>>>,
+    bans: Arc<RwLock<BanList>>,
+    pub port: u16,
+}
+
+unsafe impl Send for WebSocketListener {}
+
+unsafe impl Sync for WebSocketListener {}
+
+impl WebSocketListener {
+    pub async fn new(
+        hostname: &str,
+        port: u16,
+        authenticator: Arc<RwLock<Option<Arc<Authenticator>>>>,
+        bans: Arc<RwLock<BanList>>
+    ) -> Self {
+        let listener = TcpListener::bind(format!("{}:{}", hostname, port)).await.unwrap();
+        Self {
+            listener: Arc::new(Mutex::new(listener)),
+            authenticator,
+            bans,
+            port,
+        }
+    }
+
+    pub fn authenticator(&self) -> Option<Arc<Authenticator>> {
+        self.authenticator.read().unwrap().clone()
+    }
+
+    pub async fn poll_new_connection(&self) -> Option<TcpStream> {
+        let listener = self.listener.lock().await;
+        listener
+            .accept().await
+            .ok()
+            .map(|(stream, _)| stream)
+    }
+
+    pub async fn init_new_connection(&self, stream: TcpStream) -> Option<WebSocketConnection> {
+        debug!("new websocket connection");
+        let remote_addr = stream.peer_addr().ok()?;
+        let mut socket = match tokio_tungstenite::accept_async(stream).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("websocket upgrade failed: {:?}", e);
+                return None;
+            }
+        };
+
+        let (client_info, credentials, resume_token) = match read_frame(&mut socket).await {
+            Ok(
+                WebSocketFrame::Handshake(
+                    ConnectionHandshakePacket::JoinRequest(client_info, credentials, resume_token),
+                ),
+            ) => (client_info, credentials, resume_token),
+            Ok(_) => {
+                Self::reject_connection(&mut socket, RejectionReason::MalformedHandshake).await;
+                return None;
+            }
+            Err(e) => {
+                warn!("handshake packet error: {}", e);
+                return None;
+            }
+        };
+        if let Err(reason) = client_info.check_compatible() {
+            warn!("rejecting client running build {}: {}", client_info.build_id, reason);
+            Self::reject_connection(&mut socket, reason).await;
+            return None;
+        }
+        let session = match authenticate_join(self.authenticator(), credentials, Some(remote_addr.ip())).await {
+            Ok(session) => session,
+            Err(reason) => {
+                warn!("rejecting client: {}", reason);
+                Self::reject_connection(&mut socket, reason).await;
+                return None;
+            }
+        };
+        let identity = session.as_ref().map(|session| session.username.clone());
+        if let Some(identity) = &identity {
+            info!("client authenticated as {}", identity);
+        }
+        if let Err(reason) = check_bans(&self.bans, identity.as_ref(), Some(remote_addr.ip())) {
+            warn!("rejecting client: {}", reason);
+            Self::reject_connection(&mut socket, reason).await;
+            return None;
+        }
+
+        let uuid = generate_uuid();
+        let replies = [
+            ConnectionHandshakePacket::Accepted(ProtocolInfo::current(), session),
+            ConnectionHandshakePacket::YoureReady(uuid.clone()),
+        ];
+        for packet in replies {
+            if let Err(e) = socket.send(WebSocketFrame::Handshake(packet).encode()).await {
+                warn!("handshake packet error: {:?}", e);
+                return None;
+            }
+        }
+        debug!("websocket client {} is ready", uuid);
+
+        Some(WebSocketConnection::new(uuid, identity, resume_token, socket, remote_addr))
+    }
+
+    async fn reject_connection(socket: &mut WebSocketStream<TcpStream>, reason: RejectionReason) {
+        let frame = WebSocketFrame::Handshake(ConnectionHandshakePacket::Rejected(reason));
+        if let Err(e) = socket.send(frame.encode()).await {
+            debug!("failed to send rejection: {:?}", e);
+        }
+        let _ = socket.close(None).await;
+    }
+}
+
+#[derive(Clone)]
+pub struct WebSocketConnection {
+    outgoing: mpsc::Sender<WebSocketFrame>,
+    steady_receiver: Arc<Mutex<mpsc::Receiver<SteadyPacketData>>>,
+    fast_update_queue: Arc<Mutex<FastUpdateQueue<FastPacketData>>>,
+    pub is_connected: Arc<AtomicBool>,
+    pub remote_addr: SocketAddr,
+    pub uuid: ConnectionUUID,
+    pub identity: Option<String>,
+    pub resume_token: Option<String>,
+    pub last_successful_ping: Arc<AtomicU64>,
+    arrived: Arc<Notify>,
+}
+
+unsafe impl Send for WebSocketConnection {}
+
+unsafe impl Sync for WebSocketConnection {}
+
+impl WebSocketConnection {
+    pub fn new(
+        uuid: ConnectionUUID,
+        identity: Option<String>,
+        resume_token: Option<String>,
+        socket: WebSocketStream<TcpStream>,
+        remote_addr: SocketAddr
+    ) -> Self {
+        let (outgoing_sender, outgoing_receiver) = mpsc::channel(100);
+        let (steady_sender, steady_receiver) = mpsc::channel(100);
+        let the_self = Self {
+            outgoing: outgoing_sender,
+            steady_receiver: Arc::new(Mutex::new(steady_receiver)),
+            fast_update_queue: Arc::new(Mutex::new(FastUpdateQueue::<FastPacketData>::new(None))),
+            is_connected: Arc::new(AtomicBool::new(true)),
+            remote_addr,
+            uuid,
+            identity,
+            resume_token,
+            last_successful_ping: Arc::new(AtomicU64::new(unix_time())),
+            arrived: Arc::new(Notify::new()),
+        };
+        let the_clone = the_self.clone();
+        tokio::spawn(async move {
+            the_clone.socket_thread(socket, outgoing_receiver, steady_sender).await;
+        });
+        the_self
+    }
+
+    pub async fn serialise_and_send_fast(
+        &self,
+        packet: FastPacketData
+    ) -> Result<(), ConnectionError> {
+        self.outgoing
+            .send(WebSocketFrame::Fast(packet)).await
+            .map_err(|_| ConnectionError::ConnectionClosed)
+    }
+
+    pub async fn serialise_and_send_steady(
+        &self,
+        packet: SteadyPacketData
+    ) -> Result<(), ConnectionError> {
+        self.outgoing
+            .send(WebSocketFrame::Steady(packet)).await
+            .map_err(|_| ConnectionError::ConnectionClosed)
+    }
+
+    pub async fn attempt_receive_fast_and_deserialise(&self) -> Option<FastPacketData> {
+        self.fast_update_queue.lock().await.pop()
+    }
+
+    pub async fn attempt_receive_steady_and_deserialise(
+        &self
+    ) -> Result<Option<SteadyPacketData>, ConnectionError> {
+        match self.steady_receiver.lock().await.try_recv() {
+            Ok(packet) => Ok(Some(packet)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(ConnectionError::ConnectionClosed),
+        }
+    }
+
+    async fn socket_thread(
+        &self,
+        mut socket: WebSocketStream<TcpStream>,
+        mut outgoing: mpsc::Receiver<WebSocketFrame>,
+        steady_sender: mpsc::Sender<SteadyPacketData>
+    ) {
+        loop {
+            tokio::select! {
+                attempt = StreamExt::next(&mut socket) => {
+                    let message = match attempt {
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                            break;
+                        }
+                        Some(Ok(message)) => message,
+                    };
+                    match WebSocketFrame::decode(&message) {
+                        Some(WebSocketFrame::Steady(packet)) => {
+                            debug!("received steady packet: {:?}", packet);
+                            if steady_sender.send(packet).await.is_err() {
+                                break;
+                            }
+                            self.arrived.notify_one();
+                        }
+                        Some(WebSocketFrame::Fast(packet)) => {
+                            self.fast_update_queue.lock().await.push(packet);
+                            self.arrived.notify_one();
+                        }
+                        Some(WebSocketFrame::Handshake(_)) => {
+                            debug!("ignoring handshake packet from ready client {}", self.uuid);
+                        }
+                        None => {}
+                    }
+                }
+                attempt = outgoing.recv() => {
+                    let frame = match attempt {
+                        Some(frame) => frame,
+                        None => {
+                            break;
+                        }
+                    };
+                    if let Err(e) = socket.send(frame.encode()).await {
+                        debug!("failed to send websocket frame: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        self.is_connected.store(false, Ordering::Relaxed);
+        drop(steady_sender);
+        self.arrived.notify_one();
+        let _ = socket.close(None).await;
+    }
+}
+
+impl Transport for WebSocketConnection {
+    fn kind(&self) -> &'static str {
+        "WebSocketConnection"
+    }
+
+    fn uuid(&self) -> ConnectionUUID {
+        self.uuid.clone()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.identity.clone()
+    }
+
+    fn resume_token(&self) -> Option<String> {
+        self.resume_token.clone()
+    }
+
+    fn remote_ip(&self) -> Option<IpAddr> {
+        Some(self.remote_addr.ip())
+    }
+
+    fn last_seen(&self) -> u64 {
+        self.last_successful_ping.load(Ordering::Relaxed)
+    }
+
+    fn mark_alive(&self) {
+        self.last_successful_ping.store(unix_time(), Ordering::Relaxed);
+    }
+
+    fn send_steady(&self, packet: SteadyPacketData) -> TransportFuture<'_, bool> {
+        Box::pin(async move { self.serialise_and_send_steady(packet).await.is_ok() })
+    }
+
+    fn send_fast(&self, packet: FastPacketData) -> TransportFuture<'_, bool> {
+        Box::pin(async move { self.serialise_and_send_fast(packet).await.is_ok() })
+    }
+
+    fn receive_steady(
+        &self
+    ) -> TransportFuture<'_, Result<Option<SteadyPacketData>, ConnectionError>> {
+        Box::pin(self.attempt_receive_steady_and_deserialise())
+    }
+
+    fn receive_fast(&self) -> TransportFuture<'_, Result<Option<FastPacketData>, ConnectionError>> {
+        Box::pin(async move { Ok(self.attempt_receive_fast_and_deserialise().await) })
+    }
+
+    fn readable(&self) -> TransportFuture<'_, ()> {
+        Box::pin(self.arrived.notified())
+    }
+}
+
+pub type ClientWebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Clone)]
+pub struct ClientWebSocketConnection {
+    outgoing: mpsc::Sender<WebSocketFrame>,
+    pub fast_update_queue: Arc<Mutex<FastUpdateQueue<FastPacketData>>>,
+    pub steady_receiver_queue: Arc<Mutex<SteadyMessageQueue>>,
+    pub uuid: ConnectionUUID,
+    pub session: Option<SessionGrant>,
+}
+
+unsafe impl Send for ClientWebSocketConnection {}
+
+unsafe impl Sync for ClientWebSocketConnection {}
+
+impl ClientWebSocketConnection {
+    pub async fn connect(
+        url: &str,
+        credentials: Option<Credentials>,
+        resume_token: Option<String>
+    ) -> Result<(Self, ClientWebSocket, mpsc::Receiver<WebSocketFrame>), ClientConnectError> {
+        let (mut socket, _) = tokio_tungstenite
+            ::connect_async(url).await
+            .map_err(|e| ClientConnectError::Io(e.to_string()))?;
+        debug!("connected to websocket server");
+        let packet = ConnectionHandshakePacket::JoinRequest(
+            ProtocolInfo::current(),
+            credentials,
+            resume_token
+        );
+        socket
+            .send(WebSocketFrame::Handshake(packet).encode()).await
+            .map_err(|e| ClientConnectError::Io(e.to_string()))?;
+        debug!("sent join request");
+
+        let session = match read_frame(&mut socket).await? {
+            WebSocketFrame::Handshake(ConnectionHandshakePacket::Accepted(server_info, session)) => {
+                server_info.check_compatible().map_err(ClientConnectError::IncompatibleServer)?;
+                session
+            }
+            WebSocketFrame::Handshake(ConnectionHandshakePacket::Rejected(reason)) => {
+                return Err(ClientConnectError::Rejected(reason));
+            }
+            _ => {
+                return Err(ClientConnectError::UnexpectedPacket);
+            }
+        };
+        let uuid = match read_frame(&mut socket).await? {
+            WebSocketFrame::Handshake(ConnectionHandshakePacket::YoureReady(uuid)) => uuid,
+            _ => {
+                return Err(ClientConnectError::UnexpectedPacket);
+            }
+        };
+        debug!("our uuid is {}", uuid);
+
+        let (sender, receiver) = mpsc::channel(100);
+        Ok((
+            Self {
+                outgoing: sender,
+                fast_update_queue: Arc::new(
+                    Mutex::new(FastUpdateQueue::<FastPacketData>::new(None))
+                ),
+                steady_receiver_queue: Arc::new(Mutex::new(SteadyMessageQueue::new())),
+                uuid,
+                session,
+            },
+            socket,
+            receiver,
+        ))
+    }
+
+    pub async fn socket_thread(
+        &self,
+        mut socket: ClientWebSocket,
+        mut receiver: mpsc::Receiver<WebSocketFrame>
+    ) {
+        loop {
+            tokio::select! {
+                attempt = StreamExt::next(&mut socket) => {
+                    let message = match attempt {
+                        Some(Ok(Message::Close(_))) | None => {
+                            error!("connection closed");
+                            break;
+                        }
+                        Some(Err(e)) => {
+                            error!("connection closed: {:?}", e);
+                            break;
+                        }
+                        Some(Ok(message)) => message,
+                    };
+                    match WebSocketFrame::decode(&message) {
+                        Some(WebSocketFrame::Steady(packet)) => {
+                            self.steady_receiver_queue.lock().await.push(packet);
+                        }
+                        Some(WebSocketFrame::Fast(packet)) => {
+                            self.fast_update_queue.lock().await.push(packet);
+                        }
+                        Some(WebSocketFrame::Handshake(_)) | None => {}
+                    }
+                }
+                attempt = receiver.recv() => {
+                    let frame = match attempt {
+                        Some(frame) => frame,
+                        None => {
+                            // we let go of the connection ourselves
+                            let _ = socket.close(None).await;
+                            return;
+                        }
+                    };
+                    if let Err(e) = socket.send(frame.encode()).await {
+                        error!("connection closed, failed to send websocket frame: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        #[cfg(feature = "graphical")]
+        crate::ui::DISCONNECTED.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn send_fast_and_serialise(&self, packet: FastPacketData) -> std::io::Result<()> {
+        self.outgoing
+            .send(WebSocketFrame::Fast(packet)).await
+            .map_err(|_| {
+                std::io::Error::other("failed to send fast packet")
+            })
+    }
+
+    pub async fn send_steady_and_serialise(&self, packet: SteadyPacketData) -> std::io::Result<()> {
+        self.outgoing
+            .send(WebSocketFrame::Steady(packet)).await
+            .map_err(|_| {
+                std::io::Error::other("failed to send steady packet")
+            })
+    }
+
+    pub async fn attempt_receive_fast_and_deserialise(&self) -> Option<FastPacketData> {
+        self.fast_update_queue.lock().await.pop()
+    }
+
+    pub async fn attempt_receive_steady_and_deserialise(&self) -> Option<SteadyPacketData> {
+        self.steady_receiver_queue.lock().await.pop().await
+    }
+}
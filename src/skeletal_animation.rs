@@ -7,11 +7,16 @@ use crate::optimisations::helpers::{
     from_q64,
     gltf_matrix_to_gfx_maths_mat4,
     interpolate_quaternion,
+    invert_mat4,
     multiply_vec3_by_f64,
     to_q64,
 };
 use crate::optimisations::DoubleIndexVec::DoubleIndexVec;
 
+// meshes with an animation called "auto" play it when nothing else drives them
+pub const AUTO_ANIMATION: &str = "auto";
+pub const AUTO_ANIMATION_WEIGHT: f64 = 0.1;
+
 #[derive(Clone, Debug)]
 pub struct AnimationBlend {
     pub animation: String,
@@ -484,4 +489,35 @@ impl SkeletalAnimations {
         }
         joint_matrices
     }
+
+    pub fn get_bone_by_name(&self, name: &str) -> Option<&SkeletalBone> {
+        self.bones.iter().find(|bone| bone.name == name)
+    }
+
+    pub fn get_bone_transform(&self, name: &str) -> Option<Mat4> {
+        let bone = self.get_bone_by_name(name)?;
+        let bind_matrix = invert_mat4(bone.inverse_bind_matrix)?;
+        Some(bone.animated_transform * bind_matrix)
+    }
+
+    pub fn auto_weights(&self) -> Option<Vec<(String, f64)>> {
+        if self.animations.contains_key(AUTO_ANIMATION) {
+            Some(vec![(AUTO_ANIMATION.to_string(), AUTO_ANIMATION_WEIGHT)])
+        } else {
+            None
+        }
+    }
+
+    pub fn pose_with_weights(&mut self, animations_weights: &[(String, f64)]) {
+        let anims_weights = animations_weights
+            .iter()
+            .filter_map(|(name, weight)| {
+                self.animations.get(name).map(|animation| (Arc::new(animation.clone()), *weight))
+            })
+            .collect::<Vec<(Arc<SkeletalAnimation>, f64)>>();
+
+        for bone in self.root_bones.clone().iter() {
+            self.apply_poses(*bone, Mat4::identity(), &anims_weights);
+        }
+    }
 }
@@ -1,12 +1,14 @@
 use crate::firebase;
 use crate::firebase::db_operations::User;
 use crate::renderer::MutRenderer;
-use crate::ui_defs::chat;
+use crate::server::savegame::{ request_session, SessionRequest, QUICKSAVE_NAME };
+use crate::ui_defs::{ browser, chat };
+use crate::worldmachine::navmesh::{ DEBUG_NAVMESH, NAVMESH_DEBUG_DRAW_DISTANCE };
 use crate::worldmachine::player::Player;
 use crate::worldmachine::WorldMachine;
 use egui_glfw_gl::egui::{ self, RichText };
 use egui_glfw_gl::egui::{ Color32, Frame, Rgba, SidePanel, Style, TopBottomPanel, Ui };
-use gfx_maths::Vec3;
+use gfx_maths::{ Vec3, Vec4 };
 use once_cell::sync::Lazy;
 use std::collections::VecDeque;
 use std::sync::atomic::{ AtomicBool, Ordering };
@@ -79,6 +81,7 @@ lazy_static! {
     pub static ref SHOW_DEBUG_LOCATION: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
     pub static ref SHOW_FPS: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
     pub static ref SHOW_DEBUG_LOG: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+    pub static ref SHOW_NAVMESH: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     pub static ref DEBUG_LOCATION: Arc<Mutex<Vec3>> = Arc::new(
         Mutex::new(Vec3::new(0.0, 0.0, 0.0))
     );
@@ -100,10 +103,15 @@ lazy_static! {
     static ref COMMAND_TRIE: Trie = {
         let mut trie = Trie::new();
         trie.insert("increase_speed");
+        trie.insert("toggle_navmesh");
+        trie.insert("quicksave");
+        trie.insert("quickload");
+        trie.insert("interp_delay");
         trie
     };
     pub static ref UNSTABLE_CONNECTION: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     pub static ref DISCONNECTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    pub static ref CONNECTION_ERROR: Mutex<Option<String>> = Mutex::new(None);
 }
 
 static SYS_INFO: Lazy<Mutex<(System, Instant, f32)>> = Lazy::new(|| {
@@ -173,6 +181,43 @@ pub async fn render(renderer: &mut MutRenderer, wm: &mut WorldMachine, player: &
         wm.send_chat_message(message).await;
     }
 
+    let mut join = None;
+    egui::Window
+        ::new("servers")
+        .title_bar(true)
+        .resizable(true)
+        .collapsible(true)
+        .default_open(false)
+        .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(-30.0, 10.0))
+        .default_width(360.0)
+        .frame(Frame::none().fill(Color32::from_rgb(25, 25, 25)))
+        .show(&renderer.backend.egui_context.lock().unwrap(), |ui| {
+            join = browser::browser(ui);
+        });
+    if let Some(server) = join {
+        request_session(
+            SessionRequest::JoinLan(server.address, server.info.tcp_port, server.info.udp_port)
+        );
+    }
+
+    if DISCONNECTED.load(Ordering::Relaxed) {
+        let mut reconnect = false;
+        egui::Window
+            ::new("disconnected")
+            .title_bar(true)
+            .resizable(false)
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::new(0.0, 0.0))
+            .default_width(300.0)
+            .frame(Frame::none().fill(Color32::from_rgb(25, 25, 25)))
+            .show(&renderer.backend.egui_context.lock().unwrap(), |ui| {
+                reconnect = render_disconnected(ui, wm);
+            });
+        if reconnect {
+            request_session(SessionRequest::Reconnect);
+        }
+    }
+
     egui::Window
         ::new("debug")
         .title_bar(true)
@@ -186,17 +231,19 @@ pub async fn render(renderer: &mut MutRenderer, wm: &mut WorldMachine, player: &
                 ui.heading("Information");
             });
             ui.separator();
-            ui.horizontal(|ui| {
-                ui.label("Status:");
-                ui.colored_label(Color32::LIGHT_GREEN, "Active");
-            });
+            render_connection_status(ui);
             render_debug_location(ui);
             render_fps(ui);
+            render_interpolation_stats(ui, wm);
+            render_prediction_stats(ui, wm);
+            render_network_counters(ui, wm);
             render_memory_usage(ui);
             render_command_panel(ui, wm, player);
             firebase_admin_panel(ui);
         });
 
+    render_navmesh(renderer);
+
     let egui::FullOutput {
         platform_output,
         repaint_after: _,
@@ -218,12 +265,95 @@ pub async fn render(renderer: &mut MutRenderer, wm: &mut WorldMachine, player: &
         .paint_and_update_textures(1.0, &clipped_shapes, &textures_delta);
 }
 
+fn render_connection_status(ui: &mut Ui) {
+    ui.horizontal(|ui| {
+        ui.label("Status:");
+        if let Some(error) = CONNECTION_ERROR.lock().unwrap().as_ref() {
+            ui.colored_label(Color32::RED, error);
+        } else if DISCONNECTED.load(Ordering::Relaxed) {
+            ui.colored_label(Color32::RED, "Disconnected");
+        } else if UNSTABLE_CONNECTION.load(Ordering::Relaxed) {
+            ui.colored_label(Color32::YELLOW, "Unstable connection");
+        } else {
+            ui.colored_label(Color32::LIGHT_GREEN, "Active");
+        }
+    });
+}
+
+fn render_disconnected(ui: &mut Ui, wm: &WorldMachine) -> bool {
+    let reason = match CONNECTION_ERROR.lock().unwrap().clone() {
+        Some(error) => error,
+        None =>
+            match &wm.disconnect_reason {
+                Some(reason) => reason.to_string(),
+                None => "lost connection to the server".to_string(),
+            }
+    };
+    ui.colored_label(Color32::RED, reason);
+    if wm.resume_token.is_some() {
+        ui.label("reconnect soon to carry on where you left off");
+    }
+    match wm.server_address {
+        Some(_) => ui.button("reconnect").clicked(),
+        None => false,
+    }
+}
+
 fn render_fps(ui: &mut Ui) {
     let fps = FPS.lock().unwrap();
     let label_text = format!("FPS: {}", *fps as u32);
     ui.colored_label(Color32::GOLD, label_text);
 }
 
+fn render_interpolation_stats(ui: &mut Ui, wm: &WorldMachine) {
+    let stats = wm.interpolation.stats();
+    ui.label(
+        format!(
+            "Interpolation: {} ms delay, {} entities, {} samples, {:.0} ms buffered",
+            (stats.delay * 1000.0) as u32,
+            stats.entities,
+            stats.buffered_samples,
+            stats.buffered_ahead * 1000.0
+        )
+    );
+    let colour = if stats.holding > 0 {
+        Color32::RED
+    } else if stats.extrapolating > 0 {
+        Color32::YELLOW
+    } else {
+        Color32::LIGHT_GREEN
+    };
+    ui.colored_label(
+        colour,
+        format!(
+            "interpolating {}, extrapolating {}, holding {}, waiting {}",
+            stats.interpolating,
+            stats.extrapolating,
+            stats.holding,
+            stats.waiting
+        )
+    );
+}
+
+fn render_prediction_stats(ui: &mut Ui, wm: &WorldMachine) {
+    if let Some(player) = wm.player.as_ref() {
+        let prediction = &player.player.prediction;
+        ui.label(
+            format!(
+                "Prediction: {} unacknowledged inputs, {} corrections",
+                prediction.pending(),
+                prediction.corrections
+            )
+        );
+    }
+}
+
+fn render_network_counters(ui: &mut Ui, wm: &WorldMachine) {
+    if let Some(counters) = wm.network_counters.as_ref() {
+        ui.label(format!("Network: {}", counters));
+    }
+}
+
 fn render_memory_usage(ui: &mut Ui) {
     let memory_usage = get_memory_usage();
     let label_text = RichText::new(format!("Memory Usage: {:.2} MB", memory_usage)).color(
@@ -245,6 +375,42 @@ fn get_memory_usage() -> f32 {
     *last_value
 }
 
+fn render_navmesh(renderer: &MutRenderer) {
+    if !SHOW_NAVMESH.load(Ordering::Relaxed) {
+        return;
+    }
+    let navmesh = match DEBUG_NAVMESH.lock().unwrap().clone() {
+        Some(navmesh) => navmesh,
+        None => {
+            return;
+        }
+    };
+    let view_projection = renderer.camera.get_projection() * renderer.camera.get_view();
+    let edges = navmesh.edges_near(renderer.camera.get_position(), NAVMESH_DEBUG_DRAW_DISTANCE);
+
+    let ctx = renderer.backend.egui_context.lock().unwrap();
+    let screen = ctx.screen_rect();
+    let painter = ctx.layer_painter(egui::LayerId::background());
+    let project = |position: Vec3| -> Option<egui::Pos2> {
+        let clip = view_projection * Vec4::new(position.x, position.y + 0.05, position.z, 1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        Some(
+            egui::pos2(
+                screen.min.x + (clip.x / clip.w + 1.0) * 0.5 * screen.width(),
+                screen.min.y + (1.0 - clip.y / clip.w) * 0.5 * screen.height()
+            )
+        )
+    };
+    let stroke = egui::Stroke::new(1.0, Color32::from_rgb(0, 200, 255));
+    for (a, b) in edges {
+        if let (Some(a), Some(b)) = (project(a), project(b)) {
+            painter.line_segment([a, b], stroke);
+        }
+    }
+}
+
 fn render_debug_location(ui: &mut Ui) {
     let debug_location = DEBUG_LOCATION.lock().unwrap();
     ui.with_layout(egui::Layout::top_down(egui::Align::LEFT), |ui| {
@@ -391,7 +557,8 @@ fn render_command_panel(ui: &mut Ui, wm: &mut WorldMachine, player: &mut Player)
                             .desired_width(200.0)
                     );
                     if ui.button("Execute").clicked() {
-                        command_result = Some(handle_command(&wm.command, player));
+                        let command = wm.command.clone();
+                        command_result = Some(handle_command(&command, wm, player));
                     }
                     if ui.button("Clear").clicked() {
                         wm.command.clear();
@@ -431,12 +598,36 @@ fn render_command_panel(ui: &mut Ui, wm: &mut WorldMachine, player: &mut Player)
     }
 }
 
-fn handle_command(command: &str, player: &mut Player) -> CommandResult {
+fn handle_command(command: &str, wm: &mut WorldMachine, player: &mut Player) -> CommandResult {
+    if let Some(delay) = command.strip_prefix("interp_delay") {
+        return match delay.trim().parse::<u32>() {
+            Ok(delay) => {
+                wm.interpolation.delay = (delay as f64) / 1000.0;
+                CommandResult::Success
+            }
+            Err(_) => CommandResult::Failure("usage: interp_delay <milliseconds>".to_string()),
+        };
+    }
     match command {
         "increase_speed" => {
             player.increase_speed();
             CommandResult::Success
         }
+        "toggle_navmesh" => {
+            if DEBUG_NAVMESH.lock().unwrap().is_none() {
+                return CommandResult::Failure("no navmesh loaded".to_string());
+            }
+            SHOW_NAVMESH.fetch_xor(true, Ordering::Relaxed);
+            CommandResult::Success
+        }
+        "quicksave" => {
+            request_session(SessionRequest::Save(QUICKSAVE_NAME.to_string()));
+            CommandResult::Success
+        }
+        "quickload" => {
+            request_session(SessionRequest::Load(QUICKSAVE_NAME.to_string()));
+            CommandResult::Success
+        }
         _ => CommandResult::Failure(format!("Unknown command: {}", command)),
     }
 }
@@ -0,0 +1,98 @@
+use crate::server::discovery::{ discover_servers, DiscoveredServer, DISCOVERY_TIMEOUT };
+use egui_glfw_gl::egui::{ self, Color32, Ui };
+use std::sync::{ Arc, Mutex };
+
+lazy_static! {
+    pub static ref SERVER_LIST: Arc<Mutex<ServerList>> = Arc::new(Mutex::new(ServerList::default()));
+}
+
+#[derive(Default)]
+pub struct ServerList {
+    pub servers: Vec<DiscoveredServer>,
+    pub searching: bool,
+    pub searched: bool,
+    pub error: Option<String>,
+}
+
+pub fn refresh_servers() {
+    {
+        let mut list = SERVER_LIST.lock().unwrap();
+        if list.searching {
+            return;
+        }
+        list.searching = true;
+    }
+    tokio::spawn(async move {
+        let result = discover_servers(DISCOVERY_TIMEOUT).await;
+        let mut list = SERVER_LIST.lock().unwrap();
+        list.searching = false;
+        list.searched = true;
+        match result {
+            Ok(servers) => {
+                list.servers = servers;
+                list.error = None;
+            }
+            Err(e) => {
+                list.error = Some(e.to_string());
+            }
+        }
+    });
+}
+
+pub fn browser(ui: &mut Ui) -> Option<DiscoveredServer> {
+    let mut join = None;
+    let (searching, searched) = {
+        let list = SERVER_LIST.lock().unwrap();
+        (list.searching, list.searched)
+    };
+    if !searching && !searched {
+        refresh_servers();
+    }
+
+    ui.horizontal(|ui| {
+        if ui.add_enabled(!searching, egui::Button::new("refresh")).clicked() {
+            refresh_servers();
+        }
+        if searching {
+            ui.label("searching...");
+        }
+    });
+    ui.separator();
+
+    let list = SERVER_LIST.lock().unwrap();
+    if let Some(error) = &list.error {
+        ui.colored_label(Color32::RED, format!("discovery failed: {}", error));
+    }
+    if list.servers.is_empty() && list.searched && !searching {
+        ui.label("no servers found on this network");
+    }
+    egui::Grid
+        ::new("server_browser")
+        .striped(true)
+        .show(ui, |ui| {
+            if list.servers.is_empty() {
+                return;
+            }
+            ui.label("name");
+            ui.label("map");
+            ui.label("players");
+            ui.label("ping");
+            ui.end_row();
+            for server in list.servers.iter() {
+                ui.label(&server.info.name);
+                ui.label(&server.info.map);
+                ui.label(format!("{}/{}", server.info.players, server.info.max_players));
+                ui.label(format!("{} ms", server.ping.as_millis()));
+                if !server.is_compatible() {
+                    ui.colored_label(Color32::YELLOW, "incompatible version");
+                } else if server.info.players >= server.info.max_players {
+                    ui.colored_label(Color32::YELLOW, "full");
+                } else if ui.button("join").clicked() {
+                    join = Some(server.clone());
+                }
+                ui.end_row();
+            }
+        });
+
+    join
+}
@@ -1,7 +1,10 @@
 use std::collections::VecDeque;
 use std::sync::{ Arc, Mutex };
+#[cfg(feature = "graphical")]
 use egui_glfw_gl::egui;
+#[cfg(feature = "graphical")]
 use egui_glfw_gl::egui::Ui;
+#[cfg(feature = "graphical")]
 use crate::worldmachine::WorldMachine;
 
 lazy_static! {
@@ -39,6 +42,7 @@ pub fn write_chat(name: String, msg: String) {
     });
 }
 
+#[cfg(feature = "graphical")]
 pub fn chat(ui: &mut Ui, wm: &mut WorldMachine) -> (Option<String>, Option<String>) {
     let mut set_name = None;
 
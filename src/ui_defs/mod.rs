@@ -1 +1,3 @@
+#[cfg(feature = "graphical")]
+pub mod browser;
 pub mod chat;
\ No newline at end of file
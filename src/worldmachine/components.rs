@@ -1,6 +1,7 @@
 #![allow(clippy::new_ret_no_self)]
 
 use crate::worldmachine::ecs::*;
+use crate::worldmachine::EntityId;
 use gfx_maths::*;
 use std::collections::BTreeMap;
 
@@ -21,6 +22,8 @@ lazy_static! {
         ComponentType::create_if_not_exists("Jukebox");
     pub static ref COMPONENT_TYPE_TRIGGER: ComponentType =
         ComponentType::create_if_not_exists("Trigger");
+    pub static ref COMPONENT_TYPE_ATTACHMENT: ComponentType =
+        ComponentType::create_if_not_exists("Attachment");
     pub static ref COMPONENTS_INITIALISED: bool = {
         register_component_types();
         true
@@ -36,6 +39,7 @@ pub fn register_component_types() {
     let _ = COMPONENT_TYPE_BOX_COLLIDER.clone();
     let _ = COMPONENT_TYPE_JUKEBOX.clone();
     let _ = COMPONENT_TYPE_TRIGGER.clone();
+    let _ = COMPONENT_TYPE_ATTACHMENT.clone();
 }
 
 pub struct Transform {}
@@ -193,3 +197,42 @@ impl Jukebox {
         Self::new()
     }
 }
+
+pub struct Attachment {}
+
+impl Attachment {
+    pub fn new(
+        parent: EntityId,
+        bone: &str,
+        offset_position: Vec3,
+        offset_rotation: Quaternion
+    ) -> Component {
+        let mut parameters = BTreeMap::new();
+        parameters.insert(
+            "parent".to_string(),
+            Parameter::new("parent", ParameterValue::UnsignedInt(parent))
+        );
+        parameters.insert(
+            "bone".to_string(),
+            Parameter::new("bone", ParameterValue::String(bone.to_string()))
+        );
+        parameters.insert(
+            "offset_position".to_string(),
+            Parameter::new("offset_position", ParameterValue::Vec3(offset_position))
+        );
+        parameters.insert(
+            "offset_rotation".to_string(),
+            Parameter::new("offset_rotation", ParameterValue::Quaternion(offset_rotation))
+        );
+
+        Component {
+            name: "Attachment".to_string(),
+            parameters,
+            component_type: COMPONENT_TYPE_ATTACHMENT.clone(),
+        }
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Component {
+        Self::new(0, "", Vec3::new(0.0, 0.0, 0.0), Quaternion::new(0.0, 0.0, 0.0, 1.0))
+    }
+}
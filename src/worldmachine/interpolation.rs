@@ -0,0 +1,309 @@
+use crate::optimisations::helpers::{ lerp_vec3, nlerp_quaternion };
+use crate::server::replication::{ AppliedSnapshot, EntityState };
+use crate::worldmachine::EntityId;
+use gfx_maths::{ Quaternion, Vec3 };
+use halfbrown::HashMap;
+use std::collections::VecDeque;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+pub const DEFAULT_INTERPOLATION_DELAY: f64 = 0.1;
+pub const MAX_EXTRAPOLATION: f64 = 0.25;
+pub const INTERPOLATION_BUFFER_SIZE: usize = 32;
+const CLOCK_DRIFT_CORRECTION: f64 = 0.05;
+
+pub fn now_seconds() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |time| time.as_secs_f64())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InterpolatedState {
+    pub position: Vec3,
+    pub rotation: Quaternion,
+    pub scale: Vec3,
+    pub head_rotation: Option<Quaternion>,
+}
+
+impl InterpolatedState {
+    fn from_state(state: &EntityState) -> Self {
+        Self {
+            position: state.position.to_vec3(),
+            rotation: state.rotation.to_quaternion(),
+            scale: state.scale.to_vec3(),
+            head_rotation: state.head_rotation.map(|rotation| rotation.to_quaternion()),
+        }
+    }
+
+    fn blend(&self, other: &Self, t: f32) -> Self {
+        Self {
+            position: lerp_vec3(self.position, other.position, t),
+            rotation: nlerp_quaternion(self.rotation, other.rotation, t.clamp(0.0, 1.0)),
+            scale: lerp_vec3(self.scale, other.scale, t.clamp(0.0, 1.0)),
+            head_rotation: match (self.head_rotation, other.head_rotation) {
+                (Some(from), Some(to)) => Some(nlerp_quaternion(from, to, t.clamp(0.0, 1.0))),
+                (_, to) => to,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferStatus {
+    Waiting,
+    Interpolating,
+    Extrapolating,
+    Holding,
+}
+
+struct InterpolationBuffer {
+    samples: VecDeque<(f64, InterpolatedState)>,
+    status: BufferStatus,
+    last_output: Option<InterpolatedState>,
+}
+
+impl InterpolationBuffer {
+    fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            status: BufferStatus::Waiting,
+            last_output: None,
+        }
+    }
+
+    fn push(&mut self, time: f64, state: InterpolatedState) {
+        if self.samples.back().is_some_and(|(latest, _)| time <= *latest) {
+            return;
+        }
+        self.samples.push_back((time, state));
+        if self.samples.len() > INTERPOLATION_BUFFER_SIZE {
+            self.samples.pop_front();
+        }
+    }
+
+    fn sample(&mut self, render_time: f64) -> Option<InterpolatedState> {
+        while self.samples.len() > 2 && self.samples[1].0 <= render_time {
+            self.samples.pop_front();
+        }
+        let (oldest_time, oldest) = *self.samples.front()?;
+        if render_time <= oldest_time {
+            self.status = BufferStatus::Waiting;
+            return Some(oldest);
+        }
+        if let Some((newest_time, newest)) = self.samples.get(1).copied() {
+            if render_time <= newest_time {
+                self.status = BufferStatus::Interpolating;
+                let t = (render_time - oldest_time) / (newest_time - oldest_time);
+                return Some(oldest.blend(&newest, t as f32));
+            }
+            let ahead = render_time - newest_time;
+            self.status = if ahead <= MAX_EXTRAPOLATION {
+                BufferStatus::Extrapolating
+            } else {
+                BufferStatus::Holding
+            };
+            let t = (newest_time + ahead.min(MAX_EXTRAPOLATION) - oldest_time) /
+                (newest_time - oldest_time);
+            return Some(oldest.blend(&newest, t as f32));
+        }
+        self.status = BufferStatus::Holding;
+        Some(oldest)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InterpolationStats {
+    pub delay: f64,
+    pub entities: usize,
+    pub buffered_samples: usize,
+    pub buffered_ahead: f64,
+    pub waiting: usize,
+    pub interpolating: usize,
+    pub extrapolating: usize,
+    pub holding: usize,
+}
+
+pub struct Interpolator {
+    pub delay: f64,
+    buffers: HashMap<EntityId, InterpolationBuffer>,
+    clock_offset: Option<f64>,
+    latest_time: f64,
+}
+
+impl Default for Interpolator {
+    fn default() -> Self {
+        Self {
+            delay: DEFAULT_INTERPOLATION_DELAY,
+            buffers: HashMap::new(),
+            clock_offset: None,
+            latest_time: 0.0,
+        }
+    }
+}
+
+impl Interpolator {
+    pub fn clear(&mut self) {
+        self.buffers.clear();
+        self.clock_offset = None;
+        self.latest_time = 0.0;
+    }
+
+    pub fn push(&mut self, snapshot: &AppliedSnapshot) {
+        let offset = snapshot.time - now_seconds();
+        self.clock_offset = Some(match self.clock_offset {
+            Some(current) if offset < current => current + (offset - current) * CLOCK_DRIFT_CORRECTION,
+            _ => offset,
+        });
+        self.latest_time = self.latest_time.max(snapshot.time);
+        self.buffers.retain(|entity, _| snapshot.entities.contains_key(entity));
+        for (entity, state) in snapshot.entities.iter() {
+            self.buffers
+                .entry(*entity)
+                .or_insert_with(InterpolationBuffer::new)
+                .push(snapshot.time, InterpolatedState::from_state(state));
+        }
+    }
+
+    pub fn render_time(&self) -> Option<f64> {
+        self.clock_offset.map(|offset| now_seconds() + offset - self.delay)
+    }
+
+    pub fn sample(&mut self) -> Vec<(EntityId, InterpolatedState)> {
+        let render_time = match self.render_time() {
+            Some(render_time) => render_time,
+            None => return Vec::new(),
+        };
+        let mut moved = Vec::new();
+        for (entity, buffer) in self.buffers.iter_mut() {
+            if let Some(state) = buffer.sample(render_time) {
+                if buffer.last_output != Some(state) {
+                    buffer.last_output = Some(state);
+                    moved.push((*entity, state));
+                }
+            }
+        }
+        moved
+    }
+
+    pub fn stats(&self) -> InterpolationStats {
+        let mut stats = InterpolationStats {
+            delay: self.delay,
+            entities: self.buffers.len(),
+            buffered_ahead: self
+                .render_time()
+                .map_or(0.0, |render_time| self.latest_time - render_time),
+            ..Default::default()
+        };
+        for buffer in self.buffers.values() {
+            stats.buffered_samples += buffer.samples.len();
+            match buffer.status {
+                BufferStatus::Waiting => {
+                    stats.waiting += 1;
+                }
+                BufferStatus::Interpolating => {
+                    stats.interpolating += 1;
+                }
+                BufferStatus::Extrapolating => {
+                    stats.extrapolating += 1;
+                }
+                BufferStatus::Holding => {
+                    stats.holding += 1;
+                }
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::replication::{ QuantisedQuaternion, QuantisedVec3 };
+    use std::collections::BTreeMap;
+
+    fn at(x: f32) -> InterpolatedState {
+        InterpolatedState {
+            position: Vec3::new(x, 0.0, 0.0),
+            rotation: Quaternion::identity(),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+            head_rotation: None,
+        }
+    }
+
+    fn applied(time: f64) -> AppliedSnapshot {
+        let state = EntityState {
+            position: QuantisedVec3::new(Vec3::zero()),
+            rotation: QuantisedQuaternion::new(Quaternion::identity()),
+            scale: QuantisedVec3::new(Vec3::new(1.0, 1.0, 1.0)),
+            head_rotation: None,
+        };
+        AppliedSnapshot {
+            tick: 0,
+            time,
+            changed: Vec::new(),
+            entities: BTreeMap::from([(1, state)]),
+        }
+    }
+
+    #[test]
+    fn samples_between_two_snapshots_are_blended() {
+        let mut buffer = InterpolationBuffer::new();
+        buffer.push(10.0, at(0.0));
+        buffer.push(11.0, at(4.0));
+        // out of order samples are ignored
+        buffer.push(10.5, at(100.0));
+
+        assert_eq!(buffer.sample(9.0), Some(at(0.0)));
+        assert_eq!(buffer.status, BufferStatus::Waiting);
+        assert_eq!(buffer.sample(10.25).unwrap().position, Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(buffer.status, BufferStatus::Interpolating);
+        assert_eq!(buffer.sample(10.75).unwrap().position, Vec3::new(3.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn running_dry_extrapolates_a_little_and_then_holds() {
+        let mut buffer = InterpolationBuffer::new();
+        buffer.push(10.0, at(0.0));
+        buffer.push(11.0, at(4.0));
+
+        let ahead = buffer.sample(11.1).unwrap().position.x;
+        assert_eq!(buffer.status, BufferStatus::Extrapolating);
+        assert!((ahead - 4.4).abs() < 1e-4);
+
+        let limit = 4.0 + 4.0 * (MAX_EXTRAPOLATION as f32);
+        for render_time in [12.0, 20.0] {
+            let held = buffer.sample(render_time).unwrap().position.x;
+            assert_eq!(buffer.status, BufferStatus::Holding);
+            assert!((held - limit).abs() < 1e-4);
+        }
+
+        let mut lonely = InterpolationBuffer::new();
+        lonely.push(10.0, at(2.0));
+        assert_eq!(lonely.sample(15.0), Some(at(2.0)));
+        assert_eq!(lonely.status, BufferStatus::Holding);
+    }
+
+    #[test]
+    fn clock_offset_eases_down_but_jumps_up() {
+        let mut interpolator = Interpolator::default();
+        assert!(interpolator.render_time().is_none());
+
+        interpolator.push(&applied(now_seconds() + 5.0));
+        assert!((interpolator.clock_offset.unwrap() - 5.0).abs() < 0.01);
+
+        // a late packet only nudges the offset down, so one slow packet can't drag the clock back
+        interpolator.push(&applied(now_seconds() + 4.0));
+        let nudged = interpolator.clock_offset.unwrap();
+        assert!(nudged < 5.0 && nudged > 4.5);
+        for _ in 0..200 {
+            interpolator.push(&applied(now_seconds() + 4.0));
+        }
+        assert!((interpolator.clock_offset.unwrap() - 4.0).abs() < 0.01);
+
+        interpolator.push(&applied(now_seconds() + 6.0));
+        assert!((interpolator.clock_offset.unwrap() - 6.0).abs() < 0.01);
+
+        let render_time = interpolator.render_time().unwrap();
+        assert!((render_time - (now_seconds() + 6.0 - interpolator.delay)).abs() < 0.01);
+    }
+}
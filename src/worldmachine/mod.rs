@@ -1,55 +1,86 @@
-use fyrox_sound::context::SoundContext;
 use gfx_maths::{ Quaternion, Vec3 };
 use halfbrown::HashMap;
 use serde::{ Deserialize, Serialize };
 use std::borrow::{ Borrow, BorrowMut };
 use std::collections::VecDeque;
 use std::ops::Deref;
-use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use crate::audio::AudioBackend;
-use crate::common_anim::animation_move::MoveAnim;
 use crate::physics::{ Materials, PhysicsSystem };
+use crate::server::config::SimulationConfig;
+use crate::server::disconnect::DisconnectReason;
+use crate::server::replication::SnapshotReceiver;
 use crate::server::server_player::ServerPlayerContainer;
+use crate::server::transport::TransportCounters;
 use crate::server::{
     ConnectionClientside,
     ConnectionUUID,
     FastPacket,
     FastPacketData,
     NameRejectionReason,
+    ServerAddress,
     SteadyPacket,
     SteadyPacketData,
 };
 use crate::ui_defs::chat;
 use crate::worldmachine::components::{
+    Attachment,
+    Transform,
+    COMPONENT_TYPE_ATTACHMENT,
     COMPONENT_TYPE_BOX_COLLIDER,
-    COMPONENT_TYPE_JUKEBOX,
-    COMPONENT_TYPE_LIGHT,
-    COMPONENT_TYPE_MESH_RENDERER,
     COMPONENT_TYPE_PLAYER,
-    COMPONENT_TYPE_TERRAIN,
     COMPONENT_TYPE_TRANSFORM,
     COMPONENT_TYPE_TRIGGER,
 };
 use crate::worldmachine::ecs::*;
-use crate::worldmachine::player::{ MovementInfo, Player, PlayerContainer };
-use crate::worldmachine::MapLoadError::FolderNotFound;
-use crate::{ server, MutRenderer };
+use crate::worldmachine::interpolation::{ InterpolatedState, Interpolator };
+use crate::worldmachine::navmesh::{ NavMesh, DEBUG_NAVMESH };
+use crate::worldmachine::player::{ Player, PlayerContainer };
+use crate::worldmachine::prediction::InputCommand;
+use crate::worldmachine::MapLoadError::{ FolderNotFound, UnknownComponent };
+use crate::server;
 use mutex_timeouts::tokio::MutexWithTimeoutAuto as Mutex;
 use tokio::sync::mpsc::error::TryRecvError;
 
 use self::throwballs::ThrowingBall;
 
+#[cfg(feature = "graphical")]
+use {
+    crate::audio::AudioBackend,
+    crate::common_anim::animation_move::MoveAnim,
+    crate::optimisations::helpers::{ calculate_model_matrix, decompose_mat4 },
+    crate::skeletal_animation::SkeletalAnimations,
+    crate::worldmachine::components::{
+        COMPONENT_TYPE_JUKEBOX,
+        COMPONENT_TYPE_LIGHT,
+        COMPONENT_TYPE_MESH_RENDERER,
+        COMPONENT_TYPE_TERRAIN,
+    },
+    crate::MutRenderer,
+    fyrox_sound::context::SoundContext,
+    gfx_maths::Mat4,
+    std::sync::atomic::Ordering,
+};
+
 pub mod components;
 pub mod ecs;
 pub mod entities;
 pub mod helpers;
+pub mod interpolation;
+pub mod navmesh;
 pub mod player;
+pub mod prediction;
+pub mod snapshot;
 pub mod throwballs;
 
 pub type EntityId = u64;
+// mesh name, model matrix and animation weights of whatever an attachment hangs off
+#[cfg(feature = "graphical")]
+type AttachmentPose = (String, Mat4, Option<Vec<(String, f64)>>);
+
+pub const GAME_DATA_PATH: &str = "base";
+const MAX_FAST_MESSAGES_PER_TICK: usize = 32;
 
 #[derive(Deserialize, Serialize)]
 pub struct World {
@@ -77,10 +108,7 @@ pub enum WorldUpdate {
 
 #[derive(Clone, Debug)]
 pub enum ClientUpdate {
-    IDisplaced((Vec3, Option<MovementInfo>)),
-    ILooked(Quaternion),
-
-    IMoved(Vec3, Option<Vec3>, Quaternion, Quaternion, Option<MovementInfo>),
+    IMoved(InputCommand),
     IJumped,
     IThrewtball,
 }
@@ -88,6 +116,7 @@ pub enum ClientUpdate {
 #[derive(Clone, Debug)]
 pub enum MapLoadError {
     FolderNotFound(String),
+    UnknownComponent(String),
 }
 
 impl Clone for World {
@@ -113,21 +142,30 @@ pub struct WorldMachine {
     pub world: World,
     pub tballs: Vec<ThrowingBall>,
     pub physics: Arc<mutex_timeouts::std::MutexWithTimeout<Option<PhysicsSystem>>>,
-    pub last_physics_update: std::time::Instant,
     pub game_data_path: String,
     pub counter: f32,
+    pub tick: u64,
     pub entities_wanting_to_load_things: Vec<usize>,
     pub command: String,
     lights_changed: bool,
     is_server: bool,
     server_connection: Option<crate::server::ConnectionClientside>,
+    pub server_address: Option<ServerAddress>,
+    pub resume_token: Option<String>,
+    pub disconnect_reason: Option<DisconnectReason>,
     world_update_queue: Arc<Mutex<VecDeque<WorldUpdate>>>,
     client_update_queue: Arc<Mutex<VecDeque<ClientUpdate>>>,
     pub player: Option<PlayerContainer>,
     ignore_this_entity: Option<EntityId>,
     pub players: Option<Arc<Mutex<HashMap<ConnectionUUID, ServerPlayerContainer>>>>,
+    pub navmesh: Option<Arc<NavMesh>>,
+    snapshot_receiver: SnapshotReceiver,
+    pub interpolation: Interpolator,
+    pub network_counters: Option<TransportCounters>,
+    server_tick_rate: u32,
 
     last_ping: Instant,
+    last_input_sent: Instant,
 }
 
 impl Default for WorldMachine {
@@ -142,20 +180,29 @@ impl Default for WorldMachine {
             world,
             tballs: vec![],
             physics: Arc::new(mutex_timeouts::std::MutexWithTimeout::new(None)),
-            last_physics_update: std::time::Instant::now(),
             game_data_path: String::from(""),
             counter: 0.0,
+            tick: 0,
             command: String::new(),
             entities_wanting_to_load_things: Vec::new(),
             lights_changed: true,
             is_server: false,
             server_connection: None,
+            server_address: None,
+            resume_token: None,
+            disconnect_reason: None,
             world_update_queue: Arc::new(Mutex::new(VecDeque::new())),
             client_update_queue: Arc::new(Mutex::new(VecDeque::new())),
             player: None,
             ignore_this_entity: None,
             players: None,
+            navmesh: None,
+            snapshot_receiver: SnapshotReceiver::default(),
+            interpolation: Interpolator::default(),
+            network_counters: None,
+            server_tick_rate: SimulationConfig::default().tick_rate,
             last_ping: Instant::now(),
+            last_input_sent: Instant::now(),
         }
     }
 }
@@ -163,7 +210,7 @@ impl Default for WorldMachine {
 impl WorldMachine {
     pub fn initialise(&mut self, physics: PhysicsSystem, is_server: bool) {
         let _ = *components::COMPONENTS_INITIALISED;
-        self.game_data_path = String::from("base");
+        self.game_data_path = String::from(GAME_DATA_PATH);
         self.physics = Arc::new(mutex_timeouts::std::MutexWithTimeout::new(Some(physics)));
         self.is_server = is_server;
 
@@ -182,7 +229,11 @@ impl WorldMachine {
         }
         self.world.entities.clear();
         self.world.systems.clear();
+        if let Some(physics) = self.physics.lock().unwrap().as_ref() {
+            physics.release_colliders();
+        }
         self.counter = 0.0;
+        self.tick = 0;
         self.lights_changed = true;
     }
 
@@ -200,11 +251,12 @@ impl WorldMachine {
         for entity in world_def.world.entities {
             let mut entity_new = unsafe { Entity::new(entity.name.as_str()) };
             for component in entity.components {
-                let component_type = ComponentType::get(component.get_type().name);
-                if component_type.is_none() {
-                    panic!("component type not found: {}", component.get_type().name);
-                }
-                let component_type = component_type.unwrap();
+                let component_type = match ComponentType::get(component.get_type().name) {
+                    Some(component_type) => component_type,
+                    None => {
+                        return Err(UnknownComponent(component.get_type().name));
+                    }
+                };
                 let mut component = component;
                 component.component_type = component_type.clone();
 
@@ -213,23 +265,84 @@ impl WorldMachine {
             self.world.entities.push(entity_new);
         }
 
+        for system in world_def.world.systems {
+            self.world.systems.push(system);
+        }
+
+        self.finish_map_load(map_name, &map_dir);
+
+        Ok(())
+    }
+
+    pub fn load_saved_world(
+        &mut self,
+        map_name: &str,
+        entities: Vec<Entity>,
+        systems: Vec<System>,
+    ) -> Result<(), MapLoadError> {
+        let map_dir = format!("{}/maps/{}", self.game_data_path, map_name);
+        if !std::path::Path::new(&map_dir).exists() {
+            return Err(FolderNotFound(map_dir));
+        }
+        // checked before anything is cleared so a bad save leaves the current world alone
+        let mut entities = entities;
+        for entity in &mut entities {
+            for component in &mut entity.components {
+                component.component_type = match ComponentType::get(component.get_type().name) {
+                    Some(component_type) => component_type,
+                    None => {
+                        return Err(UnknownComponent(component.get_type().name));
+                    }
+                };
+            }
+        }
+        self.blank_slate(self.is_server);
+
+        let mut highest_uid = 0;
+        for entity in entities {
+            highest_uid = highest_uid.max(entity.uid);
+            self.world.entities.push(entity);
+        }
+        {
+            let mut eid_manager = ENTITY_ID_MANAGER.lock().unwrap();
+            eid_manager.borrow_mut().id = highest_uid;
+        }
+
+        self.world.systems = systems;
+
+        self.finish_map_load(map_name, &map_dir);
+
+        Ok(())
+    }
+
+    fn finish_map_load(&mut self, map_name: &str, map_dir: &str) {
         self.world.current_map = map_name.to_string();
 
         self.initialise_entities();
 
         if self.is_server {
+            match NavMesh::load_or_bake(map_dir, &self.world.entities, &self.game_data_path) {
+                Ok(navmesh) => {
+                    let navmesh = Arc::new(navmesh);
+                    *DEBUG_NAVMESH.lock().unwrap() = Some(navmesh.clone());
+                    self.navmesh = Some(navmesh);
+                }
+                Err(e) => {
+                    warn!("load_map: no navmesh for {}: {:?}", map_name, e);
+                    self.navmesh = None;
+                }
+            }
+
             let mut entity_init_packets = Vec::new();
             for entity in &self.world.entities {
                 entity_init_packets.push(WorldUpdate::InitEntity(entity.uid, entity.clone()));
             }
             self.queue_updates(entity_init_packets);
         }
+    }
 
-        for system in world_def.world.systems {
-            self.world.systems.push(system);
-        }
-
-        Ok(())
+    pub fn current_map(&self) -> String {
+        self.world.current_map.clone()
     }
 
     pub fn initialise_entities(&mut self) {
@@ -339,6 +452,234 @@ impl WorldMachine {
         self.world.entities.remove(index);
     }
 
+    pub async fn attach_entity(
+        &mut self,
+        entity_id: EntityId,
+        parent: EntityId,
+        bone: &str,
+        offset_position: Vec3,
+        offset_rotation: Quaternion
+    ) -> bool {
+        let entity_index = self.get_entity_index(entity_id);
+        if entity_index.is_none() {
+            warn!("attach_entity: entity {} does not exist", entity_id);
+            return false;
+        }
+        let entity = &mut self.world.entities[entity_index.unwrap()];
+        entity.remove_component(COMPONENT_TYPE_ATTACHMENT.clone());
+        entity.add_component(Attachment::new(parent, bone, offset_position, offset_rotation));
+        if !entity.has_component(COMPONENT_TYPE_TRANSFORM.clone()) {
+            entity.add_component(Transform::default());
+        }
+        entity.parent = Some(parent);
+        let entity = entity.clone();
+        if self.is_server {
+            self.queue_update(WorldUpdate::InitEntity(entity_id, entity)).await;
+        }
+        true
+    }
+
+    pub async fn detach_entity(&mut self, entity_id: EntityId) -> bool {
+        let entity_index = self.get_entity_index(entity_id);
+        if entity_index.is_none() {
+            warn!("detach_entity: entity {} does not exist", entity_id);
+            return false;
+        }
+        let entity = &mut self.world.entities[entity_index.unwrap()];
+        entity.remove_component(COMPONENT_TYPE_ATTACHMENT.clone());
+        entity.parent = None;
+        let entity = entity.clone();
+        if self.is_server {
+            self.queue_update(WorldUpdate::InitEntity(entity_id, entity)).await;
+        }
+        true
+    }
+
+    #[cfg(feature = "graphical")]
+    fn get_attachment_parent_pose(
+        &mut self,
+        parent: EntityId
+    ) -> Option<AttachmentPose> {
+        if self.ignore_this_entity == Some(parent) {
+            let player = self.player.as_mut()?;
+            let position = player.player.get_position();
+            let rotation = player.player.get_rotation();
+            let position = position + rotation.forward() * -0.2 + Vec3::new(0.0, -0.1, 0.0);
+            let move_anim = MoveAnim::from_values(player.player.speed, player.player.strafe);
+            return Some((
+                "player".to_string(),
+                calculate_model_matrix(position, rotation, Vec3::new(1.0, 1.0, 1.0)),
+                Some(move_anim.weights()),
+            ));
+        }
+
+        let entity = self.world.entities.iter().find(|entity| entity.uid == parent)?;
+
+        if let Some(player_component) = entity.get_component(COMPONENT_TYPE_PLAYER.clone()) {
+            let position = match player_component.get_parameter("position").value {
+                ParameterValue::Vec3(v) => v,
+                _ => {
+                    error!("update_attachments: player position is not a vec3");
+                    return None;
+                }
+            };
+            let rotation = match player_component.get_parameter("rotation").value {
+                ParameterValue::Quaternion(v) => v,
+                _ => {
+                    error!("update_attachments: player rotation is not a quaternion");
+                    return None;
+                }
+            };
+            let speed = match player_component.get_parameter("speed").value {
+                ParameterValue::Float(v) => v,
+                _ => 0.0,
+            };
+            let strafe = match player_component.get_parameter("strafe").value {
+                ParameterValue::Float(v) => v,
+                _ => 0.0,
+            };
+            let move_anim = MoveAnim::from_values(speed, strafe);
+            return Some((
+                "player".to_string(),
+                calculate_model_matrix(
+                    position + Vec3::new(0.0, -0.1, 0.0),
+                    rotation,
+                    Vec3::new(1.0, 1.0, 1.0)
+                ),
+                Some(move_anim.weights()),
+            ));
+        }
+
+        let mesh_renderer = entity.get_component(COMPONENT_TYPE_MESH_RENDERER.clone())?;
+        let mesh_name = match mesh_renderer.get_parameter("mesh").value {
+            ParameterValue::String(ref s) => s.clone(),
+            _ => {
+                error!("update_attachments: mesh is not a string");
+                return None;
+            }
+        };
+        let mut position = Vec3::new(0.0, 0.0, 0.0);
+        let mut rotation = Quaternion::new(0.0, 0.0, 0.0, 1.0);
+        let mut scale = Vec3::new(1.0, 1.0, 1.0);
+        if let Some(transform) = entity.get_component(COMPONENT_TYPE_TRANSFORM.clone()) {
+            if let ParameterValue::Vec3(v) = transform.get_parameter("position").value {
+                position = v;
+            }
+            if let ParameterValue::Quaternion(v) = transform.get_parameter("rotation").value {
+                rotation = v;
+            }
+            if let ParameterValue::Vec3(v) = transform.get_parameter("scale").value {
+                scale = v;
+            }
+        }
+        Some((mesh_name, calculate_model_matrix(position, rotation, scale), None))
+    }
+
+    #[cfg(feature = "graphical")]
+    fn pose_attachment_parent(
+        &mut self,
+        renderer: &MutRenderer,
+        parent: EntityId
+    ) -> Option<(String, Mat4, SkeletalAnimations)> {
+        let (mesh_name, model_matrix, anim_weights) = self.get_attachment_parent_pose(parent)?;
+        let mesh = renderer.meshes.get(&*mesh_name)?;
+        let animations = mesh.animations.lock().unwrap().clone();
+        let mut animations = match animations {
+            Some(animations) => animations,
+            None => {
+                debug!("update_attachments: mesh '{}' has no skeleton", mesh_name);
+                return None;
+            }
+        };
+        if let Some(anim_weights) = anim_weights.or_else(|| animations.auto_weights()) {
+            animations.pose_with_weights(&anim_weights);
+        }
+        Some((mesh_name, model_matrix, animations))
+    }
+
+    #[cfg(feature = "graphical")]
+    pub fn update_attachments(&mut self, renderer: &MutRenderer) {
+        let mut attachments = Vec::new();
+        for (index, entity) in self.world.entities.iter().enumerate() {
+            if let Some(attachment) = entity.get_component(COMPONENT_TYPE_ATTACHMENT.clone()) {
+                let parent = match attachment.get_parameter("parent").value {
+                    ParameterValue::UnsignedInt(v) => v,
+                    _ => {
+                        error!("update_attachments: attachment parent is not an unsigned int");
+                        continue;
+                    }
+                };
+                let bone = match attachment.get_parameter("bone").value {
+                    ParameterValue::String(ref s) => s.clone(),
+                    _ => {
+                        error!("update_attachments: attachment bone is not a string");
+                        continue;
+                    }
+                };
+                let offset_position = match attachment.get_parameter("offset_position").value {
+                    ParameterValue::Vec3(v) => v,
+                    _ => {
+                        error!("update_attachments: attachment offset_position is not a vec3");
+                        continue;
+                    }
+                };
+                let offset_rotation = match attachment.get_parameter("offset_rotation").value {
+                    ParameterValue::Quaternion(v) => v,
+                    _ => {
+                        error!(
+                            "update_attachments: attachment offset_rotation is not a quaternion"
+                        );
+                        continue;
+                    }
+                };
+                attachments.push((index, parent, bone, offset_position, offset_rotation));
+            }
+        }
+
+        // several attachments usually share a parent, so each skeleton is posed once per frame
+        let mut posed_parents: HashMap<EntityId, Option<(String, Mat4, SkeletalAnimations)>> = HashMap::new();
+        for (index, parent, bone, offset_position, offset_rotation) in attachments {
+            if !posed_parents.contains_key(&parent) {
+                let posed = self.pose_attachment_parent(renderer, parent);
+                posed_parents.insert(parent, posed);
+            }
+            let (mesh_name, model_matrix, animations) = match posed_parents.get(&parent) {
+                Some(Some(posed)) => posed,
+                _ => {
+                    continue;
+                }
+            };
+            let model_matrix = *model_matrix;
+
+            let bone_transform = animations.get_bone_transform(&bone);
+            if bone_transform.is_none() {
+                debug!("update_attachments: bone '{}' not found on mesh '{}'", bone, mesh_name);
+                continue;
+            }
+            let world_matrix =
+                model_matrix *
+                bone_transform.unwrap() *
+                calculate_model_matrix(offset_position, offset_rotation, Vec3::new(1.0, 1.0, 1.0));
+            let (position, rotation) = decompose_mat4(world_matrix);
+
+            let entity = &mut self.world.entities[index];
+            if !entity.has_component(COMPONENT_TYPE_TRANSFORM.clone()) {
+                entity.add_component(Transform::default());
+            }
+            entity.set_component_parameter(
+                COMPONENT_TYPE_TRANSFORM.clone(),
+                "position",
+                ParameterValue::Vec3(position)
+            );
+            entity.set_component_parameter(
+                COMPONENT_TYPE_TRANSFORM.clone(),
+                "rotation",
+                ParameterValue::Quaternion(rotation)
+            );
+        }
+    }
+
+    #[cfg(feature = "graphical")]
     pub fn send_lights_to_renderer(&mut self) -> Option<Vec<crate::light::Light>> {
         let mut lights = Vec::new();
         for entity in &self.world.entities {
@@ -425,6 +766,34 @@ impl WorldMachine {
         self.server_connection = Some(connection);
     }
 
+    pub fn reset_connection(&mut self) {
+        self.server_connection = None;
+        self.server_address = None;
+        self.resume_token = None;
+        self.disconnect_reason = None;
+        self.player = None;
+        self.ignore_this_entity = None;
+        self.snapshot_receiver = SnapshotReceiver::default();
+        self.interpolation.clear();
+        self.tballs.clear();
+        self.world.entities.clear();
+        self.world.systems.clear();
+        self.lights_changed = true;
+    }
+
+    pub async fn disconnect(&mut self, reason: DisconnectReason) {
+        if self.server_connection.is_none() {
+            return;
+        }
+        info!("disconnecting from server: {}", reason);
+        self.send_steady_message(SteadyPacketData {
+            packet: SteadyPacket::Disconnect(reason.clone()),
+            uuid: server::generate_uuid(),
+        }).await;
+        self.server_connection = None;
+        self.disconnect_reason = Some(reason);
+    }
+
     async fn send_fast_message(&mut self, message: FastPacketData) {
         if let Some(connection) = &mut self.server_connection {
             match connection {
@@ -436,12 +805,26 @@ impl WorldMachine {
                     }
                 }
                 ConnectionClientside::Lan(connection) => {
-                    let attempt = connection.send_fast_and_serialise(message).await;
+                    connection.send_fast_and_serialise(message).await;
+                }
+                ConnectionClientside::WebSocket(connection) => {
+                    if connection.send_fast_and_serialise(message).await.is_err() {
+                        error!("send_fast_message: failed to send message");
+                    }
                 }
             }
         }
     }
 
+    async fn flush_fast_messages(&mut self) {
+        if let Some(ConnectionClientside::Lan(connection)) = &self.server_connection {
+            if let Err(e) = connection.flush_fast().await {
+                error!("flush_fast_messages: failed to send messages: {:?}", e);
+            }
+            self.network_counters = Some(connection.counters().await);
+        }
+    }
+
     async fn send_steady_message(&mut self, message: SteadyPacketData) -> bool {
         if let Some(connection) = &mut self.server_connection {
             match connection {
@@ -462,6 +845,14 @@ impl WorldMachine {
                     }
                     true
                 }
+                ConnectionClientside::WebSocket(connection) => {
+                    let attempt = connection.send_steady_and_serialise(message).await;
+                    if attempt.is_err() {
+                        error!("send_steady_message: failed to send message");
+                        return false;
+                    }
+                    true
+                }
             }
         } else {
             false
@@ -538,6 +929,7 @@ impl WorldMachine {
             }
             SteadyPacket::Message(str_message) => {
                 info!("Received message from server: {}", str_message);
+                chat::write_chat("server".to_string(), str_message);
             }
             SteadyPacket::InitialisePlayer(uuid, id, name, position, rotation, scale) => {
                 debug!("initialise player message received");
@@ -682,6 +1074,12 @@ impl WorldMachine {
                             "your name was rejected because it is already taken".to_string()
                         );
                     }
+                    NameRejectionReason::BoundToAccount => {
+                        chat::write_chat(
+                            "server".to_string(),
+                            "you are logged in, so your name is your account name".to_string()
+                        );
+                    }
                 }
             SteadyPacket::ThrowThrowAballll(uuid, position, initial_velocity) => {
                 let mut already_have = false;
@@ -708,6 +1106,24 @@ impl WorldMachine {
                 }
             }
             SteadyPacket::Ping => {}
+            SteadyPacket::Disconnect(reason) => {
+                info!("disconnected by server: {}", reason);
+                chat::write_chat("engine".to_string(), format!("disconnected: {}", reason));
+                // the server only keeps our player around if we dropped out
+                if !reason.is_resumable() {
+                    self.resume_token = None;
+                }
+                self.disconnect_reason = Some(reason);
+                self.server_connection = None;
+                #[cfg(feature = "graphical")]
+                crate::ui::DISCONNECTED.store(true, Ordering::Relaxed);
+            }
+            SteadyPacket::ResumeToken(token) => {
+                self.resume_token = Some(token);
+            }
+            SteadyPacket::TickRate(tick_rate) => {
+                self.server_tick_rate = tick_rate.max(1);
+            }
         }
     }
 
@@ -733,145 +1149,146 @@ impl WorldMachine {
                         self.handle_steady_message(message.clone().packet).await;
                     }
                 }
+                ConnectionClientside::WebSocket(connection) => {
+                    let try_recv = connection.attempt_receive_steady_and_deserialise().await;
+                    if let Some(message) = try_recv {
+                        self.handle_steady_message(message.packet).await;
+                    }
+                }
             }
         }
     }
 
-    async fn handle_message_fast(&mut self, packet: FastPacket) {
-        match packet.clone() {
-            FastPacket::ChangePosition(entity_id, vec3) => {
-                if let Some(ignore) = self.ignore_this_entity {
-                    if entity_id == ignore {
-                        return;
-                    }
-                }
-                if let Some(entity_index) = self.get_entity_index(entity_id) {
-                    let entity = self.world.entities.get_mut(entity_index).unwrap();
-                    let transform = entity.set_component_parameter(
-                        COMPONENT_TYPE_TRANSFORM.clone(),
-                        "position",
-                        ParameterValue::Vec3(vec3)
-                    );
-                    if transform.is_none() {
-                        warn!("process_fast_messages: failed to set transform rotation");
-                    }
-                }
+    fn apply_interpolation(&mut self) {
+        for (entity_id, state) in self.interpolation.sample() {
+            self.apply_entity_state(entity_id, state);
+        }
+    }
+
+    fn apply_entity_state(&mut self, entity_id: EntityId, state: InterpolatedState) {
+        if let Some(ignore) = self.ignore_this_entity {
+            if entity_id == ignore {
+                return;
             }
-            FastPacket::ChangeRotation(entity_id, quat) => {
-                if let Some(ignore) = self.ignore_this_entity {
-                    if entity_id == ignore {
-                        return;
-                    }
-                }
-                if let Some(entity_index) = self.get_entity_index(entity_id) {
-                    let entity = self.world.entities.get_mut(entity_index).unwrap();
-                    let transform = entity.set_component_parameter(
-                        COMPONENT_TYPE_TRANSFORM.clone(),
-                        "rotation",
-                        ParameterValue::Quaternion(quat)
-                    );
-                    if transform.is_none() {
-                        warn!("process_fast_messages: failed to set transform rotation");
-                    }
+        }
+        let position = state.position;
+        let rotation = state.rotation;
+        if let Some(head_rotation) = state.head_rotation {
+            self.apply_player_movement(entity_id, position, rotation, head_rotation);
+            return;
+        }
+        if let Some(entity_index) = self.get_entity_index(entity_id) {
+            let entity = self.world.entities.get_mut(entity_index).unwrap();
+            let parameters = [
+                ("position", ParameterValue::Vec3(position)),
+                ("rotation", ParameterValue::Quaternion(rotation)),
+                ("scale", ParameterValue::Vec3(state.scale)),
+            ];
+            for (name, value) in parameters {
+                let transform = entity.set_component_parameter(
+                    COMPONENT_TYPE_TRANSFORM.clone(),
+                    name,
+                    value
+                );
+                if transform.is_none() {
+                    warn!("apply_entity_state: failed to set transform {}", name);
                 }
             }
-            FastPacket::ChangeScale(entity_id, vec3) => {
-                if let Some(ignore) = self.ignore_this_entity {
-                    if entity_id == ignore {
+        }
+    }
+
+    fn apply_player_movement(
+        &mut self,
+        entity_id: EntityId,
+        new_position: Vec3,
+        new_rotation: Quaternion,
+        new_head_rotation: Quaternion
+    ) {
+        if let Some(entity_index) = self.get_entity_index(entity_id) {
+            let entity = self.world.entities.get_mut(entity_index).unwrap();
+            let prev_transform = entity.get_component(COMPONENT_TYPE_PLAYER.clone());
+            if let Some(prev_transform) = prev_transform {
+                let prev_position = prev_transform.get_parameter("position");
+
+                let prev_position = match prev_position.value {
+                    ParameterValue::Vec3(vec3) => vec3,
+                    _ => {
+                        warn!("process_fast_messages: failed to get previous position");
                         return;
                     }
+                };
+
+                let position_diff = new_position - prev_position;
+                let forward_mag = position_diff.dot(new_rotation.forward());
+                let strafe_mag = position_diff.dot(new_rotation.right());
+                const threshold: f32 = 0.01;
+                let forward_mag = if forward_mag.abs() < threshold {
+                    0.0
+                } else {
+                    1.0 * forward_mag.signum()
+                };
+                let strafe_mag = if strafe_mag.abs() < threshold {
+                    0.0
+                } else {
+                    1.0 * strafe_mag.signum()
+                };
+
+                let player_component = entity.set_component_parameter(
+                    COMPONENT_TYPE_PLAYER.clone(),
+                    "speed",
+                    ParameterValue::Float(forward_mag as f64)
+                );
+                if player_component.is_none() {
+                    warn!("process_fast_messages: failed to set transform position");
                 }
-                if let Some(entity_index) = self.get_entity_index(entity_id) {
-                    let entity = self.world.entities.get_mut(entity_index).unwrap();
-                    let transform = entity.set_component_parameter(
-                        COMPONENT_TYPE_TRANSFORM.clone(),
-                        "scale",
-                        ParameterValue::Vec3(vec3)
-                    );
-                    if transform.is_none() {
-                        warn!("process_fast_messages: failed to set transform scale");
-                    }
+                let player_component = entity.set_component_parameter(
+                    COMPONENT_TYPE_PLAYER.clone(),
+                    "strafe",
+                    ParameterValue::Float(strafe_mag as f64)
+                );
+                if player_component.is_none() {
+                    warn!("process_fast_messages: failed to set transform position");
                 }
             }
-            FastPacket::PlayerMoved(entity_id, new_position, new_rotation, new_head_rotation) => {
-                if let Some(ignore) = self.ignore_this_entity {
-                    if entity_id == ignore {
-                        return;
-                    }
-                }
-                if let Some(entity_index) = self.get_entity_index(entity_id) {
-                    let entity = self.world.entities.get_mut(entity_index).unwrap();
-                    let prev_transform = entity.get_component(COMPONENT_TYPE_PLAYER.clone());
-                    if let Some(prev_transform) = prev_transform {
-                        let prev_position = prev_transform.get_parameter("position");
-
-                        let prev_position = match prev_position.value {
-                            ParameterValue::Vec3(vec3) => vec3,
-                            _ => {
-                                warn!("process_fast_messages: failed to get previous position");
-                                return;
-                            }
-                        };
-
-                        let position_diff = new_position - prev_position;
-                        let forward_mag = position_diff.dot(new_rotation.forward());
-                        let strafe_mag = position_diff.dot(new_rotation.right());
-                        const threshold: f32 = 0.01;
-                        let forward_mag = if forward_mag.abs() < threshold {
-                            0.0
-                        } else {
-                            1.0 * forward_mag.signum()
-                        };
-                        let strafe_mag = if strafe_mag.abs() < threshold {
-                            0.0
-                        } else {
-                            1.0 * strafe_mag.signum()
-                        };
 
-                        let player_component = entity.set_component_parameter(
-                            COMPONENT_TYPE_PLAYER.clone(),
-                            "speed",
-                            ParameterValue::Float(forward_mag as f64)
-                        );
-                        if player_component.is_none() {
-                            warn!("process_fast_messages: failed to set transform position");
-                        }
-                        let player_component = entity.set_component_parameter(
-                            COMPONENT_TYPE_PLAYER.clone(),
-                            "strafe",
-                            ParameterValue::Float(strafe_mag as f64)
-                        );
-                        if player_component.is_none() {
-                            warn!("process_fast_messages: failed to set transform position");
-                        }
-                    }
+            let player_component = entity.set_component_parameter(
+                COMPONENT_TYPE_PLAYER.clone(),
+                "position",
+                ParameterValue::Vec3(new_position)
+            );
+            if player_component.is_none() {
+                warn!("process_fast_messages: failed to set transform position");
+            }
+            let player_component = entity.set_component_parameter(
+                COMPONENT_TYPE_PLAYER.clone(),
+                "rotation",
+                ParameterValue::Quaternion(new_rotation)
+            );
+            if player_component.is_none() {
+                warn!("process_fast_messages: failed to set transform rotation");
+            }
+            let player_component = entity.set_component_parameter(
+                COMPONENT_TYPE_PLAYER.clone(),
+                "head_rotation",
+                ParameterValue::Quaternion(new_head_rotation)
+            );
+            if player_component.is_none() {
+                warn!("process_fast_messages: failed to set transform rotation");
+            }
+        }
+    }
 
-                    let player_component = entity.set_component_parameter(
-                        COMPONENT_TYPE_PLAYER.clone(),
-                        "position",
-                        ParameterValue::Vec3(new_position)
-                    );
-                    if player_component.is_none() {
-                        warn!("process_fast_messages: failed to set transform position");
-                    }
-                    let player_component = entity.set_component_parameter(
-                        COMPONENT_TYPE_PLAYER.clone(),
-                        "rotation",
-                        ParameterValue::Quaternion(new_rotation)
-                    );
-                    if player_component.is_none() {
-                        warn!("process_fast_messages: failed to set transform rotation");
-                    }
-                    let player_component = entity.set_component_parameter(
-                        COMPONENT_TYPE_PLAYER.clone(),
-                        "head_rotation",
-                        ParameterValue::Quaternion(new_head_rotation)
-                    );
-                    if player_component.is_none() {
-                        warn!("process_fast_messages: failed to set transform rotation");
-                    }
+    async fn handle_message_fast(&mut self, packet: FastPacket) {
+        match packet.clone() {
+            FastPacket::Snapshot(delta) => {
+                if let Some(applied) = self.snapshot_receiver.receive(delta) {
+                    self.send_fast_message(FastPacketData {
+                        packet: Some(FastPacket::SnapshotAck(applied.tick)),
+                    }).await;
+                    self.interpolation.push(&applied);
                 }
             }
+            FastPacket::SnapshotAck(_) => {}
             FastPacket::EntitySetParameter(
                 entity_id,
                 component_type,
@@ -895,12 +1312,9 @@ impl WorldMachine {
                     }
                 }
             }
-            FastPacket::PlayerFuckYouMoveHere(new_position) => {
+            FastPacket::PlayerInputAck(ack) => {
                 if let Some(player) = self.player.as_mut() {
-                    warn!(
-                        "we moved too fast, so the server is telling us to move to a new position"
-                    );
-                    player.player.set_position(new_position);
+                    player.player.reconcile(ack);
                 }
             }
             FastPacket::PlayerFuckYouSetRotation(new_rotation) => {
@@ -912,135 +1326,52 @@ impl WorldMachine {
                     player.player.set_head_rotation(new_rotation);
                 }
             }
-            FastPacket::PlayerCheckPosition(_, _) => {}
-            FastPacket::PlayerMove(_, _, _, _, _, _) => {}
+            FastPacket::PlayerInput(_, _) => {}
             FastPacket::PlayerJump(_) => {}
         }
     }
 
-    async fn process_fast_messages(&mut self) {
+    async fn process_fast_messages(&mut self) -> bool {
         if let Some(connection) = self.server_connection.clone() {
-            match connection {
+            let packet = match connection {
                 ConnectionClientside::Local(connection) => {
                     let mut connection = connection.lock().await;
 
                     let try_recv = connection.fast_update_receiver.try_recv();
                     drop(connection);
-                    if let Ok(message) = try_recv {
-                        self.handle_message_fast(message.clone().packet.unwrap()).await;
-                    } else if let Err(e) = try_recv {
-                        if e != TryRecvError::Empty {
-                            warn!("process_steady_messages: error receiving message: {:?}", e);
+                    match try_recv {
+                        Ok(message) => message.packet,
+                        Err(e) => {
+                            if e != TryRecvError::Empty {
+                                warn!("process_fast_messages: error receiving message: {:?}", e);
+                            }
+                            None
                         }
                     }
                 }
                 ConnectionClientside::Lan(connection) => {
-                    let try_recv = connection.attempt_receive_fast_and_deserialise().await;
-                    if let Some(message) = try_recv {
-                        self.handle_message_fast(message.clone().packet.unwrap()).await;
-                    }
+                    connection
+                        .attempt_receive_fast_and_deserialise().await
+                        .and_then(|message| message.packet)
+                }
+                ConnectionClientside::WebSocket(connection) => {
+                    connection
+                        .attempt_receive_fast_and_deserialise().await
+                        .and_then(|message| message.packet)
                 }
+            };
+            if let Some(packet) = packet {
+                self.handle_message_fast(packet).await;
+                return true;
             }
         }
+        false
     }
 
-    async fn process_client_updates(&mut self, client_updates: &mut Vec<ClientUpdate>) {
-        let mut updates = Vec::new();
-        let mut movement_updates = Vec::new();
-        let mut jumped_real = false;
-        let mut movement_info = None;
-        for client_update in client_updates {
+    async fn process_client_updates(&mut self, client_updates: &[ClientUpdate]) {
+        for client_update in client_updates.iter() {
             match client_update {
-                ClientUpdate::IDisplaced(displacement_vector) => {
-                    let position = self.player.as_mut().unwrap().player.get_position();
-                    let rotation = self.player.as_mut().unwrap().player.get_rotation();
-                    let head_rotation = self.player.as_mut().unwrap().player.get_head_rotation();
-                    if movement_info.is_none() {
-                        if let Some(movement_info_some) = displacement_vector.1 {
-                            movement_info = Some(movement_info_some);
-                        }
-                    }
-                    movement_updates.push(
-                        ClientUpdate::IMoved(
-                            position,
-                            Some(displacement_vector.0),
-                            rotation,
-                            head_rotation,
-                            movement_info
-                        )
-                    );
-                }
-                ClientUpdate::ILooked(look_quat) => {
-                    let position = self.player.as_mut().unwrap().player.get_position();
-                    let rotation = self.player.as_mut().unwrap().player.get_rotation();
-                    let head_rotation = self.player.as_mut().unwrap().player.get_head_rotation();
-                    movement_updates.push(
-                        ClientUpdate::IMoved(position, None, rotation, head_rotation, movement_info)
-                    );
-                }
-                ClientUpdate::IJumped => {
-                    jumped_real = true;
-                }
-                _ => {
-                    updates.push(client_update.clone());
-                }
-            }
-        }
-
-        let mut last_displacement_vector = None;
-        if movement_updates.len() > 0 {
-            for update in movement_updates.clone() {
-                if let ClientUpdate::IMoved(_, displacement_vector, _, _, _) = update {
-                    last_displacement_vector = displacement_vector;
-                }
-            }
-            let mut latest_movement_update = movement_updates.last().unwrap().clone();
-
-            if let Some(displacement_vector) = last_displacement_vector {
-                if
-                    let ClientUpdate::IMoved(position, _, rotation, head_rotation, jumped) =
-                        latest_movement_update
-                {
-                    let new = ClientUpdate::IMoved(
-                        position,
-                        Some(displacement_vector),
-                        rotation,
-                        head_rotation,
-                        movement_info
-                    );
-                    latest_movement_update = new;
-                }
-            }
-            updates.push(latest_movement_update.clone());
-        }
-
-        for update in updates {
-            match update {
-                ClientUpdate::IDisplaced(_) => {}
-                ClientUpdate::ILooked(_) => {}
-                ClientUpdate::IMoved(
-                    position,
-                    displacement_vector,
-                    rotation,
-                    head_rotation,
-                    jumped,
-                ) => {
-                    let uuid = self.player.as_ref().unwrap().player.uuid.clone();
-                    let displacement_vector = displacement_vector.unwrap_or(
-                        Vec3::new(0.0, 0.0, 0.0)
-                    );
-                    let packet = FastPacket::PlayerMove(
-                        uuid,
-                        position,
-                        displacement_vector,
-                        rotation,
-                        head_rotation,
-                        movement_info
-                    );
-                    self.send_fast_message(FastPacketData {
-                        packet: Some(packet),
-                    }).await;
-                }
+                ClientUpdate::IMoved(_) => {}
                 ClientUpdate::IJumped => {
                     let uuid = self.player.as_ref().unwrap().player.uuid.clone();
                     let packet = FastPacket::PlayerJump(uuid);
@@ -1053,6 +1384,26 @@ impl WorldMachine {
                 }
             }
         }
+
+        // one packet a server tick however fast we render, the server limits how often we may send
+        let tick_interval = Duration::from_secs_f32(1.0 / (self.server_tick_rate as f32));
+        if self.last_input_sent.elapsed() < tick_interval {
+            return;
+        }
+        let player = match self.player.as_mut() {
+            Some(player) => &mut player.player,
+            None => {
+                return;
+            }
+        };
+        if !player.prediction.has_unsent() {
+            return;
+        }
+        let packet = FastPacket::PlayerInput(player.uuid.clone(), player.prediction.take_batch());
+        self.last_input_sent = Instant::now();
+        self.send_fast_message(FastPacketData {
+            packet: Some(packet),
+        }).await;
     }
 
     async fn ping_if_needed(&mut self) {
@@ -1061,6 +1412,10 @@ impl WorldMachine {
                 packet: SteadyPacket::Ping,
                 uuid: server::generate_uuid(),
             }).await;
+            if !res && self.disconnect_reason.is_none() {
+                self.disconnect_reason = Some(DisconnectReason::ConnectionLost);
+            }
+            #[cfg(feature = "graphical")]
             if !res {
                 crate::ui::DISCONNECTED.store(true, Ordering::Relaxed);
             }
@@ -1068,11 +1423,17 @@ impl WorldMachine {
         }
     }
 
-    pub async fn tick_connection(&mut self, client_updates: &mut Vec<ClientUpdate>) {
+    pub async fn tick_connection(&mut self, client_updates: &[ClientUpdate]) {
         self.process_steady_messages().await;
-        self.process_fast_messages().await;
+        for _ in 0..MAX_FAST_MESSAGES_PER_TICK {
+            if !self.process_fast_messages().await {
+                break;
+            }
+        }
+        self.apply_interpolation();
         self.process_client_updates(client_updates).await;
         self.ping_if_needed().await;
+        self.flush_fast_messages().await;
     }
 
     pub async fn server_tick(&mut self) -> Option<Vec<WorldUpdate>> {
@@ -1114,6 +1475,7 @@ impl WorldMachine {
         }
     }
 
+    #[cfg(feature = "graphical")]
     pub async fn client_tick(
         &mut self,
         renderer: &mut MutRenderer,
@@ -1160,6 +1522,7 @@ impl WorldMachine {
         updates
     }
 
+    #[cfg(feature = "graphical")]
     pub fn next_frame(&mut self, renderer: &mut MutRenderer) {
         for mesh in &mut renderer.meshes.values_mut() {
             mesh.updated_animations_this_frame = false;
@@ -1169,7 +1532,12 @@ impl WorldMachine {
         }
     }
 
+    #[cfg(feature = "graphical")]
     pub fn render(&mut self, renderer: &mut MutRenderer, shadow_pass: Option<(u8, usize)>) {
+        if shadow_pass.is_none() {
+            self.update_attachments(renderer);
+        }
+
         if let Some(player) = &mut self.player {
             let position = player.player.get_position();
             let rotation = player.player.get_rotation();
@@ -1454,6 +1822,7 @@ impl WorldMachine {
         }
     }
 
+    #[cfg(feature = "graphical")]
     pub fn handle_audio(
         &mut self,
         renderer: &MutRenderer,
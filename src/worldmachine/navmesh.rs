@@ -0,0 +1,893 @@
+use crate::optimisations::helpers::{ calculate_model_matrix, distance };
+use crate::server::server_player::{ DEFAULT_HEIGHT, DEFAULT_RADIUS, DEFAULT_STEPHEIGHT };
+use crate::worldmachine::components::{
+    COMPONENT_TYPE_BOX_COLLIDER,
+    COMPONENT_TYPE_MESH_RENDERER,
+    COMPONENT_TYPE_TRANSFORM,
+};
+use crate::worldmachine::ecs::{ Component, Entity, ParameterValue };
+use gfx_maths::*;
+use halfbrown::HashMap;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{ Arc, Mutex };
+
+// bump whenever the baked format or the source hash changes so old bakes get redone
+pub const NAVMESH_VERSION: u32 = 2;
+pub const NAVMESH_FILE_NAME: &str = "navmesh";
+pub const NAVMESH_CELL_SIZE: f32 = 0.5;
+pub const NAVMESH_MAX_SLOPE: f32 = 45.0;
+pub const NAVMESH_MAX_SEARCH_NODES: usize = 100000;
+pub const NAVMESH_DEBUG_DRAW_DISTANCE: f32 = 30.0;
+
+lazy_static! {
+    pub static ref DEBUG_NAVMESH: Arc<Mutex<Option<Arc<NavMesh>>>> = Arc::new(Mutex::new(None));
+}
+
+#[derive(Clone, Debug)]
+pub enum NavMeshError {
+    Io(String),
+    Serialisation(String),
+    VersionMismatch(u32),
+    NoGeometry,
+}
+
+pub type NavNode = (usize, usize);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NavMesh {
+    pub version: u32,
+    pub source_hash: [u8; 32],
+    pub origin: Vec2,
+    pub cell_size: f32,
+    pub width: usize,
+    pub depth: usize,
+    pub cells: Vec<Vec<f32>>,
+}
+
+#[derive(Clone, Copy)]
+struct SearchEntry {
+    cost: f32,
+    progress: f32,
+    node: NavNode,
+}
+
+impl PartialEq for SearchEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Eq for SearchEntry {}
+
+impl PartialOrd for SearchEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SearchEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+struct BakeCell {
+    surfaces: Vec<f32>,
+    solids: Vec<(f32, f32)>,
+}
+
+struct OrientedBox {
+    centre: Vec3,
+    half: Vec3,
+    rotation: Quaternion,
+}
+
+impl OrientedBox {
+    fn axes(&self) -> [(Vec3, f32); 3] {
+        [
+            (self.rotation.right(), self.half.x),
+            (self.rotation.up(), self.half.y),
+            (self.rotation.forward(), self.half.z),
+        ]
+    }
+
+    fn corners(&self) -> Vec<Vec3> {
+        let [(a, ha), (b, hb), (c, hc)] = self.axes();
+        let mut corners = Vec::with_capacity(8);
+        for sa in [-1.0, 1.0] {
+            for sb in [-1.0, 1.0] {
+                for sc in [-1.0, 1.0] {
+                    corners.push(self.centre + a * (sa * ha) + b * (sb * hb) + c * (sc * hc));
+                }
+            }
+        }
+        corners
+    }
+
+    // where the vertical line through x, z enters and leaves the box, and the up component of the face it leaves through
+    fn column_span(&self, x: f32, z: f32) -> Option<(f32, f32, f32)> {
+        let (dx, dz) = (x - self.centre.x, z - self.centre.z);
+        let mut low = f32::MIN;
+        let mut high = f32::MAX;
+        let mut top_up = 1.0;
+        for (axis, half) in self.axes() {
+            let offset = axis.x * dx + axis.z * dz;
+            if axis.y.abs() < 1e-6 {
+                if offset.abs() > half {
+                    return None;
+                }
+                continue;
+            }
+            let a = (-half - offset) / axis.y;
+            let b = (half - offset) / axis.y;
+            low = low.max(a.min(b));
+            if a.max(b) < high {
+                high = a.max(b);
+                top_up = axis.y.abs();
+            }
+        }
+        if low > high {
+            return None;
+        }
+        Some((self.centre.y + low, self.centre.y + high, top_up))
+    }
+}
+
+fn get_vec3(entity: &Entity, component: &Component, name: &str) -> Option<Vec3> {
+    match component.get_parameters().get(name).map(|p| p.value.clone()) {
+        Some(ParameterValue::Vec3(v)) => Some(v),
+        _ => {
+            error!("navmesh: {} of entity {} is not a vec3", name, entity.name);
+            None
+        }
+    }
+}
+
+fn load_mesh_triangles(path: &str, mesh_name: &str, model: Mat4) -> Option<Vec<[Vec3; 3]>> {
+    let (document, buffers, _images) = gltf::import(path).ok()?;
+    let mesh = document.meshes().find(|m| m.name() == Some(mesh_name))?;
+    let mut triangles = Vec::new();
+    for primitive in mesh.primitives() {
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let positions = reader
+            .read_positions()?
+            .map(|p| {
+                let p = model * Vec4::new(p[0], p[1], p[2], 1.0);
+                Vec3::new(p.x, p.y, p.z)
+            })
+            .collect::<Vec<_>>();
+        let indices = reader.read_indices()?.into_u32().collect::<Vec<_>>();
+        for triangle in indices.chunks_exact(3) {
+            triangles.push([
+                positions[triangle[0] as usize],
+                positions[triangle[1] as usize],
+                positions[triangle[2] as usize],
+            ]);
+        }
+    }
+    Some(triangles)
+}
+
+fn triangle_height_at(triangle: &[Vec3; 3], x: f32, z: f32) -> Option<f32> {
+    let [a, b, c] = triangle;
+    let denominator = (b.z - c.z) * (a.x - c.x) + (c.x - b.x) * (a.z - c.z);
+    if denominator.abs() < f32::EPSILON {
+        return None;
+    }
+    let u = ((b.z - c.z) * (x - c.x) + (c.x - b.x) * (z - c.z)) / denominator;
+    let v = ((c.z - a.z) * (x - c.x) + (a.x - c.x) * (z - c.z)) / denominator;
+    let w = 1.0 - u - v;
+    if u < 0.0 || v < 0.0 || w < 0.0 {
+        return None;
+    }
+    Some(u * a.y + v * b.y + w * c.y)
+}
+
+fn triangle_normal(triangle: &[Vec3; 3]) -> Vec3 {
+    let [a, b, c] = triangle;
+    let ab = Vec3::new(b.x - a.x, b.y - a.y, b.z - a.z);
+    let ac = Vec3::new(c.x - a.x, c.y - a.y, c.z - a.z);
+    let normal = Vec3::new(
+        ab.y * ac.z - ab.z * ac.y,
+        ab.z * ac.x - ab.x * ac.z,
+        ab.x * ac.y - ab.y * ac.x
+    );
+    if normal.magnitude() > 0.0 {
+        normal.normalized()
+    } else {
+        normal
+    }
+}
+
+// sha256 so the hash stays the same across builds and rust releases
+pub fn hash_worlddef(map_dir: &str) -> Result<[u8; 32], NavMeshError> {
+    let bytes = std::fs
+        ::read(format!("{}/worlddef", map_dir))
+        .map_err(|e| NavMeshError::Io(e.to_string()))?;
+    Ok(Sha256::digest(&bytes).into())
+}
+
+impl NavMesh {
+    pub fn bake(entities: &[Entity], game_data_path: &str, source_hash: [u8; 32]) -> Result<Self, NavMeshError> {
+        let mut surfaces = Vec::new();
+        let mut obstacles = Vec::new();
+        let mut boxes = Vec::new();
+
+        for entity in entities {
+            let (trans_position, trans_rotation, trans_scale) = match
+                entity.get_component(COMPONENT_TYPE_TRANSFORM.clone())
+            {
+                Some(transform) => {
+                    let position = get_vec3(entity, transform, "position");
+                    let scale = get_vec3(entity, transform, "scale");
+                    let rotation = match transform.get_parameters().get("rotation").map(|p| p.value.clone()) {
+                        Some(ParameterValue::Quaternion(q)) => Some(q),
+                        _ => None,
+                    };
+                    match (position, rotation, scale) {
+                        (Some(position), Some(rotation), Some(scale)) => (position, rotation, scale),
+                        _ => {
+                            continue;
+                        }
+                    }
+                }
+                None => (Vec3::zero(), Quaternion::identity(), Vec3::new(1.0, 1.0, 1.0)),
+            };
+
+            if let Some(box_collider) = entity.get_component(COMPONENT_TYPE_BOX_COLLIDER.clone()) {
+                let position = get_vec3(entity, box_collider, "position");
+                let scale = get_vec3(entity, box_collider, "scale");
+                if let (Some(position), Some(scale)) = (position, scale) {
+                    boxes.push(OrientedBox {
+                        centre: position + trans_position,
+                        half: scale * trans_scale / 2.0,
+                        rotation: trans_rotation,
+                    });
+                }
+            }
+
+            if let Some(mesh_renderer) = entity.get_component(COMPONENT_TYPE_MESH_RENDERER.clone()) {
+                let mesh_name = match mesh_renderer.get_parameters().get("mesh").map(|p| p.value.clone()) {
+                    Some(ParameterValue::String(mesh_name)) => mesh_name,
+                    _ => {
+                        continue;
+                    }
+                };
+                let model = calculate_model_matrix(trans_position, trans_rotation, trans_scale);
+                let path = format!("{}/models/{}.glb", game_data_path, mesh_name);
+                match load_mesh_triangles(&path, &mesh_name, model) {
+                    Some(triangles) => {
+                        let min_up = NAVMESH_MAX_SLOPE.to_radians().cos();
+                        for triangle in triangles {
+                            if triangle_normal(&triangle).y >= min_up {
+                                surfaces.push(triangle);
+                            } else {
+                                obstacles.push(triangle);
+                            }
+                        }
+                    }
+                    None => {
+                        warn!("navmesh: failed to load triangles from {}, skipping", path);
+                    }
+                }
+            }
+        }
+
+        if surfaces.is_empty() && boxes.is_empty() {
+            return Err(NavMeshError::NoGeometry);
+        }
+
+        let box_corners = boxes
+            .iter()
+            .map(|oriented| oriented.corners())
+            .collect::<Vec<_>>();
+        let mut min = Vec2::new(f32::MAX, f32::MAX);
+        let mut max = Vec2::new(f32::MIN, f32::MIN);
+        for vertices in surfaces.iter().map(|triangle| &triangle[..]).chain(box_corners.iter().map(|c| &c[..])) {
+            for vertex in vertices {
+                min.x = min.x.min(vertex.x);
+                min.y = min.y.min(vertex.z);
+                max.x = max.x.max(vertex.x);
+                max.y = max.y.max(vertex.z);
+            }
+        }
+
+        let cell_size = NAVMESH_CELL_SIZE;
+        let width = (((max.x - min.x) / cell_size).ceil() as usize).max(1);
+        let depth = (((max.y - min.y) / cell_size).ceil() as usize).max(1);
+        let mut navmesh = Self {
+            version: NAVMESH_VERSION,
+            source_hash,
+            origin: min,
+            cell_size,
+            width,
+            depth,
+            cells: vec![Vec::new(); width * depth],
+        };
+
+        let mut bake_cells = (0..width * depth)
+            .map(|_| BakeCell {
+                surfaces: Vec::new(),
+                solids: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+
+        for triangle in surfaces.iter() {
+            let (x0, z0, x1, z1) = navmesh.triangle_cell_bounds(triangle);
+            for z in z0..=z1 {
+                for x in x0..=x1 {
+                    let centre = navmesh.cell_centre(x, z);
+                    if let Some(height) = triangle_height_at(triangle, centre.x, centre.y) {
+                        bake_cells[z * width + x].surfaces.push(height);
+                    }
+                }
+            }
+        }
+
+        for triangle in obstacles.iter() {
+            let [a, b, c] = *triangle;
+            let longest = distance(a, b).max(distance(b, c)).max(distance(a, c));
+            let steps = ((longest / (cell_size * 0.5)).ceil() as usize).max(1);
+            for i in 0..=steps {
+                for j in 0..=steps - i {
+                    let u = (i as f32) / (steps as f32);
+                    let v = (j as f32) / (steps as f32);
+                    let w = 1.0 - u - v;
+                    let point = a * u + b * v + c * w;
+                    if let Some(index) = navmesh.cell_index_at(point.x, point.z) {
+                        bake_cells[index].solids.push((point.y, point.y));
+                    }
+                }
+            }
+        }
+
+        let min_up = NAVMESH_MAX_SLOPE.to_radians().cos();
+        for (oriented, corners) in boxes.iter().zip(box_corners.iter()) {
+            let min_x = corners.iter().map(|c| c.x).fold(f32::MAX, f32::min);
+            let min_z = corners.iter().map(|c| c.z).fold(f32::MAX, f32::min);
+            let max_x = corners.iter().map(|c| c.x).fold(f32::MIN, f32::max);
+            let max_z = corners.iter().map(|c| c.z).fold(f32::MIN, f32::max);
+            let (x0, z0) = navmesh.clamped_cell(min_x, min_z);
+            let (x1, z1) = navmesh.clamped_cell(max_x, max_z);
+            for z in z0..=z1 {
+                for x in x0..=x1 {
+                    let centre = navmesh.cell_centre(x, z);
+                    let cell = &mut bake_cells[z * width + x];
+                    if let Some((_, top, top_up)) = oriented.column_span(centre.x, centre.y) {
+                        if top_up >= min_up {
+                            cell.surfaces.push(top);
+                        }
+                    }
+                    // the cell is blocked wherever the box covers any of it, not just its centre
+                    let half_cell = navmesh.cell_size / 2.0;
+                    let samples = [(0.0, 0.0), (-1.0, -1.0), (-1.0, 1.0), (1.0, -1.0), (1.0, 1.0)];
+                    let solid = samples
+                        .iter()
+                        .filter_map(|(sx, sz)| {
+                            oriented.column_span(centre.x + sx * half_cell, centre.y + sz * half_cell)
+                        })
+                        .fold(None, |span: Option<(f32, f32)>, (low, high, _)| {
+                            Some(match span {
+                                Some((span_low, span_high)) => (span_low.min(low), span_high.max(high)),
+                                None => (low, high),
+                            })
+                        });
+                    if let Some(solid) = solid {
+                        cell.solids.push(solid);
+                    }
+                }
+            }
+        }
+
+        for (index, cell) in bake_cells.iter_mut().enumerate() {
+            cell.surfaces.sort_by(|a, b| a.total_cmp(b));
+            cell.surfaces.dedup_by(|a, b| (*a - *b).abs() < 0.05);
+            let mut walkable = Vec::new();
+            for height in cell.surfaces.iter() {
+                let low = height + DEFAULT_STEPHEIGHT;
+                let high = height + DEFAULT_HEIGHT;
+                let blocked_by_solid = cell.solids
+                    .iter()
+                    .any(|(solid_min, solid_max)| *solid_min < high && *solid_max > low);
+                let blocked_by_surface = cell.surfaces
+                    .iter()
+                    .any(|other| *other > low && *other < high);
+                if !blocked_by_solid && !blocked_by_surface {
+                    walkable.push(*height);
+                }
+            }
+            navmesh.cells[index] = walkable;
+        }
+
+        navmesh.erode(DEFAULT_RADIUS);
+
+        let node_count: usize = navmesh.cells
+            .iter()
+            .map(|c| c.len())
+            .sum();
+        info!("baked navmesh: {}x{} cells, {} walkable nodes", width, depth, node_count);
+        Ok(navmesh)
+    }
+
+    fn erode(&mut self, radius: f32) {
+        let radius_cells = (radius / self.cell_size).ceil() as i32;
+        let mut eroded = self.cells.clone();
+        for z in 0..self.depth {
+            for x in 0..self.width {
+                let index = z * self.width + x;
+                eroded[index].retain(|height| {
+                    for dz in -radius_cells..=radius_cells {
+                        for dx in -radius_cells..=radius_cells {
+                            if dx * dx + dz * dz > radius_cells * radius_cells {
+                                continue;
+                            }
+                            let supported = match self.cell_at(x as i32 + dx, z as i32 + dz) {
+                                Some(other) => self.cells[other]
+                                    .iter()
+                                    .any(|h| (h - height).abs() <= DEFAULT_STEPHEIGHT),
+                                None => false,
+                            };
+                            if !supported {
+                                return false;
+                            }
+                        }
+                    }
+                    true
+                });
+            }
+        }
+        self.cells = eroded;
+    }
+
+    fn cell_centre(&self, x: usize, z: usize) -> Vec2 {
+        Vec2::new(
+            self.origin.x + ((x as f32) + 0.5) * self.cell_size,
+            self.origin.y + ((z as f32) + 0.5) * self.cell_size
+        )
+    }
+
+    fn cell_at(&self, x: i32, z: i32) -> Option<usize> {
+        if x < 0 || z < 0 || (x as usize) >= self.width || (z as usize) >= self.depth {
+            return None;
+        }
+        Some((z as usize) * self.width + (x as usize))
+    }
+
+    fn cell_coords_at(&self, x: f32, z: f32) -> (i32, i32) {
+        (
+            ((x - self.origin.x) / self.cell_size).floor() as i32,
+            ((z - self.origin.y) / self.cell_size).floor() as i32,
+        )
+    }
+
+    fn cell_index_at(&self, x: f32, z: f32) -> Option<usize> {
+        let (x, z) = self.cell_coords_at(x, z);
+        self.cell_at(x, z)
+    }
+
+    fn clamped_cell(&self, x: f32, z: f32) -> (usize, usize) {
+        let (x, z) = self.cell_coords_at(x, z);
+        (
+            x.clamp(0, (self.width as i32) - 1) as usize,
+            z.clamp(0, (self.depth as i32) - 1) as usize,
+        )
+    }
+
+    fn triangle_cell_bounds(&self, triangle: &[Vec3; 3]) -> (usize, usize, usize, usize) {
+        let min_x = triangle[0].x.min(triangle[1].x).min(triangle[2].x);
+        let min_z = triangle[0].z.min(triangle[1].z).min(triangle[2].z);
+        let max_x = triangle[0].x.max(triangle[1].x).max(triangle[2].x);
+        let max_z = triangle[0].z.max(triangle[1].z).max(triangle[2].z);
+        let (x0, z0) = self.clamped_cell(min_x, min_z);
+        let (x1, z1) = self.clamped_cell(max_x, max_z);
+        (x0, z0, x1, z1)
+    }
+
+    pub fn node_position(&self, node: NavNode) -> Vec3 {
+        let x = node.0 % self.width;
+        let z = node.0 / self.width;
+        let centre = self.cell_centre(x, z);
+        Vec3::new(centre.x, self.cells[node.0][node.1], centre.y)
+    }
+
+    pub fn nearest_node(&self, position: Vec3) -> Option<NavNode> {
+        let (cx, cz) = self.cell_coords_at(position.x, position.z);
+        let search_radius = (DEFAULT_RADIUS / self.cell_size).ceil() as i32 + 2;
+        let mut best: Option<(NavNode, f32)> = None;
+        for dz in -search_radius..=search_radius {
+            for dx in -search_radius..=search_radius {
+                let index = match self.cell_at(cx + dx, cz + dz) {
+                    Some(index) => index,
+                    None => {
+                        continue;
+                    }
+                };
+                for (layer, height) in self.cells[index].iter().enumerate() {
+                    let vertical = (height - position.y).abs();
+                    if vertical > DEFAULT_HEIGHT * 2.0 {
+                        continue;
+                    }
+                    let horizontal = ((dx * dx + dz * dz) as f32).sqrt() * self.cell_size;
+                    let score = horizontal + vertical;
+                    if best.map(|(_, s)| score < s).unwrap_or(true) {
+                        best = Some(((index, layer), score));
+                    }
+                }
+            }
+        }
+        best.map(|(node, _)| node)
+    }
+
+    fn connected_layer(&self, index: usize, height: f32) -> Option<usize> {
+        self.cells[index]
+            .iter()
+            .position(|h| (h - height).abs() <= DEFAULT_STEPHEIGHT)
+    }
+
+    pub fn neighbours(&self, node: NavNode) -> Vec<NavNode> {
+        let x = (node.0 % self.width) as i32;
+        let z = (node.0 / self.width) as i32;
+        let height = self.cells[node.0][node.1];
+        let step = |dx: i32, dz: i32| -> Option<NavNode> {
+            let index = self.cell_at(x + dx, z + dz)?;
+            let layer = self.connected_layer(index, height)?;
+            Some((index, layer))
+        };
+        let mut neighbours = Vec::with_capacity(8);
+        for (dx, dz) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            if let Some(neighbour) = step(dx, dz) {
+                neighbours.push(neighbour);
+            }
+        }
+        for (dx, dz) in [(1, 1), (1, -1), (-1, 1), (-1, -1)] {
+            if step(dx, 0).is_some() && step(0, dz).is_some() {
+                if let Some(neighbour) = step(dx, dz) {
+                    neighbours.push(neighbour);
+                }
+            }
+        }
+        neighbours
+    }
+
+    pub fn find_path(&self, start: Vec3, end: Vec3) -> Option<Vec<Vec3>> {
+        let start_node = self.nearest_node(start)?;
+        let end_node = self.nearest_node(end)?;
+        let end_position = self.node_position(end_node);
+
+        let mut open = BinaryHeap::new();
+        let mut came_from: HashMap<NavNode, NavNode> = HashMap::new();
+        let mut cost_so_far: HashMap<NavNode, f32> = HashMap::new();
+        open.push(SearchEntry {
+            cost: 0.0,
+            progress: 0.0,
+            node: start_node,
+        });
+        cost_so_far.insert(start_node, 0.0);
+
+        let mut found = false;
+        while let Some(SearchEntry { node, progress, .. }) = open.pop() {
+            // a node is pushed again whenever a cheaper way to it turns up, the older entries are stale
+            if progress > cost_so_far[&node] {
+                continue;
+            }
+            if node == end_node {
+                found = true;
+                break;
+            }
+            if cost_so_far.len() > NAVMESH_MAX_SEARCH_NODES {
+                warn!("find_path: search exceeded {} nodes", NAVMESH_MAX_SEARCH_NODES);
+                return None;
+            }
+            let position = self.node_position(node);
+            for neighbour in self.neighbours(node) {
+                let neighbour_position = self.node_position(neighbour);
+                let new_cost = progress + distance(position, neighbour_position);
+                if cost_so_far.get(&neighbour).map(|c| new_cost < *c).unwrap_or(true) {
+                    cost_so_far.insert(neighbour, new_cost);
+                    came_from.insert(neighbour, node);
+                    open.push(SearchEntry {
+                        cost: new_cost + distance(neighbour_position, end_position),
+                        progress: new_cost,
+                        node: neighbour,
+                    });
+                }
+            }
+        }
+        if !found {
+            return None;
+        }
+
+        let mut path = vec![self.node_position(end_node)];
+        let mut current = end_node;
+        while let Some(previous) = came_from.get(&current) {
+            path.push(self.node_position(*previous));
+            current = *previous;
+        }
+        path.reverse();
+        Some(self.smooth_path(&path))
+    }
+
+    fn step_onto(&self, x: i32, z: i32, height: f32) -> Option<f32> {
+        let index = self.cell_at(x, z)?;
+        let layer = self.connected_layer(index, height)?;
+        Some(self.cells[index][layer])
+    }
+
+    // visits every cell the line crosses, so it can't slip past the corner of a blocked one
+    pub fn is_line_walkable(&self, from: Vec3, to: Vec3) -> bool {
+        let (mut x, mut z) = self.cell_coords_at(from.x, from.z);
+        let end = self.cell_coords_at(to.x, to.z);
+        let direction = Vec2::new(to.x - from.x, to.z - from.z);
+        let axis = |delta: f32, cell: i32, origin: f32, start: f32| -> (i32, f32, f32) {
+            if delta == 0.0 {
+                return (0, f32::INFINITY, f32::INFINITY);
+            }
+            let step = if delta > 0.0 { 1 } else { -1 };
+            let boundary = origin + ((cell + step.max(0)) as f32) * self.cell_size;
+            (step, (boundary - start) / delta, self.cell_size / delta.abs())
+        };
+        let (step_x, mut next_x, delta_x) = axis(direction.x, x, self.origin.x, from.x);
+        let (step_z, mut next_z, delta_z) = axis(direction.y, z, self.origin.y, from.z);
+
+        let mut height = match self.step_onto(x, z, from.y) {
+            Some(height) => height,
+            None => {
+                return false;
+            }
+        };
+        while (x, z) != end && next_x.min(next_z) <= 1.0 {
+            if (next_x - next_z).abs() < 1e-6 {
+                // straight through a corner, so both cells beside it have to be clear too
+                if self.step_onto(x + step_x, z, height).is_none() || self.step_onto(x, z + step_z, height).is_none() {
+                    return false;
+                }
+                x += step_x;
+                z += step_z;
+                next_x += delta_x;
+                next_z += delta_z;
+            } else if next_x < next_z {
+                x += step_x;
+                next_x += delta_x;
+            } else {
+                z += step_z;
+                next_z += delta_z;
+            }
+            height = match self.step_onto(x, z, height) {
+                Some(height) => height,
+                None => {
+                    return false;
+                }
+            };
+        }
+        (x, z) == end && (height - to.y).abs() <= DEFAULT_STEPHEIGHT
+    }
+
+    pub fn smooth_path(&self, path: &[Vec3]) -> Vec<Vec3> {
+        if path.len() <= 2 {
+            return path.to_vec();
+        }
+        let mut smoothed = vec![path[0]];
+        let mut anchor = 0;
+        while anchor < path.len() - 1 {
+            let mut next = anchor + 1;
+            while next + 1 < path.len() && self.is_line_walkable(path[anchor], path[next + 1]) {
+                next += 1;
+            }
+            smoothed.push(path[next]);
+            anchor = next;
+        }
+        smoothed
+    }
+
+    pub fn edges_near(&self, centre: Vec3, radius: f32) -> Vec<(Vec3, Vec3)> {
+        let mut edges = Vec::new();
+        let (x0, z0) = self.clamped_cell(centre.x - radius, centre.z - radius);
+        let (x1, z1) = self.clamped_cell(centre.x + radius, centre.z + radius);
+        for z in z0..=z1 {
+            for x in x0..=x1 {
+                let index = z * self.width + x;
+                for layer in 0..self.cells[index].len() {
+                    let position = self.node_position((index, layer));
+                    for neighbour in self.neighbours((index, layer)) {
+                        if neighbour.0 > index {
+                            edges.push((position, self.node_position(neighbour)));
+                        }
+                    }
+                }
+            }
+        }
+        edges
+    }
+
+    pub fn load(map_dir: &str) -> Result<Self, NavMeshError> {
+        let file = std::fs::File
+            ::open(format!("{}/{}", map_dir, NAVMESH_FILE_NAME))
+            .map_err(|e| NavMeshError::Io(e.to_string()))?;
+        let mut deserializer = rmp_serde::Deserializer::new(file);
+        let navmesh: NavMesh = Deserialize::deserialize(&mut deserializer).map_err(|e|
+            NavMeshError::Serialisation(e.to_string())
+        )?;
+        if navmesh.version != NAVMESH_VERSION {
+            return Err(NavMeshError::VersionMismatch(navmesh.version));
+        }
+        Ok(navmesh)
+    }
+
+    pub fn save(&self, map_dir: &str) -> Result<(), NavMeshError> {
+        let data = rmp_serde::to_vec(self).map_err(|e| NavMeshError::Serialisation(e.to_string()))?;
+        std::fs
+            ::write(format!("{}/{}", map_dir, NAVMESH_FILE_NAME), data)
+            .map_err(|e| NavMeshError::Io(e.to_string()))
+    }
+
+    pub fn load_or_bake(
+        map_dir: &str,
+        entities: &[Entity],
+        game_data_path: &str
+    ) -> Result<Self, NavMeshError> {
+        let source_hash = hash_worlddef(map_dir)?;
+        match NavMesh::load(map_dir) {
+            Ok(navmesh) if navmesh.source_hash == source_hash => {
+                debug!("loaded baked navmesh from {}", map_dir);
+                return Ok(navmesh);
+            }
+            Ok(_) => {
+                info!("baked navmesh in {} is stale, rebaking", map_dir);
+            }
+            Err(e) => {
+                debug!("no usable baked navmesh in {} ({:?}), baking", map_dir, e);
+            }
+        }
+        let navmesh = NavMesh::bake(entities, game_data_path, source_hash)?;
+        if let Err(e) = navmesh.save(map_dir) {
+            warn!("failed to save baked navmesh to {}: {:?}", map_dir, e);
+        }
+        Ok(navmesh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worldmachine::components::Transform;
+    use crate::worldmachine::ecs::Parameter;
+    use std::collections::BTreeMap;
+
+    fn grid(width: usize, depth: usize, blocked: &[(usize, usize)]) -> NavMesh {
+        NavMesh {
+            version: NAVMESH_VERSION,
+            source_hash: [0; 32],
+            origin: Vec2::new(0.0, 0.0),
+            cell_size: 1.0,
+            width,
+            depth,
+            cells: (0..width * depth)
+                .map(|index| if blocked.contains(&(index % width, index / width)) { Vec::new() } else { vec![0.0] })
+                .collect(),
+        }
+    }
+
+    fn at(x: usize, z: usize) -> Vec3 {
+        Vec3::new((x as f32) + 0.5, 0.0, (z as f32) + 0.5)
+    }
+
+    fn crosses_blocked_cell(navmesh: &NavMesh, from: Vec3, to: Vec3) -> bool {
+        (0..=1000).any(|i| {
+            let t = (i as f32) / 1000.0;
+            let point = from + (to - from) * t;
+            navmesh.cell_index_at(point.x, point.z).is_none_or(|index| navmesh.cells[index].is_empty())
+        })
+    }
+
+    fn box_collider(position: Vec3, scale: Vec3) -> Component {
+        let mut parameters = BTreeMap::new();
+        parameters.insert("position".to_string(), Parameter::new("position", ParameterValue::Vec3(position)));
+        parameters.insert("scale".to_string(), Parameter::new("scale", ParameterValue::Vec3(scale)));
+        Component {
+            name: "BoxCollider".to_string(),
+            parameters,
+            component_type: COMPONENT_TYPE_BOX_COLLIDER.clone(),
+        }
+    }
+
+    fn entity(uid: u64, components: Vec<Component>) -> Entity {
+        Entity {
+            name: format!("entity {}", uid),
+            uid,
+            components,
+            children: Vec::new(),
+            parent: None,
+        }
+    }
+
+    #[test]
+    fn paths_go_around_obstacles() {
+        let wall = (0..4).map(|z| (3, z)).collect::<Vec<_>>();
+        let navmesh = grid(7, 5, &wall);
+        let path = navmesh.find_path(at(0, 0), at(6, 0)).unwrap();
+        assert_eq!(path.first(), Some(&at(0, 0)));
+        assert_eq!(path.last(), Some(&at(6, 0)));
+        assert!(path.iter().any(|point| point.z > 4.0));
+        for segment in path.windows(2) {
+            assert!(!crosses_blocked_cell(&navmesh, segment[0], segment[1]));
+        }
+    }
+
+    #[test]
+    fn unreachable_goals_have_no_path() {
+        let wall = (0..5).map(|z| (3, z)).collect::<Vec<_>>();
+        let navmesh = grid(7, 5, &wall);
+        assert!(navmesh.find_path(at(0, 0), at(6, 0)).is_none());
+        assert!(navmesh.find_path(at(0, 0), at(2, 4)).is_some());
+    }
+
+    #[test]
+    fn smoothing_never_cuts_through_blocked_cells() {
+        let navmesh = grid(5, 5, &[(2, 2), (1, 1)]);
+        let around = [at(1, 2), at(1, 3), at(2, 3), at(3, 3), at(3, 2), at(3, 1), at(2, 1), at(2, 0)];
+        let smoothed = navmesh.smooth_path(&around);
+        assert!(smoothed.len() < around.len());
+        assert_eq!(smoothed.last(), around.last());
+        for segment in smoothed.windows(2) {
+            assert!(!crosses_blocked_cell(&navmesh, segment[0], segment[1]));
+        }
+        // diagonally through the corner of a blocked cell
+        assert!(!navmesh.is_line_walkable(at(0, 1), at(1, 0)));
+        assert!(!navmesh.is_line_walkable(at(0, 0), Vec3::new(2.5, 0.0, 1.2)));
+        assert!(navmesh.is_line_walkable(at(0, 4), at(4, 4)));
+    }
+
+    #[test]
+    fn neighbours_do_not_cut_corners() {
+        let centre = (4, 0);
+        assert_eq!(grid(3, 3, &[]).neighbours(centre).len(), 8);
+
+        let navmesh = grid(3, 3, &[(2, 1)]);
+        let neighbours = navmesh.neighbours(centre);
+        assert_eq!(neighbours.len(), 5);
+        for blocked_corner in [(2, 0), (8, 0), (5, 0)] {
+            assert!(!neighbours.contains(&blocked_corner));
+        }
+
+        let mut ledge = grid(3, 3, &[]);
+        ledge.cells[5] = vec![DEFAULT_STEPHEIGHT * 2.0];
+        assert!(!ledge.neighbours(centre).contains(&(5, 0)));
+    }
+
+    #[test]
+    fn erode_pulls_back_from_edges_and_holes() {
+        let mut navmesh = grid(7, 7, &[(3, 3)]);
+        navmesh.erode(1.0);
+        let walkable = |x: usize, z: usize| !navmesh.cells[z * 7 + x].is_empty();
+        assert!(!walkable(0, 3));
+        assert!(!walkable(6, 6));
+        assert!(!walkable(3, 2));
+        assert!(!walkable(4, 3));
+        assert!(walkable(2, 2));
+        assert!(walkable(1, 1));
+        assert!(walkable(5, 4));
+    }
+
+    #[test]
+    fn rotated_box_colliders_block_their_own_footprint() {
+        let floor = entity(1, vec![box_collider(Vec3::new(0.0, -0.5, 0.0), Vec3::new(12.0, 1.0, 12.0))]);
+        let rotation = Quaternion::axis_angle(Vec3::new(0.0, 1.0, 0.0), std::f32::consts::FRAC_PI_4);
+        let wall = entity(2, vec![
+            Transform::new(Vec3::zero(), rotation, Vec3::new(1.0, 1.0, 1.0)),
+            box_collider(Vec3::new(0.0, 1.0, 0.0), Vec3::new(8.0, 2.0, 0.5))
+        ]);
+        let navmesh = NavMesh::bake(&[floor, wall], crate::worldmachine::GAME_DATA_PATH, [0; 32]).unwrap();
+        let on_floor = |x: f32, z: f32| {
+            let index = navmesh.cell_index_at(x, z).unwrap();
+            navmesh.cells[index].iter().any(|height| height.abs() < 0.1)
+        };
+        assert!(!on_floor(2.0, -2.0));
+        assert!(!on_floor(-2.0, 2.0));
+        // inside the footprint the wall would have without its rotation
+        assert!(on_floor(3.5, 0.0));
+        assert!(on_floor(-3.5, 0.0));
+    }
+}
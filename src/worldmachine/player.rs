@@ -1,26 +1,31 @@
 use std::collections::BTreeMap;
 use gfx_maths::*;
 use serde::{ Deserialize, Serialize };
-use crate::{ helpers, MutRenderer };
-use crate::camera::EYE_HEIGHT;
-use crate::helpers::lerp;
-use crate::input::keyboard::HTKey;
-use crate::input::mouse::MouseButtonState;
 use crate::physics::{ Materials, PhysicsCharacterController, PhysicsSystem };
 use crate::server::ConnectionUUID;
 use crate::server::server_player::{
     DEFAULT_HEIGHT,
     DEFAULT_MOVESPEED,
     DEFAULT_RADIUS,
-    DEFAULT_SPRINTSPEED,
     DEFAULT_STEPHEIGHT,
 };
 use crate::worldmachine::components::COMPONENT_TYPE_PLAYER;
 use crate::worldmachine::ecs::*;
-use crate::worldmachine::{ ClientUpdate, EntityId };
-
-use crate::input::mouse;
-use crate::input::keyboard;
+use crate::worldmachine::prediction::{ InputAck, Prediction };
+use crate::worldmachine::EntityId;
+
+#[cfg(feature = "graphical")]
+use {
+    crate::{ helpers, MutRenderer },
+    crate::camera::EYE_HEIGHT,
+    crate::helpers::lerp,
+    crate::input::keyboard::HTKey,
+    crate::input::mouse::MouseButtonState,
+    crate::input::{ keyboard, mouse },
+    crate::server::server_player::DEFAULT_SPRINTSPEED,
+    crate::worldmachine::prediction::InputCommand,
+    crate::worldmachine::ClientUpdate,
+};
 
 pub const DEFAULT_FOV: f32 = 120.0;
 pub const SPRINT_FOV: f32 = 140.0;
@@ -92,6 +97,7 @@ pub struct PlayerContainer {
 }
 
 #[derive(Clone)]
+#[cfg_attr(not(feature = "graphical"), allow(dead_code))]
 pub struct Player {
     pub uuid: String,
     pub name: String,
@@ -117,6 +123,8 @@ pub struct Player {
     bob_t: f32,
     bob_on: bool,
     pub has_camera_control: bool,
+    last_move: (Vec3, f32, f32),
+    pub prediction: Prediction,
 }
 
 impl Default for Player {
@@ -146,6 +154,8 @@ impl Default for Player {
             bob_t: 0.0,
             bob_on: true,
             has_camera_control: true,
+            last_move: (Vec3::zero(), 0.0, 0.0),
+            prediction: Prediction::default(),
         }
     }
 }
@@ -186,6 +196,7 @@ impl Player {
         self.scale = scale;
     }
 
+    #[cfg(feature = "graphical")]
     fn handle_mouse_movement(
         &mut self,
         renderer: &mut MutRenderer,
@@ -257,6 +268,7 @@ impl Player {
         }
     }
 
+    #[cfg(feature = "graphical")]
     fn handle_keyboard_movement(
         &mut self,
         renderer: &mut MutRenderer,
@@ -323,7 +335,7 @@ impl Player {
                 movement -= camera_right;
             }
             if self.sprinting {
-                info.sprinting = false;
+                info.sprinting = true;
                 speed = DEFAULT_SPRINTSPEED;
             } else {
                 info.sprinting = false;
@@ -346,6 +358,7 @@ impl Player {
         let delta_time = now.duration_since(self.last_move_call).as_secs_f32();
         self.last_move_call = now;
         let dt_movement = movement * frame_delta;
+        self.last_move = (dt_movement, delta_time, frame_delta);
         let final_movement = self.physics_controller
             .as_mut()
             .unwrap()
@@ -367,6 +380,7 @@ impl Player {
         }
     }
 
+    #[cfg(feature = "graphical")]
     fn handle_jump(&mut self, renderer: &mut MutRenderer, delta_time: f32) -> bool {
         if keyboard::check_key_down(HTKey::Space) {
             return true;
@@ -374,6 +388,7 @@ impl Player {
         false
     }
 
+    #[cfg(feature = "graphical")]
     pub fn handle_input(
         &mut self,
         renderer: &mut MutRenderer,
@@ -400,19 +415,28 @@ impl Player {
         let movement = self.handle_keyboard_movement(renderer, jump, delta_time);
 
         let mut updates = Vec::new();
-        if self.has_camera_control && self.locked_mouse {
+        let in_control = self.has_camera_control && self.locked_mouse;
+        if movement.is_some() || (in_control && (jump || look.is_some())) {
+            let mut movement_info = movement.map(|movement| movement.1).unwrap_or_default();
+            movement_info.jumped = jump;
+            let (dt_movement, move_delta, frame_delta) = self.last_move;
+            let position = self.get_position();
+            let command = self.prediction.record(InputCommand {
+                sequence: 0,
+                movement: dt_movement,
+                jump,
+                delta_time: move_delta,
+                frame_delta,
+                rotation: self.rotation,
+                head_rotation: self.head_rotation,
+                movement_info,
+                position,
+            });
+            updates.push(ClientUpdate::IMoved(command));
+        }
+        if in_control {
             if jump {
                 updates.push(ClientUpdate::IJumped);
-                if let Some(movement) = movement {
-                    let mut new_movement = movement.1;
-                    new_movement.jumped = true;
-                    updates.push(ClientUpdate::IDisplaced((movement.0, Some(new_movement))));
-                } else {
-                    updates.push(ClientUpdate::IDisplaced((Vec3::zero(), None)));
-                }
-            }
-            if let Some(look) = look {
-                updates.push(ClientUpdate::ILooked(look));
             }
 
             if mouse::get_mouse_button_state(0) == MouseButtonState::Pressed {
@@ -423,9 +447,6 @@ impl Player {
         let mut bob_mag = 0.0;
 
         if let Some(movement) = movement {
-            let mut new_movement = movement.1;
-            new_movement.jumped = jump;
-            updates.push(ClientUpdate::IDisplaced((movement.0, Some(movement.1))));
             let mut movement = movement.0;
             movement.y = 0.0;
             bob_mag = movement.magnitude() * 0.1;
@@ -438,7 +459,8 @@ impl Player {
         *crate::ui::BOB_T.lock().unwrap() = self.bob_t;
 
         if self.bob_on {
-            let initial_head = self.get_position() + Vec3::new(0.0, EYE_HEIGHT, 0.0);
+            let correction = self.prediction.smooth_correction(delta_time);
+            let initial_head = self.get_position() + correction + Vec3::new(0.0, EYE_HEIGHT, 0.0);
             let bob = if bob_mag != 0.0 {
                 initial_head + Vec3::new(0.0, 0.1 * ((self.bob_t * 12.0).sin() * bob_mag), 0.0)
             } else if delta_time > 0.0 {
@@ -489,6 +511,13 @@ impl Player {
         self.head_rotation = head_rotation;
     }
 
+    pub fn reconcile(&mut self, ack: InputAck) {
+        if let Some(physics_controller) = self.physics_controller.as_mut() {
+            self.prediction.reconcile(ack, physics_controller);
+            self.position = physics_controller.get_foot_position();
+        }
+    }
+
     pub fn increase_speed(&mut self) {
         let speed_increase = 10.0;
         self.movement_speed += speed_increase;
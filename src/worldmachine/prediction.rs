@@ -0,0 +1,233 @@
+use crate::physics::PhysicsCharacterController;
+use crate::worldmachine::player::MovementInfo;
+use gfx_maths::{ Quaternion, Vec3 };
+use serde::{ Deserialize, Serialize };
+use std::collections::VecDeque;
+
+pub const INPUT_REDUNDANCY: usize = 4;
+pub const MAX_INPUTS_PER_PACKET: usize = 32;
+pub const MAX_PENDING_INPUTS: usize = 256;
+pub const RECONCILE_TOLERANCE: f32 = 0.05;
+pub const MAX_SMOOTHED_CORRECTION: f32 = 2.0;
+pub const CORRECTION_SMOOTHING: f32 = 10.0;
+
+// sequences wrap around, so anything up to half the range ahead of `other` counts as newer
+pub fn is_newer_sequence(sequence: u32, other: u32) -> bool {
+    (sequence.wrapping_sub(other) as i32) > 0
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct InputCommand {
+    pub sequence: u32,
+    pub movement: Vec3,
+    pub jump: bool,
+    pub delta_time: f32,
+    pub frame_delta: f32,
+    pub rotation: Quaternion,
+    pub head_rotation: Quaternion,
+    pub movement_info: MovementInfo,
+    pub position: Vec3,
+}
+
+impl InputCommand {
+    pub fn is_finite(&self) -> bool {
+        let vectors = [self.movement, self.position];
+        let rotations = [self.rotation, self.head_rotation];
+        vectors.iter().all(|vector| vector.x.is_finite() && vector.y.is_finite() && vector.z.is_finite()) &&
+            rotations
+                .iter()
+                .all(|rotation| {
+                    rotation.x.is_finite() &&
+                        rotation.y.is_finite() &&
+                        rotation.z.is_finite() &&
+                        rotation.w.is_finite()
+                }) &&
+            self.delta_time.is_finite() &&
+            self.frame_delta.is_finite() &&
+            self.movement_info.speed.is_finite() &&
+            self.movement_info.strafe.is_finite()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct InputAck {
+    pub sequence: u32,
+    pub position: Vec3,
+    pub vertical_velocity: f32,
+}
+
+#[derive(Clone, Default)]
+pub struct Prediction {
+    next_sequence: u32,
+    last_acknowledged: Option<u32>,
+    pending: VecDeque<InputCommand>,
+    unsent: usize,
+    correction: Vec3,
+    pub corrections: u32,
+}
+
+impl Prediction {
+    pub fn record(&mut self, mut command: InputCommand) -> InputCommand {
+        command.sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.pending.push_back(command);
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.unsent += 1;
+        command
+    }
+
+    pub fn has_unsent(&self) -> bool {
+        self.unsent > 0
+    }
+
+    // everything recorded since the last batch, plus a few already sent in case that packet was lost
+    pub fn take_batch(&mut self) -> Vec<InputCommand> {
+        let count = (self.unsent + INPUT_REDUNDANCY).min(MAX_INPUTS_PER_PACKET);
+        self.unsent = 0;
+        let skip = self.pending.len().saturating_sub(count);
+        self.pending.iter().skip(skip).copied().collect()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // drops the acknowledged inputs and hands back where we predicted the acknowledged one would end up
+    fn acknowledge(&mut self, ack: &InputAck) -> Option<Vec3> {
+        if self.last_acknowledged.is_some_and(|last| !is_newer_sequence(ack.sequence, last)) {
+            return None;
+        }
+        let predicted = self.pending
+            .iter()
+            .find(|command| command.sequence == ack.sequence)?
+            .position;
+        self.last_acknowledged = Some(ack.sequence);
+        self.pending.retain(|command| is_newer_sequence(command.sequence, ack.sequence));
+        Some(predicted)
+    }
+
+    pub fn reconcile(&mut self, ack: InputAck, controller: &mut PhysicsCharacterController) {
+        let predicted = match self.acknowledge(&ack) {
+            Some(predicted) => predicted,
+            None => {
+                return;
+            }
+        };
+        if (predicted - ack.position).magnitude() <= RECONCILE_TOLERANCE {
+            return;
+        }
+
+        let before = controller.get_foot_position();
+        controller.set_foot_position(ack.position);
+        controller.set_y_velocity(ack.vertical_velocity);
+        for command in self.pending.iter_mut() {
+            controller.move_by(
+                command.movement,
+                command.jump,
+                None,
+                false,
+                command.delta_time,
+                command.frame_delta
+            );
+            command.position = controller.get_foot_position();
+        }
+        let after = controller.get_foot_position();
+
+        self.corrections += 1;
+        let correction = self.correction + (before - after);
+        self.correction = if correction.magnitude() > MAX_SMOOTHED_CORRECTION {
+            Vec3::zero()
+        } else {
+            correction
+        };
+    }
+
+    pub fn smooth_correction(&mut self, delta_time: f32) -> Vec3 {
+        self.correction *= 1.0 - (CORRECTION_SMOOTHING * delta_time).min(1.0);
+        if self.correction.magnitude() < 0.001 {
+            self.correction = Vec3::zero();
+        }
+        self.correction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> InputCommand {
+        InputCommand {
+            sequence: 0,
+            movement: Vec3::zero(),
+            jump: false,
+            delta_time: 0.004,
+            frame_delta: 0.004,
+            rotation: Quaternion::identity(),
+            head_rotation: Quaternion::identity(),
+            movement_info: MovementInfo::default(),
+            position: Vec3::zero(),
+        }
+    }
+
+    #[test]
+    fn batches_carry_every_unsent_input_and_a_few_old_ones() {
+        let mut prediction = Prediction::default();
+        for _ in 0..10 {
+            prediction.record(command());
+        }
+        let batch = prediction.take_batch();
+        assert_eq!(batch.len(), 10);
+        assert!(!prediction.has_unsent());
+
+        for _ in 0..3 {
+            prediction.record(command());
+        }
+        let sequences: Vec<u32> = prediction
+            .take_batch()
+            .iter()
+            .map(|command| command.sequence)
+            .collect();
+        assert_eq!(sequences, (6..13).collect::<Vec<u32>>());
+
+        for _ in 0..100 {
+            prediction.record(command());
+        }
+        assert_eq!(prediction.take_batch().len(), MAX_INPUTS_PER_PACKET);
+    }
+
+    fn ack(sequence: u32) -> InputAck {
+        InputAck {
+            sequence,
+            position: Vec3::zero(),
+            vertical_velocity: 0.0,
+        }
+    }
+
+    #[test]
+    fn acknowledgements_keep_working_across_the_wrap() {
+        assert!(is_newer_sequence(0, u32::MAX));
+        assert!(!is_newer_sequence(u32::MAX, 0));
+        assert!(!is_newer_sequence(5, 5));
+
+        let mut prediction = Prediction {
+            next_sequence: u32::MAX - 2,
+            ..Default::default()
+        };
+        let sequences: Vec<u32> = (0..6).map(|_| prediction.record(command()).sequence).collect();
+        assert_eq!(sequences, vec![u32::MAX - 2, u32::MAX - 1, u32::MAX, 0, 1, 2]);
+
+        assert!(prediction.acknowledge(&ack(u32::MAX - 1)).is_some());
+        assert_eq!(prediction.pending(), 4);
+        assert!(prediction.acknowledge(&ack(0)).is_some());
+        let left: Vec<u32> = prediction.pending.iter().map(|command| command.sequence).collect();
+        assert_eq!(left, vec![1, 2]);
+
+        // acks from before the wrap are stale now
+        assert!(prediction.acknowledge(&ack(u32::MAX)).is_none());
+        assert_eq!(prediction.pending(), 2);
+        assert!(prediction.acknowledge(&ack(2)).is_some());
+        assert_eq!(prediction.pending(), 0);
+    }
+}
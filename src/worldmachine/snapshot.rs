@@ -0,0 +1,579 @@
+use crate::server::ConnectionUUID;
+use crate::worldmachine::ecs::{ Component, Entity, ParameterValue, System, ENTITY_ID_MANAGER };
+use crate::worldmachine::throwballs::ThrowingBall;
+use crate::worldmachine::{ EntityId, WorldMachine, WorldUpdate };
+use gfx_maths::*;
+use serde::{ Deserialize, Serialize };
+use std::borrow::BorrowMut;
+use std::collections::{ BTreeMap, BTreeSet };
+use std::fmt::{ Display, Formatter };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+pub const SNAPSHOT_FILE_EXTENSION: &str = "json";
+
+#[derive(Clone, Debug)]
+pub enum SnapshotError {
+    Io(String),
+    Serialisation(String),
+    InvalidName(String),
+    MapMismatch(String, String),
+    NoPhysics,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BallSnapshot {
+    pub uuid: String,
+    pub position: Vec3,
+    pub linear_velocity: Vec3,
+    pub angular_velocity: Vec3,
+    pub time_to_live: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ControllerSnapshot {
+    pub connection: ConnectionUUID,
+    pub entity_id: Option<EntityId>,
+    pub position: Vec3,
+    pub rotation: Quaternion,
+    pub head_rotation: Quaternion,
+    pub y_velocity: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    pub tick: u64,
+    pub taken_at: u64,
+    pub map_name: String,
+    pub entities: Vec<Entity>,
+    pub systems: Vec<System>,
+    pub balls: Vec<BallSnapshot>,
+    pub controllers: Vec<ControllerSnapshot>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SnapshotDiff {
+    pub from_tick: u64,
+    pub to_tick: u64,
+    pub changes: Vec<String>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl Display for SnapshotDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "tick {} -> tick {}: no differences", self.from_tick, self.to_tick);
+        }
+        write!(
+            f,
+            "tick {} -> tick {}: {} differences",
+            self.from_tick,
+            self.to_tick,
+            self.changes.len()
+        )?;
+        for change in &self.changes {
+            write!(f, "\n  {}", change)?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_vec3(value: Vec3) -> String {
+    format!("({:.4}, {:.4}, {:.4})", value.x, value.y, value.z)
+}
+
+fn describe_quaternion(value: Quaternion) -> String {
+    format!("({:.4}, {:.4}, {:.4}, {:.4})", value.x, value.y, value.z, value.w)
+}
+
+fn describe_value(value: &ParameterValue) -> String {
+    match value {
+        ParameterValue::Vec3(value) => describe_vec3(*value),
+        ParameterValue::Quaternion(value) => describe_quaternion(*value),
+        ParameterValue::Vec2(value) => format!("({:.4}, {:.4})", value.x, value.y),
+        ParameterValue::Float(value) => format!("{:.4}", value),
+        ParameterValue::Int(value) => value.to_string(),
+        ParameterValue::UnsignedInt(value) => value.to_string(),
+        ParameterValue::Bool(value) => value.to_string(),
+        ParameterValue::String(value) => format!("{:?}", value),
+    }
+}
+
+fn diff_components(entity: &Entity, from: &Component, to: &Component, changes: &mut Vec<String>) {
+    let names = from.parameters
+        .keys()
+        .chain(to.parameters.keys())
+        .collect::<BTreeSet<_>>();
+    for name in names {
+        match (from.parameters.get(name), to.parameters.get(name)) {
+            (Some(from_parameter), Some(to_parameter)) => {
+                if from_parameter.value != to_parameter.value {
+                    changes.push(
+                        format!(
+                            "entity {} ({}) {}.{}: {} -> {}",
+                            entity.uid,
+                            entity.name,
+                            from.name,
+                            name,
+                            describe_value(&from_parameter.value),
+                            describe_value(&to_parameter.value)
+                        )
+                    );
+                }
+            }
+            (Some(_), None) => {
+                changes.push(
+                    format!(
+                        "entity {} ({}) {}.{} removed",
+                        entity.uid,
+                        entity.name,
+                        from.name,
+                        name
+                    )
+                );
+            }
+            (None, Some(to_parameter)) => {
+                changes.push(
+                    format!(
+                        "entity {} ({}) {}.{} added: {}",
+                        entity.uid,
+                        entity.name,
+                        to.name,
+                        name,
+                        describe_value(&to_parameter.value)
+                    )
+                );
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+fn diff_entities(from: &Entity, to: &Entity, changes: &mut Vec<String>) {
+    if from.name != to.name {
+        changes.push(format!("entity {} renamed: {} -> {}", from.uid, from.name, to.name));
+    }
+    if from.parent != to.parent {
+        changes.push(
+            format!(
+                "entity {} ({}) parent: {:?} -> {:?}",
+                from.uid,
+                from.name,
+                from.parent,
+                to.parent
+            )
+        );
+    }
+    if from.children.len() != to.children.len() {
+        changes.push(
+            format!(
+                "entity {} ({}) children: {} -> {}",
+                from.uid,
+                from.name,
+                from.children.len(),
+                to.children.len()
+            )
+        );
+    }
+    for component in &from.components {
+        match to.components.iter().find(|other| other.name == component.name) {
+            Some(other) => diff_components(from, component, other, changes),
+            None => {
+                changes.push(
+                    format!("entity {} ({}) lost component {}", from.uid, from.name, component.name)
+                );
+            }
+        }
+    }
+    for component in &to.components {
+        if !from.components.iter().any(|other| other.name == component.name) {
+            changes.push(
+                format!("entity {} ({}) gained component {}", from.uid, from.name, component.name)
+            );
+        }
+    }
+}
+
+impl WorldSnapshot {
+    pub fn path_for(name: &str) -> Result<String, SnapshotError> {
+        if
+            name.is_empty() ||
+            !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(SnapshotError::InvalidName(name.to_string()));
+        }
+        Ok(format!("{}/{}.{}", SNAPSHOTS_DIR, name, SNAPSHOT_FILE_EXTENSION))
+    }
+
+    pub fn write(&self, name: &str) -> Result<String, SnapshotError> {
+        let path = WorldSnapshot::path_for(name)?;
+        std::fs::create_dir_all(SNAPSHOTS_DIR).map_err(|e| SnapshotError::Io(e.to_string()))?;
+        let data = serde_json
+            ::to_vec_pretty(self)
+            .map_err(|e| SnapshotError::Serialisation(e.to_string()))?;
+        std::fs::write(&path, data).map_err(|e| SnapshotError::Io(e.to_string()))?;
+        Ok(path)
+    }
+
+    pub fn read(name: &str) -> Result<Self, SnapshotError> {
+        let path = WorldSnapshot::path_for(name)?;
+        let data = std::fs::read(&path).map_err(|e| SnapshotError::Io(e.to_string()))?;
+        serde_json::from_slice(&data).map_err(|e| SnapshotError::Serialisation(e.to_string()))
+    }
+
+    pub fn diff(&self, other: &WorldSnapshot) -> SnapshotDiff {
+        let mut changes = Vec::new();
+
+        if self.map_name != other.map_name {
+            changes.push(format!("map: {} -> {}", self.map_name, other.map_name));
+        }
+
+        let from_entities = self.entities
+            .iter()
+            .map(|entity| (entity.uid, entity))
+            .collect::<BTreeMap<_, _>>();
+        let to_entities = other.entities
+            .iter()
+            .map(|entity| (entity.uid, entity))
+            .collect::<BTreeMap<_, _>>();
+        for (uid, entity) in &from_entities {
+            match to_entities.get(uid) {
+                Some(other) => diff_entities(entity, other, &mut changes),
+                None => changes.push(format!("entity {} ({}) removed", uid, entity.name)),
+            }
+        }
+        for (uid, entity) in &to_entities {
+            if !from_entities.contains_key(uid) {
+                changes.push(format!("entity {} ({}) added", uid, entity.name));
+            }
+        }
+
+        for system in &self.systems {
+            match other.systems.iter().find(|other| other.uid == system.uid) {
+                Some(other) => {
+                    if system.affected_entities != other.affected_entities {
+                        changes.push(
+                            format!(
+                                "system {} ({}) affected entities: {:?} -> {:?}",
+                                system.uid,
+                                system.name,
+                                system.affected_entities,
+                                other.affected_entities
+                            )
+                        );
+                    }
+                }
+                None => changes.push(format!("system {} ({}) removed", system.uid, system.name)),
+            }
+        }
+        for system in &other.systems {
+            if !self.systems.iter().any(|other| other.uid == system.uid) {
+                changes.push(format!("system {} ({}) added", system.uid, system.name));
+            }
+        }
+
+        for ball in &self.balls {
+            match other.balls.iter().find(|other| other.uuid == ball.uuid) {
+                Some(other) => {
+                    if ball.position != other.position {
+                        changes.push(
+                            format!(
+                                "ball {} position: {} -> {}",
+                                ball.uuid,
+                                describe_vec3(ball.position),
+                                describe_vec3(other.position)
+                            )
+                        );
+                    }
+                    if ball.linear_velocity != other.linear_velocity {
+                        changes.push(
+                            format!(
+                                "ball {} velocity: {} -> {}",
+                                ball.uuid,
+                                describe_vec3(ball.linear_velocity),
+                                describe_vec3(other.linear_velocity)
+                            )
+                        );
+                    }
+                    if ball.angular_velocity != other.angular_velocity {
+                        changes.push(
+                            format!(
+                                "ball {} angular velocity: {} -> {}",
+                                ball.uuid,
+                                describe_vec3(ball.angular_velocity),
+                                describe_vec3(other.angular_velocity)
+                            )
+                        );
+                    }
+                }
+                None => changes.push(format!("ball {} removed", ball.uuid)),
+            }
+        }
+        for ball in &other.balls {
+            if !self.balls.iter().any(|other| other.uuid == ball.uuid) {
+                changes.push(
+                    format!("ball {} added at {}", ball.uuid, describe_vec3(ball.position))
+                );
+            }
+        }
+
+        for controller in &self.controllers {
+            match other.controllers.iter().find(|other| other.connection == controller.connection) {
+                Some(other) => {
+                    if controller.position != other.position {
+                        changes.push(
+                            format!(
+                                "player {} position: {} -> {}",
+                                controller.connection,
+                                describe_vec3(controller.position),
+                                describe_vec3(other.position)
+                            )
+                        );
+                    }
+                    if controller.rotation != other.rotation {
+                        changes.push(
+                            format!(
+                                "player {} rotation: {} -> {}",
+                                controller.connection,
+                                describe_quaternion(controller.rotation),
+                                describe_quaternion(other.rotation)
+                            )
+                        );
+                    }
+                    if controller.head_rotation != other.head_rotation {
+                        changes.push(
+                            format!(
+                                "player {} head rotation: {} -> {}",
+                                controller.connection,
+                                describe_quaternion(controller.head_rotation),
+                                describe_quaternion(other.head_rotation)
+                            )
+                        );
+                    }
+                    if controller.y_velocity != other.y_velocity {
+                        changes.push(
+                            format!(
+                                "player {} vertical velocity: {:.4} -> {:.4}",
+                                controller.connection,
+                                controller.y_velocity,
+                                other.y_velocity
+                            )
+                        );
+                    }
+                }
+                None => changes.push(format!("player {} left", controller.connection)),
+            }
+        }
+        for controller in &other.controllers {
+            if !self.controllers.iter().any(|other| other.connection == controller.connection) {
+                changes.push(format!("player {} joined", controller.connection));
+            }
+        }
+
+        SnapshotDiff {
+            from_tick: self.tick,
+            to_tick: other.tick,
+            changes,
+        }
+    }
+}
+
+impl WorldMachine {
+    pub async fn snapshot(&mut self) -> WorldSnapshot {
+        let balls = self.tballs
+            .iter_mut()
+            .map(|ball| BallSnapshot {
+                uuid: ball.uuid.clone(),
+                position: ball.get_position(),
+                linear_velocity: ball.get_linear_velocity(),
+                angular_velocity: ball.get_angular_velocity(),
+                time_to_live: ball.time_to_live,
+            })
+            .collect::<Vec<_>>();
+
+        let mut controllers = Vec::new();
+        if let Some(players) = self.players.clone() {
+            let players = players.lock().await.clone();
+            for (uuid, player) in players.iter() {
+                controllers.push(ControllerSnapshot {
+                    connection: uuid.clone(),
+                    entity_id: player.entity_id,
+                    position: player.player.get_position(None, None).await,
+                    rotation: player.player.get_rotation(None, None).await,
+                    head_rotation: player.player.get_head_rotation(None, None).await,
+                    y_velocity: player.player.get_vertical_velocity().await,
+                });
+            }
+        }
+        controllers.sort_by(|a, b| a.connection.cmp(&b.connection));
+
+        WorldSnapshot {
+            tick: self.tick,
+            taken_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            map_name: self.current_map(),
+            entities: self.world.entities.clone(),
+            systems: self.world.systems.clone(),
+            balls,
+            controllers,
+        }
+    }
+
+    pub async fn restore_snapshot(&mut self, snapshot: &WorldSnapshot) -> Result<(), SnapshotError> {
+        let current_map = self.current_map();
+        if current_map != snapshot.map_name {
+            return Err(SnapshotError::MapMismatch(current_map, snapshot.map_name.clone()));
+        }
+        let physics = self.physics.lock().unwrap().clone();
+        if physics.is_none() && !snapshot.balls.is_empty() {
+            return Err(SnapshotError::NoPhysics);
+        }
+
+        if self.is_server {
+            for entity in &self.world.entities {
+                if !snapshot.entities.iter().any(|other| other.uid == entity.uid) {
+                    let update = WorldUpdate::EntityNoLongerExists(entity.uid);
+                    self.world_update_queue.lock().await.push_back(update);
+                }
+            }
+        }
+
+        self.world.entities = snapshot.entities.clone();
+        self.world.systems = snapshot.systems.clone();
+        self.tick = snapshot.tick;
+        {
+            let highest_uid = self.world.entities
+                .iter()
+                .map(|entity| entity.uid)
+                .max()
+                .unwrap_or(0);
+            let mut eid_manager = ENTITY_ID_MANAGER.lock().unwrap();
+            let eid_manager = eid_manager.borrow_mut();
+            eid_manager.id = eid_manager.id.max(highest_uid);
+        }
+        // static colliders belong to the entities they were built from, so rebuild them for the restored ones
+        if let Some(physics) = physics.as_ref() {
+            physics.release_colliders();
+            self.initialise_entities();
+        }
+
+        let mut old_balls = std::mem::take(&mut self.tballs);
+        for ball in &snapshot.balls {
+            let mut restored = match old_balls.iter().position(|old| old.uuid == ball.uuid) {
+                Some(index) => old_balls.swap_remove(index),
+                None => {
+                    let mut restored = ThrowingBall::new(
+                        ball.position,
+                        Vec3::zero(),
+                        physics.as_ref().unwrap()
+                    );
+                    restored.uuid = ball.uuid.clone();
+                    restored
+                }
+            };
+            restored.set_state(ball.position, ball.linear_velocity, ball.angular_velocity);
+            restored.time_to_live = ball.time_to_live;
+            self.tballs.push(restored);
+        }
+        drop(old_balls);
+
+        if let Some(players) = self.players.clone() {
+            let players = players.lock().await.clone();
+            for controller in &snapshot.controllers {
+                let player = match players.get(&controller.connection) {
+                    Some(player) => player,
+                    None => {
+                        warn!("restore_snapshot: player {} is no longer connected", controller.connection);
+                        continue;
+                    }
+                };
+                player.player.set_position(controller.position, None, self).await;
+                player.player.set_rotation(controller.rotation, None, self).await;
+                player.player.set_head_rotation(controller.head_rotation, None, self).await;
+                player.player.set_vertical_velocity(controller.y_velocity).await;
+            }
+        }
+
+        if self.is_server {
+            let mut entity_init_packets = Vec::new();
+            for entity in &self.world.entities {
+                entity_init_packets.push(WorldUpdate::InitEntity(entity.uid, entity.clone()));
+            }
+            self.queue_updates(entity_init_packets);
+        } else {
+            self.entities_wanting_to_load_things = (0..self.world.entities.len()).collect();
+        }
+
+        info!("restored snapshot of tick {}", snapshot.tick);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::PhysicsSystem;
+    use crate::worldmachine::components::{ Transform, COMPONENT_TYPE_BOX_COLLIDER };
+    use crate::worldmachine::ecs::Parameter;
+    use physx_sys::{ PxActorTypeFlags, PxScene_getNbActors };
+
+    fn crate_entity(uid: u64, position: Vec3) -> Entity {
+        let mut parameters = BTreeMap::new();
+        parameters.insert("position".to_string(), Parameter::new("position", ParameterValue::Vec3(Vec3::zero())));
+        parameters.insert("scale".to_string(), Parameter::new("scale", ParameterValue::Vec3(Vec3::one())));
+        let box_collider = Component {
+            name: "BoxCollider".to_string(),
+            parameters,
+            component_type: COMPONENT_TYPE_BOX_COLLIDER.clone(),
+        };
+        Entity {
+            name: format!("crate {}", uid),
+            uid,
+            components: vec![Transform::new(position, Quaternion::identity(), Vec3::one()), box_collider],
+            children: Vec::new(),
+            parent: None,
+        }
+    }
+
+    fn static_actors(world_machine: &WorldMachine) -> u32 {
+        let physics = world_machine.physics.lock().unwrap().clone().unwrap();
+        unsafe { PxScene_getNbActors(physics.scene, PxActorTypeFlags::RigidStatic) }
+    }
+
+    #[tokio::test]
+    async fn restoring_a_snapshot_undoes_changes() {
+        let mut world_machine = WorldMachine::default();
+        world_machine.initialise(PhysicsSystem::init(), false);
+        world_machine.world.entities = vec![
+            crate_entity(1, Vec3::new(0.0, 0.0, 0.0)),
+            crate_entity(2, Vec3::new(4.0, 0.0, 0.0))
+        ];
+        world_machine.initialise_entities();
+        let before = world_machine.snapshot().await;
+        assert_eq!(static_actors(&world_machine), 2);
+
+        world_machine.world.entities.remove(0);
+        world_machine.world.entities[0].components[0] = Transform::new(
+            Vec3::new(8.0, 0.0, 0.0),
+            Quaternion::identity(),
+            Vec3::one()
+        );
+        world_machine.world.entities.push(crate_entity(3, Vec3::new(0.0, 4.0, 0.0)));
+        world_machine.world.entities.push(crate_entity(4, Vec3::new(0.0, 8.0, 0.0)));
+        world_machine.tick += 10;
+        world_machine.physics.lock().unwrap().as_ref().unwrap().release_colliders();
+        world_machine.initialise_entities();
+        assert!(!before.diff(&world_machine.snapshot().await).is_empty());
+        assert_eq!(static_actors(&world_machine), 3);
+
+        world_machine.restore_snapshot(&before).await.unwrap();
+        let after = world_machine.snapshot().await;
+        assert!(before.diff(&after).is_empty(), "{}", before.diff(&after));
+        assert_eq!(after.tick, before.tick);
+        assert_eq!(static_actors(&world_machine), 2);
+    }
+}
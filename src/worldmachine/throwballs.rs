@@ -30,8 +30,8 @@ impl ThrowingBall {
         physics: &PhysicsSystem
     ) -> Self {
         info!("creating ThrowingBall (clientside) at {:?}", position);
-        let mut oneshots = crate::audio::ONESHOTS.lock().unwrap();
-        oneshots.push(("donk.wav".to_string(), position));
+        #[cfg(feature = "graphical")]
+        crate::audio::ONESHOTS.lock().unwrap().push(("donk.wav".to_string(), position));
         let phys = physics.create_sphere_actor(position, 0.05, Materials::Player).unwrap();
         phys.add_self_to_scene(physics.clone());
         phys.set_velocity(initial_velocity);
@@ -49,4 +49,19 @@ impl ThrowingBall {
         self.position = position;
         position
     }
+
+    pub fn get_linear_velocity(&self) -> Vec3 {
+        self.physics_object.get_linear_velocity()
+    }
+
+    pub fn get_angular_velocity(&self) -> Vec3 {
+        self.physics_object.get_angular_velocity()
+    }
+
+    pub fn set_state(&mut self, position: Vec3, linear_velocity: Vec3, angular_velocity: Vec3) {
+        self.position = position;
+        self.physics_object.set_position(position);
+        self.physics_object.set_linear_velocity(linear_velocity);
+        self.physics_object.set_angular_velocity(angular_velocity);
+    }
 }